@@ -287,7 +287,7 @@ mod util {
 //     session::{Interests, Role, SessionInit, SessionMode},
 // };
 //
-// const ALPN: &[u8] = b"iroh-willow/0";
+// const ALPN: &[u8] = b"iroh-willow/1";
 //
 // #[tokio::main(flavor = "multi_thread")]
 // async fn main() -> anyhow::Result<()> {
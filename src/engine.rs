@@ -1,38 +1,61 @@
 //! Engine for driving a willow store and synchronisation sessions.
 
-use std::sync::{Arc, OnceLock};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use anyhow::Result;
 use futures_lite::future::Boxed;
 use futures_util::{
     future::{MapErr, Shared},
-    FutureExt, TryFutureExt,
+    FutureExt, Stream, StreamExt, TryFutureExt,
 };
 use iroh::{endpoint::Connection, protocol::ProtocolHandler, Endpoint, NodeId};
+use iroh_blobs::net_protocol::ProtectCb;
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot},
     task::JoinError,
 };
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use tokio_util::task::AbortOnDropHandle;
 use tracing::{debug, error, error_span, Instrument};
 
 use crate::{
+    proto::{
+        data_model::{Path, SubspaceId},
+        keys::NamespaceId,
+    },
     rpc::{client::MemClient, handler::RpcHandler},
     session::{
         intents::{Intent, IntentHandle},
-        SessionInit,
+        Error as SessionError, SessionInfo, SessionInit,
     },
     store::traits::Storage,
 };
 
 mod actor;
+mod events;
 mod peer_manager;
 
+pub(crate) use self::events::EngineEventSender;
 use self::peer_manager::PeerManager;
-pub use self::{actor::ActorHandle, peer_manager::AcceptOpts};
+pub use self::{
+    actor::ActorHandle,
+    events::EngineEvent,
+    peer_manager::{
+        AcceptOpts, AcceptOutcome, AcceptRequest, ConnStatus, ConnectionLimits, PeerHistory,
+        PeerPolicy, PeerStatus, QueuePolicy, RejectReason,
+    },
+};
 
 const PEER_MANAGER_INBOX_CAP: usize = 128;
 
+/// Delay before retrying to list the referenced payloads for the garbage collection, see
+/// [`Engine::protect_cb`]. Doubles after each failure, up to [`PROTECT_RETRY_MAX_DELAY`].
+const PROTECT_RETRY_INITIAL_DELAY: Duration = Duration::from_millis(100);
+const PROTECT_RETRY_MAX_DELAY: Duration = Duration::from_secs(10);
+
 /// The [`Engine`] is the main handle onto a Willow store with networking.
 ///
 /// It runs a dedicated thread for all storage operations, and a peer manager to coordinate network
@@ -45,6 +68,7 @@ pub struct Engine {
     actor_handle: ActorHandle,
     pub(crate) endpoint: Endpoint,
     peer_manager_inbox: mpsc::Sender<peer_manager::Input>,
+    events: EngineEventSender,
     // `Engine` needs to be `Clone + Send`, and we need to `task.await` in its `shutdown()` impl.
     // So we need
     // - `Shared` so we can `task.await` from all `Node` clones
@@ -80,6 +104,9 @@ impl Engine {
     ///
     /// The engine will spawn a dedicated storage thread, and the `create_store` closure will be called on
     /// this thread, so that the [`Storage`] does not have to be `Send`.
+    ///
+    /// If garbage collection is enabled on the blob store which holds the payloads, register
+    /// [`Engine::protect_cb`] with it, otherwise the payloads of stored entries are deleted.
     pub fn spawn<S: Storage>(
         endpoint: Endpoint,
         create_store: impl 'static + Send + FnOnce() -> S,
@@ -88,11 +115,13 @@ impl Engine {
         let me = endpoint.node_id();
         let actor_handle = ActorHandle::spawn(create_store, me);
         let (pm_inbox_tx, pm_inbox_rx) = mpsc::channel(PEER_MANAGER_INBOX_CAP);
+        let (events, _) = broadcast::channel(events::ENGINE_EVENTS_CAP);
         let peer_manager = PeerManager::new(
             actor_handle.clone(),
             endpoint.clone(),
             pm_inbox_rx,
             accept_opts,
+            events.clone(),
         );
         let peer_manager_task = tokio::task::spawn(
             async move { peer_manager.run().await.map_err(|e| e.to_string()) }
@@ -105,6 +134,7 @@ impl Engine {
             actor_handle,
             endpoint,
             peer_manager_inbox: pm_inbox_tx,
+            events,
             peer_manager_task,
             rpc_handler: Default::default(),
         }
@@ -137,6 +167,160 @@ impl Engine {
         Ok(handle)
     }
 
+    /// Fetches the payload of an entry from a peer.
+    ///
+    /// The entry must be in our store already, and we must have an active session with `peer`
+    /// which includes a read capability for the entry. The payload is requested starting at the
+    /// bytes we are missing, and the verified bytes are imported into our blob store.
+    ///
+    /// Sessions in [`SessionMode::ReconcileOnce`] stay open until all payload requests which were
+    /// made before reconciliation finished are completed. Once such a session closed, this
+    /// returns an error.
+    ///
+    /// Returns once the full payload is in our blob store, or with an error if the peer does not
+    /// have the payload. Peers only answer with complete payloads: if the peer has only part of
+    /// the payload, the request is rejected with an error as well.
+    ///
+    /// [`SessionMode::ReconcileOnce`]: crate::session::SessionMode::ReconcileOnce
+    pub async fn fetch_payload(
+        &self,
+        peer: NodeId,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: Path,
+    ) -> Result<()> {
+        let entry = self
+            .get_entry(namespace, subspace, path)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("entry not found"))?;
+        let (reply, reply_rx) = oneshot::channel();
+        self.peer_manager_inbox
+            .send(peer_manager::Input::FetchPayload {
+                peer,
+                entry: entry.into_parts().0,
+                reply,
+            })
+            .await?;
+        // The reply is dropped if the session closed before the request was sent.
+        reply_rx.await.unwrap_or(Err(SessionError::ChannelClosed))?;
+        Ok(())
+    }
+
+    /// Returns the state of all peers with which we have a connection, a session, or intents
+    /// waiting to connect.
+    pub async fn peers(&self) -> Result<Vec<PeerStatus>> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.peer_manager_inbox
+            .send(peer_manager::Input::Peers { reply })
+            .await?;
+        Ok(reply_rx.await?)
+    }
+
+    /// Returns information about the active session with `peer`, or `None` if there is no
+    /// active session with `peer`.
+    pub async fn session_info(&self, peer: NodeId) -> Result<Option<SessionInfo>> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.peer_manager_inbox
+            .send(peer_manager::Input::SessionInfo { peer, reply })
+            .await?;
+        Ok(reply_rx.await?)
+    }
+
+    /// Returns the current [`PeerPolicy`] for incoming connections.
+    pub async fn peer_policy(&self) -> Result<PeerPolicy> {
+        self.update_peer_policy(|_| {}).await
+    }
+
+    /// Replaces the [`PeerPolicy`] for incoming connections.
+    pub async fn set_peer_policy(&self, policy: PeerPolicy) -> Result<()> {
+        self.update_peer_policy(move |current| *current = policy)
+            .await?;
+        Ok(())
+    }
+
+    /// Allows incoming connections from `peer`, see [`PeerPolicy::allow`].
+    pub async fn allow_peer(&self, peer: NodeId) -> Result<()> {
+        self.update_peer_policy(move |policy| policy.allow(peer))
+            .await?;
+        Ok(())
+    }
+
+    /// Denies incoming connections from `peer`, see [`PeerPolicy::deny`].
+    ///
+    /// Existing connections with `peer` are not closed.
+    pub async fn deny_peer(&self, peer: NodeId) -> Result<()> {
+        self.update_peer_policy(move |policy| policy.deny(peer))
+            .await?;
+        Ok(())
+    }
+
+    /// Updates the [`PeerPolicy`] for incoming connections with `update`.
+    ///
+    /// The new policy applies to all connections accepted afterwards, and is returned.
+    pub async fn update_peer_policy(
+        &self,
+        update: impl FnOnce(&mut PeerPolicy) + Send + 'static,
+    ) -> Result<PeerPolicy> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.peer_manager_inbox
+            .send(peer_manager::Input::UpdatePeerPolicy {
+                update: Box::new(update),
+                reply,
+            })
+            .await?;
+        Ok(reply_rx.await?)
+    }
+
+    /// Subscribes to [`EngineEvent`]s from the connections and sessions with all peers.
+    ///
+    /// The stream only contains events which happen after subscribing. Events are never awaited
+    /// by the sessions: if the subscriber does not keep up, the oldest events are dropped from the
+    /// stream, and replaced by an [`EngineEvent::Lagged`] with our own node id.
+    pub fn subscribe_events(&self) -> impl Stream<Item = (NodeId, EngineEvent)> + Send + 'static {
+        let our_node_id = self.endpoint.node_id();
+        BroadcastStream::new(self.events.subscribe()).map(move |event| match event {
+            Ok(event) => event,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                debug!(
+                    missed,
+                    "engine event subscriber lagged, events were dropped"
+                );
+                (our_node_id, EngineEvent::Lagged { missed })
+            }
+        })
+    }
+
+    /// Returns a callback which adds the payloads referenced by stored entries to the live set of
+    /// the blob store's garbage collection.
+    ///
+    /// Register it with [`iroh_blobs::net_protocol::Blobs::add_protected`] before enabling garbage
+    /// collection, otherwise the payloads of stored entries may be deleted.
+    ///
+    /// If the referenced payloads cannot be listed, the callback retries until it succeeds, which
+    /// holds back the garbage collection run instead of letting it delete referenced payloads.
+    pub fn protect_cb(&self) -> ProtectCb {
+        let actor_handle = self.actor_handle.clone();
+        Box::new(move |live| {
+            let actor_handle = actor_handle.clone();
+            Box::pin(async move {
+                let mut delay = PROTECT_RETRY_INITIAL_DELAY;
+                loop {
+                    match actor_handle.referenced_payloads().await {
+                        Ok(hashes) => {
+                            live.extend(hashes);
+                            break;
+                        }
+                        Err(err) => {
+                            error!(?err, ?delay, "failed to get referenced payloads, retry");
+                            tokio::time::sleep(delay).await;
+                            delay = (delay * 2).min(PROTECT_RETRY_MAX_DELAY);
+                        }
+                    }
+                }
+            })
+        })
+    }
+
     /// Shutdown the engine.
     ///
     /// This will try to close all connections gracefully for up to 10 seconds,
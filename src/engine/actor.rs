@@ -1,9 +1,11 @@
-use std::{sync::Arc, thread::JoinHandle};
+use std::{collections::BTreeSet, sync::Arc, thread::JoinHandle};
 
 use anyhow::Result;
 use futures_lite::{stream::Stream, StreamExt};
 use iroh::NodeId;
+use iroh_blobs::Hash;
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     sync::{mpsc, oneshot},
     task::JoinSet,
 };
@@ -11,20 +13,25 @@ use tokio_stream::wrappers::ReceiverStream;
 use tracing::{debug, error, error_span, trace, warn, Instrument};
 
 use crate::{
-    form::{AuthForm, EntryOrForm},
+    form::{AuthForm, EntryForm, EntryOrForm, TimestampForm},
     interest::{CapSelector, CapabilityPack, DelegateTo, InterestMap, Interests},
     net::ConnHandle,
     proto::{
-        data_model::{AuthorisedEntry, Path, SubspaceId},
+        data_model::{AuthorisedEntry, EntryExt as _, Path, SubspaceId},
         grouping::{Area, Range3d},
         keys::{NamespaceId, NamespaceKind, UserId, UserSecretKey},
         meadowcap::{self, AccessMode},
     },
-    session::{intents::Intent, run_session, Error, EventSender, SessionHandle},
+    session::{
+        intents::Intent, run_session, Error, EventSender, SessionHandle, SessionTracker,
+        StaticTokenLimits,
+    },
+    sideload::{self, DropStats},
     store::{
+        backup::{self, BackupStats, ExportOpts},
         traits::{
-            EntryOrigin, EntryReader, EntryStorage, SecretStorage, Storage, StoreEvent,
-            SubscribeParams,
+            EntryOrigin, EntryReader, EntryStorage, SecretStorage, SecretsCredential, Storage,
+            StoreEvent, SubscribeParams,
         },
         Store,
     },
@@ -111,6 +118,45 @@ impl ActorHandle {
         Ok(())
     }
 
+    /// Sets the passphrase or key of the secret store, if its secrets are encrypted at rest.
+    ///
+    /// This encrypts the secrets stored in plaintext and unlocks the store. It fails if the store
+    /// already has a key. See [`crate::store::encrypted`] for details.
+    pub async fn set_secrets_passphrase(
+        &self,
+        credential: impl Into<SecretsCredential>,
+    ) -> Result<()> {
+        let credential = credential.into();
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Input::SetSecretsPassphrase { credential, reply })
+            .await?;
+        reply_rx.await??;
+        Ok(())
+    }
+
+    /// Unlocks the secret store, if its secrets are encrypted at rest.
+    ///
+    /// The key must have been set with [`Self::set_secrets_passphrase`] before. See
+    /// [`crate::store::encrypted`] for details.
+    pub async fn unlock_secrets(&self, credential: impl Into<SecretsCredential>) -> Result<()> {
+        let credential = credential.into();
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Input::UnlockSecrets { credential, reply })
+            .await?;
+        reply_rx.await??;
+        Ok(())
+    }
+
+    /// Locks the secret store, if its secrets are encrypted at rest.
+    ///
+    /// Until the store is unlocked again, operations which need secret keys fail.
+    pub async fn lock_secrets(&self) -> Result<()> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Input::LockSecrets { reply }).await?;
+        reply_rx.await?;
+        Ok(())
+    }
+
     pub async fn get_entry(
         &self,
         namespace: NamespaceId,
@@ -143,15 +189,198 @@ impl ActorHandle {
         Ok(ReceiverStream::new(rx))
     }
 
+    /// Deletes the entry at `path` in `subspace` by writing a tombstone over it.
+    ///
+    /// The tombstone is an entry with an empty payload and a timestamp just after the deleted
+    /// entry. Entries below `path` are pruned as well if they are older than the deleted entry,
+    /// see [`Self::delete_prefix`] to delete all of them.
+    ///
+    /// Returns the tombstone, or `None` if there is no entry at `path` or it is a tombstone
+    /// already. Fails if the entry has the maximum timestamp, because no tombstone can be newer.
+    pub async fn delete(
+        &self,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: Path,
+        auth: impl Into<AuthForm>,
+    ) -> Result<Option<AuthorisedEntry>> {
+        let Some(entry) = self.get_entry(namespace, subspace, path.clone()).await? else {
+            return Ok(None);
+        };
+        if entry.entry().is_tombstone() {
+            return Ok(None);
+        }
+        let timestamp =
+            entry.entry().timestamp().checked_add(1).ok_or_else(|| {
+                anyhow::anyhow!("cannot delete an entry with the maximum timestamp")
+            })?;
+        let mut form = EntryForm::new_tombstone(namespace, path).subspace(subspace);
+        form.timestamp = TimestampForm::Exact(timestamp);
+        let (tombstone, inserted) = self.insert_entry(form, auth).await?;
+        Ok(inserted.then_some(tombstone))
+    }
+
+    /// Deletes all entries in `subspace` whose path starts with `prefix` by writing a tombstone
+    /// at `prefix`.
+    ///
+    /// The tombstone is an entry with an empty payload and the current time as timestamp.
+    ///
+    /// Returns the tombstone, and whether it was inserted. It is not inserted if a newer entry
+    /// exists at `prefix` or a prefix of it.
+    pub async fn delete_prefix(
+        &self,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        prefix: Path,
+        auth: impl Into<AuthForm>,
+    ) -> Result<(AuthorisedEntry, bool)> {
+        let form = EntryForm::new_tombstone(namespace, prefix).subspace(subspace);
+        self.insert_entry(form, auth).await
+    }
+
+    pub async fn forget_entry(
+        &self,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: Path,
+    ) -> Result<bool> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Input::ForgetEntry {
+            namespace,
+            subspace,
+            path,
+            reply,
+        })
+        .await?;
+        reply_rx.await?
+    }
+
+    pub async fn forget_area(&self, namespace: NamespaceId, area: Area) -> Result<u64> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Input::ForgetArea {
+            namespace,
+            area,
+            reply,
+        })
+        .await?;
+        reply_rx.await?
+    }
+
+    pub async fn forget_payload(
+        &self,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: Path,
+    ) -> Result<bool> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Input::ForgetPayload {
+            namespace,
+            subspace,
+            path,
+            reply,
+        })
+        .await?;
+        reply_rx.await?
+    }
+
+    /// Returns the hashes of all payloads referenced by stored entries.
+    ///
+    /// These must be protected from garbage collection in the blob store.
+    pub async fn referenced_payloads(&self) -> Result<BTreeSet<Hash>> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Input::ReferencedPayloads { reply }).await?;
+        reply_rx.await?
+    }
+
+    /// Exports the store to `writer`, and the payloads of the exported entries to `payloads`.
+    ///
+    /// The export runs in the background on a snapshot of the store. See [`backup::export`].
+    pub async fn export(
+        &self,
+        opts: ExportOpts,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+        payloads: Option<Box<dyn AsyncWrite + Send + Unpin>>,
+    ) -> Result<BackupStats> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Input::Export {
+            opts,
+            writer: Box::new(writer),
+            payloads,
+            reply,
+        })
+        .await?;
+        reply_rx.await?
+    }
+
+    /// Imports an export created with [`Self::export`] or [`backup::export`].
+    ///
+    /// See [`backup::import`].
+    pub async fn import(
+        &self,
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        payloads: Option<Box<dyn AsyncRead + Send + Unpin>>,
+    ) -> Result<BackupStats> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Input::Import {
+            reader: Box::new(reader),
+            payloads,
+            reply,
+        })
+        .await?;
+        reply_rx.await?
+    }
+
+    /// Writes an encrypted drop for sideloading to `writer`.
+    ///
+    /// The drop contains the entries selected by `interests` which are included in the read
+    /// capabilities in `caps`, and can only be ingested by the receiver of these capabilities.
+    /// See [`sideload`](crate::sideload) for details.
+    pub async fn create_drop(
+        &self,
+        interests: impl Into<Interests>,
+        caps: Vec<CapabilityPack>,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Result<DropStats> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Input::CreateDrop {
+            interests: interests.into(),
+            caps,
+            writer: Box::new(writer),
+            reply,
+        })
+        .await?;
+        reply_rx.await?
+    }
+
+    /// Ingests a drop created with [`Self::create_drop`].
+    ///
+    /// The secret key of the receiver of the drop must be in our store.
+    pub async fn ingest_drop(
+        &self,
+        reader: impl AsyncRead + Send + Unpin + 'static,
+    ) -> Result<DropStats> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Input::IngestDrop {
+            reader: Box::new(reader),
+            reply,
+        })
+        .await?;
+        reply_rx.await?
+    }
+
     pub(crate) async fn init_session(
         &self,
         conn: ConnHandle,
         intents: Vec<Intent>,
+        static_token_limits: StaticTokenLimits,
+        tracker: SessionTracker,
     ) -> Result<SessionHandle> {
         let (reply, reply_rx) = oneshot::channel();
         self.send(Input::InitSession {
             conn,
             intents,
+            static_token_limits,
+            tracker,
             reply,
         })
         .await?;
@@ -290,6 +519,8 @@ pub enum Input {
     InitSession {
         conn: ConnHandle,
         intents: Vec<Intent>,
+        static_token_limits: StaticTokenLimits,
+        tracker: SessionTracker,
         reply: oneshot::Sender<Result<SessionHandle>>,
     },
     GetEntries {
@@ -313,10 +544,68 @@ pub enum Input {
         auth: AuthForm,
         reply: oneshot::Sender<Result<(AuthorisedEntry, bool), Error>>,
     },
+    ForgetEntry {
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: Path,
+        reply: oneshot::Sender<Result<bool>>,
+    },
+    ForgetArea {
+        namespace: NamespaceId,
+        area: Area,
+        reply: oneshot::Sender<Result<u64>>,
+    },
+    ForgetPayload {
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: Path,
+        reply: oneshot::Sender<Result<bool>>,
+    },
+    ReferencedPayloads {
+        reply: oneshot::Sender<Result<BTreeSet<Hash>>>,
+    },
+    Export {
+        opts: ExportOpts,
+        #[debug(skip)]
+        writer: Box<dyn AsyncWrite + Send + Unpin>,
+        #[debug(skip)]
+        payloads: Option<Box<dyn AsyncWrite + Send + Unpin>>,
+        reply: oneshot::Sender<Result<BackupStats>>,
+    },
+    Import {
+        #[debug(skip)]
+        reader: Box<dyn AsyncRead + Send + Unpin>,
+        #[debug(skip)]
+        payloads: Option<Box<dyn AsyncRead + Send + Unpin>>,
+        reply: oneshot::Sender<Result<BackupStats>>,
+    },
+    CreateDrop {
+        interests: Interests,
+        caps: Vec<CapabilityPack>,
+        #[debug(skip)]
+        writer: Box<dyn AsyncWrite + Send + Unpin>,
+        reply: oneshot::Sender<Result<DropStats>>,
+    },
+    IngestDrop {
+        #[debug(skip)]
+        reader: Box<dyn AsyncRead + Send + Unpin>,
+        reply: oneshot::Sender<Result<DropStats>>,
+    },
     InsertSecret {
         secret: meadowcap::SecretKey,
         reply: oneshot::Sender<Result<()>>,
     },
+    SetSecretsPassphrase {
+        credential: SecretsCredential,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    UnlockSecrets {
+        credential: SecretsCredential,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    LockSecrets {
+        reply: oneshot::Sender<()>,
+    },
     CreateNamespace {
         kind: NamespaceKind,
         owner: UserId,
@@ -422,6 +711,8 @@ impl<S: Storage> Actor<S> {
             Input::InitSession {
                 conn,
                 intents,
+                static_token_limits,
+                tracker,
                 reply,
             } => {
                 let session_id = self.next_session_id();
@@ -436,9 +727,11 @@ impl<S: Storage> Actor<S> {
                     store,
                     conn,
                     intents,
+                    static_token_limits,
                     session_id,
                     EventSender(event_tx),
                     update_rx,
+                    tracker,
                 )
                 .instrument(error_span!("session", peer = %peer.fmt_short()));
 
@@ -507,10 +800,111 @@ impl<S: Storage> Actor<S> {
                 let res = res.map_err(Into::into);
                 send_reply(reply, res)
             }
+            Input::ForgetEntry {
+                namespace,
+                subspace,
+                path,
+                reply,
+            } => {
+                let res = self
+                    .store
+                    .entries()
+                    .forget_entry(namespace, subspace, &path);
+                send_reply(reply, res.map(|entry| entry.is_some()))
+            }
+            Input::ForgetArea {
+                namespace,
+                area,
+                reply,
+            } => {
+                let res = self.store.entries().forget_area(namespace, &area);
+                send_reply(reply, res)
+            }
+            Input::ForgetPayload {
+                namespace,
+                subspace,
+                path,
+                reply,
+            } => {
+                let res = self.store.forget_payload(namespace, subspace, &path).await;
+                send_reply(reply, res)
+            }
+            Input::ReferencedPayloads { reply } => {
+                let res = self
+                    .store
+                    .entries()
+                    .referenced_payloads()
+                    .map(|digests| digests.into_iter().map(Hash::from).collect());
+                send_reply(reply, res)
+            }
+            Input::Export {
+                opts,
+                writer,
+                mut payloads,
+                reply,
+            } => {
+                let storage = self.store.storage().clone();
+                self.tasks.spawn_local(async move {
+                    let payloads = payloads
+                        .as_deref_mut()
+                        .map(|w| w as &mut (dyn AsyncWrite + Unpin));
+                    let res = backup::export(&storage, opts, writer, payloads).await;
+                    reply.send(res).ok();
+                });
+                Ok(())
+            }
+            Input::Import {
+                reader,
+                mut payloads,
+                reply,
+            } => {
+                let storage = self.store.storage().clone();
+                self.tasks.spawn_local(async move {
+                    let payloads = payloads
+                        .as_deref_mut()
+                        .map(|r| r as &mut (dyn AsyncRead + Unpin));
+                    let res = backup::import(&storage, reader, payloads).await;
+                    reply.send(res).ok();
+                });
+                Ok(())
+            }
+            Input::CreateDrop {
+                interests,
+                caps,
+                writer,
+                reply,
+            } => {
+                let store = self.store.clone();
+                self.tasks.spawn_local(async move {
+                    let res = sideload::create_drop(&store, interests, caps, writer).await;
+                    reply.send(res).ok();
+                });
+                Ok(())
+            }
+            Input::IngestDrop { reader, reply } => {
+                let store = self.store.clone();
+                self.tasks.spawn_local(async move {
+                    let res = sideload::ingest_drop(&store, reader).await;
+                    reply.send(res).ok();
+                });
+                Ok(())
+            }
             Input::InsertSecret { secret, reply } => {
                 let res = self.store.secrets().insert(secret);
                 send_reply(reply, res.map_err(anyhow::Error::from))
             }
+            Input::SetSecretsPassphrase { credential, reply } => {
+                let res = self.store.secrets().set_passphrase(&credential);
+                send_reply(reply, res.map_err(anyhow::Error::from))
+            }
+            Input::UnlockSecrets { credential, reply } => {
+                let res = self.store.secrets().unlock(&credential);
+                send_reply(reply, res.map_err(anyhow::Error::from))
+            }
+            Input::LockSecrets { reply } => {
+                self.store.secrets().lock();
+                send_reply(reply, ())
+            }
             Input::CreateNamespace { kind, owner, reply } => {
                 let res = self
                     .store
@@ -0,0 +1,65 @@
+//! Events from all sessions of the engine, see [`Engine::subscribe_events`].
+//!
+//! [`Engine::subscribe_events`]: super::Engine::subscribe_events
+
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    proto::{
+        grouping::serde_encoding::{SerdeArea, SerdeAreaOfInterest},
+        keys::NamespaceId,
+    },
+    session::Role,
+};
+
+/// Capacity of the event broadcast channel.
+///
+/// Subscribers which fall behind by more than this many events miss the oldest events.
+pub(super) const ENGINE_EVENTS_CAP: usize = 1024;
+
+/// Sender for [`EngineEvent`]s to all subscribers.
+pub(crate) type EngineEventSender = broadcast::Sender<(NodeId, EngineEvent)>;
+
+/// An event from a connection or session with a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EngineEvent {
+    /// A connection to the peer was established.
+    ConnectionEstablished {
+        /// Our role in the connection.
+        role: Role,
+    },
+    /// The connection to the peer was closed.
+    ConnectionClosed {
+        /// The error if the connection was not closed gracefully.
+        error: Option<String>,
+    },
+    /// A session with the peer was started.
+    SessionStarted {
+        /// Our role in the session.
+        role: Role,
+    },
+    /// The session with the peer completed.
+    SessionCompleted {
+        /// The result of the session.
+        result: Result<(), String>,
+    },
+    /// Our and their capabilities intersect in an area.
+    CapabilityIntersection {
+        namespace: NamespaceId,
+        area: SerdeArea,
+    },
+    /// An area of interest was reconciled.
+    Reconciled {
+        namespace: NamespaceId,
+        area: SerdeAreaOfInterest,
+    },
+    /// The subscriber did not keep up, and missed events.
+    ///
+    /// This event is not about a peer: it is emitted with our own node id.
+    Lagged {
+        /// The number of missed events.
+        missed: u64,
+    },
+}
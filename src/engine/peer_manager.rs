@@ -1,32 +1,45 @@
-use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{Context, Result};
 use futures_buffered::join_all;
 use futures_lite::{future::Boxed, StreamExt};
 use futures_util::{FutureExt, TryFutureExt};
 use iroh::{
-    endpoint::{Connection, ConnectionError},
+    endpoint::{Connection, ConnectionError, ConnectionType, RemoteInfo},
     Endpoint, NodeId,
 };
+use serde::{Deserialize, Serialize};
 use tokio::{
     sync::{mpsc, oneshot},
     task::{AbortHandle, JoinSet},
+    time::Instant,
 };
 use tokio_stream::{wrappers::ReceiverStream, StreamMap};
 use tokio_util::{either::Either, sync::CancellationToken, task::AbortOnDropHandle};
 use tracing::{debug, error_span, instrument, trace, warn, Instrument, Span};
 
-use super::actor::ActorHandle;
+use super::{
+    actor::ActorHandle,
+    events::{EngineEvent, EngineEventSender},
+};
 use crate::{
     interest::Interests,
     net::{
-        establish, prepare_channels, terminate_gracefully, ChannelStreams, ConnHandle, ALPN,
-        ERROR_CODE_DUPLICATE_CONN, ERROR_CODE_SHUTDOWN,
+        establish, prepare_channels, terminate_gracefully, ChannelStats, ChannelStreams,
+        ConnHandle, ALPN, DEFAULT_SESSION_MEMORY_LIMIT, ERROR_CODE_BUSY, ERROR_CODE_DENIED,
+        ERROR_CODE_DUPLICATE_CONN, ERROR_CODE_REJECTED, ERROR_CODE_SHUTDOWN,
     },
-    proto::wgps::AccessChallenge,
+    proto::{data_model::Entry, wgps::AccessChallenge},
     session::{
         intents::{EventKind, EventReceiver, Intent},
-        Error, InitialTransmission, Role, SessionEvent, SessionHandle, SessionInit, SessionUpdate,
+        Error, InitialTransmission, ReconcileOpts, Role, SessionEvent, SessionHandle, SessionInfo,
+        SessionInit, SessionTracker, SessionUpdate, StaticTokenLimits,
     },
 };
 
@@ -40,15 +53,33 @@ const GRACEFUL_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 /// * Do not track events for sessions created from incoming connections for which we did not
 ///   signal a specific interest ourselves as well
 ///
-/// Use [`Self::accept_custom`] to customize which sessions to accept, and which interests to
-/// submit.
+/// Use [`Self::accept_custom`] or [`Self::accept_with`] to customize which sessions to accept,
+/// and which interests to submit.
+///
+/// Use [`Self::peer_policy`] to allow or deny incoming connections by [`NodeId`]. The policy can
+/// be updated at runtime with [`Engine::update_peer_policy`].
 ///
 /// Use [`Self::track_events`] to receive events for sessions we accepted.
+///
+/// Use [`Self::session_memory_limit`] to limit the memory used for buffering incoming messages.
+///
+/// Use [`Self::static_token_limits`] to limit the number of static token handles per session.
+///
+/// Use [`Self::reconcile_opts`] to tune the reconciliation of accepted sessions.
+///
+/// Use [`Self::connection_limits`] to limit the number of concurrent connections and sessions.
+///
+/// [`Engine::update_peer_policy`]: super::Engine::update_peer_policy
 #[derive(derive_more::Debug, Default)]
 pub struct AcceptOpts {
     #[debug("{:?}", accept_cb.as_ref().map(|_| "_"))]
     accept_cb: Option<AcceptCb>,
+    peer_policy: PeerPolicy,
     track_events: Option<mpsc::Sender<(NodeId, EventKind)>>,
+    session_memory_limit: Option<usize>,
+    static_token_limits: StaticTokenLimits,
+    reconcile_opts: Option<ReconcileOpts>,
+    connection_limits: ConnectionLimits,
 }
 
 impl AcceptOpts {
@@ -61,19 +92,54 @@ impl AcceptOpts {
     ///
     /// The default behavior, if not registering a callback, is to accept all incoming connections with
     /// interests in everything we have and in live session mode.
-    pub fn accept_custom<F, Fut>(mut self, cb: F) -> Self
+    ///
+    /// See [`Self::accept_with`] for a callback which gets more information about the connection.
+    pub fn accept_custom<F, Fut>(self, cb: F) -> Self
     where
         F: Fn(NodeId) -> Fut + Send + Sync + 'static,
         Fut: Future<Output = Option<SessionInit>> + Send + 'static,
     {
-        let cb = Box::new(move |peer: NodeId| {
-            let fut: Boxed<Option<SessionInit>> = Box::pin((cb)(peer));
+        self.accept_with(move |request: AcceptRequest| {
+            let fut = (cb)(request.peer);
+            async move {
+                match fut.await {
+                    Some(init) => AcceptOutcome::Accept(init),
+                    None => AcceptOutcome::Reject(RejectReason::Other("not accepted".to_string())),
+                }
+            }
+        })
+    }
+
+    /// Registers a callback to determine the fate of incoming connections.
+    ///
+    /// The callback gets an [`AcceptRequest`] with information about the connection and our
+    /// history with the connecting peer, and must return a future that resolves to an
+    /// [`AcceptOutcome`]. When rejecting a connection, the [`RejectReason`] is sent to the other
+    /// peer when closing the connection.
+    ///
+    /// The callback is only called for peers which are allowed by the [`PeerPolicy`].
+    pub fn accept_with<F, Fut>(mut self, cb: F) -> Self
+    where
+        F: Fn(AcceptRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = AcceptOutcome> + Send + 'static,
+    {
+        let cb = Box::new(move |request: AcceptRequest| {
+            let fut: Boxed<AcceptOutcome> = Box::pin((cb)(request));
             fut
         });
         self.accept_cb = Some(cb);
         self
     }
 
+    /// Sets the initial [`PeerPolicy`] for incoming connections.
+    ///
+    /// Connections from peers which are not allowed by the policy are closed before calling the
+    /// accept callback. Defaults to allowing all peers.
+    pub fn peer_policy(mut self, policy: PeerPolicy) -> Self {
+        self.peer_policy = policy;
+        self
+    }
+
     /// Registers an event channel for events from accepted connections.
     ///
     /// If called, the passed [`mpsc::Sender`] will receive all events emitted from session
@@ -86,6 +152,275 @@ impl AcceptOpts {
         self.track_events = Some(sender);
         self
     }
+
+    /// Sets the maximum number of bytes each session may buffer for incoming messages.
+    ///
+    /// The limit applies to all sessions, both accepted and initiated by us. It is split evenly
+    /// between the channels of a session, and we only issue as many guarantees to the other peer
+    /// as fit into our buffers. Each channel will buffer at least 48 KiB, regardless of this limit.
+    ///
+    /// Defaults to 448 KiB (64 KiB per channel).
+    pub fn session_memory_limit(mut self, bytes: usize) -> Self {
+        self.session_memory_limit = Some(bytes);
+        self
+    }
+
+    /// Sets the limits for static token handles bound in each session.
+    ///
+    /// Long-running sessions bind a static token handle for each author they send entries for.
+    /// Once more than [`StaticTokenLimits::max_handles`] handles are bound, we free handles
+    /// selected by [`StaticTokenLimits::eviction`].
+    ///
+    /// The limits apply to all sessions, both accepted and initiated by us.
+    pub fn static_token_limits(mut self, limits: StaticTokenLimits) -> Self {
+        self.static_token_limits = limits;
+        self
+    }
+
+    /// Sets the tuning parameters for the reconciliation of accepted sessions.
+    ///
+    /// These are used for accepted sessions whose [`SessionInit`] does not set
+    /// [`SessionInit::reconcile_opts`] itself, including the sessions accepted by default.
+    pub fn reconcile_opts(mut self, opts: ReconcileOpts) -> Self {
+        self.reconcile_opts = Some(opts);
+        self
+    }
+
+    /// Sets the limits for concurrent connections and sessions.
+    ///
+    /// The limits apply to all sessions, both accepted and initiated by us. Incoming connections
+    /// over the limits are rejected with [`RejectReason::Busy`], and outgoing intents over the
+    /// limits are handled according to [`ConnectionLimits::queue_policy`].
+    ///
+    /// Defaults to no limits.
+    pub fn connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connection_limits = limits;
+        self
+    }
+}
+
+/// Limits for concurrent connections and sessions, see [`AcceptOpts::connection_limits`].
+///
+/// A session counts against the limits from the moment we start to establish its connection
+/// until the connection is closed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionLimits {
+    /// Maximum number of concurrent sessions, including connections which are being established.
+    pub max_sessions: Option<usize>,
+    /// Maximum number of outgoing connections which are being established concurrently.
+    ///
+    /// Incoming connections are not limited by this, only by the limits on sessions.
+    pub max_pending_connects: Option<usize>,
+    /// Maximum number of concurrent sessions with the same remote.
+    ///
+    /// Sessions count as the same remote if they are with the same [`NodeId`], or with peers
+    /// connected directly from the same IP address. Applies to both incoming and outgoing
+    /// connections, whether direct or relayed.
+    pub max_sessions_per_remote: Option<usize>,
+    /// What to do with outgoing intents which would exceed the limits.
+    pub queue_policy: QueuePolicy,
+}
+
+/// What to do with outgoing intents which would exceed the [`ConnectionLimits`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueuePolicy {
+    /// Keep the intents until a session slot is free, and connect to their peers in the order in
+    /// which they were submitted.
+    #[default]
+    Wait,
+    /// Abort the intents with an error.
+    Abort,
+}
+
+/// Information about an incoming connection, passed to the callback registered with
+/// [`AcceptOpts::accept_with`].
+#[derive(Debug, Clone)]
+pub struct AcceptRequest {
+    /// The connecting peer.
+    pub peer: NodeId,
+    /// What our endpoint knows about the addresses of the connecting peer.
+    pub remote_info: Option<RemoteInfo>,
+    /// The ALPN negotiated for the connection.
+    pub alpn: Option<Vec<u8>>,
+    /// Whether we have intents of our own which wait for a connection to this peer.
+    pub has_outgoing_intent: bool,
+    /// Counters for our past sessions with this peer.
+    pub history: PeerHistory,
+}
+
+/// Counters for our past sessions and connections with a peer.
+///
+/// The counters are kept in memory since the engine was started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerHistory {
+    /// Number of sessions which completed without error.
+    pub sessions: u64,
+    /// Number of sessions which failed.
+    pub failed_sessions: u64,
+    /// Number of incoming connections which we rejected.
+    pub rejected: u64,
+}
+
+/// The state of a peer known to the engine, see [`Engine::peers`].
+///
+/// [`Engine::peers`]: super::Engine::peers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerStatus {
+    /// The node id of the peer.
+    pub node_id: NodeId,
+    /// The state of our connection to the peer.
+    pub conn: ConnStatus,
+    /// The active session with the peer, if any.
+    pub session: Option<SessionInfo>,
+    /// Number of intents which wait for a session with the peer.
+    pub pending_intents: usize,
+    /// Number of intents which wait to reconnect to the peer after a failure.
+    pub retrying_intents: usize,
+    /// Whether we wait for a free session slot to connect to the peer, see [`QueuePolicy::Wait`].
+    pub queued: bool,
+    /// Counters for our past sessions with the peer.
+    pub history: PeerHistory,
+}
+
+/// The state of the connection to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConnStatus {
+    /// There is no connection.
+    None,
+    /// The connection is being established.
+    Establishing {
+        /// Whether we dialed the peer, or the peer connected to us.
+        our_dial: bool,
+    },
+    /// The connection is active.
+    Active,
+    /// The connection is closing.
+    Terminating,
+}
+
+/// The outcome of the accept callback, see [`AcceptOpts::accept_with`].
+#[derive(Debug)]
+pub enum AcceptOutcome {
+    /// Accept the connection and start a session with these options.
+    Accept(SessionInit),
+    /// Reject the connection.
+    Reject(RejectReason),
+}
+
+/// Why an incoming connection was rejected.
+///
+/// The reason is sent to the other peer as the close code of the connection, and is reported as
+/// [`Error::Rejected`] to the intents of the other peer.
+#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
+pub enum RejectReason {
+    /// The peer is denied by the [`PeerPolicy`].
+    #[display("denied by peer policy")]
+    Denied,
+    /// The [`ConnectionLimits`] of the peer are reached.
+    #[display("peer is busy")]
+    Busy,
+    /// The connection was rejected for another reason.
+    #[display("{_0}")]
+    Other(String),
+}
+
+impl RejectReason {
+    fn close(&self, conn: &Connection) {
+        match self {
+            Self::Denied => conn.close(ERROR_CODE_DENIED, b"denied"),
+            Self::Busy => conn.close(ERROR_CODE_BUSY, b"busy"),
+            Self::Other(reason) => conn.close(ERROR_CODE_REJECTED, reason.as_bytes()),
+        }
+    }
+
+    /// Returns the reason if the connection was closed by the other peer because it rejected us.
+    fn from_conn_error(err: &ConnectionError) -> Option<Self> {
+        let ConnectionError::ApplicationClosed(close) = err else {
+            return None;
+        };
+        if close.error_code == ERROR_CODE_DENIED {
+            Some(Self::Denied)
+        } else if close.error_code == ERROR_CODE_BUSY {
+            Some(Self::Busy)
+        } else if close.error_code == ERROR_CODE_REJECTED {
+            Some(Self::Other(
+                String::from_utf8_lossy(&close.reason).into_owned(),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// An allowlist and denylist for incoming connections.
+///
+/// The denylist takes precedence: a peer is allowed if it is not denied, and either there is no
+/// allowlist or the peer is on the allowlist.
+///
+/// The policy only applies to incoming connections, we still connect to denied peers if we submit
+/// intents for them. Updating the policy does not close existing connections.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerPolicy {
+    allow: Option<HashSet<NodeId>>,
+    deny: HashSet<NodeId>,
+}
+
+impl PeerPolicy {
+    /// Creates a policy which allows all peers that are not denied.
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// Creates a policy which only allows `peers`.
+    pub fn allowlist(peers: impl IntoIterator<Item = NodeId>) -> Self {
+        Self {
+            allow: Some(peers.into_iter().collect()),
+            deny: Default::default(),
+        }
+    }
+
+    /// Allows `peer`: removes it from the denylist, and adds it to the allowlist if there is one.
+    pub fn allow(&mut self, peer: NodeId) {
+        self.deny.remove(&peer);
+        if let Some(allow) = &mut self.allow {
+            allow.insert(peer);
+        }
+    }
+
+    /// Denies `peer`: adds it to the denylist, and removes it from the allowlist.
+    pub fn deny(&mut self, peer: NodeId) {
+        self.deny.insert(peer);
+        if let Some(allow) = &mut self.allow {
+            allow.remove(&peer);
+        }
+    }
+
+    /// Removes `peer` from both the allowlist and the denylist.
+    pub fn remove(&mut self, peer: &NodeId) {
+        self.deny.remove(peer);
+        if let Some(allow) = &mut self.allow {
+            allow.remove(peer);
+        }
+    }
+
+    /// Returns `true` if incoming connections from `peer` are allowed.
+    pub fn is_allowed(&self, peer: &NodeId) -> bool {
+        !self.deny.contains(peer)
+            && self
+                .allow
+                .as_ref()
+                .map_or(true, |allow| allow.contains(peer))
+    }
+
+    /// Returns the allowlist, or `None` if all peers which are not denied are allowed.
+    pub fn allowed(&self) -> Option<&HashSet<NodeId>> {
+        self.allow.as_ref()
+    }
+
+    /// Returns the denylist.
+    pub fn denied(&self) -> &HashSet<NodeId> {
+        &self.deny
+    }
 }
 
 /// Input commands for the [`PeerManager`] actor.
@@ -99,12 +434,29 @@ pub(super) enum Input {
         #[debug("Connection")]
         conn: Connection,
     },
+    FetchPayload {
+        peer: NodeId,
+        entry: Entry,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    Peers {
+        reply: oneshot::Sender<Vec<PeerStatus>>,
+    },
+    SessionInfo {
+        peer: NodeId,
+        reply: oneshot::Sender<Option<SessionInfo>>,
+    },
+    UpdatePeerPolicy {
+        #[debug("_")]
+        update: Box<dyn FnOnce(&mut PeerPolicy) + Send + 'static>,
+        reply: oneshot::Sender<PeerPolicy>,
+    },
     Shutdown {
         reply: oneshot::Sender<()>,
     },
 }
 
-type AcceptCb = Box<dyn Fn(NodeId) -> Boxed<Option<SessionInit>> + Send + Sync + 'static>;
+type AcceptCb = Box<dyn Fn(AcceptRequest) -> Boxed<AcceptOutcome> + Send + Sync + 'static>;
 
 /// Manages incoming and outgoing connections.
 #[derive(Debug)]
@@ -114,8 +466,21 @@ pub(super) struct PeerManager {
     inbox: mpsc::Receiver<Input>,
     session_events_rx: StreamMap<NodeId, ReceiverStream<SessionEvent>>,
     peers: HashMap<NodeId, PeerInfo>,
+    history: HashMap<NodeId, PeerHistory>,
     accept_handlers: AcceptHandlers,
+    session_memory_limit: usize,
+    static_token_limits: StaticTokenLimits,
+    connection_limits: ConnectionLimits,
+    /// Peers with intents waiting for a free session slot, see [`QueuePolicy::Wait`].
+    connect_queue: VecDeque<NodeId>,
     conn_tasks: JoinSet<(NodeId, ConnStep)>,
+    /// Sender for [`EngineEvent`]s. Sending only fails if there are no subscribers, so send
+    /// errors are ignored.
+    events: EngineEventSender,
+    /// Intents waiting to reconnect to their peer, see [`RetryPolicy`].
+    ///
+    /// [`RetryPolicy`]: crate::session::RetryPolicy
+    retry_queue: Vec<RetryingIntent>,
     shutting_down: bool,
 }
 
@@ -125,15 +490,28 @@ impl PeerManager {
         endpoint: Endpoint,
         inbox: mpsc::Receiver<Input>,
         accept_opts: AcceptOpts,
+        events: EngineEventSender,
     ) -> Self {
+        let session_memory_limit = accept_opts
+            .session_memory_limit
+            .unwrap_or(DEFAULT_SESSION_MEMORY_LIMIT);
+        let static_token_limits = accept_opts.static_token_limits;
+        let connection_limits = accept_opts.connection_limits;
         PeerManager {
             endpoint: endpoint.clone(),
             actor: actor_handle,
             inbox,
             session_events_rx: Default::default(),
             peers: Default::default(),
+            history: Default::default(),
             accept_handlers: AcceptHandlers::new(accept_opts),
+            session_memory_limit,
+            static_token_limits,
+            connection_limits,
+            connect_queue: Default::default(),
             conn_tasks: Default::default(),
+            events,
+            retry_queue: Default::default(),
             shutting_down: false,
         }
     }
@@ -146,12 +524,26 @@ impl PeerManager {
         let mut shutdown_reply = None;
 
         loop {
+            let next_retry = self.retry_queue.iter().map(|retry| retry.at).min();
             tokio::select! {
                 Some(input) = self.inbox.recv(), if !self.shutting_down => {
                     trace!(?input, "tick: inbox");
                     match input {
                         Input::SubmitIntent { peer, intent } => self.submit_intent(peer, intent).await,
                         Input::HandleConnection { conn } => self.handle_connection(conn).await,
+                        Input::FetchPayload { peer, entry, reply } => self.fetch_payload(peer, entry, reply).await,
+                        Input::Peers { reply } => {
+                            reply.send(self.peer_statuses()).ok();
+                        }
+                        Input::SessionInfo { peer, reply } => {
+                            let info = self.peers.get(&peer).and_then(|info| info.session_state.info());
+                            reply.send(info).ok();
+                        }
+                        Input::UpdatePeerPolicy { update, reply } => {
+                            let policy = &mut self.accept_handlers.peer_policy;
+                            update(policy);
+                            reply.send(policy.clone()).ok();
+                        }
                         Input::Shutdown { reply } => {
                             self.init_shutdown().await;
                             if self.conn_tasks.is_empty() {
@@ -166,7 +558,12 @@ impl PeerManager {
                 }
                 Some((peer, event)) = self.session_events_rx.next(), if !self.session_events_rx.is_empty() => {
                     trace!(peer=%peer.fmt_short(), ?event, "tick: session event");
-                    self.handle_session_event(peer, event);
+                    self.handle_session_event(peer, event).await;
+                    self.connect_queued().await;
+                }
+                _ = tokio::time::sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => {
+                    trace!("tick: retry timer");
+                    self.resubmit_due_intents().await;
                 }
                 Some(res) = self.conn_tasks.join_next(), if !self.conn_tasks.is_empty() => {
                     trace!(active=self.conn_tasks.len(), "tick: conn task joined");
@@ -175,6 +572,7 @@ impl PeerManager {
                         Err(err) => Err(err).context("conn task panicked")?,
                         Ok((peer, out)) => self.handle_conn_output(peer, out).await?,
                     }
+                    self.connect_queued().await;
                     if self.shutting_down && self.conn_tasks.is_empty() {
                         debug!("all connections gracefully terminated");
                         break;
@@ -209,14 +607,34 @@ impl PeerManager {
             }
         };
 
-        let Some(intent) = self.accept_handlers.accept(peer).await else {
-            debug!("ignore incoming connection (accept handler returned none)");
-            return;
+        let request = AcceptRequest {
+            peer,
+            remote_info: self.endpoint.remote_info(peer),
+            alpn: conn.alpn(),
+            has_outgoing_intent: self.has_outgoing_intent(peer),
+            history: self.history.get(&peer).copied().unwrap_or_default(),
+        };
+        let remote_ip = request.remote_info.as_ref().and_then(direct_ip);
+        let res = match self.check_incoming_limits(peer, remote_ip) {
+            Ok(()) => self.accept_handlers.accept(request).await,
+            Err(reason) => Err(reason),
+        };
+        let intent = match res {
+            Ok(intent) => intent,
+            Err(reason) => {
+                debug!(peer = %peer.fmt_short(), %reason, "reject incoming connection");
+                self.history.entry(peer).or_default().rejected += 1;
+                reason.close(&conn);
+                return;
+            }
         };
         let peer_info = self
             .peers
             .entry(peer)
             .or_insert_with(|| PeerInfo::new(peer));
+        if remote_ip.is_some() {
+            peer_info.remote_ip = remote_ip;
+        }
 
         debug!(peer = %peer.fmt_short(), our_state=%peer_info.conn_state, "incoming connection");
 
@@ -275,6 +693,157 @@ impl PeerManager {
         }
     }
 
+    /// Checks if accepting a connection from `peer` would exceed our [`ConnectionLimits`].
+    fn check_incoming_limits(
+        &self,
+        peer: NodeId,
+        remote_ip: Option<IpAddr>,
+    ) -> Result<(), RejectReason> {
+        // Incoming connections for peers with which we already have a connection do not take a
+        // new session slot: either they replace our dial or they are rejected as duplicates.
+        if self.peers.get(&peer).is_some_and(|info| info.has_slot()) {
+            return Ok(());
+        }
+        if self.sessions_limit_reached() || self.remote_limit_reached(peer, remote_ip) {
+            Err(RejectReason::Busy)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns `true` if dialing `peer` would exceed our [`ConnectionLimits`].
+    fn dial_limits_reached(&self, peer: NodeId) -> bool {
+        self.outgoing_limits_reached() || self.remote_limit_reached(peer, self.remote_ip(peer))
+    }
+
+    /// Returns `true` if a new outgoing connection would exceed the global [`ConnectionLimits`].
+    fn outgoing_limits_reached(&self) -> bool {
+        let connects_reached = self
+            .connection_limits
+            .max_pending_connects
+            .is_some_and(|max| {
+                let connects = self
+                    .peers
+                    .values()
+                    .filter(|info| {
+                        matches!(
+                            info.conn_state,
+                            ConnState::Establishing {
+                                our_dial: Some(_),
+                                ..
+                            }
+                        )
+                    })
+                    .count();
+                connects >= max
+            });
+        connects_reached || self.sessions_limit_reached()
+    }
+
+    /// Returns `true` if a new session would exceed [`ConnectionLimits::max_sessions`].
+    fn sessions_limit_reached(&self) -> bool {
+        self.connection_limits.max_sessions.is_some_and(|max| {
+            let sessions = self.peers.values().filter(|info| info.has_slot()).count();
+            sessions >= max
+        })
+    }
+
+    /// Returns `true` if a new session with `peer` would exceed
+    /// [`ConnectionLimits::max_sessions_per_remote`].
+    fn remote_limit_reached(&self, peer: NodeId, remote_ip: Option<IpAddr>) -> bool {
+        self.connection_limits
+            .max_sessions_per_remote
+            .is_some_and(|max| {
+                let sessions = self
+                    .peers
+                    .values()
+                    .filter(|info| {
+                        info.has_slot()
+                            && (info.node_id == peer
+                                || (remote_ip.is_some() && info.remote_ip == remote_ip))
+                    })
+                    .count();
+                sessions >= max
+            })
+    }
+
+    /// Returns the IP address of our direct connection to `peer`, if any.
+    fn remote_ip(&self, peer: NodeId) -> Option<IpAddr> {
+        self.endpoint.remote_info(peer).as_ref().and_then(direct_ip)
+    }
+
+    /// Connects to queued peers while our [`ConnectionLimits`] permit.
+    ///
+    /// Peers over [`ConnectionLimits::max_sessions_per_remote`] stay queued, without blocking
+    /// the peers queued after them.
+    async fn connect_queued(&mut self) {
+        while !self.shutting_down && !self.outgoing_limits_reached() {
+            let Some(pos) = self
+                .connect_queue
+                .iter()
+                .position(|peer| !self.dial_limits_reached(*peer))
+            else {
+                break;
+            };
+            let peer = self
+                .connect_queue
+                .remove(pos)
+                .expect("position is in bounds");
+            if self
+                .peers
+                .get(&peer)
+                .is_some_and(|info| !info.pending_intents.is_empty())
+            {
+                debug!(peer=%peer.fmt_short(), "connect queued peer");
+                self.connect_if_inactive(peer).await;
+            }
+        }
+    }
+
+    /// Returns the state of all peers with which we have a connection or intents.
+    fn peer_statuses(&self) -> Vec<PeerStatus> {
+        let mut node_ids: Vec<NodeId> = self.peers.keys().copied().collect();
+        for retry in &self.retry_queue {
+            if !node_ids.contains(&retry.peer) {
+                node_ids.push(retry.peer);
+            }
+        }
+        node_ids
+            .into_iter()
+            .map(|node_id| {
+                let info = self.peers.get(&node_id);
+                PeerStatus {
+                    node_id,
+                    conn: info.map_or(ConnStatus::None, |info| info.conn_state.status()),
+                    session: info.and_then(|info| info.session_state.info()),
+                    pending_intents: info.map_or(0, |info| info.pending_intents.len()),
+                    retrying_intents: self
+                        .retry_queue
+                        .iter()
+                        .filter(|retry| retry.peer == node_id)
+                        .count(),
+                    queued: self.connect_queue.contains(&node_id),
+                    history: self.history.get(&node_id).copied().unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns `true` if we have intents for `peer` which wait for a connection.
+    fn has_outgoing_intent(&self, peer: NodeId) -> bool {
+        let pending = self.peers.get(&peer).is_some_and(|info| {
+            !info.pending_intents.is_empty()
+                || matches!(
+                    info.conn_state,
+                    ConnState::Establishing {
+                        our_dial: Some(_),
+                        ..
+                    }
+                )
+        });
+        pending || self.retry_queue.iter().any(|retry| retry.peer == peer)
+    }
+
     async fn submit_intent(&mut self, peer: NodeId, intent: Intent) {
         let peer_info = self
             .peers
@@ -283,16 +852,71 @@ impl PeerManager {
 
         debug!(peer=%peer.fmt_short(), state=%peer_info.conn_state, "submit intent");
         if !peer_info.push_intent(intent).await {
-            self.connect_if_inactive(peer);
+            self.connect_if_inactive(peer).await;
         }
     }
 
-    fn connect_if_inactive(&mut self, peer: NodeId) {
+    /// Requests a payload from a peer with which we have an active session.
+    ///
+    /// Does not connect to the peer: the payload request needs the read capabilities which were
+    /// bound in the session.
+    async fn fetch_payload(
+        &mut self,
+        peer: NodeId,
+        entry: Entry,
+        reply: oneshot::Sender<Result<(), Error>>,
+    ) {
+        let Some(SessionState::Active { update_tx, .. }) =
+            self.peers.get(&peer).map(|info| &info.session_state)
+        else {
+            debug!(peer=%peer.fmt_short(), "no active session, cannot fetch payload");
+            reply.send(Err(Error::SessionNotFound)).ok();
+            return;
+        };
+        if let Err(err) = update_tx
+            .send(SessionUpdate::FetchPayload { entry, reply })
+            .await
+        {
+            if let SessionUpdate::FetchPayload { reply, .. } = err.0 {
+                reply.send(Err(Error::SessionNotFound)).ok();
+            }
+        }
+    }
+
+    async fn connect_if_inactive(&mut self, peer: NodeId) {
+        let limits_reached = self.dial_limits_reached(peer);
+        let remote_ip = self.remote_ip(peer);
         let peer_info = self
             .peers
             .entry(peer)
             .or_insert_with(|| PeerInfo::new(peer));
-        if matches!(peer_info.conn_state, ConnState::None) {
+        if matches!(peer_info.conn_state, ConnState::None) && limits_reached {
+            match self.connection_limits.queue_policy {
+                QueuePolicy::Wait => {
+                    if !self.connect_queue.contains(&peer) {
+                        debug!(peer=%peer.fmt_short(), "connection limits reached, queue peer");
+                        self.connect_queue.push_back(peer);
+                    }
+                }
+                QueuePolicy::Abort => {
+                    debug!(peer=%peer.fmt_short(), "connection limits reached, abort intents");
+                    let intents = std::mem::take(&mut peer_info.pending_intents);
+                    if peer_info.session_state.is_none() {
+                        self.peers.remove(&peer);
+                    }
+                    let error = Arc::new(Error::ConnectionLimitReached);
+                    join_all(
+                        intents
+                            .into_iter()
+                            .map(|intent| intent.send_abort(error.clone())),
+                    )
+                    .await;
+                }
+            }
+        } else if matches!(peer_info.conn_state, ConnState::None) {
+            if remote_ip.is_some() {
+                peer_info.remote_ip = remote_ip;
+            }
             let our_nonce = AccessChallenge::generate();
             let endpoint = self.endpoint.clone();
             let cancel_dial = CancellationToken::new();
@@ -308,7 +932,11 @@ impl PeerManager {
                     }
                 }?;
                 let (initial_transmission, channel_streams) = tokio::select! {
-                    res = establish(&conn, Role::Alfie, our_nonce) => res?,
+                    res = establish(&conn, Role::Alfie, our_nonce) => match res {
+                        Ok(res) => res,
+                        // Report why the other peer closed the connection, e.g. because it rejected us.
+                        Err(err) => return Err(conn.close_reason().map_or(err, Into::into)),
+                    },
                     _ = cancel_dial.cancelled() => {
                         debug!("dial cancelled during establish");
                         conn.close(ERROR_CODE_DUPLICATE_CONN, b"duplicate-your-dial-wins");
@@ -331,22 +959,102 @@ impl PeerManager {
         }
     }
 
+    /// Schedules intents with a retry policy to reconnect after a failure, and aborts all others.
+    async fn retry_or_abort_intents(
+        &mut self,
+        peer: NodeId,
+        intents: Vec<Intent>,
+        error: Arc<Error>,
+    ) {
+        if self.shutting_down || matches!(*error, Error::Rejected(RejectReason::Denied)) {
+            let error = if self.shutting_down {
+                Arc::new(Error::ShuttingDown)
+            } else {
+                error
+            };
+            join_all(
+                intents
+                    .into_iter()
+                    .map(|intent| intent.send_abort(error.clone())),
+            )
+            .await;
+            return;
+        }
+        for intent in intents {
+            if let Some((delay, intent)) = intent.retry_or_abort(error.clone()).await {
+                debug!(peer=%peer.fmt_short(), ?delay, "reconnect intent after delay");
+                self.retry_queue.push(RetryingIntent {
+                    peer,
+                    at: Instant::now() + delay,
+                    intent,
+                });
+            }
+        }
+    }
+
+    /// Resubmits the intents whose reconnect delay elapsed.
+    async fn resubmit_due_intents(&mut self) {
+        let now = Instant::now();
+        let (due, waiting) = std::mem::take(&mut self.retry_queue)
+            .into_iter()
+            .partition(|retry| retry.at <= now);
+        self.retry_queue = waiting;
+        for RetryingIntent { peer, intent, .. } in due {
+            debug!(peer=%peer.fmt_short(), "reconnect intent");
+            self.submit_intent(peer, intent).await;
+        }
+    }
+
+    /// Takes the pending intents of `peer` after its connection failed, and retries or aborts them.
+    async fn fail_pending_intents(&mut self, peer: NodeId, err: anyhow::Error) {
+        let Some(peer_info) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        let intents = std::mem::take(&mut peer_info.pending_intents);
+        let error = match err.downcast_ref().and_then(RejectReason::from_conn_error) {
+            Some(reason) => Error::Rejected(reason),
+            None => Error::Net(err),
+        };
+        self.retry_or_abort_intents(peer, intents, Arc::new(error))
+            .await;
+    }
+
     #[instrument("conn", skip_all, fields(peer=%peer.fmt_short()))]
-    fn handle_session_event(&mut self, peer: NodeId, event: SessionEvent) {
+    async fn handle_session_event(&mut self, peer: NodeId, event: SessionEvent) {
         match event {
             SessionEvent::Established => {}
             SessionEvent::Complete {
                 result,
                 senders,
                 mut remaining_intents,
+                retry_intents,
                 we_cancelled: _,
             } => {
-                debug!(error=?result.err(), remaining_intents=remaining_intents.len(), "session complete");
+                debug!(error=?result.as_ref().err(), remaining_intents=remaining_intents.len(), "session complete");
+
+                let history = self.history.entry(peer).or_default();
+                match &result {
+                    Ok(()) => history.sessions += 1,
+                    Err(_) => history.failed_sessions += 1,
+                }
+                let event = EngineEvent::SessionCompleted {
+                    result: result.as_ref().map(|_| ()).map_err(|err| err.to_string()),
+                };
+                self.events.send((peer, event)).ok();
 
                 // Close the channel senders. This will cause our send loops to close,
                 // which in turn causes the receive loops of the other peer to close.
                 senders.close_all();
 
+                if !retry_intents.is_empty() {
+                    let error = match &result {
+                        Err(error) => error.clone(),
+                        Ok(()) => Arc::new(Error::SessionClosedByPeer),
+                    };
+                    self.retry_or_abort_intents(peer, retry_intents, error)
+                        .await;
+                }
+
                 let Some(peer_info) = self.peers.get_mut(&peer) else {
                     warn!("got session complete event for unknown peer");
                     return;
@@ -358,7 +1066,7 @@ impl PeerManager {
                 if peer_info.conn_state.is_none() && peer_info.pending_intents.is_empty() {
                     self.peers.remove(&peer);
                 } else if peer_info.conn_state.is_none() {
-                    self.connect_if_inactive(peer);
+                    self.connect_if_inactive(peer).await;
                 }
                 trace!("entering closing state");
             }
@@ -403,10 +1111,11 @@ impl PeerManager {
                         peer_info.conn_state = ConnState::None;
                         match &peer_info.session_state {
                             SessionState::None => {
-                                println!("Error: {err:#}");
-                                peer_info
-                                    .abort_pending_intents(err.context("failed while establishing"))
-                                    .await;
+                                self.fail_pending_intents(
+                                    peer,
+                                    err.context("failed while establishing"),
+                                )
+                                .await;
                                 self.peers.remove(&peer);
                             }
                             SessionState::Active { .. } => {
@@ -445,15 +1154,50 @@ impl PeerManager {
                     return Ok(());
                 }
 
+                // The connection may have become direct while it was established.
+                if let Some(remote_ip) =
+                    self.endpoint.remote_info(peer).as_ref().and_then(direct_ip)
+                {
+                    peer_info.remote_ip = Some(remote_ip);
+                }
+
                 debug!(?our_role, "connection ready: init session");
-                let (channels, fut) = prepare_channels(channel_streams)?;
+                let event = EngineEvent::ConnectionEstablished { role: our_role };
+                self.events.send((peer, event)).ok();
+                let channel_stats = Arc::new(ChannelStats::default());
+                let (channels, fut) = prepare_channels(
+                    channel_streams,
+                    self.session_memory_limit,
+                    channel_stats.clone(),
+                )?;
                 let conn_handle = ConnHandle {
                     initial_transmission,
                     channels,
                     our_role,
                     peer,
                 };
-                let session_handle = self.actor.init_session(conn_handle, intents).await?;
+                let event = EngineEvent::SessionStarted { role: our_role };
+                self.events.send((peer, event)).ok();
+                let tracker = SessionTracker::new(peer, our_role, Some(self.events.clone()));
+                let session_handle = match self
+                    .actor
+                    .init_session(
+                        conn_handle,
+                        intents,
+                        self.static_token_limits,
+                        tracker.clone(),
+                    )
+                    .await
+                {
+                    Ok(session_handle) => session_handle,
+                    Err(err) => {
+                        // Every started session is followed by a completed event.
+                        let result = Err(format!("{err:#}"));
+                        let event = EngineEvent::SessionCompleted { result };
+                        self.events.send((peer, event)).ok();
+                        return Err(err);
+                    }
+                };
 
                 let fut = fut.map_ok(|()| conn).map(ConnStep::Done);
                 let abort_handle = spawn_conn_task(&mut self.conn_tasks, peer_info, fut);
@@ -466,7 +1210,11 @@ impl PeerManager {
                     .insert(peer, ReceiverStream::new(event_rx));
 
                 peer_info.conn_state = ConnState::Active { abort_handle };
-                peer_info.session_state = SessionState::Active { update_tx };
+                peer_info.session_state = SessionState::Active {
+                    update_tx,
+                    tracker,
+                    channel_stats,
+                };
             }
             ConnStep::Done(Ok(conn)) => {
                 trace!("connection loop finished");
@@ -487,7 +1235,11 @@ impl PeerManager {
                 let ConnState::Active { .. } = &peer_info.conn_state else {
                     unreachable!("connection state mismatch: Done comes after Active only");
                 };
-                if let SessionState::Active { update_tx } = &peer_info.session_state {
+                let error = Some(format!("{err:#}"));
+                self.events
+                    .send((peer, EngineEvent::ConnectionClosed { error }))
+                    .ok();
+                if let SessionState::Active { update_tx, .. } = &peer_info.session_state {
                     warn!(?err, "connection failed while active");
                     update_tx
                         .send(SessionUpdate::Abort(Error::ConnectionClosed(err)))
@@ -496,27 +1248,30 @@ impl PeerManager {
                     peer_info.conn_state = ConnState::None;
                 } else {
                     debug!(?err, "connection failed while on session is active");
-                    peer_info
-                        .abort_pending_intents(err.context("failed while active"))
+                    self.fail_pending_intents(peer, err.context("failed while active"))
                         .await;
                     self.peers.remove(&peer);
                 }
             }
             ConnStep::Closed(res) => {
                 debug!(?res, "connection closed");
+                let error = res.as_ref().err().map(|err| format!("{err:#}"));
+                self.events
+                    .send((peer, EngineEvent::ConnectionClosed { error }))
+                    .ok();
                 match &peer_info.conn_state {
                     ConnState::Terminating { .. } => {
                         peer_info.conn_state = ConnState::None;
                         if !peer_info.pending_intents.is_empty() {
                             debug!("peer has pending intents, reconnect");
                             match res {
-                                Ok(()) => self.connect_if_inactive(peer),
+                                Ok(()) => self.connect_if_inactive(peer).await,
                                 Err(err) => {
-                                    peer_info
-                                        .abort_pending_intents(
-                                            err.context("failed while closing connection"),
-                                        )
-                                        .await
+                                    self.fail_pending_intents(
+                                        peer,
+                                        err.context("failed while closing connection"),
+                                    )
+                                    .await
                                 }
                             }
                         } else if peer_info.session_state.is_none() {
@@ -541,13 +1296,33 @@ impl PeerManager {
 
     async fn init_shutdown(&mut self) {
         self.shutting_down = true;
+        let error = Arc::new(Error::ShuttingDown);
+        join_all(
+            self.retry_queue
+                .drain(..)
+                .map(|retry| retry.intent.send_abort(error.clone())),
+        )
+        .await;
+        for peer in std::mem::take(&mut self.connect_queue) {
+            if let Some(peer_info) = self.peers.get_mut(&peer) {
+                if peer_info.conn_state.is_none() {
+                    let intents = std::mem::take(&mut peer_info.pending_intents);
+                    join_all(
+                        intents
+                            .into_iter()
+                            .map(|intent| intent.send_abort(error.clone())),
+                    )
+                    .await;
+                }
+            }
+        }
         for peer in self.peers.values() {
             if let ConnState::Establishing { abort_handle, .. } = &peer.conn_state {
                 // We are in pending state, which means the session has not yet been started.
                 // Hard-abort the task and let the other peer handle the error.
                 abort_handle.abort();
             }
-            if let SessionState::Active { update_tx } = &peer.session_state {
+            if let SessionState::Active { update_tx, .. } = &peer.session_state {
                 // We are in active state. We cancel our session, which leads to graceful connection termination.
                 update_tx
                     .send(SessionUpdate::Abort(Error::ShuttingDown))
@@ -577,9 +1352,21 @@ struct PeerInfo {
     pending_intents: Vec<Intent>,
     conn_state: ConnState,
     session_state: SessionState,
+    /// The IP address of the last direct connection with this peer.
+    remote_ip: Option<IpAddr>,
 }
 
 impl PeerInfo {
+    /// Returns `true` if the peer takes a session slot of the [`ConnectionLimits`].
+    fn has_slot(&self) -> bool {
+        matches!(
+            self.conn_state,
+            ConnState::Establishing { .. }
+                | ConnState::Active { .. }
+                | ConnState::Terminating { .. }
+        )
+    }
+
     /// Returns `true` if the intent was pushed into the session channel and `false` if it was added to the pending intent list.
     async fn push_intent(&mut self, intent: Intent) -> bool {
         match &self.session_state {
@@ -587,7 +1374,7 @@ impl PeerInfo {
                 self.pending_intents.push(intent);
                 false
             }
-            SessionState::Active { update_tx } => {
+            SessionState::Active { update_tx, .. } => {
                 if let Err(err) = update_tx.send(SessionUpdate::SubmitIntent(intent)).await {
                     debug!("failed to submit intent into active session, queue in peer state");
                     if let SessionUpdate::SubmitIntent(intent) = err.0 {
@@ -601,16 +1388,14 @@ impl PeerInfo {
             }
         }
     }
+}
 
-    async fn abort_pending_intents(&mut self, err: anyhow::Error) {
-        let err = Arc::new(Error::Net(err));
-        join_all(
-            self.pending_intents
-                .drain(..)
-                .map(|intent| intent.send_abort(err.clone())),
-        )
-        .await;
-    }
+/// An intent waiting to reconnect to its peer.
+#[derive(Debug)]
+struct RetryingIntent {
+    peer: NodeId,
+    at: Instant,
+    intent: Intent,
 }
 
 #[derive(Debug, Default, strum::Display)]
@@ -619,6 +1404,8 @@ enum SessionState {
     None,
     Active {
         update_tx: mpsc::Sender<SessionUpdate>,
+        tracker: SessionTracker,
+        channel_stats: Arc<ChannelStats>,
     },
 }
 
@@ -626,6 +1413,21 @@ impl SessionState {
     pub fn is_none(&self) -> bool {
         matches!(self, Self::None)
     }
+
+    fn info(&self) -> Option<SessionInfo> {
+        match self {
+            Self::None => None,
+            Self::Active {
+                tracker,
+                channel_stats,
+                ..
+            } => {
+                let mut info = tracker.info();
+                info.channels = channel_stats.snapshot();
+                Some(info)
+            }
+        }
+    }
 }
 
 #[derive(Debug, Default, strum::Display)]
@@ -648,6 +1450,17 @@ impl ConnState {
     pub fn is_none(&self) -> bool {
         matches!(self, Self::None)
     }
+
+    fn status(&self) -> ConnStatus {
+        match self {
+            Self::None => ConnStatus::None,
+            Self::Establishing { our_dial, .. } => ConnStatus::Establishing {
+                our_dial: our_dial.is_some(),
+            },
+            Self::Active { .. } => ConnStatus::Active,
+            Self::Terminating { .. } => ConnStatus::Terminating,
+        }
+    }
 }
 
 impl PeerInfo {
@@ -658,10 +1471,19 @@ impl PeerInfo {
             session_state: Default::default(),
             conn_state: Default::default(),
             pending_intents: Default::default(),
+            remote_ip: None,
         }
     }
 }
 
+/// Returns the IP address of a direct connection to a peer.
+fn direct_ip(info: &RemoteInfo) -> Option<IpAddr> {
+    match info.conn_type {
+        ConnectionType::Direct(addr) | ConnectionType::Mixed(addr, _) => Some(addr.ip()),
+        ConnectionType::Relay(_) | ConnectionType::None => None,
+    }
+}
+
 #[derive(Debug)]
 struct Established {
     our_role: Role,
@@ -682,23 +1504,37 @@ enum ConnStep {
 struct AcceptHandlers {
     #[debug("{:?}", accept_cb.as_ref().map(|_| "_"))]
     accept_cb: Option<AcceptCb>,
+    peer_policy: PeerPolicy,
     event_forwarder: Option<EventForwarder>,
+    reconcile_opts: Option<ReconcileOpts>,
 }
 
 impl AcceptHandlers {
     pub fn new(opts: AcceptOpts) -> Self {
         Self {
             accept_cb: opts.accept_cb,
+            peer_policy: opts.peer_policy,
             event_forwarder: opts.track_events.map(EventForwarder::new),
+            reconcile_opts: opts.reconcile_opts,
         }
     }
 
-    pub async fn accept(&self, peer: NodeId) -> Option<Intent> {
-        let init = match &self.accept_cb {
-            None => Some(SessionInit::continuous(Interests::All)),
-            Some(cb) => cb(peer).await,
+    pub async fn accept(&self, request: AcceptRequest) -> Result<Intent, RejectReason> {
+        let peer = request.peer;
+        if !self.peer_policy.is_allowed(&peer) {
+            return Err(RejectReason::Denied);
+        }
+        let outcome = match &self.accept_cb {
+            None => AcceptOutcome::Accept(SessionInit::continuous(Interests::All)),
+            Some(cb) => cb(request).await,
+        };
+        let mut init = match outcome {
+            AcceptOutcome::Accept(init) => init,
+            AcceptOutcome::Reject(reason) => return Err(reason),
         };
-        let init = init?;
+        if init.reconcile_opts.is_none() {
+            init.reconcile_opts = self.reconcile_opts;
+        }
 
         let intent = match &self.event_forwarder {
             None => Intent::new_detached(init),
@@ -710,7 +1546,7 @@ impl AcceptHandlers {
             }
         };
 
-        Some(intent)
+        Ok(intent)
     }
 }
 
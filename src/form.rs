@@ -8,7 +8,7 @@ use futures_lite::Stream;
 use iroh_blobs::{
     store::{ImportMode, MapEntry},
     util::progress::IgnoreProgressSender,
-    BlobFormat, Hash,
+    BlobFormat, Hash, HashAndFormat, TempTag,
 };
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncRead;
@@ -42,45 +42,50 @@ pub enum PayloadForm {
 }
 
 impl PayloadForm {
+    /// Imports the payload into `store`, if needed.
+    ///
+    /// Returns a [`TempTag`] for the payload, together with its length. The tag protects the
+    /// payload from garbage collection, and must be kept until an entry referencing the payload
+    /// is stored.
     pub async fn submit<S: iroh_blobs::store::Store>(
         self,
         store: &S,
-    ) -> anyhow::Result<(Hash, u64)> {
-        let (hash, len) = match self {
+    ) -> anyhow::Result<(TempTag, u64)> {
+        let (tag, len) = match self {
             PayloadForm::Hash(digest) => {
+                let tag = store.temp_tag(HashAndFormat::raw(digest));
                 let entry = store.get(&digest).await?;
                 let entry = entry.ok_or_else(|| anyhow::anyhow!("hash not foundA"))?;
-                (digest, entry.size().value())
+                (tag, entry.size().value())
+            }
+            PayloadForm::HashUnchecked(digest, len) => {
+                (store.temp_tag(HashAndFormat::raw(digest)), len)
             }
-            PayloadForm::HashUnchecked(digest, len) => (digest, len),
             PayloadForm::Bytes(bytes) => {
                 let len = bytes.len();
                 let temp_tag = store.import_bytes(bytes, BlobFormat::Raw).await?;
-                (*temp_tag.hash(), len as u64)
+                (temp_tag, len as u64)
             }
             PayloadForm::File(path, mode) => {
                 let progress = IgnoreProgressSender::default();
-                let (temp_tag, len) = store
+                store
                     .import_file(path, mode, BlobFormat::Raw, progress)
-                    .await?;
-                (*temp_tag.hash(), len)
+                    .await?
             }
             PayloadForm::Stream(stream) => {
                 let progress = IgnoreProgressSender::default();
-                let (temp_tag, len) = store
+                store
                     .import_stream(stream, BlobFormat::Raw, progress)
-                    .await?;
-                (*temp_tag.hash(), len)
+                    .await?
             }
             PayloadForm::Reader(reader) => {
                 let progress = IgnoreProgressSender::default();
-                let (temp_tag, len) = store
+                store
                     .import_reader(reader, BlobFormat::Raw, progress)
-                    .await?;
-                (*temp_tag.hash(), len)
+                    .await?
             }
         };
-        Ok((hash, len))
+        Ok((tag, len))
     }
 }
 
@@ -114,6 +119,21 @@ impl EntryForm {
         }
     }
 
+    /// Creates a new [`EntryForm`] for a tombstone, i.e. an entry with an empty payload.
+    ///
+    /// Ingesting the tombstone deletes all older entries in its subspace whose path starts with
+    /// `path`. The subspace is set to the user authenticating the entry, and the timestamp is the
+    /// current system time.
+    pub fn new_tombstone(namespace_id: NamespaceId, path: Path) -> Self {
+        EntryForm {
+            namespace_id,
+            subspace_id: SubspaceForm::User,
+            path,
+            timestamp: TimestampForm::Now,
+            payload: PayloadForm::HashUnchecked(Hash::new(b""), 0),
+        }
+    }
+
     /// Sets the subspace for the entry.
     pub fn subspace(mut self, subspace: SubspaceId) -> Self {
         self.subspace_id = SubspaceForm::Exact(subspace);
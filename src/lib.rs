@@ -10,6 +10,7 @@ pub(crate) mod net;
 pub mod proto;
 pub mod rpc;
 pub mod session;
+pub mod sideload;
 pub mod store;
 pub mod util;
 
@@ -1,8 +1,17 @@
 //! Networking implementation for iroh-willow.
 
-use std::{future::Future, io, time::Duration};
+use std::{
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::{anyhow, ensure, Context as _, Result};
+use bytes::{Bytes, BytesMut};
 use futures_concurrency::future::TryJoin;
 use futures_util::future::TryFutureExt;
 use iroh::{
@@ -24,7 +33,7 @@ use crate::{
             ChannelReceivers, ChannelSenders, Channels, LogicalChannelReceivers,
             LogicalChannelSenders,
         },
-        InitialTransmission, Role,
+        ChannelInfo, InitialTransmission, Role,
     },
     util::channel::{
         inbound_channel, outbound_channel, Guarantees, Reader, Receiver, Sender, Writer,
@@ -34,8 +43,22 @@ use crate::{
 /// Default capacity for the in-memory pipes between networking and session.
 const CHANNEL_CAP: usize = 1024 * 64;
 
+/// Default limit for the memory a session may use to buffer incoming messages.
+///
+/// The limit is split evenly between the channels of the session.
+pub(crate) const DEFAULT_SESSION_MEMORY_LIMIT: usize = CHANNEL_CAP * Channel::COUNT;
+
+/// Minimum capacity for the in-memory pipe of an incoming channel.
+///
+/// This must be large enough to fit the biggest message we expect, which is a payload chunk of
+/// 32 KiB plus the message header, with some headroom.
+const MIN_INBOUND_CHANNEL_CAP: usize = 1024 * 48;
+
 /// The ALPN protocol name for iroh-willow.
-pub const ALPN: &[u8] = b"iroh-willow/0";
+///
+/// The version suffix is bumped whenever the encoding of the messages changes incompatibly, so that
+/// peers with different versions reject each other's connections.
+pub const ALPN: &[u8] = b"iroh-willow/1";
 
 /// QUIC application error code for closing with failure.
 pub const ERROR_CODE_FAIL: VarInt = VarInt::from_u32(1);
@@ -49,6 +72,20 @@ pub const ERROR_CODE_DUPLICATE_CONN: VarInt = VarInt::from_u32(3);
 /// QUIC application error code when closing connection because our node is shutting down.
 pub const ERROR_CODE_SHUTDOWN: VarInt = VarInt::from_u32(4);
 
+/// QUIC application error code when closing an incoming connection from a peer which is denied by
+/// our [`PeerPolicy`].
+///
+/// [`PeerPolicy`]: crate::engine::PeerPolicy
+pub const ERROR_CODE_DENIED: VarInt = VarInt::from_u32(5);
+
+/// QUIC application error code when closing an incoming connection which was rejected by the accept
+/// callback. The close reason contains the reason given by the callback.
+pub const ERROR_CODE_REJECTED: VarInt = VarInt::from_u32(6);
+
+/// QUIC application error code when closing an incoming connection because our connection limits
+/// are reached.
+pub const ERROR_CODE_BUSY: VarInt = VarInt::from_u32(7);
+
 /// Timeout until we abort a connection attempt.
 const ESTABLISH_TIMEOUT: Duration = Duration::from_secs(10);
 /// Timeout until we abort a graceful termination attempt.
@@ -165,15 +202,66 @@ async fn open_channel_streams(conn: &Connection, our_role: Role) -> Result<Chann
     Ok(channels)
 }
 
+/// Counts the bytes sent and received on each channel of a connection.
+#[derive(Debug, Default)]
+pub(crate) struct ChannelStats {
+    sent: [AtomicU64; Channel::COUNT],
+    received: [AtomicU64; Channel::COUNT],
+}
+
+impl ChannelStats {
+    fn index(channel: Channel) -> usize {
+        match channel {
+            Channel::Control => 0,
+            Channel::Logical(channel) => channel as usize + 1,
+        }
+    }
+
+    fn add_sent(&self, channel: Channel, bytes: usize) {
+        self.sent[Self::index(channel)].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn add_received(&self, channel: Channel, bytes: usize) {
+        self.received[Self::index(channel)].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the bytes sent and received so far for each channel.
+    pub(crate) fn snapshot(&self) -> Vec<ChannelInfo> {
+        Channel::all()
+            .into_iter()
+            .map(|channel| {
+                let i = Self::index(channel);
+                ChannelInfo {
+                    channel,
+                    bytes_sent: self.sent[i].load(Ordering::Relaxed),
+                    bytes_received: self.received[i].load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+}
+
 /// Create a future for each WGPS channel that pipes between the QUIC channels and the
 /// [`Sender`] and [`Receiver`] for each channel to be used in the session.
 ///
+/// `session_memory_limit` is the total number of bytes that may be buffered for incoming
+/// messages, over all channels. It determines the amount of guarantees we issue to the other
+/// peer.
+///
+/// The bytes sent and received on each channel are counted in `stats`.
+///
 /// Returns [`Channels`], which contains all senders and receivers, and a future that drives
 /// the send and receive loops for all channels combined.
 pub(crate) fn prepare_channels(
     channels: ChannelStreams,
+    session_memory_limit: usize,
+    stats: Arc<ChannelStats>,
 ) -> Result<(Channels, impl Future<Output = Result<()>> + Send)> {
-    let mut channels = channels.map(|(ch, send, recv)| (ch, Some(prepare_channel(ch, send, recv))));
+    let inbound_cap = (session_memory_limit / Channel::COUNT).max(MIN_INBOUND_CHANNEL_CAP);
+    let mut channels = channels.map(|(ch, send, recv)| {
+        let streams = prepare_channel(ch, send, recv, inbound_cap, stats.clone());
+        (ch, Some(streams))
+    });
 
     let mut find = |channel| {
         channels
@@ -235,6 +323,8 @@ fn prepare_channel(
     ch: Channel,
     send_stream: SendStream,
     recv_stream: RecvStream,
+    inbound_cap: usize,
+    stats: Arc<ChannelStats>,
 ) -> (
     Sender<Message>,
     Receiver<Message>,
@@ -244,14 +334,13 @@ fn prepare_channel(
         Channel::Control => Guarantees::Unlimited,
         Channel::Logical(_) => Guarantees::Limited(0),
     };
-    let cap = CHANNEL_CAP;
-    let (sender, outbound_reader) = outbound_channel(cap, guarantees);
-    let (inbound_writer, receiver) = inbound_channel(cap);
+    let (sender, outbound_reader) = outbound_channel(CHANNEL_CAP, guarantees);
+    let (inbound_writer, receiver) = inbound_channel(inbound_cap);
 
-    let recv_fut = recv_loop(ch, recv_stream, inbound_writer)
+    let recv_fut = recv_loop(ch, recv_stream, inbound_writer, stats.clone())
         .map_err(move |e| e.context(format!("receive loop for {ch:?} failed")));
 
-    let send_fut = send_loop(ch, send_stream, outbound_reader)
+    let send_fut = send_loop(ch, send_stream, outbound_reader, stats)
         .map_err(move |e| e.context(format!("send loop for {ch:?} failed")));
 
     let fut = (recv_fut, send_fut).try_join().map_ok(|_| ());
@@ -265,9 +354,22 @@ fn prepare_channel(
 const ERROR_CODE_SESSION_CLOSED: VarInt = VarInt::from_u32(1);
 
 async fn recv_loop(
+    channel: Channel,
+    recv_stream: RecvStream,
+    channel_writer: Writer,
+    stats: Arc<ChannelStats>,
+) -> Result<()> {
+    match channel {
+        Channel::Control => recv_loop_control(channel, recv_stream, channel_writer, stats).await,
+        Channel::Logical(_) => recv_loop_logical(channel, recv_stream, channel_writer, stats).await,
+    }
+}
+
+async fn recv_loop_control(
     channel: Channel,
     mut recv_stream: RecvStream,
     mut channel_writer: Writer,
+    stats: Arc<ChannelStats>,
 ) -> Result<()> {
     trace!(?channel, "recv: start");
     let max_buffer_size = channel_writer.max_buffer_size();
@@ -277,6 +379,7 @@ async fn recv_loop(
         .context("failed to read from quic stream")?
     {
         trace!(len = buf.bytes.len(), "read");
+        stats.add_received(channel, buf.bytes.len());
         match channel_writer.write_all(&buf.bytes[..]).await {
             Ok(()) => {
                 trace!(len = buf.bytes.len(), "sent");
@@ -294,15 +397,75 @@ async fn recv_loop(
     Ok(())
 }
 
+/// Receive loop for logical channels.
+///
+/// Splits the incoming data into messages and writes them into the channel. If a message does not
+/// fit into the channel buffer, because the other peer sent more than we guaranteed, it is dropped,
+/// and all further messages are dropped until the other peer sent a [`Message::ControlApologise`]
+/// for this channel on the control channel, see [`CapacityWatcher::apologised`].
+///
+/// [`CapacityWatcher::apologised`]: crate::util::channel::CapacityWatcher::apologised
+async fn recv_loop_logical(
+    channel: Channel,
+    mut recv_stream: RecvStream,
+    channel_writer: Writer,
+    stats: Arc<ChannelStats>,
+) -> Result<()> {
+    trace!(?channel, "recv: start");
+    let max_buffer_size = channel_writer.max_buffer_size();
+    let mut pending = BytesMut::new();
+    'recv: while let Some(buf) = recv_stream
+        .read_chunk(max_buffer_size, true)
+        .await
+        .context("failed to read from quic stream")?
+    {
+        trace!(len = buf.bytes.len(), "read");
+        stats.add_received(channel, buf.bytes.len());
+        pending.extend_from_slice(&buf.bytes[..]);
+        while let Some(message) = next_message(&mut pending, max_buffer_size)? {
+            match channel_writer.write_or_drop(&message) {
+                Ok(true) => {}
+                Ok(false) => trace!(?channel, "drop message"),
+                Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                    debug!("closing recv channel: session closed");
+                    recv_stream.stop(ERROR_CODE_SESSION_CLOSED)?;
+                    break 'recv;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    trace!(?channel, "recv: stream close");
+    channel_writer.close();
+    Ok(())
+}
+
+/// Splits the next complete encoded message from `buf`.
+fn next_message(buf: &mut BytesMut, max_len: usize) -> Result<Option<Bytes>> {
+    let Some(len) = Message::peek_encoded_len(buf) else {
+        return Ok(None);
+    };
+    ensure!(
+        len <= max_len,
+        "received message of {len} bytes, which exceeds our channel capacity"
+    );
+    if buf.len() < len {
+        return Ok(None);
+    }
+    Ok(Some(buf.split_to(len).freeze()))
+}
+
 async fn send_loop(
     channel: Channel,
     mut send_stream: SendStream,
     channel_reader: Reader,
+    stats: Arc<ChannelStats>,
 ) -> Result<()> {
     trace!(?channel, "send: start");
     while let Some(data) = channel_reader.read_bytes().await {
         // let len = data.len();
         // trace!(len, "send");
+        stats.add_sent(channel, data.len());
         send_stream
             .write_chunk(data)
             .await
@@ -421,7 +584,7 @@ mod tests {
     use rand_chacha::ChaCha12Rng;
     use tracing::{info, Instrument};
 
-    use super::{establish, prepare_channels};
+    use super::{establish, prepare_channels, DEFAULT_SESSION_MEMORY_LIMIT};
     use crate::{
         engine::ActorHandle,
         form::{AuthForm, EntryForm, PayloadForm, SubspaceForm, TimestampForm},
@@ -434,10 +597,10 @@ mod tests {
             meadowcap::AccessMode,
             wgps::AccessChallenge,
         },
-        session::{intents::Intent, Role, SessionHandle, SessionInit, SessionMode},
+        session::{intents::Intent, Role, SessionHandle, SessionInit, SessionMode, SessionTracker},
     };
 
-    const ALPN: &[u8] = b"iroh-willow/0";
+    const ALPN: &[u8] = b"iroh-willow/1";
 
     fn create_rng(seed: &str) -> ChaCha12Rng {
         let seed = iroh_blobs::Hash::new(seed);
@@ -457,7 +620,11 @@ mod tests {
         let (initial_transmission, channel_streams) = establish(&conn, our_role, our_nonce)
             .instrument(span.clone())
             .await?;
-        let (channels, fut) = prepare_channels(channel_streams)?;
+        let (channels, fut) = prepare_channels(
+            channel_streams,
+            DEFAULT_SESSION_MEMORY_LIMIT,
+            Default::default(),
+        )?;
         let net_task = tokio::task::spawn(fut.instrument(span));
         let conn_handle = ConnHandle {
             initial_transmission,
@@ -465,7 +632,10 @@ mod tests {
             peer,
             channels,
         };
-        let session_handle = actor.init_session(conn_handle, intents).await?;
+        let tracker = SessionTracker::new(peer, our_role, None);
+        let session_handle = actor
+            .init_session(conn_handle, intents, Default::default(), tracker)
+            .await?;
         Ok((session_handle, net_task))
     }
 
@@ -170,6 +170,12 @@ pub trait EntryExt {
 
     /// Returns a tuple of namespace, subspace and path.
     fn as_sortable_tuple(&self) -> (&NamespaceId, &SubspaceId, &Path);
+
+    /// Returns `true` if the entry has an empty payload.
+    ///
+    /// Willow has no explicit deletion. Instead, entries are deleted by writing a newer entry with
+    /// an empty payload at the same path or at a prefix of it, which prunes the older entries.
+    fn is_tombstone(&self) -> bool;
 }
 
 impl EntryExt for Entry {
@@ -187,6 +193,10 @@ impl EntryExt for Entry {
     fn as_sortable_tuple(&self) -> (&NamespaceId, &SubspaceId, &Path) {
         (self.namespace_id(), self.subspace_id(), self.path())
     }
+
+    fn is_tombstone(&self) -> bool {
+        self.payload_length() == 0
+    }
 }
 
 /// An entry in a willow store.
@@ -209,6 +209,11 @@ impl UserSecretKey {
     pub fn verify(&self, msg: &[u8], signature: &UserSignature) -> Result<(), SignatureError> {
         self.0.verify_strict(msg, &signature.0)
     }
+
+    /// Convert into a [`crypto_box::SecretKey`] to decrypt data sealed for this user.
+    pub fn to_crypto_box(&self) -> crypto_box::SecretKey {
+        crypto_box::SecretKey::from(self.0.to_scalar_bytes())
+    }
 }
 
 /// The corresponding public key for a [`UserSecretKey].
@@ -247,6 +252,11 @@ impl UserPublicKey {
     pub fn id(&self) -> UserId {
         self.into()
     }
+
+    /// Convert into a [`crypto_box::PublicKey`] to seal data for this user.
+    pub fn to_crypto_box(&self) -> crypto_box::PublicKey {
+        crypto_box::PublicKey::from(self.0.to_montgomery().to_bytes())
+    }
 }
 
 impl FromStr for UserSecretKey {
@@ -3,7 +3,7 @@ use strum::{EnumCount, VariantArray};
 
 use super::messages::Message;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, derive_more::TryFrom)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Hash, derive_more::TryFrom)]
 pub enum Channel {
     Control,
     Logical(LogicalChannel),
@@ -143,9 +143,12 @@ impl Message {
                 Channel::Logical(LogicalChannel::Reconciliation)
             }
 
+            // TODO: Send payload requests on a separate PayloadRequest channel, as in the spec.
             Message::DataSendEntry(_)
             | Message::DataSendPayload(_)
-            | Message::DataSetMetadata(_) => Channel::Logical(LogicalChannel::Data),
+            | Message::DataSetMetadata(_)
+            | Message::DataBindPayloadRequest(_)
+            | Message::DataReplyPayload(_) => Channel::Logical(LogicalChannel::Data),
 
             Message::CommitmentReveal(_)
             | Message::PaiRequestSubspaceCapability(_)
@@ -155,7 +158,8 @@ impl Message {
             | Message::ControlPlead(_)
             | Message::ControlAnnounceDropping(_)
             | Message::ControlApologise(_)
-            | Message::ControlFreeHandle(_) => Channel::Control,
+            | Message::ControlFreeHandle(_)
+            | Message::ControlUpgradeSession(_) => Channel::Control,
         }
     }
 }
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 
 /// The different resource handles employed by the WGPS.
-#[derive(Debug, Serialize, Deserialize, strum::Display)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, strum::Display)]
 pub enum HandleType {
     /// Resource handle for the private set intersection part of private area intersection.
     /// More precisely, an IntersectionHandle stores a PsiGroup member together with one of two possible states:
@@ -41,12 +41,16 @@ pub struct CapabilityHandle(u64);
 #[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone, Copy, derive_more::From)]
 pub struct StaticTokenHandle(u64);
 
+#[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone, Copy, derive_more::From)]
+pub struct PayloadRequestHandle(u64);
+
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Copy, derive_more::From)]
 pub enum ResourceHandle {
     AreaOfInterest(AreaOfInterestHandle),
     Intersection(IntersectionHandle),
     Capability(CapabilityHandle),
     StaticToken(StaticTokenHandle),
+    PayloadRequest(PayloadRequestHandle),
 }
 
 impl IsHandle for CapabilityHandle {
@@ -73,6 +77,14 @@ impl IsHandle for AreaOfInterestHandle {
         self.0
     }
 }
+impl IsHandle for PayloadRequestHandle {
+    fn handle_type(&self) -> HandleType {
+        HandleType::PayloadRequest
+    }
+    fn value(&self) -> u64 {
+        self.0
+    }
+}
 impl IsHandle for IntersectionHandle {
     fn handle_type(&self) -> HandleType {
         HandleType::Intersection
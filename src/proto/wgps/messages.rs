@@ -6,7 +6,8 @@ use super::{
     channels::LogicalChannel,
     fingerprint::Fingerprint,
     handles::{
-        AreaOfInterestHandle, CapabilityHandle, HandleType, IntersectionHandle, StaticTokenHandle,
+        AreaOfInterestHandle, CapabilityHandle, HandleType, IntersectionHandle,
+        PayloadRequestHandle, StaticTokenHandle,
     },
 };
 use crate::{
@@ -104,8 +105,6 @@ pub enum Message {
     DataSendPayload(DataSendPayload),
     #[debug("{:?}", _0)]
     DataSetMetadata(DataSetMetadata),
-    // DataBindPayloadRequest
-    // DataReplyPayload
     #[debug("{:?}", _0)]
     ControlIssueGuarantee(ControlIssueGuarantee),
     #[debug("{:?}", _0)]
@@ -118,6 +117,13 @@ pub enum Message {
     ControlApologise(ControlApologise),
     #[debug("{:?}", _0)]
     ControlFreeHandle(ControlFreeHandle),
+    // New variants are appended here, so that the encoding of the existing variants is stable.
+    #[debug("{:?}", _0)]
+    DataBindPayloadRequest(DataBindPayloadRequest),
+    #[debug("{:?}", _0)]
+    DataReplyPayload(DataReplyPayload),
+    #[debug("{:?}", _0)]
+    ControlUpgradeSession(ControlUpgradeSession),
 }
 
 impl Message {
@@ -125,6 +131,15 @@ impl Message {
         std::mem::discriminant(self) == std::mem::discriminant(other)
     }
 
+    /// Returns the length of the encoded message at the start of `data`, including its header.
+    ///
+    /// Returns `None` if `data` is too short to contain the message header.
+    pub fn peek_encoded_len(data: &[u8]) -> Option<usize> {
+        let header = data.get(..4)?;
+        let len = u32::from_be_bytes(header.try_into().expect("just checked")) as usize;
+        Some(len + 4)
+    }
+
     pub fn covers_region(&self) -> Option<(AreaOfInterestHandle, u64)> {
         match self {
             Message::ReconciliationSendFingerprint(msg) => {
@@ -156,12 +171,10 @@ impl Encoder for Message {
 impl Decoder for Message {
     fn decode_from(data: &[u8]) -> anyhow::Result<DecodeOutcome<Self>> {
         // tracing::debug!(input_len = data.len(), "Message decode: start");
-        if data.len() < 4 {
+        let Some(end) = Message::peek_encoded_len(data) else {
             return Ok(DecodeOutcome::NeedMoreData);
-        }
-        let len = u32::from_be_bytes(data[..4].try_into().expect("just checked")) as usize;
-        // tracing::debug!(msg_len = len, "Message decode: parsed len");
-        let end = len + 4;
+        };
+        // tracing::debug!(msg_len = end, "Message decode: parsed len");
         if data.len() < end {
             // tracing::debug!("Message decode: need more data");
             return Ok(DecodeOutcome::NeedMoreData);
@@ -218,6 +231,8 @@ pub enum DataMessage {
     SendEntry(DataSendEntry),
     SendPayload(DataSendPayload),
     SetMetadata(DataSetMetadata),
+    BindPayloadRequest(DataBindPayloadRequest),
+    ReplyPayload(DataReplyPayload),
 }
 
 impl TryFrom<Message> for DataMessage {
@@ -227,6 +242,8 @@ impl TryFrom<Message> for DataMessage {
             Message::DataSendEntry(msg) => Ok(msg.into()),
             Message::DataSendPayload(msg) => Ok(msg.into()),
             Message::DataSetMetadata(msg) => Ok(msg.into()),
+            Message::DataBindPayloadRequest(msg) => Ok(msg.into()),
+            Message::DataReplyPayload(msg) => Ok(msg.into()),
             _ => Err(()),
         }
     }
@@ -238,6 +255,8 @@ impl From<DataMessage> for Message {
             DataMessage::SendEntry(message) => message.into(),
             DataMessage::SendPayload(message) => message.into(),
             DataMessage::SetMetadata(message) => message.into(),
+            DataMessage::BindPayloadRequest(message) => message.into(),
+            DataMessage::ReplyPayload(message) => message.into(),
         }
     }
 }
@@ -427,30 +446,38 @@ pub struct DataSendPayload {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DataSetMetadata {
     /// An AreaOfInterestHandle, bound by the sender of this message.
-    sender_handle: AreaOfInterestHandle,
+    pub sender_handle: AreaOfInterestHandle,
     /// An AreaOfInterestHandle, bound by the receiver of this message.
-    receiver_handle: AreaOfInterestHandle,
-    // Whether the other peer should eagerly forward Payloads in this intersection.
-    is_eager: bool,
-}
-
-// /// Bind an Entry to a PayloadRequestHandle and request transmission of its Payload from an offset.
-// #[derive(Debug, Clone, Serialize, Deserialize)]
-// pub struct DataBindPayloadRequest {
-//     /// The Entry to request.
-//     entry: Entry,
-//     /// The offset in the Payload starting from which the sender would like to receive the Payload bytes.
-//     offset: u64,
-//     /// A resource handle for a ReadCapability bound by the sender that grants them read access to the bound Entry.
-//     capability: CapabilityHandle,
-// }
-//
-// /// Set up the state for replying to a DataBindPayloadRequest message.
-// #[derive(Debug, Clone, Serialize, Deserialize)]
-// pub struct DataReplyPayload {
-//     /// The PayloadRequestHandle to which to reply.
-//     handle: u64,
-// }
+    pub receiver_handle: AreaOfInterestHandle,
+    /// Whether the other peer should eagerly forward Payloads in this intersection.
+    pub is_eager: bool,
+}
+
+impl DataSetMetadata {
+    pub fn handles(&self) -> (AreaOfInterestHandle, AreaOfInterestHandle) {
+        (self.receiver_handle, self.sender_handle)
+    }
+}
+
+/// Bind an Entry to a PayloadRequestHandle and request transmission of its Payload from an offset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataBindPayloadRequest {
+    /// The Entry to request.
+    pub entry: SerdeEntry,
+    /// The offset in the Payload starting from which the sender would like to receive the Payload bytes.
+    pub offset: u64,
+    /// A resource handle for a ReadCapability bound by the sender that grants them read access to the bound Entry.
+    pub capability: CapabilityHandle,
+}
+
+/// Set up the state for replying to a DataBindPayloadRequest message.
+///
+/// The Payload bytes follow in [`DataSendPayload`] messages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataReplyPayload {
+    /// The PayloadRequestHandle to which to reply.
+    pub handle: PayloadRequestHandle,
+}
 
 /// Make a binding promise of available buffer capacity to the other peer
 #[derive(Debug, Serialize, Deserialize)]
@@ -483,9 +510,16 @@ pub struct ControlAnnounceDropping {
 }
 
 /// The client notifies the server that it can stop dropping messages on this logical channel.
+///
+/// Because each logical channel is transmitted over its own QUIC stream, messages which the client
+/// sent on the logical channel before the apologise may arrive after it. The apologise thus
+/// includes the offset of the logical channel at which the client resends the dropped messages,
+/// and the server keeps dropping messages until it received all bytes before that offset.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ControlApologise {
     pub channel: LogicalChannel,
+    /// Number of bytes the client sent on the logical channel before the resent messages.
+    pub resume_at: u64,
 }
 
 /// Ask the other peer to free a resource handle.
@@ -494,12 +528,20 @@ pub struct ControlApologise {
 /// and bind resource handles to the same handle types.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ControlFreeHandle {
-    handle: u64,
+    pub handle: u64,
     /// Indicates whether the peer sending this message is the one who created the handle (true) or not (false).
-    mine: bool,
-    handle_type: HandleType,
+    pub mine: bool,
+    pub handle_type: HandleType,
 }
 
+/// Notify the other peer that we switched the session to continuous mode.
+///
+/// This message is not part of the WGPS spec. Sessions are upgraded once an intent which wants a
+/// continuous session is submitted to a session which only reconciles once. The other peer then
+/// keeps the session open after reconciliation as well, and sends new entries live.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ControlUpgradeSession;
+
 pub type PsiGroupBytes = [u8; 32];
 
 /// Bind data to an IntersectionHandle for performing private area intersection.
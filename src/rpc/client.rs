@@ -28,6 +28,7 @@ use tokio_stream::{StreamMap, StreamNotifyClose};
 
 use super::RpcClient;
 use crate::{
+    engine::{EngineEvent, PeerStatus},
     form::{AuthForm, SubspaceForm, TimestampForm},
     interest::{
         AreaOfInterestSelector, CapSelector, CapabilityPack, DelegateTo, Interests, RestrictArea,
@@ -41,7 +42,7 @@ use crate::{
     rpc::proto::*,
     session::{
         intents::{serde_encoding::Event, Completion, IntentUpdate},
-        SessionInit, SessionMode,
+        SessionInfo, SessionInit, SessionMode,
     },
     store::traits::{StoreEvent, SubscribeParams},
 };
@@ -196,6 +197,40 @@ impl<C: quic_rpc::Connector<RpcService>> Client<C> {
         self.rpc.rpc(AddAddrRequest { addr }).await??;
         Ok(())
     }
+
+    /// Returns the state of all peers with which the node has a connection, a session, or intents
+    /// waiting to connect.
+    ///
+    /// See also [`Engine::peers`](crate::Engine::peers).
+    pub async fn peers(&self) -> Result<Vec<PeerStatus>> {
+        let res = self.rpc.rpc(PeersRequest).await??;
+        Ok(res.0)
+    }
+
+    /// Returns information about the active session with `peer`, if any.
+    ///
+    /// See also [`Engine::session_info`](crate::Engine::session_info).
+    pub async fn session_info(&self, peer: NodeId) -> Result<Option<SessionInfo>> {
+        let res = self.rpc.rpc(SessionInfoRequest { peer }).await??;
+        Ok(res.0)
+    }
+
+    /// Subscribes to events from the connections and sessions with all peers.
+    ///
+    /// See also [`Engine::subscribe_events`](crate::Engine::subscribe_events).
+    pub async fn subscribe_events(
+        &self,
+    ) -> Result<impl Stream<Item = Result<(NodeId, EngineEvent)>>> {
+        let stream = self
+            .rpc
+            .try_server_streaming(SubscribeEventsRequest)
+            .await?;
+        let stream = stream.map(|item| {
+            item.map(|res| (res.peer, res.event))
+                .map_err(anyhow::Error::from)
+        });
+        Ok(stream)
+    }
 }
 
 /// A space to store entries in.
@@ -312,14 +347,111 @@ impl<C: quic_rpc::Connector<RpcService>> Space<C> {
         Ok(entry.0.map(Into::into))
     }
 
+    /// Delete the entry at `path` in `subspace` by writing a tombstone over it.
+    ///
+    /// The tombstone is an entry with an empty payload and a timestamp just after the deleted
+    /// entry. Entries below `path` are deleted as well if they are older than the deleted entry,
+    /// use [`Self::delete_prefix`] to delete all of them.
+    ///
+    /// Returns the tombstone, or `None` if there is no entry at `path` or it is a tombstone
+    /// already.
+    pub async fn delete(
+        &self,
+        subspace: SubspaceId,
+        path: Path,
+        auth: impl Into<AuthForm>,
+    ) -> Result<Option<AuthorisedEntry>> {
+        let req = DeleteEntryRequest {
+            namespace: self.namespace_id,
+            subspace,
+            path,
+            auth: auth.into(),
+        };
+        let res = self.rpc.rpc(req).await??;
+        Ok(res.0.map(Into::into))
+    }
+
+    /// Delete all entries in `subspace` whose path starts with `prefix` by writing a tombstone at
+    /// `prefix`.
+    ///
+    /// The tombstone is an entry with an empty payload and the current time as timestamp.
+    pub async fn delete_prefix(
+        &self,
+        subspace: SubspaceId,
+        prefix: Path,
+        auth: impl Into<AuthForm>,
+    ) -> Result<InsertEntrySuccess> {
+        let req = DeletePrefixRequest {
+            namespace: self.namespace_id,
+            subspace,
+            prefix,
+            auth: auth.into(),
+        };
+        let res = self.rpc.rpc(req).await??;
+        Ok(res)
+    }
+
+    /// Forget a single entry locally.
+    ///
+    /// The entry may be synced again from peers which still have it.
+    ///
+    /// Returns `false` if there is no entry at `subspace` and `path`.
+    pub async fn forget_entry(&self, subspace: SubspaceId, path: Path) -> Result<bool> {
+        let req = ForgetEntryRequest {
+            namespace: self.namespace_id,
+            subspace,
+            path,
+        };
+        let res = self.rpc.rpc(req).await??;
+        Ok(res.0)
+    }
+
+    /// Forget all entries included in an area locally.
+    ///
+    /// Returns the number of forgotten entries.
+    pub async fn forget_area(&self, area: Area) -> Result<u64> {
+        let req = ForgetAreaRequest {
+            namespace: self.namespace_id,
+            area,
+        };
+        let res = self.rpc.rpc(req).await??;
+        Ok(res.0)
+    }
+
+    /// Forget the payload of an entry locally, while keeping the entry.
+    ///
+    /// This removes the payload from the blob store. Fails if other entries reference the same
+    /// payload.
+    ///
+    /// Returns `false` if there is no entry at `subspace` and `path`.
+    pub async fn forget_payload(&self, subspace: SubspaceId, path: Path) -> Result<bool> {
+        let req = ForgetPayloadRequest {
+            namespace: self.namespace_id,
+            subspace,
+            path,
+        };
+        let res = self.rpc.rpc(req).await??;
+        Ok(res.0)
+    }
+
     /// Get entries by range.
     pub async fn get_many(
         &self,
         range: Range3d,
+    ) -> Result<impl Stream<Item = Result<AuthorisedEntry>>> {
+        self.get_many_with_opts(range, Default::default()).await
+    }
+
+    /// Get entries by range, with [`GetEntriesOpts`] to e.g. hide tombstones.
+    pub async fn get_many_with_opts(
+        &self,
+        range: Range3d,
+        opts: GetEntriesOpts,
     ) -> Result<impl Stream<Item = Result<AuthorisedEntry>>> {
         let req = GetEntriesRequest {
             namespace: self.namespace_id,
             range,
+            opts,
         };
         let stream = self.rpc.try_server_streaming(req).await?;
         Ok(stream.map(|res| res.map(|r| r.0).map_err(anyhow::Error::from)))
@@ -11,6 +11,7 @@ use tokio_util::task::AbortOnDropHandle;
 
 use crate::{
     form::EntryOrForm,
+    proto::data_model::EntryExt as _,
     rpc::{client::MemClient, proto::*},
     Engine,
 };
@@ -72,6 +73,12 @@ impl Engine {
                         .get_entries(req.namespace, req.range)
                         .await
                         .map_err(map_err)?;
+                    let hide_tombstones = req.opts.hide_tombstones;
+                    let stream = stream.filter(move |res| {
+                        let hide = hide_tombstones
+                            && res.as_ref().is_ok_and(|entry| entry.entry().is_tombstone());
+                        futures_util::future::ready(!hide)
+                    });
                     Ok(stream.map(|res| res.map(GetEntriesResponse).map_err(map_err)))
                 })
                 .await
@@ -86,6 +93,62 @@ impl Engine {
                 })
                 .await
             }
+            DeleteEntry(msg) => {
+                chan.rpc(msg, self, |engine, req| async move {
+                    engine
+                        .delete(req.namespace, req.subspace, req.path, req.auth)
+                        .await
+                        .map(|entry| DeleteEntryResponse(entry.map(Into::into)))
+                        .map_err(map_err)
+                })
+                .await
+            }
+            DeletePrefix(msg) => {
+                chan.rpc(msg, self, |engine, req| async move {
+                    engine
+                        .delete_prefix(req.namespace, req.subspace, req.prefix, req.auth)
+                        .await
+                        .map(|(entry, inserted)| {
+                            if inserted {
+                                InsertEntrySuccess::Inserted(entry)
+                            } else {
+                                InsertEntrySuccess::Obsolete
+                            }
+                        })
+                        .map_err(map_err)
+                })
+                .await
+            }
+            ForgetEntry(msg) => {
+                chan.rpc(msg, self, |engine, req| async move {
+                    engine
+                        .forget_entry(req.namespace, req.subspace, req.path)
+                        .await
+                        .map(ForgetEntryResponse)
+                        .map_err(map_err)
+                })
+                .await
+            }
+            ForgetArea(msg) => {
+                chan.rpc(msg, self, |engine, req| async move {
+                    engine
+                        .forget_area(req.namespace, req.area)
+                        .await
+                        .map(ForgetAreaResponse)
+                        .map_err(map_err)
+                })
+                .await
+            }
+            ForgetPayload(msg) => {
+                chan.rpc(msg, self, |engine, req| async move {
+                    engine
+                        .forget_payload(req.namespace, req.subspace, req.path)
+                        .await
+                        .map(ForgetPayloadResponse)
+                        .map_err(map_err)
+                })
+                .await
+            }
             CreateNamespace(msg) => {
                 chan.rpc(msg, self, |engine, req| async move {
                     engine
@@ -180,6 +243,31 @@ impl Engine {
                 })
                 .await
             }
+            Peers(msg) => {
+                chan.rpc(msg, self, |engine, _req| async move {
+                    engine.peers().await.map(PeersResponse).map_err(map_err)
+                })
+                .await
+            }
+            SessionInfo(msg) => {
+                chan.rpc(msg, self, |engine, req| async move {
+                    engine
+                        .session_info(req.peer)
+                        .await
+                        .map(SessionInfoResponse)
+                        .map_err(map_err)
+                })
+                .await
+            }
+            SubscribeEvents(msg) => {
+                chan.try_server_streaming(msg, self, |engine, _req| async move {
+                    let events = engine
+                        .subscribe_events()
+                        .map(|(peer, event)| Ok(SubscribeEventsResponse { peer, event }));
+                    Ok(events)
+                })
+                .await
+            }
         }
     }
 }
@@ -6,6 +6,7 @@ use quic_rpc_derive::rpc_requests;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    engine::{EngineEvent, PeerStatus},
     form::{AuthForm, SubspaceForm, TimestampForm},
     interest::{CapSelector, CapabilityPack, DelegateTo},
     proto::{
@@ -19,7 +20,7 @@ use crate::{
     },
     session::{
         intents::{serde_encoding::Event, IntentUpdate},
-        SessionInit,
+        SessionInfo, SessionInit,
     },
     store::traits::{StoreEvent, SubscribeParams},
 };
@@ -51,6 +52,16 @@ pub enum Request {
     GetEntries(GetEntriesRequest),
     #[rpc(response = RpcResult<GetEntryResponse>)]
     GetEntry(GetEntryRequest),
+    #[rpc(response = RpcResult<DeleteEntryResponse>)]
+    DeleteEntry(DeleteEntryRequest),
+    #[rpc(response = RpcResult<InsertEntrySuccess>)]
+    DeletePrefix(DeletePrefixRequest),
+    #[rpc(response = RpcResult<ForgetEntryResponse>)]
+    ForgetEntry(ForgetEntryRequest),
+    #[rpc(response = RpcResult<ForgetAreaResponse>)]
+    ForgetArea(ForgetAreaRequest),
+    #[rpc(response = RpcResult<ForgetPayloadResponse>)]
+    ForgetPayload(ForgetPayloadRequest),
     #[rpc(response = RpcResult<CreateNamespaceResponse>)]
     CreateNamespace(CreateNamespaceRequest),
     #[rpc(response = RpcResult<CreateUserResponse>)]
@@ -69,6 +80,13 @@ pub enum Request {
     Addr(AddrRequest),
     #[rpc(response = RpcResult<()>)]
     AddAddr(AddAddrRequest),
+    // requests for peer and session introspection
+    #[rpc(response = RpcResult<PeersResponse>)]
+    Peers(PeersRequest),
+    #[rpc(response = RpcResult<SessionInfoResponse>)]
+    SessionInfo(SessionInfoRequest),
+    #[try_server_streaming(create_error = RpcError, item_error = RpcError, item = SubscribeEventsResponse)]
+    SubscribeEvents(SubscribeEventsRequest),
 }
 
 #[allow(missing_docs)]
@@ -80,6 +98,10 @@ pub enum Response {
     InsertSecret(RpcResult<InsertSecretResponse>),
     GetEntries(RpcResult<GetEntriesResponse>),
     GetEntry(RpcResult<GetEntryResponse>),
+    DeleteEntry(RpcResult<DeleteEntryResponse>),
+    ForgetEntry(RpcResult<ForgetEntryResponse>),
+    ForgetArea(RpcResult<ForgetAreaResponse>),
+    ForgetPayload(RpcResult<ForgetPayloadResponse>),
     CreateNamespace(RpcResult<CreateNamespaceResponse>),
     CreateUser(RpcResult<CreateUserResponse>),
     DelegateCaps(RpcResult<DelegateCapsResponse>),
@@ -90,6 +112,10 @@ pub enum Response {
     // responses for endpoint info
     Addr(RpcResult<NodeAddr>),
     AddAddr(RpcResult<()>),
+    // responses for peer and session introspection
+    Peers(RpcResult<PeersResponse>),
+    SessionInfo(RpcResult<SessionInfoResponse>),
+    SubscribeEvents(RpcResult<SubscribeEventsResponse>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -144,6 +170,15 @@ pub struct GetEntriesRequest {
     pub namespace: NamespaceId,
     #[serde(with = "grouping::serde_encoding::range_3d")]
     pub range: Range3d,
+    #[serde(default)]
+    pub opts: GetEntriesOpts,
+}
+
+/// Options for [`GetEntriesRequest`].
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct GetEntriesOpts {
+    /// Skip tombstones, i.e. entries with an empty payload.
+    pub hide_tombstones: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -162,6 +197,63 @@ pub struct GetEntryRequest {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetEntryResponse(pub Option<SerdeAuthorisedEntry>);
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteEntryRequest {
+    pub namespace: NamespaceId,
+    pub subspace: SubspaceId,
+    #[serde(with = "data_model::serde_encoding::path")]
+    pub path: Path,
+    pub auth: AuthForm,
+}
+
+/// The tombstone written over the deleted entry, if any.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteEntryResponse(pub Option<SerdeAuthorisedEntry>);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeletePrefixRequest {
+    pub namespace: NamespaceId,
+    pub subspace: SubspaceId,
+    #[serde(with = "data_model::serde_encoding::path")]
+    pub prefix: Path,
+    pub auth: AuthForm,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgetEntryRequest {
+    pub namespace: NamespaceId,
+    pub subspace: SubspaceId,
+    #[serde(with = "data_model::serde_encoding::path")]
+    pub path: Path,
+}
+
+/// Whether an entry was forgotten.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgetEntryResponse(pub bool);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgetAreaRequest {
+    pub namespace: NamespaceId,
+    #[serde(with = "grouping::serde_encoding::area")]
+    pub area: Area,
+}
+
+/// The number of forgotten entries.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgetAreaResponse(pub u64);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgetPayloadRequest {
+    pub namespace: NamespaceId,
+    pub subspace: SubspaceId,
+    #[serde(with = "data_model::serde_encoding::path")]
+    pub path: Path,
+}
+
+/// Whether a payload was forgotten.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgetPayloadResponse(pub bool);
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateNamespaceRequest {
     pub kind: NamespaceKind,
@@ -284,3 +376,26 @@ pub struct AddrRequest;
 pub struct AddAddrRequest {
     pub addr: NodeAddr,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeersRequest;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeersResponse(pub Vec<PeerStatus>);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionInfoRequest {
+    pub peer: NodeId,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionInfoResponse(pub Option<SessionInfo>);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubscribeEventsRequest;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubscribeEventsResponse {
+    pub peer: NodeId,
+    pub event: EngineEvent,
+}
@@ -7,15 +7,18 @@
 //! Internally, this module contains the full implementation of the protocol, which is started with
 //! the `run_session` function (which is not public).
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use channels::ChannelSenders;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 
 use crate::{
     interest::Interests,
+    proto::{data_model::Entry, wgps::MAX_PAYLOAD_SIZE},
     session::{error::ChannelReceiverDropped, intents::Intent},
+    store::traits::SplitOpts,
 };
 
 mod aoi_finder;
@@ -24,6 +27,7 @@ mod challenge;
 pub(crate) mod channels;
 mod data;
 mod error;
+mod info;
 pub mod intents;
 mod pai_finder;
 mod payload;
@@ -33,7 +37,13 @@ mod run;
 mod static_tokens;
 
 pub(crate) use self::{
-    challenge::InitialTransmission, channels::Channels, error::Error, run::run_session,
+    challenge::InitialTransmission, channels::Channels, error::Error, info::SessionTracker,
+    run::run_session,
+};
+pub use self::{
+    info::{AttachedIntent, ChannelInfo, SessionInfo, MAX_INFO_AREAS},
+    resource::EvictionPolicy,
+    static_tokens::StaticTokenLimits,
 };
 
 /// Id per session to identify store subscriptions.
@@ -41,7 +51,7 @@ pub(crate) type SessionId = u64;
 
 /// To break symmetry, we refer to the peer that initiated the synchronisation session as Alfie,
 /// and the other peer as Betty.
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum Role {
     /// The peer that initiated the synchronisation session.
     Alfie,
@@ -65,6 +75,11 @@ impl Role {
 /// * [`Self::Continuous`] will enable the live data channels to synchronize updates in real-time.
 /// * [`Self::ReconcileOnce`] will run a single reconciliation of the interests declared at session
 ///   start, and then close the session.
+///
+/// A session running in [`Self::ReconcileOnce`] mode is upgraded to [`Self::Continuous`] if an
+/// intent with [`Self::Continuous`] is submitted before the reconciliation finished. The upgrade
+/// is announced to the other peer, which then upgrades its side of the session as well: it keeps
+/// the session open until we close it, and sends us new entries live.
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub enum SessionMode {
     /// Run a single, full reconciliation, and then quit.
@@ -87,12 +102,68 @@ pub struct SessionInit {
     pub interests: Interests,
     /// Selects the session mode (once or continuous).
     pub mode: SessionMode,
+    /// Selects areas we wish to synchronize without eagerly receiving their payloads.
+    ///
+    /// These interests are synchronised in addition to [`Self::interests`], and the other peer is
+    /// asked to not send payloads eagerly in them. Their entries are synchronised as usual, but
+    /// payloads are only transferred when requested with [`Engine::fetch_payload`] while the
+    /// session is active. The preference is sent alongside the reconciliation, so payloads which
+    /// the other peer sent before it received the preference may still arrive.
+    ///
+    /// [`Engine::fetch_payload`]: crate::engine::Engine::fetch_payload
+    #[serde(default)]
+    pub lazy_interests: Option<Interests>,
+    /// Tuning parameters for the reconciliation of this session.
+    ///
+    /// If multiple intents are submitted for the same session, the options of the intent which
+    /// started the session are used. If `None`, the defaults of [`ReconcileOpts`] are used, or,
+    /// for accepted sessions, the options set with [`AcceptOpts::reconcile_opts`].
+    ///
+    /// [`AcceptOpts::reconcile_opts`]: crate::engine::AcceptOpts::reconcile_opts
+    #[serde(default)]
+    pub reconcile_opts: Option<ReconcileOpts>,
+    /// Whether and how to reconnect if the connection to the peer is lost.
+    ///
+    /// If `None`, the intent is aborted when the session fails. Otherwise the engine reconnects
+    /// to the peer and resubmits the interests of the intent, see [`RetryPolicy`].
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
 }
 
 impl SessionInit {
     pub fn new(interests: impl Into<Interests>, mode: SessionMode) -> Self {
         let interests = interests.into();
-        Self { interests, mode }
+        Self {
+            interests,
+            mode,
+            lazy_interests: None,
+            reconcile_opts: None,
+            retry: None,
+        }
+    }
+
+    /// Sets the tuning parameters for the reconciliation.
+    ///
+    /// See [`ReconcileOpts`] for details.
+    pub fn reconcile_opts(mut self, opts: ReconcileOpts) -> Self {
+        self.reconcile_opts = Some(opts);
+        self
+    }
+
+    /// Reconnects to the peer with `policy` if the session fails.
+    ///
+    /// See [`RetryPolicy`] for details.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Sets the interests which are synchronised without eagerly receiving their payloads.
+    ///
+    /// See [`Self::lazy_interests`] for details.
+    pub fn lazy_interests(mut self, interests: impl Into<Interests>) -> Self {
+        self.lazy_interests = Some(interests.into());
+        self
     }
 
     /// Creates a new [`SessionInit`] with [`SessionMode::Continuous`].
@@ -106,6 +177,137 @@ impl SessionInit {
     }
 }
 
+/// Tuning parameters for the reconciliation in a session.
+///
+/// Larger sets and split factors need fewer round trips to reconcile a range, at the cost of
+/// sending entries and fingerprints which the other peer may not need. On high-latency links,
+/// it is usually worth it to increase them.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReconcileOpts {
+    /// Up to how many entries to send directly when splitting a range, instead of a fingerprint.
+    ///
+    /// Defaults to 1.
+    pub max_set_size: usize,
+    /// Into how many subranges to split a range whose fingerprint did not match.
+    ///
+    /// This is `k` in the range-based set reconciliation. Values below 2 are treated as 2.
+    /// Defaults to 2.
+    pub split_factor: usize,
+    /// Up to which size payloads are sent eagerly with their entries during reconciliation.
+    ///
+    /// Larger payloads have to be requested from the other peer. The limit announced by the other
+    /// peer applies in any case. Defaults to 256 KiB, the size we announce ourselves.
+    pub max_eager_payload_size: u64,
+}
+
+impl Default for ReconcileOpts {
+    fn default() -> Self {
+        let split_opts = SplitOpts::default();
+        Self {
+            max_set_size: split_opts.max_set_size,
+            split_factor: split_opts.split_factor,
+            max_eager_payload_size: MAX_PAYLOAD_SIZE as u64,
+        }
+    }
+}
+
+impl ReconcileOpts {
+    /// Returns the options used to split ranges in the store.
+    pub(crate) fn split_opts(&self) -> SplitOpts {
+        SplitOpts {
+            max_set_size: self.max_set_size,
+            split_factor: self.split_factor.max(2),
+        }
+    }
+}
+
+/// Policy to reconnect to a peer after a session failed.
+///
+/// When the session of an intent with a retry policy fails, the intent is not aborted. Instead,
+/// its handle receives [`EventKind::Reconnecting`], and the engine waits for the backoff delay
+/// and then reconnects to the peer and resubmits the interests of the intent. Once the interests
+/// are submitted into the new session, the handle receives [`EventKind::Reconnected`].
+///
+/// The delay starts at [`Self::initial_backoff`], and is multiplied by [`Self::multiplier`]
+/// after each failed attempt, up to [`Self::max_backoff`]. If all [`Self::max_attempts`] failed,
+/// the intent is aborted. The count of attempts is reset once the intent reconciled all its
+/// interests, or once a session stayed up for at least [`Self::min_uptime`]. This way, a peer
+/// which accepts connections but fails each session soon after does not reset the backoff.
+///
+/// Intents are also retried if the other peer closed the session, because it may have restarted.
+/// They are not retried if the engine shuts down, or if the other peer denied us.
+///
+/// [`EventKind::Reconnecting`]: intents::EventKind::Reconnecting
+/// [`EventKind::Reconnected`]: intents::EventKind::Reconnected
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Delay before the first attempt to reconnect.
+    ///
+    /// Defaults to 1 second.
+    pub initial_backoff: Duration,
+    /// Upper limit for the delay between attempts.
+    ///
+    /// Defaults to 1 minute.
+    pub max_backoff: Duration,
+    /// Factor by which the delay grows after each failed attempt.
+    ///
+    /// Defaults to 2.
+    pub multiplier: u32,
+    /// Whether to randomize each delay to between half and the full delay.
+    ///
+    /// This avoids that many peers reconnect at the same time. Defaults to `true`.
+    pub jitter: bool,
+    /// Maximum number of attempts to reconnect, or `None` to try forever.
+    ///
+    /// Defaults to 10.
+    pub max_attempts: Option<u32>,
+    /// Time a session has to stay up for the count of attempts to be reset.
+    ///
+    /// Defaults to 1 minute.
+    pub min_uptime: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            multiplier: 2,
+            jitter: true,
+            max_attempts: Some(10),
+            min_uptime: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the attempt number `attempt`, starting at 1, without jitter.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .max(1)
+            .saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// Returns `true` if the attempt number `attempt` exceeds [`Self::max_attempts`].
+    pub fn is_exhausted(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt > max)
+    }
+
+    /// Returns the delay before the attempt number `attempt`, with jitter if enabled.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.backoff(attempt);
+        if self.jitter {
+            rand::thread_rng().gen_range(backoff / 2..=backoff)
+        } else {
+            backoff
+        }
+    }
+}
+
 /// Sender for session events
 #[derive(Debug, Clone)]
 pub(crate) struct EventSender(pub mpsc::Sender<SessionEvent>);
@@ -128,6 +330,9 @@ pub(crate) enum SessionEvent {
         #[debug("ChannelSenders")]
         senders: ChannelSenders,
         remaining_intents: Vec<Intent>,
+        /// Intents with a [`RetryPolicy`] which were not aborted although the session failed or
+        /// was closed by the other peer.
+        retry_intents: Vec<Intent>,
     },
 }
 
@@ -135,6 +340,11 @@ pub(crate) enum SessionEvent {
 #[derive(Debug)]
 pub(crate) enum SessionUpdate {
     SubmitIntent(Intent),
+    /// Request the payload of an entry from the other peer.
+    FetchPayload {
+        entry: Entry,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
     Abort(Error),
 }
 
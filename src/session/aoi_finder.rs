@@ -10,13 +10,15 @@ use crate::{
         keys::NamespaceId,
         meadowcap::{ReadAuthorisation, ReadCapability},
         wgps::{
-            AreaOfInterestHandle, CapabilityHandle, IntersectionHandle, SetupBindAreaOfInterest,
+            AreaOfInterestHandle, CapabilityHandle, ControlFreeHandle, IntersectionHandle, Message,
+            SetupBindAreaOfInterest,
         },
     },
     session::{
         capabilities::Capabilities,
+        intents::NamespaceInterests,
         pai_finder::PaiIntersection,
-        resource::{ResourceMap, Scope},
+        resource::{self, ResourceMap, Scope, MAX_RETAINED_FREED},
         Error,
     },
     util::gen_stream::GenStream,
@@ -29,6 +31,8 @@ pub struct AoiIntersection {
     pub their_handle: AreaOfInterestHandle,
     pub intersection: AreaOfInterest,
     pub namespace: NamespaceId,
+    /// Whether we want the other peer to eagerly send us payloads in this intersection.
+    pub is_eager: bool,
 }
 
 impl AoiIntersection {
@@ -43,17 +47,23 @@ impl AoiIntersection {
 
 #[derive(Debug)]
 pub enum Input {
-    AddInterests(InterestMap),
+    AddInterests {
+        interests: InterestMap,
+        /// The areas of interest in which we do not want payloads to be sent eagerly.
+        lazy: NamespaceInterests,
+    },
     PaiIntersection(PaiIntersection),
     ReceivedValidatedAoi {
         namespace: NamespaceId,
         aoi: AreaOfInterest,
     },
+    /// The other peer sent a [`ControlFreeHandle`] for an area of interest handle.
+    FreeHandle(ControlFreeHandle),
 }
 
 #[derive(Debug)]
 pub enum Output {
-    SendMessage(SetupBindAreaOfInterest),
+    SendMessage(Message),
     SubmitAuthorisation(Box<ReadAuthorisation>),
     AoiIntersection(AoiIntersection),
     SignAndSendCapability {
@@ -69,6 +79,7 @@ pub struct IntersectionFinder {
     caps: Capabilities,
     handles: AoiResources,
     interests: InterestMap,
+    lazy: NamespaceInterests,
 }
 
 impl IntersectionFinder {
@@ -87,6 +98,7 @@ impl IntersectionFinder {
             co,
             caps,
             interests: Default::default(),
+            lazy: Default::default(),
             handles: Default::default(),
         }
     }
@@ -95,7 +107,10 @@ impl IntersectionFinder {
         tokio::pin!(inbox);
         while let Some(input) = inbox.next().await {
             match input {
-                Input::AddInterests(interests) => self.add_interests(interests).await,
+                Input::AddInterests { interests, lazy } => {
+                    self.add_lazy(lazy);
+                    self.add_interests(interests).await
+                }
                 Input::PaiIntersection(intersection) => {
                     self.on_pai_intersection(intersection).await?;
                 }
@@ -104,11 +119,35 @@ impl IntersectionFinder {
                         .bind_validated(&self.co, Scope::Theirs, namespace, aoi)
                         .await;
                 }
+                Input::FreeHandle(message) => {
+                    let reply = resource::on_free_handle(
+                        &mut self.handles.our_handles,
+                        &mut self.handles.their_handles,
+                        &message,
+                        MAX_RETAINED_FREED,
+                    )?;
+                    if let Some(reply) = reply {
+                        self.co.yield_(Output::SendMessage(reply.into())).await;
+                    }
+                }
             }
         }
         Ok(())
     }
 
+    fn add_lazy(&mut self, lazy: NamespaceInterests) {
+        for (namespace, aois) in lazy {
+            self.lazy.entry(namespace).or_default().extend(aois);
+        }
+    }
+
+    fn is_eager(&self, namespace: &NamespaceId, aoi: &AreaOfInterest) -> bool {
+        !self
+            .lazy
+            .get(namespace)
+            .is_some_and(|aois| aois.contains(aoi))
+    }
+
     async fn add_interests(&mut self, interests: InterestMap) {
         for (authorisation, aois) in interests.into_iter() {
             let namespace = authorisation.namespace();
@@ -122,8 +161,15 @@ impl IntersectionFinder {
                         // the AoI right away.
                         if existing.insert(aoi.clone()) {
                             if let Some(capability_handle) = capability_handle {
+                                let is_eager = self.is_eager(&namespace, &aoi);
                                 self.handles
-                                    .bind_and_send_ours(&self.co, namespace, capability_handle, aoi)
+                                    .bind_and_send_ours(
+                                        &self.co,
+                                        namespace,
+                                        capability_handle,
+                                        aoi,
+                                        is_eager,
+                                    )
                                     .await;
                             }
                         }
@@ -162,8 +208,9 @@ impl IntersectionFinder {
         }
 
         for aoi in aois.into_iter() {
+            let is_eager = self.is_eager(&namespace, &aoi);
             self.handles
-                .bind_and_send_ours(&self.co, namespace, capability_handle, aoi)
+                .bind_and_send_ours(&self.co, namespace, capability_handle, aoi, is_eager)
                 .await;
         }
         Ok(())
@@ -183,14 +230,15 @@ impl AoiResources {
         namespace: NamespaceId,
         authorisation: CapabilityHandle,
         aoi: AreaOfInterest,
+        is_eager: bool,
     ) {
-        self.bind_validated(co, Scope::Ours, namespace, aoi.clone())
+        self.bind(co, Scope::Ours, namespace, aoi.clone(), is_eager)
             .await;
         let msg = SetupBindAreaOfInterest {
             area_of_interest: aoi.into(),
             authorisation,
         };
-        co.yield_(Output::SendMessage(msg)).await;
+        co.yield_(Output::SendMessage(msg.into())).await;
     }
     pub async fn bind_validated(
         &mut self,
@@ -198,10 +246,22 @@ impl AoiResources {
         scope: Scope,
         namespace: NamespaceId,
         aoi: AreaOfInterest,
+    ) {
+        self.bind(co, scope, namespace, aoi, true).await
+    }
+
+    async fn bind(
+        &mut self,
+        co: &Co<Output>,
+        scope: Scope,
+        namespace: NamespaceId,
+        aoi: AreaOfInterest,
+        is_eager: bool,
     ) {
         let info = AoiInfo {
             aoi: aoi.clone(),
             namespace,
+            is_eager,
         };
         let bound_handle = match scope {
             Scope::Ours => self.our_handles.bind(info),
@@ -214,7 +274,7 @@ impl AoiResources {
         };
 
         // TODO: If we stored the AoIs by namespace we would need to iterate less.
-        for (other_handle, other_aoi) in store_to_check_against.iter() {
+        for (other_handle, other_aoi) in store_to_check_against.iter_active() {
             if other_aoi.namespace != namespace {
                 continue;
             }
@@ -222,15 +282,16 @@ impl AoiResources {
             // Check if we have an intersection.
             if let Some(intersection) = other_aoi.aoi.intersection(&aoi) {
                 // We found an intersection!
-                let (our_handle, their_handle) = match scope {
-                    Scope::Ours => (bound_handle, other_handle),
-                    Scope::Theirs => (other_handle, bound_handle),
+                let (our_handle, their_handle, is_eager) = match scope {
+                    Scope::Ours => (bound_handle, other_handle, is_eager),
+                    Scope::Theirs => (other_handle, bound_handle, other_aoi.is_eager),
                 };
                 let intersection = AoiIntersection {
                     our_handle,
                     their_handle,
                     intersection,
                     namespace,
+                    is_eager,
                 };
                 co.yield_(Output::AoiIntersection(intersection)).await;
             }
@@ -242,4 +303,6 @@ impl AoiResources {
 struct AoiInfo {
     aoi: AreaOfInterest,
     namespace: NamespaceId,
+    /// For our areas of interest, whether we want payloads to be sent eagerly.
+    is_eager: bool,
 }
@@ -7,14 +7,19 @@ use std::{
 
 use crate::{
     proto::{
+        data_model::Entry,
         keys::UserSignature,
         meadowcap::{ReadCapability, SubspaceCapability},
         wgps::{
-            AccessChallenge, CapabilityHandle, ChallengeHash, CommitmentReveal, IntersectionHandle,
-            PaiReplySubspaceCapability, SetupBindReadCapability,
+            AccessChallenge, CapabilityHandle, ChallengeHash, CommitmentReveal, ControlFreeHandle,
+            IntersectionHandle, PaiReplySubspaceCapability, SetupBindReadCapability,
         },
     },
-    session::{challenge::ChallengeState, resource::ResourceMap, Error, Role},
+    session::{
+        challenge::ChallengeState,
+        resource::{self, ResourceMap, MAX_RETAINED_FREED},
+        Error, Role,
+    },
     store::traits::SecretStorage,
 };
 
@@ -59,10 +64,36 @@ impl Capabilities {
         self.0.borrow().challenge.is_revealed()
     }
 
+    /// Handles a [`ControlFreeHandle`] message for a capability handle.
+    ///
+    /// Returns the message to reply with, if the other peer proposed to free the handle.
+    pub fn on_free_handle(
+        &self,
+        message: &ControlFreeHandle,
+    ) -> Result<Option<ControlFreeHandle>, Error> {
+        let mut inner = self.0.borrow_mut();
+        let Inner { ours, theirs, .. } = &mut *inner;
+        let reply = resource::on_free_handle(ours, theirs, message, MAX_RETAINED_FREED)?;
+        Ok(reply)
+    }
+
     pub fn find_ours(&self, cap: &ReadCapability) -> Option<CapabilityHandle> {
         self.0.borrow().ours.find(cap)
     }
 
+    /// Finds one of our bound capabilities which grants read access to `entry`.
+    pub fn find_ours_for_entry(&self, entry: &Entry) -> Option<CapabilityHandle> {
+        self.0
+            .borrow()
+            .ours
+            .iter_active()
+            .find(|(_handle, cap)| {
+                cap.granted_namespace() == entry.namespace_id()
+                    && cap.granted_area().includes_entry(entry)
+            })
+            .map(|(handle, _cap)| *handle)
+    }
+
     pub fn sign_capability<S: SecretStorage>(
         &self,
         secret_store: &S,
@@ -98,11 +129,20 @@ impl Capabilities {
         Ok(())
     }
 
-    pub async fn get_theirs_eventually(&self, handle: CapabilityHandle) -> ReadCapability {
+    /// Waits until the other peer bound the capability `handle`.
+    ///
+    /// Fails if the handle was bound and freed since.
+    pub async fn get_theirs_eventually(
+        &self,
+        handle: CapabilityHandle,
+    ) -> Result<ReadCapability, Error> {
         poll_fn(|cx| {
             let mut inner = self.0.borrow_mut();
+            if inner.theirs.is_freed(&handle) {
+                return Poll::Ready(Err(Error::MissingResource(handle.into())));
+            }
             let cap = ready!(inner.theirs.poll_get_eventually(handle, cx));
-            Poll::Ready(cap.clone())
+            Poll::Ready(Ok(cap.clone()))
         })
         .await
     }
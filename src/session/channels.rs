@@ -13,7 +13,7 @@ use crate::{
         Channel, DataMessage, IntersectionMessage, LogicalChannel, Message, ReconciliationMessage,
         SetupBindAreaOfInterest, SetupBindReadCapability, SetupBindStaticToken,
     },
-    util::channel::{Receiver, Sender, WriteError},
+    util::channel::{CapacityWatcher, Receiver, Sender, WriteError},
 };
 
 #[derive(Debug)]
@@ -31,6 +31,10 @@ impl<T: TryFrom<Message>> MessageReceiver<T> {
     //     self.inner.close()
     // }
 
+    pub fn capacity_watcher(&self) -> CapacityWatcher {
+        self.inner.capacity_watcher()
+    }
+
     pub fn poll_recv(&mut self, cx: &mut task::Context<'_>) -> Poll<Option<Result<T, Error>>> {
         let message = ready!(Pin::new(&mut self.inner).poll_next(cx));
         let message = match message {
@@ -73,6 +77,17 @@ pub struct LogicalChannelReceivers {
 }
 
 impl LogicalChannelReceivers {
+    pub fn capacity_watcher(&self, channel: LogicalChannel) -> CapacityWatcher {
+        match channel {
+            LogicalChannel::Intersection => self.intersection_recv.capacity_watcher(),
+            LogicalChannel::Reconciliation => self.reconciliation_recv.capacity_watcher(),
+            LogicalChannel::StaticToken => self.static_tokens_recv.capacity_watcher(),
+            LogicalChannel::Capability => self.capability_recv.capacity_watcher(),
+            LogicalChannel::AreaOfInterest => self.aoi_recv.capacity_watcher(),
+            LogicalChannel::Data => self.data_recv.capacity_watcher(),
+        }
+    }
+
     // pub fn close(&self) {
     //     self.intersection_recv.close();
     //     self.reconciliation_recv.close();
@@ -1,15 +1,33 @@
-use futures_lite::StreamExt;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    sync::Arc,
+};
+
+use futures_lite::{Stream, StreamExt};
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use tracing::{debug, warn};
 
 use super::{
     aoi_finder::AoiIntersection,
-    payload::{send_payload_chunked, CurrentPayload},
+    capabilities::Capabilities,
+    intents::{self, EventKind},
+    payload::{payload_available, send_payload_chunked, CurrentPayload},
 };
 use crate::{
     proto::{
-        data_model::AuthorisedEntry,
-        wgps::{DataMessage, DataSendEntry, DataSendPayload, StaticToken},
+        data_model::{AuthorisedEntry, Entry},
+        wgps::{
+            AreaOfInterestHandle, ControlFreeHandle, DataBindPayloadRequest, DataMessage,
+            DataReplyPayload, DataSendEntry, DataSendPayload, DataSetMetadata, HandleType,
+            IsHandle, PayloadRequestHandle, StaticToken,
+        },
+    },
+    session::{
+        channels::ChannelSenders, resource::MissingResource, static_tokens::StaticTokens, Error,
+        SessionId, SessionMode,
     },
-    session::{channels::ChannelSenders, static_tokens::StaticTokens, Error, SessionId},
     store::{
         traits::{EntryOrigin, EntryStorage, Storage, StoreEvent, SubscribeParams},
         Store,
@@ -20,82 +38,468 @@ use crate::{
 #[derive(Debug)]
 pub enum Input {
     AoiIntersection(AoiIntersection),
+    /// Request the payload of an entry from the other peer.
+    ///
+    /// The payload is requested from the number of bytes we already have locally.
+    RequestPayload {
+        entry: Entry,
+        reply: PendingReply,
+    },
+}
+
+/// A payload request from the other peer which we validated and will reply to.
+#[derive(Debug)]
+pub struct PayloadReply {
+    handle: PayloadRequestHandle,
+    entry: Entry,
+    offset: u64,
+    _pending: PendingGuard,
+}
+
+/// Whether payloads are sent eagerly in the intersections of a session.
+///
+/// The data receiver updates the preferences of the other peer from their [`DataSetMetadata`]
+/// messages. They are sent on the data channel, so they apply from when they are received:
+/// payloads which were sent before may still reach us. Therefore we also track our own
+/// preferences, and discard payloads which we did not want to be sent eagerly.
+#[derive(Debug, Clone, Default)]
+pub struct PayloadPreferences(Rc<RefCell<PayloadPreferencesInner>>);
+
+#[derive(Debug, Default)]
+struct PayloadPreferencesInner {
+    /// Intersections in which the other peer does not want payloads to be sent eagerly.
+    theirs_lazy: HashSet<(AreaOfInterestHandle, AreaOfInterestHandle)>,
+    /// Our intersections, with our own preferences.
+    ours: Vec<AoiIntersection>,
+}
+
+impl PayloadPreferences {
+    fn set(&self, message: &DataSetMetadata) {
+        let lazy = &mut self.0.borrow_mut().theirs_lazy;
+        if message.is_eager {
+            lazy.remove(&message.handles());
+        } else {
+            lazy.insert(message.handles());
+        }
+    }
+
+    /// Adds an intersection with our own preference from [`AoiIntersection::is_eager`].
+    pub fn add_ours(&self, intersection: AoiIntersection) {
+        self.0.borrow_mut().ours.push(intersection);
+    }
+
+    /// Returns `true` unless the other peer asked us to not send payloads eagerly in the
+    /// intersection with the handles `id`.
+    pub fn is_eager(&self, id: &(AreaOfInterestHandle, AreaOfInterestHandle)) -> bool {
+        !self.0.borrow().theirs_lazy.contains(id)
+    }
+
+    /// Returns `true` if we want the payload of `entry` to be sent eagerly.
+    ///
+    /// This is the case unless all our intersections which include the entry are lazy. Entries
+    /// outside of all intersections known so far are treated as eager.
+    pub fn we_want_eager(&self, entry: &Entry) -> bool {
+        let inner = self.0.borrow();
+        let mut intersections = inner
+            .ours
+            .iter()
+            .filter(|intersection| {
+                intersection.namespace == *entry.namespace_id()
+                    && intersection.area().includes_entry(entry)
+            })
+            .peekable();
+        intersections.peek().is_none() || intersections.any(|intersection| intersection.is_eager)
+    }
+}
+
+/// State for payload requests, shared between the data loops, the reconciler and the control
+/// loop.
+#[derive(Debug, Clone, Default)]
+pub struct PayloadRequests(Rc<PayloadRequestsShared>);
+
+#[derive(Debug, Default)]
+struct PayloadRequestsShared {
+    inner: RefCell<PayloadRequestsInner>,
+    /// Number of our requests and of their requests which are not completed yet.
+    pending: Cell<usize>,
+    /// Notified whenever one of our requests is answered or completed.
+    changed: Notify,
+}
+
+#[derive(Debug, Default)]
+struct PayloadRequestsInner {
+    ours: HashMap<PayloadRequestHandle, PayloadRequest>,
+    next_ours: u64,
+    next_theirs: u64,
+    /// Set once we cannot receive replies anymore.
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct PayloadRequest {
+    entry: Entry,
+    offset: u64,
+    reply: PendingReply,
+}
+
+/// Sender for the result of one of our payload requests.
+///
+/// The request counts as pending until the result is sent, see [`PayloadRequests::completed`].
+#[derive(derive_more::Debug)]
+pub struct PendingReply {
+    #[debug("reply")]
+    reply: oneshot::Sender<Result<(), Error>>,
+    _pending: PendingGuard,
+}
+
+impl PendingReply {
+    pub fn send(self, result: Result<(), Error>) {
+        self.reply.send(result).ok();
+    }
+}
+
+/// Counts a payload request as pending until dropped, see [`PayloadRequests::completed`].
+#[derive(Debug)]
+struct PendingGuard(PayloadRequests);
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let shared = &(self.0).0;
+        shared.pending.set(shared.pending.get() - 1);
+        shared.changed.notify_waiters();
+    }
+}
+
+impl PayloadRequests {
+    /// Requests the payload of `entry` from the other peer, starting at the bytes we are missing.
+    ///
+    /// The result is sent on `reply` once the payload is complete, or once the request failed.
+    /// Returns the handle of the request, or `None` if the result was sent right away, because we
+    /// have the complete payload or cannot request it.
+    ///
+    /// The other peer only answers requests if it has the complete payload, and rejects them
+    /// otherwise, which fails the request with [`Error::PayloadNotAvailable`].
+    pub async fn request<S: Storage>(
+        &self,
+        store: &Store<S>,
+        caps: &Capabilities,
+        send: &ChannelSenders,
+        entry: Entry,
+        reply: PendingReply,
+    ) -> Result<Option<PayloadRequestHandle>, Error> {
+        if self.0.inner.borrow().closed {
+            reply.send(Err(Error::ChannelClosed));
+            return Ok(None);
+        }
+        let payload_len = entry.payload_length();
+        let offset =
+            payload_available(*entry.payload_digest(), payload_len, store.payloads()).await?;
+        if offset == payload_len {
+            reply.send(Ok(()));
+            return Ok(None);
+        }
+        let Some(capability) = caps.find_ours_for_entry(&entry) else {
+            reply.send(Err(Error::MissingReadCapability));
+            return Ok(None);
+        };
+        let msg = DataBindPayloadRequest {
+            entry: entry.clone().into(),
+            offset,
+            capability,
+        };
+        let handle = {
+            let mut inner = self.0.inner.borrow_mut();
+            let handle = PayloadRequestHandle::from(inner.next_ours);
+            inner.next_ours += 1;
+            inner.ours.insert(
+                handle,
+                PayloadRequest {
+                    entry,
+                    offset,
+                    reply,
+                },
+            );
+            handle
+        };
+        debug!(handle = handle.value(), offset, "request payload");
+        send.send(msg).await?;
+        Ok(Some(handle))
+    }
+
+    /// Wraps the sender for the result of one of our requests.
+    ///
+    /// The request counts as pending from now on, so that a session does not close while the
+    /// request waits to be sent.
+    pub fn pending_reply(&self, reply: oneshot::Sender<Result<(), Error>>) -> PendingReply {
+        PendingReply {
+            reply,
+            _pending: self.pending(),
+        }
+    }
+
+    fn pending(&self) -> PendingGuard {
+        self.0.pending.set(self.0.pending.get() + 1);
+        PendingGuard(self.clone())
+    }
+
+    fn bind_theirs(&self) -> PayloadRequestHandle {
+        let mut inner = self.0.inner.borrow_mut();
+        let handle = PayloadRequestHandle::from(inner.next_theirs);
+        inner.next_theirs += 1;
+        handle
+    }
+
+    fn take_ours(&self, handle: PayloadRequestHandle) -> Result<PayloadRequest, MissingResource> {
+        let request = self
+            .0
+            .inner
+            .borrow_mut()
+            .ours
+            .remove(&handle)
+            .ok_or(MissingResource(handle.into()))?;
+        self.0.changed.notify_waiters();
+        Ok(request)
+    }
+
+    /// Waits until the other peer answered all requests in `handles`.
+    ///
+    /// A request is answered once the other peer replies with the payload, or frees the request
+    /// handle because it cannot reply.
+    pub async fn answered(&self, handles: &[PayloadRequestHandle]) {
+        loop {
+            let changed = self.0.changed.notified();
+            let answered = {
+                let inner = self.0.inner.borrow();
+                handles
+                    .iter()
+                    .all(|handle| !inner.ours.contains_key(handle))
+            };
+            if answered {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Waits until all our requests are completed, and all replies to their requests are sent.
+    pub async fn completed(&self) {
+        loop {
+            let changed = self.0.changed.notified();
+            if self.0.pending.get() == 0 {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Fails all our requests which were not answered yet, and all further requests.
+    ///
+    /// Called once the data receiver terminated, because no replies can be received anymore.
+    pub fn close(&self) {
+        let ours = {
+            let mut inner = self.0.inner.borrow_mut();
+            inner.closed = true;
+            std::mem::take(&mut inner.ours)
+        };
+        for request in ours.into_values() {
+            request.reply.send(Err(Error::ChannelClosed));
+        }
+        self.0.changed.notify_waiters();
+    }
+
+    /// Handles a [`ControlFreeHandle`] message for a payload request handle.
+    ///
+    /// The other peer frees our request handles if they cannot reply with the payload.
+    /// We do not keep state for their request handles, so there is nothing to free for those.
+    pub fn on_free_handle(&self, message: ControlFreeHandle) {
+        if message.mine {
+            return;
+        }
+        let handle = PayloadRequestHandle::from(message.handle);
+        if let Ok(request) = self.take_ours(handle) {
+            request.reply.send(Err(Error::PayloadNotAvailable));
+        }
+    }
 }
 
 #[derive(derive_more::Debug)]
 pub struct DataSender<S: Storage> {
     inbox: CancelableReceiver<Input>,
+    replies: mpsc::Receiver<PayloadReply>,
+    mode: watch::Receiver<SessionMode>,
     store: Store<S>,
     send: ChannelSenders,
     static_tokens: StaticTokens,
+    caps: Capabilities,
+    requests: PayloadRequests,
     session_id: SessionId,
+    preferences: PayloadPreferences,
+    intersections: Vec<AoiIntersection>,
 }
 
 impl<S: Storage> DataSender<S> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         inbox: CancelableReceiver<Input>,
+        replies: mpsc::Receiver<PayloadReply>,
+        mode: watch::Receiver<SessionMode>,
         store: Store<S>,
         send: ChannelSenders,
         static_tokens: StaticTokens,
+        caps: Capabilities,
+        requests: PayloadRequests,
+        preferences: PayloadPreferences,
         session_id: SessionId,
     ) -> Self {
         Self {
             inbox,
+            replies,
+            mode,
             store,
             send,
             static_tokens,
+            caps,
+            requests,
+            preferences,
             session_id,
+            intersections: Default::default(),
         }
     }
+    /// Run the data sender.
+    ///
+    /// We only subscribe to new entries once the session is in live mode. For intersections found
+    /// before, we remember the progress id of the store at that time, so that no entries are
+    /// missed if a session is upgraded from [`SessionMode::ReconcileOnce`] to
+    /// [`SessionMode::Continuous`].
     pub async fn run(mut self) -> Result<(), Error> {
         let mut entry_stream = futures_concurrency::stream::StreamGroup::new();
+        // Intersections we did not subscribe to yet, with the progress id to start from.
+        let mut unsubscribed = vec![];
         loop {
+            let is_live = self.mode.borrow().is_live();
+            if is_live {
+                for (intersection, progress_id) in unsubscribed.drain(..) {
+                    entry_stream.insert(self.subscribe(&intersection, progress_id));
+                }
+            }
             tokio::select! {
                 input = self.inbox.next() => {
                     let Some(input) = input else {
                         break;
                     };
-                    let Input::AoiIntersection(intersection) = input;
-                    let params = SubscribeParams::default().ingest_only().ignore_remote(self.session_id);
-                    // TODO: We could start at the progress id at the beginning of the session.
-                    let stream = self
-                        .store
-                        .entries()
-                        .subscribe_area(
-                            intersection.namespace,
-                            intersection.intersection.area.clone(),
-                            params,
-                        )
-                        .filter_map(|event| match event {
-                            StoreEvent::Ingested(_id, entry, _origin) => Some(entry),
-                            // We get only Ingested events because we set ingest_only() param above.
-                            _ => unreachable!("expected only Ingested event but got another event"),
-                        });
-                    entry_stream.insert(stream);
+                    let intersection = match input {
+                        Input::AoiIntersection(intersection) => intersection,
+                        Input::RequestPayload { entry, reply } => {
+                            self.requests
+                                .request(&self.store, &self.caps, &self.send, entry, reply)
+                                .await?;
+                            continue;
+                        }
+                    };
+                    // Tell the other peer if we do not want payloads sent eagerly.
+                    if !intersection.is_eager {
+                        let msg = DataSetMetadata {
+                            sender_handle: intersection.our_handle,
+                            receiver_handle: intersection.their_handle,
+                            is_eager: false,
+                        };
+                        self.send.send(msg).await?;
+                    }
+                    let progress_id = self.store.entries().next_progress_id(intersection.namespace);
+                    self.intersections.push(intersection.clone());
+                    unsubscribed.push((intersection, progress_id));
                 },
-                entry = entry_stream.next(), if !entry_stream.is_empty() => {
+                Ok(()) = self.mode.changed(), if !is_live => {
+                    debug!(mode = ?*self.mode.borrow(), "session mode changed");
+                }
+                entry = entry_stream.next(), if is_live && !entry_stream.is_empty() => {
                     match entry {
-                        Some(entry) => self.send_entry(entry).await?,
+                        Some(entry) => {
+                            let entry = entry?;
+                            // An offset equal to the payload length sends no payload.
+                            let offset = if self.wants_payload(entry.entry()) {
+                                0
+                            } else {
+                                entry.entry().payload_length()
+                            };
+                            self.send_entry(entry, offset).await?
+                        }
                         None => break,
                     }
                 }
+                Some(reply) = self.replies.recv() => {
+                    self.reply_payload(reply).await?;
+                }
             }
         }
         Ok(())
     }
 
-    async fn send_entry(&mut self, authorised_entry: AuthorisedEntry) -> Result<(), Error> {
+    /// Subscribes to the entries ingested into `intersection` since `progress_id`.
+    ///
+    /// If we missed store events, the stream yields [`Error::MissedStoreEvents`]: entries ingested
+    /// in the gap would never be sent, so the session fails instead, and is reconciled again once
+    /// it is reconnected.
+    fn subscribe(
+        &self,
+        intersection: &AoiIntersection,
+        progress_id: u64,
+    ) -> impl Stream<Item = Result<AuthorisedEntry, Error>> + Unpin + 'static {
+        let params = SubscribeParams::default()
+            .ingest_only()
+            .ignore_remote(self.session_id);
+        self.store
+            .entries()
+            .resume_subscription(
+                progress_id,
+                intersection.namespace,
+                intersection.intersection.area.clone(),
+                params,
+            )
+            .filter_map(|event| match event {
+                StoreEvent::Ingested(_id, entry, _origin) => Some(Ok(entry)),
+                StoreEvent::Lagged(_) => {
+                    warn!("missed store events, fail the session");
+                    Some(Err(Error::MissedStoreEvents))
+                }
+                // We get only Ingested events because we set ingest_only() param above.
+                _ => unreachable!("expected only Ingested event but got another event"),
+            })
+    }
+
+    /// Returns `true` if the other peer wants the payload of `entry` to be sent eagerly.
+    ///
+    /// This is the case unless all intersections which include the entry are marked as lazy.
+    fn wants_payload(&self, entry: &Entry) -> bool {
+        let mut intersections = self.intersections.iter().filter(|intersection| {
+            intersection.namespace == *entry.namespace_id()
+                && intersection.area().includes_entry(entry)
+        });
+        intersections.any(|intersection| self.preferences.is_eager(&intersection.id()))
+    }
+
+    /// Sends an entry, and the bytes of its payload from `offset` up to what we have available.
+    async fn send_entry(
+        &mut self,
+        authorised_entry: AuthorisedEntry,
+        offset: u64,
+    ) -> Result<(), Error> {
         let (entry, token) = authorised_entry.into_parts();
         let static_token: StaticToken = token.capability.into();
         let dynamic_token = token.signature;
-        // TODO: partial payloads
-        // let available = entry.payload_length;
+        let digest = *entry.payload_digest();
+        let payload_len = entry.payload_length();
+        let available = payload_available(digest, payload_len, self.store.payloads()).await?;
+        // An offset equal to the payload length signals that no payload will be transmitted.
+        let offset = if offset < available {
+            offset
+        } else {
+            payload_len
+        };
         let static_token_handle = self
             .static_tokens
             .bind_and_send_ours(static_token, &self.send)
             .await?;
-        let digest = *entry.payload_digest();
-        let offset = 0;
         let msg = DataSendEntry {
             entry: entry.into(),
             static_token_handle,
@@ -103,9 +507,9 @@ impl<S: Storage> DataSender<S> {
             offset,
         };
         self.send.send(msg).await?;
+        self.static_tokens.release_ours(static_token_handle)?;
 
-        // TODO: only send payload if configured to do so and/or under size limit.
-        let send_payloads = true;
+        let send_payloads = offset < available;
         if send_payloads {
             send_payload_chunked(digest, self.store.payloads(), &self.send, offset, |bytes| {
                 DataSendPayload { bytes }.into()
@@ -114,23 +518,83 @@ impl<S: Storage> DataSender<S> {
         }
         Ok(())
     }
+
+    /// Replies to a payload request from the other peer.
+    ///
+    /// We only reply if we have the complete payload. Requests for payloads which we only have
+    /// partially are rejected: we free their request handle, to let them know that we cannot
+    /// reply.
+    async fn reply_payload(&mut self, reply: PayloadReply) -> Result<(), Error> {
+        let PayloadReply {
+            handle,
+            entry,
+            offset,
+            _pending,
+        } = reply;
+        let digest = *entry.payload_digest();
+        let payload_len = entry.payload_length();
+        let available = payload_available(digest, payload_len, self.store.payloads()).await?;
+        if available < payload_len || offset >= payload_len {
+            debug!(
+                handle = handle.value(),
+                "payload not available, free request"
+            );
+            let msg = ControlFreeHandle {
+                handle: handle.value(),
+                mine: false,
+                handle_type: HandleType::PayloadRequest,
+            };
+            self.send.send(msg).await?;
+            return Ok(());
+        }
+        self.send.send(DataReplyPayload { handle }).await?;
+        send_payload_chunked(digest, self.store.payloads(), &self.send, offset, |bytes| {
+            DataSendPayload { bytes }.into()
+        })
+        .await?;
+        Ok(())
+    }
 }
 
 #[derive(derive_more::Debug)]
 pub struct DataReceiver<S: Storage> {
     store: Store<S>,
     current_payload: CurrentPayload,
+    /// The reply sender for our payload request which is currently being answered.
+    current_request: Option<PendingReply>,
     static_tokens: StaticTokens,
+    caps: Capabilities,
+    requests: PayloadRequests,
+    replies: mpsc::Sender<PayloadReply>,
+    preferences: PayloadPreferences,
     session_id: SessionId,
+    /// Inbox of the intents, to report entries we rejected.
+    intents: mpsc::Sender<intents::Input>,
 }
 
 impl<S: Storage> DataReceiver<S> {
-    pub fn new(store: Store<S>, static_tokens: StaticTokens, session_id: SessionId) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        store: Store<S>,
+        static_tokens: StaticTokens,
+        caps: Capabilities,
+        requests: PayloadRequests,
+        replies: mpsc::Sender<PayloadReply>,
+        preferences: PayloadPreferences,
+        session_id: SessionId,
+        intents: mpsc::Sender<intents::Input>,
+    ) -> Self {
         Self {
             store,
             static_tokens,
+            caps,
+            requests,
+            replies,
+            preferences,
             session_id,
+            intents,
             current_payload: Default::default(),
+            current_request: None,
         }
     }
 
@@ -138,13 +602,69 @@ impl<S: Storage> DataReceiver<S> {
         match message {
             DataMessage::SendEntry(message) => self.on_send_entry(message).await?,
             DataMessage::SendPayload(message) => self.on_send_payload(message).await?,
-            DataMessage::SetMetadata(_) => {}
+            DataMessage::SetMetadata(message) => {
+                debug!(is_eager = message.is_eager, "recv SetMetadata");
+                self.preferences.set(&message);
+            }
+            DataMessage::BindPayloadRequest(message) => {
+                self.on_bind_payload_request(message).await?
+            }
+            DataMessage::ReplyPayload(message) => self.on_reply_payload(message).await?,
+        }
+        Ok(())
+    }
+
+    /// Ends the transmission of the current payload, if any.
+    ///
+    /// A new entry or payload reply ends the transmission of the previous payload. The other peer
+    /// may not have had the full payload, so we keep what we received so far.
+    async fn finish_current_payload(&mut self) {
+        self.current_payload.finalize_partial().await;
+        if let Some(reply) = self.current_request.take() {
+            reply.send(Err(Error::PayloadIncomplete));
         }
+    }
+
+    async fn on_bind_payload_request(
+        &mut self,
+        message: DataBindPayloadRequest,
+    ) -> Result<(), Error> {
+        let handle = self.requests.bind_theirs();
+        let entry: Entry = message.entry.into();
+        let cap = self.caps.get_theirs_eventually(message.capability).await?;
+        if cap.granted_namespace() != entry.namespace_id()
+            || !cap.granted_area().includes_entry(&entry)
+        {
+            return Err(Error::UnauthorisedArea);
+        }
+        let reply = PayloadReply {
+            handle,
+            entry,
+            offset: message.offset,
+            _pending: self.requests.pending(),
+        };
+        // Waits if the data sender has too many replies queued, which stops us from reading
+        // further data messages until the sender catches up.
+        // The data sender is closed only once the session terminates.
+        self.replies.send(reply).await.ok();
+        Ok(())
+    }
+
+    async fn on_reply_payload(&mut self, message: DataReplyPayload) -> Result<(), Error> {
+        self.finish_current_payload().await;
+        let request = self.requests.take_ours(message.handle)?;
+        let PayloadRequest {
+            entry,
+            offset,
+            reply,
+        } = request;
+        self.current_payload.set(entry, None, Some(offset))?;
+        self.current_request = Some(reply);
         Ok(())
     }
 
     async fn on_send_entry(&mut self, message: DataSendEntry) -> Result<(), Error> {
-        self.current_payload.ensure_none()?;
+        self.finish_current_payload().await;
         let authorised_entry = self
             .static_tokens
             .authorise_entry_eventually(
@@ -153,26 +673,57 @@ impl<S: Storage> DataReceiver<S> {
                 message.dynamic_token,
             )
             .await?;
-        self.store
-            .entries()
-            .ingest_entry(&authorised_entry, EntryOrigin::Remote(self.session_id))?;
+        // An entry over quota is rejected on its own, the session continues.
+        let store_payload = match self.store.check_remote_quotas(authorised_entry.entry())? {
+            Ok(store_payload) => {
+                self.store
+                    .entries()
+                    .ingest_entry(&authorised_entry, EntryOrigin::Remote(self.session_id))?;
+                store_payload
+            }
+            Err(err) => {
+                debug!(%err, "skip entry: exceeds quota");
+                let event = EventKind::EntryRejected {
+                    entry: authorised_entry.entry().clone(),
+                    error: Arc::new(err.into()),
+                };
+                // The intents inbox is only closed after the data receiver terminated.
+                self.intents
+                    .send(intents::Input::EmitEvent(event))
+                    .await
+                    .ok();
+                false
+            }
+        };
         let (entry, _token) = authorised_entry.into_parts();
-        // TODO: handle offset
-        self.current_payload.set(
-            *entry.payload_digest(),
-            entry.payload_length(),
-            None,
-            Some(message.offset),
-        )?;
+        let len = entry.payload_length();
+        let eager = self.preferences.we_want_eager(&entry);
+        self.current_payload
+            .set(entry, None, Some(message.offset))?;
+        if !store_payload {
+            debug!(len, "skip payload: exceeds quota");
+            self.current_payload.skip()?;
+        } else if !eager {
+            debug!(len, "skip payload: lazy area");
+            self.current_payload.skip()?;
+        }
         Ok(())
     }
 
     async fn on_send_payload(&mut self, message: DataSendPayload) -> Result<(), Error> {
         self.current_payload
-            .recv_chunk(self.store.payloads(), message.bytes)
+            .recv_chunk(&self.store, message.bytes)
             .await?;
         if self.current_payload.is_complete() {
-            self.current_payload.finalize().await?;
+            let res = self.current_payload.finalize(&self.store).await;
+            if let Some(reply) = self.current_request.take() {
+                let res = match &res {
+                    Ok(()) => Ok(()),
+                    Err(_) => Err(Error::PayloadIncomplete),
+                };
+                reply.send(res);
+            }
+            res?;
         }
         Ok(())
     }
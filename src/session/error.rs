@@ -2,9 +2,13 @@ use ed25519_dalek::SignatureError;
 use tokio::sync::mpsc;
 
 use crate::{
-    proto::{data_model::UnauthorisedWriteError, meadowcap::UserId, wgps::ResourceHandle},
+    proto::{
+        data_model::UnauthorisedWriteError,
+        meadowcap::UserId,
+        wgps::{HandleType, ResourceHandle},
+    },
     session::{pai_finder::PaiError, resource::MissingResource},
-    store::traits::SecretStoreError,
+    store::traits::{QuotaExceeded, SecretStoreError},
     util::channel::{ReadError, WriteError},
 };
 
@@ -32,6 +36,8 @@ pub enum Error {
     WrongSecretKeyForCapability,
     #[error("missing resource {0:?}")]
     MissingResource(ResourceHandle),
+    #[error("the other peer bound more {0} handles than we allow")]
+    HandleLimitExceeded(HandleType),
     #[error("received capability is invalid")]
     InvalidCapability,
     #[error("received capability has an invalid signature")]
@@ -82,6 +88,20 @@ pub enum Error {
     ConnectionClosed(#[source] anyhow::Error),
     #[error("Session was closed by peer")]
     SessionClosedByPeer,
+    #[error("connection was rejected by peer: {0}")]
+    Rejected(crate::engine::RejectReason),
+    #[error("our connection limits are reached")]
+    ConnectionLimitReached,
+    #[error("no read capability for the requested entry is bound in this session")]
+    MissingReadCapability,
+    #[error("the other peer does not have the requested payload")]
+    PayloadNotAvailable,
+    #[error("the payload transfer ended before the payload was complete")]
+    PayloadIncomplete,
+    #[error("missed new entries in the store, because the session did not keep up")]
+    MissedStoreEvents,
+    #[error("received an entry which exceeds our storage quota: {0}")]
+    QuotaExceeded(#[from] QuotaExceeded),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -112,6 +132,7 @@ impl PartialEq for Error {
             (Self::InvalidParameters(l0), Self::InvalidParameters(r0)) => l0 == r0,
             (Self::InvalidState(l0), Self::InvalidState(r0)) => l0 == r0,
             (Self::MissingUserKey(l0), Self::MissingUserKey(r0)) => l0 == r0,
+            (Self::QuotaExceeded(l0), Self::QuotaExceeded(r0)) => l0 == r0,
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
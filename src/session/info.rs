@@ -0,0 +1,158 @@
+//! Introspection of running sessions.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    engine::{EngineEvent, EngineEventSender},
+    interest::Interests,
+    proto::{
+        grouping::{
+            serde_encoding::{SerdeArea, SerdeAreaOfInterest},
+            Area, AreaOfInterest,
+        },
+        keys::NamespaceId,
+        wgps::Channel,
+    },
+    session::{intents::IntentId, Role, SessionMode},
+};
+
+/// Maximum number of areas kept in each list of a [`SessionInfo`].
+///
+/// Long-running sessions may see an unbounded number of areas. Further areas are still emitted
+/// as [`EngineEvent`]s, but not kept in the info.
+pub const MAX_INFO_AREAS: usize = 1024;
+
+/// Information about a running session.
+///
+/// The lists of areas contain no duplicates, and hold at most the first [`MAX_INFO_AREAS`] areas each.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// Our role in the session.
+    pub role: Role,
+    /// The current mode of the session.
+    pub mode: SessionMode,
+    /// When the session was started.
+    pub started: SystemTime,
+    /// The intents attached to the session which are not complete yet.
+    pub intents: Vec<AttachedIntent>,
+    /// The areas in which our and their capabilities intersect.
+    pub capability_intersections: Vec<(NamespaceId, SerdeArea)>,
+    /// The areas in which our and their areas of interest intersect.
+    pub interest_intersections: Vec<(NamespaceId, SerdeAreaOfInterest)>,
+    /// The areas of interest which were reconciled.
+    pub reconciled: Vec<(NamespaceId, SerdeAreaOfInterest)>,
+    /// Bytes sent and received on each channel of the connection.
+    pub channels: Vec<ChannelInfo>,
+}
+
+/// An intent attached to a running session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachedIntent {
+    /// Id of the intent, unique within the session.
+    pub id: IntentId,
+    /// The session mode requested by the intent.
+    pub mode: SessionMode,
+    /// The interests the intent was submitted with.
+    pub interests: Interests,
+    /// Interests which were added to the intent after it was submitted.
+    pub added_interests: Vec<Interests>,
+    /// The areas of interest of the intent which were not reconciled yet.
+    ///
+    /// Holds at most the first [`MAX_INFO_AREAS`] areas.
+    pub pending: Vec<(NamespaceId, SerdeAreaOfInterest)>,
+}
+
+/// Bytes sent and received on a channel of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelInfo {
+    /// The channel.
+    pub channel: Channel,
+    /// Number of bytes we sent on the channel.
+    pub bytes_sent: u64,
+    /// Number of bytes we received on the channel.
+    pub bytes_received: u64,
+}
+
+/// Tracks the [`SessionInfo`] of a running session, and emits [`EngineEvent`]s for it.
+///
+/// The session updates the info, while the peer manager reads it from another thread.
+#[derive(Debug, Clone)]
+pub(crate) struct SessionTracker {
+    info: Arc<Mutex<SessionInfo>>,
+    peer: NodeId,
+    events: Option<EngineEventSender>,
+}
+
+impl SessionTracker {
+    pub(crate) fn new(peer: NodeId, role: Role, events: Option<EngineEventSender>) -> Self {
+        let info = Arc::new(Mutex::new(SessionInfo {
+            role,
+            mode: SessionMode::ReconcileOnce,
+            started: SystemTime::now(),
+            intents: Default::default(),
+            capability_intersections: Default::default(),
+            interest_intersections: Default::default(),
+            reconciled: Default::default(),
+            channels: Default::default(),
+        }));
+        Self { info, peer, events }
+    }
+
+    fn emit(&self, event: EngineEvent) {
+        if let Some(events) = &self.events {
+            // Sending only fails if there are no subscribers.
+            events.send((self.peer, event)).ok();
+        }
+    }
+
+    /// Returns a snapshot of the session info.
+    pub(crate) fn info(&self) -> SessionInfo {
+        self.info.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_mode(&self, mode: SessionMode) {
+        self.info.lock().unwrap().mode = mode;
+    }
+
+    pub(crate) fn set_intents(&self, intents: Vec<AttachedIntent>) {
+        self.info.lock().unwrap().intents = intents;
+    }
+
+    pub(crate) fn add_capability_intersection(&self, namespace: NamespaceId, area: Area) {
+        let area = SerdeArea(area);
+        push_bounded(
+            &mut self.info.lock().unwrap().capability_intersections,
+            (namespace, area.clone()),
+        );
+        self.emit(EngineEvent::CapabilityIntersection { namespace, area });
+    }
+
+    pub(crate) fn add_interest_intersection(&self, namespace: NamespaceId, aoi: AreaOfInterest) {
+        push_bounded(
+            &mut self.info.lock().unwrap().interest_intersections,
+            (namespace, SerdeAreaOfInterest(aoi)),
+        );
+    }
+
+    pub(crate) fn add_reconciled(&self, namespace: NamespaceId, aoi: AreaOfInterest) {
+        let area = SerdeAreaOfInterest(aoi);
+        push_bounded(
+            &mut self.info.lock().unwrap().reconciled,
+            (namespace, area.clone()),
+        );
+        self.emit(EngineEvent::Reconciled { namespace, area });
+    }
+}
+
+/// Pushes `item` onto `list`, unless it is already contained or the list is full.
+fn push_bounded<T: PartialEq>(list: &mut Vec<T>, item: T) {
+    if list.len() < MAX_INFO_AREAS && !list.contains(&item) {
+        list.push(item);
+    }
+}
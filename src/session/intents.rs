@@ -12,6 +12,7 @@ use std::{
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
@@ -27,15 +28,19 @@ use tracing::{debug, trace, warn};
 use crate::{
     interest::{InterestMap, Interests},
     proto::{
-        grouping::{Area, AreaOfInterest},
+        data_model::Entry,
+        grouping::{serde_encoding::SerdeAreaOfInterest, Area, AreaOfInterest},
         keys::NamespaceId,
     },
-    session::{error::ChannelReceiverDropped, Error, SessionInit, SessionMode},
+    session::{
+        error::ChannelReceiverDropped, AttachedIntent, Error, SessionInit, SessionMode,
+        SessionTracker, MAX_INFO_AREAS,
+    },
     store::{auth::Auth, traits::Storage},
     util::gen_stream::GenStream,
 };
 
-type NamespaceInterests = HashMap<NamespaceId, HashSet<AreaOfInterest>>;
+pub(super) type NamespaceInterests = HashMap<NamespaceId, HashSet<AreaOfInterest>>;
 
 const INTENT_UPDATE_CAP: usize = 16;
 const INTENT_EVENT_CAP: usize = 64;
@@ -63,8 +68,29 @@ pub enum EventKind {
     },
     /// We reconciled all interests submitted in this intent.
     ReconciledAll,
+    /// We did not store an entry received from the peer, because it exceeds our storage quota.
+    ///
+    /// Emitted to all intents of the session. The session continues. The peer still has the entry,
+    /// so it is sent again in each later reconciliation of its area, and rejected again until the
+    /// quota allows to store it.
+    EntryRejected {
+        entry: Entry,
+        /// The reason, an [`Error::QuotaExceeded`].
+        error: Arc<Error>,
+    },
     /// The session was closed with an error.
     Abort { error: Arc<Error> },
+    /// The session failed, and we will try to reconnect after `delay`.
+    ///
+    /// Only emitted for intents with a [`RetryPolicy`](crate::session::RetryPolicy).
+    Reconnecting {
+        /// The number of the upcoming attempt, starting at 1.
+        attempt: u32,
+        delay: Duration,
+        error: Arc<Error>,
+    },
+    /// We reconnected to the peer, and resubmitted the interests of this intent.
+    Reconnected,
 }
 
 impl EventKind {
@@ -74,6 +100,7 @@ impl EventKind {
             EventKind::CapabilityIntersection { namespace, .. } => Some(*namespace),
             EventKind::InterestIntersection { namespace, .. } => Some(*namespace),
             EventKind::Reconciled { namespace, .. } => Some(*namespace),
+            EventKind::EntryRejected { entry, .. } => Some(*entry.namespace_id()),
             _ => None,
         }
     }
@@ -100,6 +127,10 @@ pub enum IntentUpdate {
 #[derive(Debug)]
 pub struct Intent {
     pub(super) init: SessionInit,
+    /// Interests added with [`IntentUpdate::AddInterests`] in a previous session.
+    added_interests: Vec<Interests>,
+    /// The number of failed attempts to reconnect, see [`Self::retry_or_abort`].
+    attempt: u32,
     channels: Option<IntentChannels>,
 }
 
@@ -121,6 +152,8 @@ impl Intent {
     pub fn new_detached(init: SessionInit) -> Self {
         Self {
             init,
+            added_interests: Default::default(),
+            attempt: 0,
             channels: None,
         }
     }
@@ -139,6 +172,8 @@ impl Intent {
         };
         let intent = Intent {
             init,
+            added_interests: Default::default(),
+            attempt: 0,
             channels: Some(channels),
         };
         (intent, handle)
@@ -156,6 +191,45 @@ impl Intent {
                 .ok();
         }
     }
+
+    /// Returns `true` if the intent has a [`RetryPolicy`](crate::session::RetryPolicy).
+    pub(crate) fn has_retry_policy(&self) -> bool {
+        self.init.retry.is_some()
+    }
+
+    /// Prepares the intent to reconnect after its session failed with `error`.
+    ///
+    /// Returns the delay after which the intent should be resubmitted. If the intent has no retry
+    /// policy left, or if its handle was dropped, the intent is aborted and `None` is returned.
+    pub(crate) async fn retry_or_abort(mut self, error: Arc<Error>) -> Option<(Duration, Self)> {
+        let Some(policy) = self.init.retry else {
+            self.send_abort(error).await;
+            return None;
+        };
+        let attempt = self.attempt + 1;
+        if policy.is_exhausted(attempt) {
+            debug!(
+                attempts = self.attempt,
+                "retry policy exhausted, abort intent"
+            );
+            self.send_abort(error).await;
+            return None;
+        }
+        let delay = policy.delay(attempt);
+        if let Some(channels) = &self.channels {
+            let event = EventKind::Reconnecting {
+                attempt,
+                delay,
+                error,
+            };
+            if channels.event_tx.send(event).await.is_err() {
+                debug!("intent handle dropped, do not reconnect");
+                return None;
+            }
+        }
+        self.attempt = attempt;
+        Some((delay, self))
+    }
 }
 
 /// Outcome of driving an intent to completion.
@@ -287,44 +361,66 @@ struct IntentChannels {
 pub(super) enum Input {
     EmitEvent(EventKind),
     SubmitIntent(Intent),
+    /// The other peer upgraded the session to continuous mode.
+    PeerUpgraded,
 }
 
 #[derive(Debug)]
 pub(super) enum Output {
-    SubmitInterests(InterestMap),
+    SubmitInterests {
+        interests: InterestMap,
+        /// The areas of interest in which we do not want payloads to be sent eagerly.
+        lazy: NamespaceInterests,
+    },
+    /// The session mode changed, because an intent with a different mode was submitted.
+    ///
+    /// The change is announced to the other peer.
+    SetMode(SessionMode),
+    /// The other peer upgraded the session to continuous mode.
+    PeerUpgraded,
     AllIntentsDropped,
 }
 
 #[derive(Debug)]
 pub(crate) struct RemainingIntents {
-    pub(crate) active_incomplete: Vec<Sender<EventKind>>,
+    pub(crate) active_incomplete: Vec<Intent>,
     pub(crate) queued: Vec<Intent>,
 }
 
 impl RemainingIntents {
     /// Abort both incomplete active and queued unprocessed intents.
     pub async fn abort_all(self, error: Arc<Error>) {
-        let futs = Iterator::chain(
-            self.queued
-                .into_iter()
-                .flat_map(|intent| intent.channels.map(|ch| ch.event_tx)),
-            self.active_incomplete,
-        )
-        .map(|event_tx| {
-            let error = error.clone();
-            async move { event_tx.send(EventKind::Abort { error }).await }
-        });
+        let futs = Iterator::chain(self.queued.into_iter(), self.active_incomplete)
+            .map(|intent| intent.send_abort(error.clone()));
         let _ = futures_buffered::join_all(futs).await;
     }
 
-    /// Abort incomplete active intents, and return queued unprocessed intents.
-    pub async fn abort_active(self, error: Arc<Error>) -> Vec<Intent> {
-        let futs = self.active_incomplete.into_iter().map(|event_tx| {
-            let error = error.clone();
-            async move { event_tx.send(EventKind::Abort { error }).await }
-        });
+    /// Abort incomplete active and queued intents without a retry policy, and return the intents
+    /// with a retry policy.
+    pub async fn abort_unless_retry(self, error: Arc<Error>) -> Vec<Intent> {
+        let (retry, abort): (Vec<_>, Vec<_>) =
+            Iterator::chain(self.queued.into_iter(), self.active_incomplete)
+                .partition(Intent::has_retry_policy);
+        let futs = abort
+            .into_iter()
+            .map(|intent| intent.send_abort(error.clone()));
+        let _ = futures_buffered::join_all(futs).await;
+        retry
+    }
+
+    /// Abort incomplete active intents without a retry policy.
+    ///
+    /// Returns the queued unprocessed intents, and the active intents with a retry policy.
+    pub async fn abort_active_unless_retry(self, error: Arc<Error>) -> (Vec<Intent>, Vec<Intent>) {
+        let (retry, abort): (Vec<_>, Vec<_>) = self
+            .active_incomplete
+            .into_iter()
+            .partition(Intent::has_retry_policy);
+        let futs = abort
+            .into_iter()
+            .map(|intent| intent.send_abort(error.clone()));
         let _ = futures_buffered::join_all(futs).await;
-        self.queued
+        (self.queued, retry)
     }
 }
 
@@ -338,6 +434,12 @@ pub(super) struct IntentDispatcher<S: Storage> {
     intent_update_rx: StreamMap<IntentId, StreamNotifyClose<ReceiverStream<IntentUpdate>>>,
     next_intent_id: u64,
     complete_areas: NamespaceInterests,
+    /// The mode our intents want.
+    mode: SessionMode,
+    /// Whether the other peer upgraded the session to continuous mode. If so, the session stays
+    /// open even once all our intents are dropped, until the other peer closes it.
+    peer_live: bool,
+    tracker: SessionTracker,
 }
 
 impl<S: Storage> IntentDispatcher<S> {
@@ -345,6 +447,8 @@ impl<S: Storage> IntentDispatcher<S> {
         auth: Auth<S>,
         initial_intents: impl IntoIterator<Item = Intent>,
         inbox: mpsc::Receiver<Input>,
+        mode: SessionMode,
+        tracker: SessionTracker,
     ) -> Self {
         Self {
             inbox,
@@ -354,6 +458,9 @@ impl<S: Storage> IntentDispatcher<S> {
             intent_update_rx: Default::default(),
             next_intent_id: 0,
             complete_areas: Default::default(),
+            mode,
+            peer_live: false,
+            tracker,
         }
     }
 
@@ -366,21 +473,30 @@ impl<S: Storage> IntentDispatcher<S> {
             match item {
                 Input::EmitEvent(event) => self.emit_event_inner(event).await,
                 Input::SubmitIntent(intent) => queued.push(intent),
+                Input::PeerUpgraded => {}
             }
         }
 
         // Drain pending intents.
         queued.extend(self.pending_intents.into_iter());
 
-        // Drain incomplete active intents
-        let active_incomplete = self
-            .intents
-            .drain()
-            .filter_map(|(_id, info)| (!info.is_complete()).then_some(info.event_tx).flatten());
+        // Drain incomplete active intents, so that they can be aborted or resubmitted.
+        let mut active_incomplete = vec![];
+        for (id, info) in self.intents.drain() {
+            if info.is_complete() {
+                continue;
+            }
+            let update_rx = self
+                .intent_update_rx
+                .remove(&id)
+                .and_then(|rx| rx.into_inner())
+                .map(|rx| rx.into_inner());
+            active_incomplete.push(info.into_intent(update_rx));
+        }
 
         RemainingIntents {
             queued,
-            active_incomplete: active_incomplete.collect(),
+            active_incomplete,
         }
     }
 
@@ -408,6 +524,12 @@ impl<S: Storage> IntentDispatcher<S> {
                     match input {
                         Input::SubmitIntent(data) => self.submit_intent(&co, data).await?,
                         Input::EmitEvent(event) => self.emit_event(&co, event).await,
+                        Input::PeerUpgraded => {
+                            if !self.peer_live {
+                                self.peer_live = true;
+                                co.yield_(Output::PeerUpgraded).await;
+                            }
+                        }
                     }
                 }
                 Some((intent_id, event)) = self.intent_update_rx.next(), if !self.intent_update_rx.is_empty() => {
@@ -437,7 +559,22 @@ impl<S: Storage> IntentDispatcher<S> {
 
     async fn submit_intent(&mut self, co: &Co<Output>, intent: Intent) -> Result<(), Error> {
         debug!("submit intent");
-        let interests = self.auth.resolve_interests(intent.init.interests.clone())?;
+        let mut interests = self.auth.resolve_interests(intent.init.interests.clone())?;
+        for added in &intent.added_interests {
+            for (auth, aois) in self.auth.resolve_interests(added.clone())? {
+                interests.entry(auth).or_default().extend(aois);
+            }
+        }
+        // Lazy interests are synchronised like all other interests, but are marked as lazy.
+        let mut lazy = NamespaceInterests::new();
+        if let Some(lazy_interests) = &intent.init.lazy_interests {
+            for (auth, aois) in self.auth.resolve_interests(lazy_interests.clone())? {
+                lazy.entry(auth.namespace())
+                    .or_default()
+                    .extend(aois.iter().cloned());
+                interests.entry(auth).or_default().extend(aois);
+            }
+        }
         let intent_id = {
             let intent_id = self.next_intent_id;
             self.next_intent_id += 1;
@@ -454,8 +591,17 @@ impl<S: Storage> IntentDispatcher<S> {
         let mut info = IntentInfo {
             interests: flatten_interests(&interests),
             mode: intent.init.mode,
+            init: intent.init,
+            added_interests: intent.added_interests,
             event_tx,
+            attempt: intent.attempt,
+            submitted_at: Instant::now(),
+            reconciled_all: false,
         };
+        if intent.attempt > 0 {
+            debug!(attempts = intent.attempt, "intent reconnected");
+            info.send(EventKind::Reconnected).await.ok();
+        }
         // Send out reconciled events for already-complete areas.
         for (namespace, areas) in &self.complete_areas {
             for area in areas {
@@ -463,27 +609,48 @@ impl<S: Storage> IntentDispatcher<S> {
             }
         }
 
+        // Upgrade the session to continuous mode if the intent wants it. A session is never
+        // downgraded, because other intents may still depend on the live data.
+        if info.mode.is_live() && !self.mode.is_live() {
+            self.mode = SessionMode::Continuous;
+            co.yield_(Output::SetMode(self.mode)).await;
+        }
+
         if !info.is_complete() {
             self.intents.insert(intent_id, info);
+            self.update_tracker();
             if let Some(update_rx) = update_rx {
                 self.intent_update_rx.insert(
                     intent_id,
                     StreamNotifyClose::new(ReceiverStream::new(update_rx)),
                 );
             }
-            co.yield_(Output::SubmitInterests(interests)).await;
+            co.yield_(Output::SubmitInterests { interests, lazy }).await;
         }
 
         Ok(())
     }
 
+    /// Updates the intents in the [`SessionInfo`](super::SessionInfo).
+    fn update_tracker(&self) {
+        let intents = self
+            .intents
+            .iter()
+            .map(|(id, info)| info.attached(*id))
+            .collect();
+        self.tracker.set_intents(intents);
+    }
+
     async fn emit_event_inner(&mut self, event: EventKind) {
-        if let EventKind::Reconciled { namespace, area } = &event {
+        let reconciled = if let EventKind::Reconciled { namespace, area } = &event {
             self.complete_areas
                 .entry(*namespace)
                 .or_default()
                 .insert(area.clone());
-        }
+            true
+        } else {
+            false
+        };
         let send_futs = self
             .intents
             .iter_mut()
@@ -503,11 +670,14 @@ impl<S: Storage> IntentDispatcher<S> {
                 }
             }
         }
+        if reconciled {
+            self.update_tracker();
+        }
     }
 
     async fn emit_event(&mut self, co: &Co<Output>, event: EventKind) {
         self.emit_event_inner(event).await;
-        if self.intents.is_empty() {
+        if self.intents.is_empty() && !self.peer_live {
             co.yield_(Output::AllIntentsDropped).await;
         }
     }
@@ -521,12 +691,18 @@ impl<S: Storage> IntentDispatcher<S> {
         trace!(?intent_id, ?update, "intent update");
         match update {
             IntentUpdate::AddInterests(interests) => {
-                let add_interests = self.auth.resolve_interests(interests)?;
+                let add_interests = self.auth.resolve_interests(interests.clone())?;
                 let Some(intent_info) = self.intents.get_mut(&intent_id) else {
                     anyhow::bail!("invalid intent id");
                 };
                 intent_info.merge_interests(&add_interests);
-                co.yield_(Output::SubmitInterests(add_interests)).await;
+                intent_info.added_interests.push(interests);
+                self.update_tracker();
+                co.yield_(Output::SubmitInterests {
+                    interests: add_interests,
+                    lazy: Default::default(),
+                })
+                .await;
             }
             IntentUpdate::Close => {
                 self.cancel_intent(co, intent_id).await;
@@ -539,11 +715,12 @@ impl<S: Storage> IntentDispatcher<S> {
         trace!(?intent_id, "cancel intent");
         self.intent_update_rx.remove(&intent_id);
         self.intents.remove(&intent_id);
+        self.update_tracker();
     }
 
     async fn cancel_intent(&mut self, co: &Co<Output>, intent_id: u64) {
         self.cancel_intent_inner(intent_id);
-        if self.intents.is_empty() {
+        if self.intents.is_empty() && !self.peer_live {
             co.yield_(Output::AllIntentsDropped).await;
         }
     }
@@ -553,10 +730,70 @@ impl<S: Storage> IntentDispatcher<S> {
 pub(super) struct IntentInfo {
     interests: NamespaceInterests,
     mode: SessionMode,
+    /// The options the intent was submitted with, to resubmit it after reconnecting.
+    init: SessionInit,
+    added_interests: Vec<Interests>,
     event_tx: Option<Sender<EventKind>>,
+    /// The number of failed attempts to reconnect before the intent was submitted.
+    attempt: u32,
+    /// When the intent was submitted into the session.
+    submitted_at: Instant,
+    /// Whether all interests of the intent were reconciled in the session.
+    reconciled_all: bool,
 }
 
 impl IntentInfo {
+    /// Turns the info back into an [`Intent`], which can be submitted into another session.
+    ///
+    /// If the update receiver was already closed, the intent gets an update receiver which is
+    /// closed too.
+    ///
+    /// The count of failed attempts is kept, unless the intent reconciled all its interests or
+    /// the session stayed up for at least [`RetryPolicy::min_uptime`].
+    ///
+    /// [`RetryPolicy::min_uptime`]: crate::session::RetryPolicy::min_uptime
+    fn into_intent(self, update_rx: Option<Receiver<IntentUpdate>>) -> Intent {
+        let ran_successfully = self.reconciled_all
+            || self
+                .init
+                .retry
+                .is_some_and(|policy| self.submitted_at.elapsed() >= policy.min_uptime);
+        let attempt = if ran_successfully { 0 } else { self.attempt };
+        let channels = self.event_tx.map(|event_tx| {
+            let update_rx = update_rx.unwrap_or_else(|| mpsc::channel(1).1);
+            IntentChannels {
+                event_tx,
+                update_rx,
+            }
+        });
+        Intent {
+            init: self.init,
+            added_interests: self.added_interests,
+            attempt,
+            channels,
+        }
+    }
+
+    /// Returns the [`AttachedIntent`] for the info of this intent.
+    fn attached(&self, id: IntentId) -> AttachedIntent {
+        let pending = self
+            .interests
+            .iter()
+            .flat_map(|(namespace, aois)| {
+                aois.iter()
+                    .map(|aoi| (*namespace, SerdeAreaOfInterest(aoi.clone())))
+            })
+            .take(MAX_INFO_AREAS)
+            .collect();
+        AttachedIntent {
+            id,
+            mode: self.mode,
+            interests: self.init.interests.clone(),
+            added_interests: self.added_interests.clone(),
+            pending,
+        }
+    }
+
     fn merge_interests(&mut self, interests: &InterestMap) {
         for (auth, aois) in interests.iter() {
             self.interests
@@ -585,6 +822,7 @@ impl IntentInfo {
             })
             .await?;
             if self.interests.is_empty() {
+                self.reconciled_all = true;
                 self.send(EventKind::ReconciledAll).await?
             }
         }
@@ -637,8 +875,9 @@ impl IntentInfo {
             EventKind::Reconciled { area, namespace } => {
                 self.complete_area_if_matches(namespace, &area.area)
             }
-            EventKind::Abort { .. } => true,
+            EventKind::Abort { .. } | EventKind::EntryRejected { .. } => true,
             EventKind::ReconciledAll => false,
+            EventKind::Reconnecting { .. } | EventKind::Reconnected => true,
         };
         let is_reconciled = matches!(event, EventKind::Reconciled { .. });
         if matches {
@@ -671,10 +910,13 @@ fn flatten_interests(interests: &InterestMap) -> NamespaceInterests {
 }
 
 pub mod serde_encoding {
+    use std::time::Duration;
+
     use serde::{Deserialize, Serialize};
 
     use crate::{
         proto::{
+            data_model::serde_encoding::SerdeEntry,
             grouping::serde_encoding::{SerdeArea, SerdeAreaOfInterest},
             keys::NamespaceId,
         },
@@ -697,9 +939,19 @@ pub mod serde_encoding {
             area: SerdeAreaOfInterest,
         },
         ReconciledAll,
+        EntryRejected {
+            entry: SerdeEntry,
+            error: String,
+        },
         Abort {
             error: String, // Simplified error representation
         },
+        Reconnecting {
+            attempt: u32,
+            delay: Duration,
+            error: String,
+        },
+        Reconnected,
     }
 
     impl From<EventKind> for Event {
@@ -722,9 +974,23 @@ pub mod serde_encoding {
                     area: SerdeAreaOfInterest(area),
                 },
                 EventKind::ReconciledAll => Event::ReconciledAll,
+                EventKind::EntryRejected { entry, error } => Event::EntryRejected {
+                    entry: SerdeEntry(entry),
+                    error: error.to_string(),
+                },
                 EventKind::Abort { error } => Event::Abort {
                     error: error.to_string(),
                 },
+                EventKind::Reconnecting {
+                    attempt,
+                    delay,
+                    error,
+                } => Event::Reconnecting {
+                    attempt,
+                    delay,
+                    error: error.to_string(),
+                },
+                EventKind::Reconnected => Event::Reconnected,
             }
         }
     }
@@ -23,12 +23,12 @@ use crate::{
         meadowcap::{ReadAuthorisation, SubspaceCapability},
         pai::{Fragment, FragmentKind, FragmentSet, PaiScheme, PsiGroup, PsiScalar},
         wgps::{
-            IntersectionHandle, IntersectionMessage, Message, PaiBindFragment, PaiReplyFragment,
-            PaiRequestSubspaceCapability,
+            ControlFreeHandle, IntersectionHandle, IntersectionMessage, Message, PaiBindFragment,
+            PaiReplyFragment, PaiRequestSubspaceCapability,
         },
     },
     session::{
-        resource::{MissingResource, ResourceMap, Scope},
+        resource::{self, MissingResource, ResourceMap, Scope, MAX_RETAINED_FREED},
         Error,
     },
     util::gen_stream::GenStream,
@@ -58,6 +58,8 @@ pub enum Input {
     ReceivedSubspaceCapRequest(IntersectionHandle),
     ReceivedVerifiedSubspaceCapReply(IntersectionHandle, NamespaceId),
     ReceivedReadCapForIntersection(IntersectionHandle),
+    /// The other peer sent a [`ControlFreeHandle`] for an intersection handle.
+    FreeHandle(ControlFreeHandle),
 }
 
 #[derive(Debug)]
@@ -159,6 +161,26 @@ impl PaiFinder {
             Input::ReceivedReadCapForIntersection(handle) => {
                 self.received_read_cap_for_intersection(handle).await?
             }
+            Input::FreeHandle(message) => self.free_handle(message).await?,
+        }
+        Ok(())
+    }
+
+    async fn free_handle(&mut self, message: ControlFreeHandle) -> Result<(), MissingResource> {
+        let reply = resource::on_free_handle(
+            &mut self.our_intersection_handles,
+            &mut self.their_intersection_handles,
+            &message,
+            MAX_RETAINED_FREED,
+        )?;
+        // Drop the state we keep for our handle once it is deleted.
+        let handle = IntersectionHandle::from(message.handle);
+        if !message.mine && self.our_intersection_handles.is_freed(&handle) {
+            self.fragments_info.remove(&handle);
+            self.requested_subspace_cap_handles.remove(&handle);
+        }
+        if let Some(reply) = reply {
+            self.out(Output::SendMessage(reply.into())).await;
         }
         Ok(())
     }
@@ -11,16 +11,54 @@ use iroh_blobs::{
 use iroh_io::TokioStreamReader;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
+use tracing::debug;
 
 use super::Error;
 use crate::{
-    proto::{data_model::PayloadDigest, wgps::Message},
+    proto::{
+        data_model::{Entry, PayloadDigest},
+        wgps::Message,
+    },
     session::channels::ChannelSenders,
+    store::{
+        traits::{EntryStorage, Storage},
+        Store,
+    },
     util::pipe::chunked_pipe,
 };
 
 const CHUNK_SIZE: usize = 1024 * 32;
 
+/// Returns the number of consecutive bytes of a payload, starting at byte 0, which are
+/// available in the `payload_store`.
+pub async fn payload_available<P: PayloadStore>(
+    digest: PayloadDigest,
+    total_length: u64,
+    payload_store: &P,
+) -> Result<u64, Error> {
+    let hash: Hash = digest.into();
+    let entry = payload_store
+        .get(&hash)
+        .await
+        .map_err(Error::PayloadStore)?;
+    let Some(entry) = entry else {
+        return Ok(0);
+    };
+    if entry.is_complete() {
+        return Ok(total_length);
+    }
+    let ranges = entry
+        .available_ranges()
+        .await
+        .map_err(Error::PayloadStore)?;
+    let available = match ranges.boundaries() {
+        [start, end, ..] if start.0 == 0 => end.to_bytes(),
+        [start] if start.0 == 0 => total_length,
+        _ => 0,
+    };
+    Ok(available.min(total_length))
+}
+
 /// Send a payload in chunks.
 ///
 /// Returns `true` if the payload was sent.
@@ -63,11 +101,12 @@ pub struct CurrentPayload(Option<CurrentPayloadInner>);
 
 #[derive(Debug)]
 struct CurrentPayloadInner {
-    payload_digest: PayloadDigest,
+    entry: Entry,
     expected_length: u64,
     received_length: u64,
-    total_length: u64,
     offset: u64,
+    /// If set, received chunks are discarded instead of being written to the payload store.
+    skip: bool,
     writer: Option<PayloadWriter>,
 }
 
@@ -79,11 +118,10 @@ struct PayloadWriter {
 }
 
 impl CurrentPayload {
-    /// Set the payload to be received.
+    /// Set the payload of `entry` to be received.
     pub fn set(
         &mut self,
-        payload_digest: PayloadDigest,
-        total_length: u64,
+        entry: Entry,
         available_length: Option<u64>,
         offset: Option<u64>,
     ) -> Result<(), Error> {
@@ -91,32 +129,49 @@ impl CurrentPayload {
             return Err(Error::InvalidMessageInCurrentState);
         }
         let offset = offset.unwrap_or(0);
-        let available_length = available_length.unwrap_or(total_length);
-        let expected_length = available_length - offset;
+        let available_length = available_length.unwrap_or(entry.payload_length());
+        let expected_length = available_length.saturating_sub(offset);
         self.0 = Some(CurrentPayloadInner {
-            payload_digest,
+            entry,
             writer: None,
             expected_length,
-            total_length,
             offset,
             received_length: 0,
+            skip: false,
         });
         Ok(())
     }
 
-    pub async fn recv_chunk<P: PayloadStore>(
+    /// Discard the chunks of the current payload instead of storing them.
+    pub fn skip(&mut self) -> Result<(), Error> {
+        let state = self.0.as_mut().ok_or(Error::InvalidMessageInCurrentState)?;
+        state.skip = true;
+        Ok(())
+    }
+
+    /// Writes a chunk of the current payload to the payload store.
+    ///
+    /// The payload is marked as partially stored with the first chunk, so that the transfer is
+    /// resumed in a later session if it is interrupted.
+    pub async fn recv_chunk<S: Storage>(
         &mut self,
-        store: &P,
+        store: &Store<S>,
         chunk: Bytes,
     ) -> anyhow::Result<()> {
         let state = self.0.as_mut().ok_or(Error::InvalidMessageInCurrentState)?;
         let len = chunk.len();
-        let store = store.clone();
+        if state.skip {
+            state.received_length += len as u64;
+            return Ok(());
+        }
+        if state.writer.is_none() {
+            store.entries().set_partial_payload(&state.entry, true)?;
+        }
         let writer = state.writer.get_or_insert_with(|| {
             let (tx, rx) = tokio::sync::mpsc::channel(2);
-            let store = store.clone();
-            let hash: Hash = state.payload_digest.into();
-            let total_length = state.total_length;
+            let store = store.payloads().clone();
+            let hash: Hash = (*state.entry.payload_digest()).into();
+            let total_length = state.entry.payload_length();
             let offset = state.offset;
             let tag = store.temp_tag(HashAndFormat::raw(hash));
             let mut reader =
@@ -146,7 +201,7 @@ impl CurrentPayload {
         state.received_length >= state.expected_length
     }
 
-    pub async fn finalize(&mut self) -> Result<(), Error> {
+    pub async fn finalize<S: Storage>(&mut self, store: &Store<S>) -> Result<(), Error> {
         let state = self.0.take().ok_or(Error::InvalidMessageInCurrentState)?;
         // The writer is only set if we received at least one payload chunk.
         if let Some(writer) = state.writer {
@@ -156,12 +211,44 @@ impl CurrentPayload {
                 .await
                 .expect("payload writer panicked")
                 .map_err(Error::PayloadStore)?;
-            // TODO: Make sure blobs referenced from entries are protected from GC by now.
+            // The entry was ingested before its payload, so the payload is protected from GC as
+            // part of the store's referenced payloads from now on.
             drop(writer.tag);
+            // The other peer may have sent all it had, but still not the complete payload.
+            let entry = &state.entry;
+            let len = entry.payload_length();
+            let available =
+                payload_available(*entry.payload_digest(), len, store.payloads()).await?;
+            if available == len {
+                store.entries().set_partial_payload(entry, false)?;
+            }
         }
         Ok(())
     }
 
+    /// Finalizes the current payload, if any, even if not all expected bytes were received.
+    ///
+    /// The bytes received so far are kept in the payload store, and the payload stays marked as
+    /// partially stored, so that the transfer is resumed later on.
+    pub async fn finalize_partial(&mut self) {
+        let Some(state) = self.0.take() else {
+            return;
+        };
+        if let Some(writer) = state.writer {
+            drop(writer.sender);
+            match writer.task.await.expect("payload writer panicked") {
+                Ok(()) => {}
+                Err(err) => debug!(
+                    received = state.received_length,
+                    expected = state.expected_length,
+                    ?err,
+                    "payload incomplete"
+                ),
+            }
+            drop(writer.tag);
+        }
+    }
+
     pub fn is_active(&self) -> bool {
         self.0.as_ref().map(|s| s.writer.is_some()).unwrap_or(false)
     }
@@ -174,3 +261,24 @@ impl CurrentPayload {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use iroh_blobs::BlobFormat;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn payload_available_complete_and_missing() -> anyhow::Result<()> {
+        let store = iroh_blobs::store::mem::Store::default();
+        let bytes = Bytes::from(vec![7u8; 1024 * 64]);
+        let tag = store.import_bytes(bytes.clone(), BlobFormat::Raw).await?;
+        let digest = PayloadDigest(*tag.hash());
+        let len = bytes.len() as u64;
+        assert_eq!(payload_available(digest, len, &store).await?, len);
+
+        let missing = PayloadDigest(Hash::new(b"missing"));
+        assert_eq!(payload_available(missing, 7, &store).await?, 0);
+        Ok(())
+    }
+}
@@ -1,14 +1,17 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use bytes::Bytes;
 use futures_lite::StreamExt;
 use genawaiter::rc::Co;
-use iroh_blobs::store::Store as PayloadStore;
-use tracing::{debug, trace};
+use tokio::sync::oneshot;
+use tracing::{debug, trace, warn};
 
 use crate::{
     proto::{
-        data_model::{PathExt, PayloadDigest},
+        data_model::{Entry, PathExt},
         grouping::{AreaExt, AreaOfInterest, Range3d},
         keys::NamespaceId,
         wgps::{
@@ -20,13 +23,17 @@ use crate::{
     },
     session::{
         aoi_finder::AoiIntersection,
+        capabilities::Capabilities,
         channels::{ChannelSenders, MessageReceiver},
-        payload::{send_payload_chunked, CurrentPayload},
+        data::{PayloadPreferences, PayloadRequests},
+        payload::{payload_available, send_payload_chunked, CurrentPayload},
         static_tokens::StaticTokens,
         Error, Role, SessionId,
     },
     store::{
-        traits::{EntryOrigin, EntryReader, EntryStorage, SplitAction, SplitOpts, Storage},
+        traits::{
+            EntryOrigin, EntryReader, EntryStorage, QuotaExceeded, SplitAction, SplitOpts, Storage,
+        },
         Store,
     },
     util::{
@@ -35,6 +42,10 @@ use crate::{
     },
 };
 
+/// How long we wait for the other peer to answer the requests for our partial payloads before we
+/// take part in the reconciliation of an area, see [`Target::resume_partial_payloads`].
+const RESUME_PAYLOADS_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug)]
 pub enum Input {
     AoiIntersection(AoiIntersection),
@@ -47,6 +58,11 @@ pub enum Output {
         area: AreaOfInterest,
     },
     ReconciledAll,
+    /// An entry from the other peer was not ingested because it exceeds our quotas.
+    EntryRejected {
+        entry: Entry,
+        error: QuotaExceeded,
+    },
 }
 
 #[derive(derive_more::Debug)]
@@ -72,7 +88,11 @@ impl<S: Storage> Reconciler<S> {
         session_id: SessionId,
         send: ChannelSenders,
         our_role: Role,
+        split_opts: SplitOpts,
         max_eager_payload_size: u64,
+        caps: Capabilities,
+        payload_requests: PayloadRequests,
+        preferences: PayloadPreferences,
     ) -> impl futures_lite::Stream<Item = Result<Output, Error>> {
         GenStream::new(|co| {
             let shared = Shared {
@@ -82,7 +102,11 @@ impl<S: Storage> Reconciler<S> {
                 send,
                 static_tokens,
                 session_id,
+                split_opts,
                 max_eager_payload_size,
+                caps,
+                payload_requests,
+                preferences,
             };
             Self {
                 shared,
@@ -161,20 +185,53 @@ impl<S: Storage> Reconciler<S> {
                         message.dynamic_token,
                     )
                     .await?;
+                // An entry over quota is rejected on its own, the session continues.
+                let (ingest, store_payload) = match self
+                    .shared
+                    .store
+                    .check_remote_quotas(authorised_entry.entry())?
+                {
+                    Ok(store_payload) => (true, store_payload),
+                    Err(err) => {
+                        debug!(%err, "skip entry: exceeds quota");
+                        self.out(Output::EntryRejected {
+                            entry: authorised_entry.entry().clone(),
+                            error: err,
+                        })
+                        .await;
+                        (false, false)
+                    }
+                };
                 self.entry_state.received_send_entry(
-                    *authorised_entry.entry().payload_digest(),
-                    authorised_entry.entry().payload_length(),
+                    authorised_entry.entry().clone(),
                     message.entry.available,
                 )?;
-                self.shared.store.entries().ingest_entry(
-                    &authorised_entry,
-                    EntryOrigin::Remote(self.shared.session_id),
-                )?;
+                // The other peer may not know yet that we want payloads in this area lazily.
+                let eager = self.targets.is_eager(&self.entry_state.target()?);
+                if !store_payload {
+                    debug!(
+                        len = authorised_entry.entry().payload_length(),
+                        "skip payload: exceeds quota"
+                    );
+                    self.entry_state.skip_payload()?;
+                } else if !eager {
+                    debug!(
+                        len = authorised_entry.entry().payload_length(),
+                        "skip payload: lazy area"
+                    );
+                    self.entry_state.skip_payload()?;
+                }
+                if ingest {
+                    self.shared.store.entries().ingest_entry(
+                        &authorised_entry,
+                        EntryOrigin::Remote(self.shared.session_id),
+                    )?;
+                }
             }
             ReconciliationMessage::SendPayload(message) => {
                 trace!("recv SendPayload");
                 self.entry_state
-                    .received_send_payload(self.shared.store.payloads(), message.bytes)
+                    .received_send_payload(&self.shared.store, message.bytes)
                     .await?;
             }
             ReconciliationMessage::TerminatePayload(ReconciliationTerminatePayload {
@@ -183,7 +240,7 @@ impl<S: Storage> Reconciler<S> {
                 trace!(?is_final, "recv TerminatePayloade");
                 if let Some(completed_target) = self
                     .entry_state
-                    .received_terminate_payload(is_final)
+                    .received_terminate_payload(&self.shared.store, is_final)
                     .await?
                 {
                     let target = self
@@ -252,6 +309,13 @@ impl TargetMap {
         Ok(self.map.get_mut(requested_id).unwrap())
     }
 
+    /// Returns `true` unless we want payloads in the target `id` to be sent lazily.
+    pub fn is_eager(&self, id: &TargetId) -> bool {
+        self.map
+            .get(id)
+            .map_or(true, |target| target.intersection.is_eager)
+    }
+
     async fn wait_for_target<S: Storage>(
         &mut self,
         shared: &Shared<S>,
@@ -315,24 +379,33 @@ impl EntryState {
 
     pub fn received_send_entry(
         &mut self,
-        payload_digest: PayloadDigest,
-        total_payload_length: u64,
+        entry: Entry,
         available_payload_length: u64,
     ) -> Result<(), Error> {
         let state = self.get_mut()?;
         state.current_payload.ensure_none()?;
-        state.current_payload.set(
-            payload_digest,
-            total_payload_length,
-            Some(available_payload_length),
-            None,
-        )?;
+        state
+            .current_payload
+            .set(entry, Some(available_payload_length), None)?;
         Ok(())
     }
 
-    pub async fn received_send_payload<P: PayloadStore>(
+    /// Returns the target of the entries which are currently received.
+    pub fn target(&self) -> Result<TargetId, Error> {
+        match self.0.as_ref() {
+            Some(s) => Ok(s.target),
+            None => Err(Error::InvalidMessageInCurrentState),
+        }
+    }
+
+    /// Discards the payload of the current entry instead of storing it.
+    pub fn skip_payload(&mut self) -> Result<(), Error> {
+        self.get_mut()?.current_payload.skip()
+    }
+
+    pub async fn received_send_payload<S: Storage>(
         &mut self,
-        store: &P,
+        store: &Store<S>,
         bytes: Bytes,
     ) -> Result<(), Error> {
         self.get_mut()?
@@ -342,12 +415,13 @@ impl EntryState {
         Ok(())
     }
 
-    pub async fn received_terminate_payload(
+    pub async fn received_terminate_payload<S: Storage>(
         &mut self,
+        store: &Store<S>,
         is_final: bool,
     ) -> Result<Option<TargetId>, Error> {
         let state = self.get_mut()?;
-        state.current_payload.finalize().await?;
+        state.current_payload.finalize(store).await?;
         if is_final {
             let target_id = state.target;
             self.0 = None;
@@ -380,7 +454,11 @@ struct Shared<S: Storage> {
     send: ChannelSenders,
     static_tokens: StaticTokens,
     session_id: SessionId,
+    split_opts: SplitOpts,
     max_eager_payload_size: u64,
+    caps: Capabilities,
+    payload_requests: PayloadRequests,
+    preferences: PayloadPreferences,
 }
 
 #[derive(Debug)]
@@ -407,12 +485,54 @@ impl Target {
             our_range_counter: 0,
             their_range_counter: 0,
         };
+        this.resume_partial_payloads(shared).await?;
         if shared.our_role == Role::Alfie {
             this.initiate(shared).await?;
         }
         Ok(this)
     }
 
+    /// Requests the rest of the payloads in our area which we only have partially.
+    ///
+    /// These are payloads whose transfer was interrupted in an earlier session, see
+    /// [`EntryStorage::partial_payloads`]. We only take part in the reconciliation of the area
+    /// once the other peer answered the requests, so that it cannot finish a
+    /// [`SessionMode::ReconcileOnce`] session and close it before it saw our requests. A peer
+    /// which does not answer within [`RESUME_PAYLOADS_TIMEOUT`] does not hold back the
+    /// reconciliation: the requests stay pending, and we reconcile anyway.
+    ///
+    /// [`SessionMode::ReconcileOnce`]: crate::session::SessionMode::ReconcileOnce
+    async fn resume_partial_payloads<S: Storage>(&self, shared: &Shared<S>) -> Result<(), Error> {
+        let entries = shared
+            .store
+            .entries()
+            .partial_payloads(self.namespace(), self.intersection.area())?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        debug!(count = entries.len(), "resume partial payloads");
+        let mut handles = Vec::with_capacity(entries.len());
+        for authorised_entry in entries {
+            // Nobody waits for the result, the payload store is updated in any case.
+            let (reply, _reply_rx) = oneshot::channel();
+            let reply = shared.payload_requests.pending_reply(reply);
+            let (entry, _token) = authorised_entry.into_parts();
+            let handle = shared
+                .payload_requests
+                .request(&shared.store, &shared.caps, &shared.send, entry, reply)
+                .await?;
+            handles.extend(handle);
+        }
+        let answered = shared.payload_requests.answered(&handles);
+        if tokio::time::timeout(RESUME_PAYLOADS_TIMEOUT, answered)
+            .await
+            .is_err()
+        {
+            warn!("partial payload requests not answered in time, reconcile anyway");
+        }
+        Ok(())
+    }
+
     fn namespace(&self) -> NamespaceId {
         self.intersection.namespace
     }
@@ -461,11 +581,9 @@ impl Target {
         // case 3: fingerprint doesn't match and is non-empty
         else {
             // reply by splitting the range into parts unless it is very short
-            // TODO: Expose these options to a higher level.
-            let split_opts = SplitOpts::default();
             let store = shared.store.entries().clone();
             let mut iter = store
-                .split_range(self.namespace(), &message.range, &split_opts)?
+                .split_range(self.namespace(), &message.range, &shared.split_opts)?
                 .peekable();
             while let Some(res) = iter.next() {
                 let (subrange, action) = res?;
@@ -574,29 +692,29 @@ impl Target {
         };
 
         // Otherwise send all the entries in our iterator, and payloads if applicable.
+        let eager_payloads = shared.preferences.is_eager(&self.id());
         while let Some(authorised_entry) = iter.next() {
             let authorised_entry = authorised_entry?;
             let (entry, token) = authorised_entry.into_parts();
 
             let static_token = token.capability.into();
             let dynamic_token = token.signature;
-            // TODO: partial payloads
             let payload_len = entry.payload_length();
-            let available = payload_len;
+            let digest = *entry.payload_digest();
+            let available = payload_available(digest, payload_len, shared.store.payloads()).await?;
             let static_token_handle = shared
                 .static_tokens
                 .bind_and_send_ours(static_token, &shared.send)
                 .await?;
-            let digest = *entry.payload_digest();
             let msg = ReconciliationSendEntry {
                 entry: LengthyEntry::new(entry, available),
                 static_token_handle,
                 dynamic_token,
             };
             shared.send.send(msg).await?;
+            shared.static_tokens.release_ours(static_token_handle)?;
 
-            // TODO: only send payload if configured to do so and/or under size limit.
-            if payload_len <= shared.max_eager_payload_size {
+            if eager_payloads && available > 0 && payload_len <= shared.max_eager_payload_size {
                 send_payload_chunked(digest, shared.store.payloads(), &shared.send, 0, |bytes| {
                     ReconciliationSendPayload { bytes }.into()
                 })
@@ -4,7 +4,14 @@ use std::{
 };
 
 use super::Error;
-use crate::proto::wgps::{IsHandle, ResourceHandle};
+use crate::proto::wgps::{ControlFreeHandle, IsHandle, ResourceHandle};
+
+/// Maximum number of handles freed by the other peer which we keep around, for handle types
+/// without a configurable limit.
+///
+/// Messages referring to a handle may still be in flight on other channels when the other peer
+/// frees it, so freed handles are only deleted once more than this many are retained.
+pub const MAX_RETAINED_FREED: usize = 1024;
 
 /// The bind scope for resources.
 ///
@@ -17,11 +24,27 @@ pub enum Scope {
     Theirs,
 }
 
+/// Policy to select which resource to free once a [`ResourceMap`] is full.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum EvictionPolicy {
+    /// Free the resource which was used least recently.
+    #[default]
+    LeastRecentlyUsed,
+    /// Free the resource which was bound first.
+    FirstBound,
+}
+
 #[derive(Debug)]
 pub struct ResourceMap<H, R> {
     next_handle: u64,
     map: HashMap<H, Resource<R>>,
     wakers: HashMap<H, VecDeque<Waker>>,
+    /// Counter to track when resources were used last.
+    clock: u64,
+    /// Handles that are marked to be deleted, in the order they were marked.
+    to_be_deleted: VecDeque<H>,
+    /// Number of resources in the [`ResourceState::Active`] state.
+    active: usize,
 }
 
 impl<H, R> Default for ResourceMap<H, R> {
@@ -30,6 +53,9 @@ impl<H, R> Default for ResourceMap<H, R> {
             next_handle: 0,
             map: Default::default(),
             wakers: Default::default(),
+            clock: 0,
+            to_be_deleted: Default::default(),
+            active: 0,
         }
     }
 }
@@ -42,11 +68,26 @@ where
         self.map.iter().map(|(h, r)| (h, &r.value))
     }
 
+    /// Iterates over the resources which are active, i.e. not proposed to be freed.
+    pub fn iter_active(&self) -> impl Iterator<Item = (&H, &R)> + '_ {
+        self.map
+            .iter()
+            .filter(|(_, r)| r.state == ResourceState::Active)
+            .map(|(h, r)| (h, &r.value))
+    }
+
+    /// Returns the number of bound resources which are active, i.e. not proposed to be freed.
+    pub fn active_len(&self) -> usize {
+        self.active
+    }
+
     pub fn bind(&mut self, resource: R) -> H {
         let handle: H = self.next_handle.into();
         self.next_handle += 1;
-        let resource = Resource::new(resource);
+        let mut resource = Resource::new(resource);
+        resource.last_used = self.tick();
         self.map.insert(handle, resource);
+        self.active += 1;
         tracing::trace!(?handle, "bind");
         if let Some(mut wakers) = self.wakers.remove(&handle) {
             tracing::trace!(?handle, "notify {}", wakers.len());
@@ -65,10 +106,17 @@ where
             .ok_or_else(|| MissingResource((*handle).into()))
     }
 
+    /// Returns `true` if the handle was bound, but was freed since.
+    pub fn is_freed(&self, handle: &H) -> bool {
+        handle.value() < self.next_handle && !self.map.contains_key(handle)
+    }
+
     pub fn poll_get_eventually(&mut self, handle: H, cx: &mut Context<'_>) -> Poll<&R> {
         // cannot use self.get() and self.register_waker() here due to borrow checker.
-        if let Some(resource) = self.map.get(&handle).as_ref().map(|r| &r.value) {
-            Poll::Ready(resource)
+        let now = self.tick();
+        if let Some(resource) = self.map.get_mut(&handle) {
+            resource.last_used = now;
+            Poll::Ready(&resource.value)
         } else {
             self.wakers
                 .entry(handle)
@@ -87,60 +135,269 @@ where
             }
         }
     }
+
+    /// Increases the reference count of a resource.
+    ///
+    /// A resource will not be deleted while its reference count is not zero.
+    pub fn acquire(&mut self, handle: H) -> Result<(), MissingResource> {
+        let now = self.tick();
+        let resource = self.get_mut(&handle)?;
+        resource.refcount += 1;
+        resource.last_used = now;
+        Ok(())
+    }
+
+    /// Decreases the reference count of a resource.
+    ///
+    /// Deletes the resource if it is marked to be deleted and no references remain.
+    pub fn release(&mut self, handle: H) -> Result<(), MissingResource> {
+        let resource = self.get_mut(&handle)?;
+        resource.refcount = resource.refcount.saturating_sub(1);
+        if resource.state == ResourceState::ToBeDeleted && resource.refcount == 0 {
+            self.delete(handle);
+        }
+        Ok(())
+    }
+
+    /// Selects an active and unreferenced resource to be freed according to `policy`.
+    pub fn eviction_candidate(&self, policy: EvictionPolicy) -> Option<H> {
+        let candidates = self
+            .map
+            .iter()
+            .filter(|(_, r)| r.state == ResourceState::Active && r.refcount == 0);
+        match policy {
+            EvictionPolicy::LeastRecentlyUsed => {
+                candidates.min_by_key(|(_, r)| r.last_used).map(|(h, _)| *h)
+            }
+            EvictionPolicy::FirstBound => {
+                candidates.min_by_key(|(h, _)| h.value()).map(|(h, _)| *h)
+            }
+        }
+    }
+
+    /// Marks a resource as proposed to be freed by us.
+    ///
+    /// The resource will not be used for new bindings anymore. It is deleted once the other peer
+    /// confirmed the freeing with [`Self::on_free_received`].
+    ///
+    /// Returns `false` if the resource is not active.
+    pub fn propose_free(&mut self, handle: H) -> bool {
+        match self.map.get_mut(&handle) {
+            Some(resource) if resource.state == ResourceState::Active => {
+                resource.state = ResourceState::WeProposedFree;
+                self.active -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Handles a request from the other peer to free a resource.
+    ///
+    /// The resource is marked to be deleted. If `delete_now` is true, it is deleted as soon as it
+    /// is no longer referenced. Otherwise, it is kept until removed by [`Self::prune_freed`], so
+    /// that messages which reference the handle and are still in flight on other channels can be
+    /// processed.
+    ///
+    /// Returns `true` if we have to reply with a free message of our own, which is the case if we
+    /// did not propose to free the resource ourselves.
+    pub fn on_free_received(
+        &mut self,
+        handle: H,
+        delete_now: bool,
+    ) -> Result<bool, MissingResource> {
+        let resource = self.get_mut(&handle)?;
+        let reply = match resource.state {
+            ResourceState::Active => true,
+            ResourceState::WeProposedFree => false,
+            ResourceState::ToBeDeleted => return Ok(false),
+        };
+        resource.state = ResourceState::ToBeDeleted;
+        if reply {
+            self.active -= 1;
+        }
+        if delete_now && resource.refcount == 0 {
+            self.delete(handle);
+        } else {
+            self.to_be_deleted.push_back(handle);
+        }
+        Ok(reply)
+    }
+
+    /// Deletes resources marked to be deleted, until at most `retain` of them remain.
+    ///
+    /// Resources are deleted in the order they were marked, and only if they are not referenced.
+    pub fn prune_freed(&mut self, retain: usize) {
+        let mut referenced = vec![];
+        while self.to_be_deleted.len() > retain {
+            let Some(handle) = self.to_be_deleted.pop_front() else {
+                break;
+            };
+            match self.map.get(&handle) {
+                Some(resource) if resource.refcount > 0 => referenced.push(handle),
+                Some(_) => self.delete(handle),
+                None => {}
+            }
+        }
+        for handle in referenced.into_iter().rev() {
+            self.to_be_deleted.push_front(handle);
+        }
+    }
+
+    fn delete(&mut self, handle: H) {
+        tracing::trace!(?handle, "delete");
+        self.map.remove(&handle);
+    }
+
+    fn get_mut(&mut self, handle: &H) -> Result<&mut Resource<R>, MissingResource> {
+        self.map
+            .get_mut(handle)
+            .ok_or_else(|| MissingResource((*handle).into()))
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
 }
 impl<H, R> ResourceMap<H, R>
 where
     H: IsHandle,
     R: Eq + PartialEq,
 {
+    /// Binds a resource, unless an equal resource is bound and active already.
     pub fn bind_if_new(&mut self, resource: R) -> (H, bool) {
-        // TODO: Optimize / find out if reverse index is better than find_map
-        if let Some(handle) = self
-            .map
-            .iter()
-            .find_map(|(handle, r)| (r.value == resource).then_some(handle))
-        {
-            (*handle, false)
+        if let Some(handle) = self.find(&resource) {
+            let now = self.tick();
+            if let Some(resource) = self.map.get_mut(&handle) {
+                resource.last_used = now;
+            }
+            (handle, false)
         } else {
             let handle = self.bind(resource);
             (handle, true)
         }
     }
 
+    /// Finds the handle of an active resource.
     pub fn find(&self, resource: &R) -> Option<H> {
-        self.map
-            .iter()
-            .find_map(|(handle, r)| (r.value == *resource).then_some(*handle))
+        // TODO: Optimize / find out if reverse index is better than find_map
+        self.map.iter().find_map(|(handle, r)| {
+            (r.state == ResourceState::Active && r.value == *resource).then_some(*handle)
+        })
     }
 }
 
+/// Handles a [`ControlFreeHandle`] message for the resources in `ours` and `theirs`.
+///
+/// Our resources are deleted as soon as they are no longer referenced. Their resources are kept
+/// until more than `retain` freed resources exist, see [`ResourceMap::prune_freed`].
+///
+/// Returns the [`ControlFreeHandle`] message to reply with, if the other peer proposed to free the
+/// handle.
+pub fn on_free_handle<H: IsHandle, R>(
+    ours: &mut ResourceMap<H, R>,
+    theirs: &mut ResourceMap<H, R>,
+    message: &ControlFreeHandle,
+    retain: usize,
+) -> Result<Option<ControlFreeHandle>, MissingResource> {
+    let handle = H::from(message.handle);
+    // `mine` is set if the handle was bound by the sender of the message.
+    let reply = if message.mine {
+        let reply = theirs.on_free_received(handle, false)?;
+        theirs.prune_freed(retain);
+        reply
+    } else {
+        ours.on_free_received(handle, true)?
+    };
+    Ok(reply.then_some(ControlFreeHandle {
+        handle: message.handle,
+        mine: !message.mine,
+        handle_type: message.handle_type,
+    }))
+}
+
 #[derive(Debug, thiserror::Error)]
 #[error("missing resource {0:?}")]
 pub struct MissingResource(pub ResourceHandle);
 
-// #[derive(Debug)]
-// enum ResourceState {
-//     Active,
-//     WeProposedFree,
-//     ToBeDeleted,
-// }
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum ResourceState {
+    /// The resource is bound and may be used.
+    Active,
+    /// We asked the other peer to free the resource, and wait for its confirmation.
+    WeProposedFree,
+    /// Both peers agreed to free the resource, and it is deleted once no longer referenced.
+    ToBeDeleted,
+}
 
 #[derive(Debug)]
 struct Resource<V> {
     value: V,
-    // state: ResourceState,
-    // unprocessed_messages: usize,
+    state: ResourceState,
+    refcount: usize,
+    last_used: u64,
 }
 impl<V> Resource<V> {
     pub fn new(value: V) -> Self {
         Self {
             value,
-            // state: ResourceState::Active,
-            // unprocessed_messages: 0,
+            state: ResourceState::Active,
+            refcount: 0,
+            last_used: 0,
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::wgps::{AreaOfInterestHandle, HandleType};
+
+    fn free(handle: u64, mine: bool) -> ControlFreeHandle {
+        ControlFreeHandle {
+            handle,
+            mine,
+            handle_type: HandleType::AreaOfInterest,
+        }
+    }
+
+    #[test]
+    fn free_ours_and_theirs() {
+        let mut ours = ResourceMap::<AreaOfInterestHandle, u8>::default();
+        let mut theirs = ResourceMap::<AreaOfInterestHandle, u8>::default();
+        let our_handle = ours.bind(1);
+        theirs.bind(2);
+        theirs.bind(3);
+        assert_eq!(theirs.active_len(), 2);
+
+        // The other peer proposes to free our handle: we reply and delete it right away.
+        let reply = on_free_handle(&mut ours, &mut theirs, &free(0, false), 0).unwrap();
+        assert!(matches!(
+            reply,
+            Some(ControlFreeHandle {
+                handle: 0,
+                mine: true,
+                ..
+            })
+        ));
+        assert!(ours.is_freed(&our_handle));
+        assert_eq!(ours.active_len(), 0);
+
+        // The other peer frees its own handles: they are kept until more than `retain` exist.
+        let reply = on_free_handle(&mut ours, &mut theirs, &free(0, true), 1).unwrap();
+        assert!(reply.is_some());
+        assert!(!theirs.is_freed(&AreaOfInterestHandle::from(0u64)));
+        on_free_handle(&mut ours, &mut theirs, &free(1, true), 1).unwrap();
+        assert!(theirs.is_freed(&AreaOfInterestHandle::from(0u64)));
+        assert!(!theirs.is_freed(&AreaOfInterestHandle::from(1u64)));
+        assert_eq!(theirs.active_len(), 0);
+
+        // Freeing a handle which was never bound fails.
+        assert!(on_free_handle(&mut ours, &mut theirs, &free(5, true), 1).is_err());
+    }
+}
+
 // #[derive(Debug, Default)]
 // pub struct Resources {
 //     pub ours: ScopedResources,
@@ -6,20 +6,24 @@ use futures_concurrency::{
 };
 use futures_lite::StreamExt as _;
 use strum::IntoEnumIterator;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error_span, trace, Instrument, Span};
 
 use super::{
     channels::ChannelReceivers,
-    data::{DataReceiver, DataSender},
+    data::{DataReceiver, DataSender, PayloadPreferences, PayloadRequests},
     reconciler::Reconciler,
     SessionMode,
 };
 use crate::{
     net::ConnHandle,
-    proto::wgps::{ControlIssueGuarantee, LogicalChannel, Message, SetupBindAreaOfInterest},
+    proto::wgps::{
+        ControlAbsolve, ControlAnnounceDropping, ControlApologise, ControlIssueGuarantee,
+        ControlPlead, ControlUpgradeSession, HandleType, LogicalChannel, Message,
+        SetupBindAreaOfInterest,
+    },
     session::{
         aoi_finder::{self, IntersectionFinder},
         capabilities::Capabilities,
@@ -28,25 +32,28 @@ use crate::{
         intents::{self, EventKind, Intent},
         pai_finder::{self as pai, PaiFinder},
         reconciler,
-        static_tokens::StaticTokens,
-        Channels, Error, EventSender, Role, SessionEvent, SessionId, SessionUpdate,
+        static_tokens::{StaticTokenLimits, StaticTokens},
+        Channels, Error, EventSender, Role, SessionEvent, SessionId, SessionTracker, SessionUpdate,
     },
     store::{traits::Storage, Store},
     util::{
-        channel::Receiver,
+        channel::{CapacityEvent, CapacityWatcher, Receiver},
         stream::{Cancelable, CancelableReceiver},
     },
 };
 
-const INITIAL_GUARANTEES: u64 = u64::MAX;
+/// Maximum number of payload requests from the other peer which wait to be answered.
+const PAYLOAD_REPLY_CAP: usize = 16;
 
 pub(crate) async fn run_session<S: Storage>(
     store: Store<S>,
     conn: ConnHandle,
     initial_intents: Vec<Intent>,
+    static_token_limits: StaticTokenLimits,
     session_id: SessionId,
     event_sender: EventSender,
     update_receiver: ReceiverStream<SessionUpdate>,
+    tracker: SessionTracker,
 ) -> Result<(), Arc<Error>> {
     let ConnHandle {
         peer: _,
@@ -58,6 +65,19 @@ pub(crate) async fn run_session<S: Storage>(
         send: channel_sender,
         recv,
     } = channels;
+
+    // Watch the buffers of our inbound logical channels, to issue guarantees to the other peer
+    // whenever space frees up.
+    let capacity_watchers = LogicalChannel::iter()
+        .map(|channel| (channel, recv.logical_recv.capacity_watcher(channel)))
+        .collect::<Vec<_>>();
+    // The reconciliation channel is idle after reconciliation finished in continuous sessions, so
+    // we plead the other peer to give back its guarantees, and restore the buffer once a new
+    // intersection is reconciled.
+    let reconciliation_watcher = recv
+        .logical_recv
+        .capacity_watcher(LogicalChannel::Reconciliation);
+
     let ChannelReceivers {
         control_recv,
         logical_recv:
@@ -71,7 +91,8 @@ pub(crate) async fn run_session<S: Storage>(
             },
     } = recv;
 
-    // TODO: make mode change on intent changes
+    // The mode is upgraded to continuous once an intent that wants a continuous session is
+    // submitted, see the intents loop below.
     let mode = initial_intents
         .iter()
         .fold(SessionMode::ReconcileOnce, |cur, intent| {
@@ -81,7 +102,18 @@ pub(crate) async fn run_session<S: Storage>(
             }
         });
 
-    debug!(role = ?our_role, ?mode, "start session");
+    // The reconciliation options are taken from the first intent which sets them.
+    let reconcile_opts = initial_intents
+        .iter()
+        .find_map(|intent| intent.init.reconcile_opts)
+        .unwrap_or_default();
+    // We never send payloads eagerly which are larger than what the other peer accepts.
+    let max_eager_payload_size = reconcile_opts
+        .max_eager_payload_size
+        .min(initial_transmission.their_max_payload_size);
+
+    debug!(role = ?our_role, ?mode, ?reconcile_opts, "start session");
+    tracker.set_mode(mode);
 
     // Make all our receivers close once the close session token is triggered.
     let close_session_token = CancellationToken::new();
@@ -97,7 +129,9 @@ pub(crate) async fn run_session<S: Storage>(
         initial_transmission.our_nonce,
         initial_transmission.received_commitment,
     );
-    let tokens = StaticTokens::default();
+    let tokens = StaticTokens::new(static_token_limits);
+    let payload_requests = PayloadRequests::default();
+    let payload_preferences = PayloadPreferences::default();
 
     // Setup channels for communication between the loops.
     // All channels but the intents channel are "cancelable", which means that once the cancel
@@ -117,31 +151,42 @@ pub(crate) async fn run_session<S: Storage>(
     // dropped once all other work is done.
     let (intents_inbox, intents_inbox_rx) = channel::<intents::Input>(2);
 
-    // Setup data channels only if in live mode.
-    // TODO: Adapt to changing mode.
-    let (data_inbox, data_inbox_rx) = if mode == SessionMode::Continuous {
-        let (data_inbox, data_inbox_rx) =
-            cancelable_channel::<data::Input>(2, close_inboxes_token.clone());
-        (Some(data_inbox), Some(data_inbox_rx))
-    } else {
-        (None, None)
-    };
-
-    let mut intents =
-        intents::IntentDispatcher::new(store.auth().clone(), initial_intents, intents_inbox_rx);
+    // The data sender only sends new entries once the session is in live mode.
+    let (mode_tx, mode_rx) = watch::channel(mode);
+    let (data_inbox, data_inbox_rx) =
+        cancelable_channel::<data::Input>(2, close_inboxes_token.clone());
+
+    let mut intents = intents::IntentDispatcher::new(
+        store.auth().clone(),
+        initial_intents,
+        intents_inbox_rx,
+        mode,
+        tracker.clone(),
+    );
     let intents_fut = with_span(error_span!("intents"), async {
         use intents::Output;
         let mut intents_gen = intents.run_gen();
         while let Some(output) = intents_gen.try_next().await? {
             trace!(?output, "yield");
             match output {
-                Output::SubmitInterests(interests) => {
+                Output::SubmitInterests { interests, lazy } => {
                     intersection_inbox
-                        .send(aoi_finder::Input::AddInterests(interests))
+                        .send(aoi_finder::Input::AddInterests { interests, lazy })
                         .await
                         .ok();
                 }
-                // TODO: Add Output::SetMode(SessionMode) to propagate mode changes.
+                Output::SetMode(mode) => {
+                    debug!(?mode, "change session mode");
+                    tracker.set_mode(mode);
+                    mode_tx.send_replace(mode);
+                    // Let the other peer know, so that it keeps the session open as well.
+                    channel_sender.send(ControlUpgradeSession).await?;
+                }
+                Output::PeerUpgraded => {
+                    debug!("other peer upgraded the session to continuous mode");
+                    tracker.set_mode(SessionMode::Continuous);
+                    mode_tx.send_replace(SessionMode::Continuous);
+                }
                 Output::AllIntentsDropped => {
                     debug!("close session (all intents dropped)");
                     close_session_token.cancel();
@@ -151,31 +196,47 @@ pub(crate) async fn run_session<S: Storage>(
         Ok(())
     });
 
+    let intents_inbox_2 = intents_inbox.clone();
     let data_loop = with_span(error_span!("data"), async {
-        // Start data loop only if in live mode.
-        if let Some(inbox) = data_inbox_rx {
-            let send_fut = DataSender::new(
-                inbox,
+        // Payload requests from the other peer are passed from the receiver to the sender.
+        // If the sender falls behind, the receiver waits, and stops reading new requests.
+        let (replies_tx, replies_rx) = mpsc::channel(PAYLOAD_REPLY_CAP);
+        // The data sender runs in all modes to track the intersections, but only sends new
+        // entries once the session is in live mode.
+        let send_fut = DataSender::new(
+            data_inbox_rx,
+            replies_rx,
+            mode_rx.clone(),
+            store.clone(),
+            channel_sender.clone(),
+            tokens.clone(),
+            caps.clone(),
+            payload_requests.clone(),
+            payload_preferences.clone(),
+            session_id,
+        )
+        .run();
+        // The data receiver runs in all modes, because payload requests are answered in all modes.
+        let recv_fut = async {
+            let mut data_receiver = DataReceiver::new(
                 store.clone(),
-                channel_sender.clone(),
                 tokens.clone(),
+                caps.clone(),
+                payload_requests.clone(),
+                replies_tx,
+                payload_preferences.clone(),
                 session_id,
-            )
-            .run();
-            let recv_fut = async {
-                let mut data_receiver =
-                    DataReceiver::new(store.clone(), tokens.clone(), session_id);
-                while let Some(message) = data_recv.try_next().await? {
-                    data_receiver.on_message(message).await?;
-                }
-                trace!("data receiver terminated");
-                Ok(())
-            };
-            (send_fut, recv_fut).try_join().await?;
-            Ok(())
-        } else {
+                intents_inbox_2,
+            );
+            while let Some(message) = data_recv.try_next().await? {
+                data_receiver.on_message(message).await?;
+            }
+            trace!("data receiver terminated");
+            payload_requests.close();
             Ok(())
-        }
+        };
+        (send_fut, recv_fut).try_join().await?;
+        Ok(())
     });
 
     let mut abort_err = None;
@@ -189,6 +250,17 @@ pub(crate) async fn run_session<S: Storage>(
                         .send(intents::Input::SubmitIntent(data))
                         .await?;
                 }
+                SessionUpdate::FetchPayload { entry, reply } => {
+                    // The request is pending from now on, so that a session which is not in live
+                    // mode only closes once the request completed.
+                    let reply = payload_requests.pending_reply(reply);
+                    let input = data::Input::RequestPayload { entry, reply };
+                    if let Err(err) = data_inbox.send(input).await {
+                        if let data::Input::RequestPayload { reply, .. } = err.0 {
+                            reply.send(Err(Error::ChannelClosed));
+                        }
+                    }
+                }
                 SessionUpdate::Abort(err) => {
                     abort_err = Some(err);
                     close_session_token.cancel();
@@ -216,6 +288,15 @@ pub(crate) async fn run_session<S: Storage>(
                 Output::AoiIntersection(intersection) => {
                     let area = intersection.intersection.clone();
                     let namespace = intersection.namespace;
+                    tracker.add_interest_intersection(namespace, area.clone());
+                    payload_preferences.add_ours(intersection.clone());
+                    let amount = reconciliation_watcher.restore_buffer_size();
+                    if amount > 0 {
+                        let channel = LogicalChannel::Reconciliation;
+                        channel_sender
+                            .send(ControlIssueGuarantee { amount, channel })
+                            .await?;
+                    }
                     reconciler_inbox
                         .send(reconciler::Input::AoiIntersection(intersection.clone()))
                         .await
@@ -224,12 +305,10 @@ pub(crate) async fn run_session<S: Storage>(
                     intents_inbox_2
                         .send(intents::Input::EmitEvent(event))
                         .await?;
-                    if let Some(data_inbox) = &data_inbox {
-                        data_inbox
-                            .send(data::Input::AoiIntersection(intersection.clone()))
-                            .await
-                            .ok();
-                    }
+                    data_inbox
+                        .send(data::Input::AoiIntersection(intersection.clone()))
+                        .await
+                        .ok();
                 }
                 Output::SignAndSendCapability { handle, capability } => {
                     let message = caps.sign_capability(store.secrets(), handle, capability)?;
@@ -250,10 +329,10 @@ pub(crate) async fn run_session<S: Storage>(
             match output {
                 Output::SendMessage(message) => channel_sender.send(message).await?,
                 Output::NewIntersection(intersection) => {
-                    let event = EventKind::CapabilityIntersection {
-                        namespace: intersection.authorisation.namespace(),
-                        area: intersection.authorisation.read_cap().granted_area().clone(),
-                    };
+                    let namespace = intersection.authorisation.namespace();
+                    let area = intersection.authorisation.read_cap().granted_area().clone();
+                    tracker.add_capability_intersection(namespace, area.clone());
+                    let event = EventKind::CapabilityIntersection { namespace, area };
                     let _ = (
                         intersection_inbox.send(aoi_finder::Input::PaiIntersection(intersection)),
                         intents_inbox_2.send(intents::Input::EmitEvent(event)),
@@ -282,11 +361,16 @@ pub(crate) async fn run_session<S: Storage>(
             session_id,
             channel_sender.clone(),
             our_role,
-            initial_transmission.their_max_payload_size,
+            reconcile_opts.split_opts(),
+            max_eager_payload_size,
+            caps.clone(),
+            payload_requests.clone(),
+            payload_preferences.clone(),
         );
         while let Some(output) = gen.try_next().await? {
             match output {
                 Output::ReconciledArea { namespace, area } => {
+                    tracker.add_reconciled(namespace, area.clone());
                     intents_inbox_2
                         .send(intents::Input::EmitEvent(EventKind::Reconciled {
                             namespace,
@@ -294,13 +378,31 @@ pub(crate) async fn run_session<S: Storage>(
                         }))
                         .await?;
                 }
+                Output::EntryRejected { entry, error } => {
+                    let error = Arc::new(error.into());
+                    intents_inbox_2
+                        .send(intents::Input::EmitEvent(EventKind::EntryRejected {
+                            entry,
+                            error,
+                        }))
+                        .await?;
+                }
                 Output::ReconciledAll => {
-                    // Stop session if not in live mode;
-                    if !mode.is_live() {
+                    // Stop session if not in live mode, once all payload requests completed.
+                    if !mode_rx.borrow().is_live() {
+                        payload_requests.completed().await;
                         debug!("close session (reconciliation finished and not in live mode)");
                         close_session_token.cancel();
                         break;
                     }
+                    if reconciliation_watcher.plead() {
+                        channel_sender
+                            .send(ControlPlead {
+                                target: 0,
+                                channel: LogicalChannel::Reconciliation,
+                            })
+                            .await?;
+                    }
                 }
             }
         }
@@ -310,7 +412,7 @@ pub(crate) async fn run_session<S: Storage>(
 
     let token_recv_loop = with_span(error_span!("token_recv"), async {
         while let Some(message) = static_tokens_recv.try_next().await? {
-            tokens.bind_theirs(message.static_token);
+            tokens.bind_theirs(message.static_token)?;
         }
         Ok(())
     });
@@ -327,16 +429,67 @@ pub(crate) async fn run_session<S: Storage>(
         Ok(())
     });
 
+    let guarantees_loop = with_span(error_span!("guarantees"), async {
+        // Issue guarantees for the full capacity of our inbound buffers, and issue further
+        // guarantees once the session consumed messages from the buffers.
+        for (channel, watcher) in capacity_watchers.iter() {
+            let amount = watcher.max_buffer_size() as u64;
+            let channel = *channel;
+            channel_sender
+                .send(ControlIssueGuarantee { amount, channel })
+                .await?;
+        }
+        let events = futures_util::stream::select_all(
+            capacity_watchers
+                .iter()
+                .map(|(channel, watcher)| watcher.clone().map(move |event| (*channel, event))),
+        );
+        let mut events = Cancelable::new(events, close_session_token.clone());
+        while let Some((channel, event)) = events.next().await {
+            match event {
+                CapacityEvent::Freed(amount) => {
+                    channel_sender
+                        .send(ControlIssueGuarantee { amount, channel })
+                        .await?;
+                }
+                CapacityEvent::Dropping { freed } => {
+                    // We have to issue all outstanding guarantees before announcing that we
+                    // drop messages, so that the other peer knows which messages were dropped.
+                    if freed > 0 {
+                        channel_sender
+                            .send(ControlIssueGuarantee {
+                                amount: freed,
+                                channel,
+                            })
+                            .await?;
+                    }
+                    debug!(?channel, "announce dropping");
+                    channel_sender
+                        .send(ControlAnnounceDropping { channel })
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    });
+
+    let intents_inbox_2 = intents_inbox.clone();
     let control_loop = with_span(error_span!("control"), async {
         let res = control_loop(
             control_recv,
             our_role,
             &caps,
+            &tokens,
+            &payload_requests,
             &channel_sender,
+            &capacity_watchers,
             &pai_inbox,
+            &intersection_inbox,
+            &intents_inbox_2,
             &event_sender,
         )
         .await;
+        drop(intents_inbox_2);
         // Once the control loop closed, close the inboxes.
         close_inboxes_token.cancel();
         res
@@ -349,7 +502,7 @@ pub(crate) async fn run_session<S: Storage>(
                 authorisation,
             } = message;
             let area_of_interest = area_of_interest.0;
-            let cap = caps.get_theirs_eventually(authorisation).await;
+            let cap = caps.get_theirs_eventually(authorisation).await?;
             if !cap.granted_area().includes_area(&area_of_interest.area) {
                 return Err(Error::UnauthorisedArea);
             }
@@ -373,6 +526,7 @@ pub(crate) async fn run_session<S: Storage>(
     let result = (
         intents_fut,
         control_loop,
+        guarantees_loop,
         data_loop,
         update_loop,
         pai_loop,
@@ -400,6 +554,9 @@ pub(crate) async fn run_session<S: Storage>(
     while let Some(update) = update_receiver.recv().await {
         match update {
             SessionUpdate::SubmitIntent(intent) => remaining_intents.queued.push(intent),
+            SessionUpdate::FetchPayload { reply, .. } => {
+                reply.send(Err(Error::ChannelClosed)).ok();
+            }
             SessionUpdate::Abort(err) => {
                 abort_err = Some(err);
             }
@@ -412,23 +569,28 @@ pub(crate) async fn run_session<S: Storage>(
         _ => Ok(()),
     };
 
-    let remaining_intents = match result.as_ref() {
-        Err(err) => {
+    let (remaining_intents, retry_intents) = match result.as_ref() {
+        Err(err) if matches!(err.as_ref(), Error::ShuttingDown) => {
             remaining_intents.abort_all(err.clone()).await;
-            vec![]
+            (vec![], vec![])
+        }
+        Err(err) => {
+            let retry_intents = remaining_intents.abort_unless_retry(err.clone()).await;
+            (vec![], retry_intents)
         }
         Ok(()) if we_cancelled => {
             drop(remaining_intents.active_incomplete);
-            remaining_intents.queued
+            (remaining_intents.queued, vec![])
         }
         Ok(()) => {
+            // The other peer may have restarted, so intents with a retry policy reconnect.
             remaining_intents
-                .abort_active(Arc::new(Error::SessionClosedByPeer))
+                .abort_active_unless_retry(Arc::new(Error::SessionClosedByPeer))
                 .await
         }
     };
 
-    debug!(error=?result.as_ref().err(), remaining_intents=remaining_intents.len(), ?we_cancelled, "session complete");
+    debug!(error=?result.as_ref().err(), remaining_intents=remaining_intents.len(), retry_intents=retry_intents.len(), ?we_cancelled, "session complete");
 
     if let Err(_receiver_dropped) = event_sender
         .send(SessionEvent::Complete {
@@ -436,6 +598,7 @@ pub(crate) async fn run_session<S: Storage>(
             we_cancelled,
             senders: channel_sender,
             remaining_intents,
+            retry_intents,
         })
         .await
     {
@@ -445,27 +608,24 @@ pub(crate) async fn run_session<S: Storage>(
     result
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn control_loop(
     mut control_recv: Cancelable<Receiver<Message>>,
     our_role: Role,
     caps: &Capabilities,
+    tokens: &StaticTokens,
+    payload_requests: &PayloadRequests,
     sender: &ChannelSenders,
+    capacity_watchers: &[(LogicalChannel, CapacityWatcher)],
     pai_inbox: &mpsc::Sender<pai::Input>,
+    intersection_inbox: &mpsc::Sender<aoi_finder::Input>,
+    intents_inbox: &mpsc::Sender<intents::Input>,
     event_sender: &EventSender,
 ) -> Result<(), Error> {
     // Reveal our nonce.
     let reveal_message = caps.reveal_commitment()?;
     sender.send(reveal_message).await?;
 
-    // Issue guarantees for all logical channels.
-    for channel in LogicalChannel::iter() {
-        let msg = ControlIssueGuarantee {
-            amount: INITIAL_GUARANTEES,
-            channel,
-        };
-        sender.send(msg).await?;
-    }
-
     // Handle incoming messages on the control channel.
     while let Some(message) = control_recv.try_next().await? {
         match message {
@@ -479,6 +639,57 @@ async fn control_loop(
                 // trace!(?channel, %amount, "add guarantees");
                 sender.get_logical(channel).add_guarantees(amount);
             }
+            Message::ControlAbsolve(msg) => {
+                // The other peer will not use `amount` of the guarantees we issued, so we can
+                // shrink our buffer accordingly.
+                let ControlAbsolve { amount, channel } = msg;
+                let amount = capacity_watcher(capacity_watchers, channel).absolved(amount);
+                if amount > 0 {
+                    // We need the buffer again, so we issue the guarantees again.
+                    sender
+                        .send(ControlIssueGuarantee { amount, channel })
+                        .await?;
+                }
+            }
+            Message::ControlPlead(msg) => {
+                // Always reply, even if we absolve nothing, so that the other peer knows that
+                // its plead was handled.
+                let channel = msg.channel;
+                let amount = sender.get_logical(channel).absolve(msg.target);
+                sender.send(ControlAbsolve { amount, channel }).await?;
+            }
+            Message::ControlAnnounceDropping(msg) => {
+                let channel = msg.channel;
+                debug!(?channel, "peer dropped messages, resend");
+                let resume_at = sender.get_logical(channel).resend_dropped()?;
+                sender.send(ControlApologise { channel, resume_at }).await?;
+            }
+            Message::ControlFreeHandle(msg) => match msg.handle_type {
+                HandleType::StaticToken => tokens.on_free_handle(msg, sender).await?,
+                HandleType::PayloadRequest => payload_requests.on_free_handle(msg),
+                HandleType::Capability => {
+                    if let Some(reply) = caps.on_free_handle(&msg)? {
+                        sender.send(reply).await?;
+                    }
+                }
+                HandleType::AreaOfInterest => {
+                    intersection_inbox
+                        .send(aoi_finder::Input::FreeHandle(msg))
+                        .await?;
+                }
+                HandleType::Intersection => {
+                    pai_inbox.send(pai::Input::FreeHandle(msg)).await?;
+                }
+            },
+            Message::ControlUpgradeSession(_) => {
+                intents_inbox.send(intents::Input::PeerUpgraded).await?;
+            }
+            Message::ControlApologise(msg) => {
+                let ControlApologise { channel, resume_at } = msg;
+                if !capacity_watcher(capacity_watchers, channel).apologised(resume_at) {
+                    debug!(?channel, "ignore apologise: we are not dropping messages");
+                }
+            }
             Message::PaiRequestSubspaceCapability(msg) => {
                 if !caps.is_revealed() {
                     return Err(Error::InvalidMessageInCurrentState);
@@ -507,6 +718,16 @@ async fn control_loop(
     Ok(())
 }
 
+fn capacity_watcher(
+    capacity_watchers: &[(LogicalChannel, CapacityWatcher)],
+    channel: LogicalChannel,
+) -> &CapacityWatcher {
+    capacity_watchers
+        .iter()
+        .find_map(|(ch, watcher)| (*ch == channel).then_some(watcher))
+        .expect("capacity watchers exist for all logical channels")
+}
+
 fn channel<T: Send + 'static>(cap: usize) -> (mpsc::Sender<T>, mpsc::Receiver<T>) {
     let (tx, rx) = mpsc::channel(cap);
     (tx, rx)
@@ -8,11 +8,49 @@ use std::{
 use crate::{
     proto::{
         data_model::{AuthorisationToken, AuthorisedEntry, Entry},
-        wgps::{DynamicToken, SetupBindStaticToken, StaticToken, StaticTokenHandle},
+        wgps::{
+            ControlFreeHandle, DynamicToken, HandleType, IsHandle, SetupBindStaticToken,
+            StaticToken, StaticTokenHandle,
+        },
+    },
+    session::{
+        channels::ChannelSenders,
+        resource::{self, EvictionPolicy, ResourceMap},
+        Error,
     },
-    session::{channels::ChannelSenders, resource::ResourceMap, Error},
 };
 
+/// Limits for the static token handles bound in a session.
+#[derive(Debug, Clone, Copy)]
+pub struct StaticTokenLimits {
+    /// Maximum number of static token handles we keep bound at the same time.
+    ///
+    /// Once reached, a handle is selected by [`Self::eviction`] and freed before binding a new one.
+    pub max_handles: usize,
+    /// Policy to select which of our handles to free once [`Self::max_handles`] is reached.
+    pub eviction: EvictionPolicy,
+    /// Maximum number of static token handles the other peer may keep bound at the same time.
+    ///
+    /// The session fails if the other peer binds more handles without freeing them.
+    pub max_their_handles: usize,
+    /// Maximum number of handles freed by the other peer which we keep around.
+    ///
+    /// Messages referring to a handle may still be in flight on other channels when the other
+    /// peer frees it. Entries which refer to a handle after it was deleted fail the session.
+    pub max_retained_freed: usize,
+}
+
+impl Default for StaticTokenLimits {
+    fn default() -> Self {
+        Self {
+            max_handles: 1024,
+            eviction: EvictionPolicy::default(),
+            max_their_handles: 4096,
+            max_retained_freed: 1024,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct StaticTokens(Rc<RefCell<Inner>>);
 
@@ -20,26 +58,103 @@ pub struct StaticTokens(Rc<RefCell<Inner>>);
 struct Inner {
     ours: ResourceMap<StaticTokenHandle, StaticToken>,
     theirs: ResourceMap<StaticTokenHandle, StaticToken>,
+    limits: StaticTokenLimits,
 }
 
 impl StaticTokens {
-    pub fn bind_theirs(&self, token: StaticToken) {
-        self.0.borrow_mut().theirs.bind(token);
+    pub fn new(limits: StaticTokenLimits) -> Self {
+        Self(Rc::new(RefCell::new(Inner {
+            limits,
+            ..Default::default()
+        })))
     }
 
+    pub fn bind_theirs(&self, token: StaticToken) -> Result<(), Error> {
+        let mut inner = self.0.borrow_mut();
+        if inner.theirs.active_len() >= inner.limits.max_their_handles {
+            return Err(Error::HandleLimitExceeded(HandleType::StaticToken));
+        }
+        let retain = inner.limits.max_retained_freed;
+        inner.theirs.prune_freed(retain);
+        inner.theirs.bind(token);
+        Ok(())
+    }
+
+    /// Binds a static token, if not yet bound, and acquires a reference to its handle.
+    ///
+    /// The handle will not be freed until [`Self::release_ours`] is called. If sending the
+    /// messages fails, no reference is kept.
     pub async fn bind_and_send_ours(
         &self,
         static_token: StaticToken,
         send: &ChannelSenders,
     ) -> Result<StaticTokenHandle, Error> {
-        let (handle, is_new) = { self.0.borrow_mut().ours.bind_if_new(static_token.clone()) };
-        if is_new {
-            let msg = SetupBindStaticToken { static_token };
-            send.send(msg).await?;
+        let (handle, is_new, evicted) = {
+            let mut inner = self.0.borrow_mut();
+            let (handle, is_new) = inner.ours.bind_if_new(static_token.clone());
+            // Acquire the handle while sending, so that it is not evicted in the meantime.
+            inner.ours.acquire(handle)?;
+            let evicted = if is_new && inner.ours.active_len() > inner.limits.max_handles {
+                inner
+                    .ours
+                    .eviction_candidate(inner.limits.eviction)
+                    .filter(|handle| inner.ours.propose_free(*handle))
+            } else {
+                None
+            };
+            (handle, is_new, evicted)
+        };
+        let send_messages = async {
+            if let Some(evicted) = evicted {
+                let msg = ControlFreeHandle {
+                    handle: evicted.value(),
+                    mine: true,
+                    handle_type: HandleType::StaticToken,
+                };
+                send.send(msg).await?;
+            }
+            if is_new {
+                let msg = SetupBindStaticToken { static_token };
+                send.send(msg).await?;
+            }
+            Ok::<_, Error>(())
+        };
+        if let Err(err) = send_messages.await {
+            self.release_ours(handle)?;
+            return Err(err);
         }
         Ok(handle)
     }
 
+    /// Releases a reference to a handle acquired with [`Self::bind_and_send_ours`].
+    pub fn release_ours(&self, handle: StaticTokenHandle) -> Result<(), Error> {
+        self.0.borrow_mut().ours.release(handle)?;
+        Ok(())
+    }
+
+    /// Handles a [`ControlFreeHandle`] message for a static token handle.
+    ///
+    /// Replies with a [`ControlFreeHandle`] if the other peer proposed to free the handle.
+    pub async fn on_free_handle(
+        &self,
+        message: ControlFreeHandle,
+        send: &ChannelSenders,
+    ) -> Result<(), Error> {
+        let reply = {
+            let mut inner = self.0.borrow_mut();
+            let Inner {
+                ours,
+                theirs,
+                limits,
+            } = &mut *inner;
+            resource::on_free_handle(ours, theirs, &message, limits.max_retained_freed)?
+        };
+        if let Some(reply) = reply {
+            send.send(reply).await?;
+        }
+        Ok(())
+    }
+
     pub async fn authorise_entry_eventually(
         &self,
         entry: Entry,
@@ -49,10 +164,13 @@ impl StaticTokens {
         let inner = self.0.clone();
         let static_token = poll_fn(move |cx| {
             let mut inner = inner.borrow_mut();
+            if inner.theirs.is_freed(&static_token_handle) {
+                return Poll::Ready(Err(Error::MissingResource(static_token_handle.into())));
+            }
             let token = ready!(inner.theirs.poll_get_eventually(static_token_handle, cx));
-            Poll::Ready(token.clone())
+            Poll::Ready(Ok(token.clone()))
         })
-        .await;
+        .await?;
 
         let token = AuthorisationToken::new(static_token.0, dynamic_token);
         let authorised_entry = AuthorisedEntry::new(entry, token)?;
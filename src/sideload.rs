@@ -0,0 +1,450 @@
+//! Sideloading of entries and payloads through encrypted drops.
+//!
+//! A drop contains the entries and payloads of a set of areas of interest for which the
+//! recipient holds read capabilities, together with these capabilities. Drops allow to sync
+//! without a network connection, e.g. by passing a file on a USB stick. See the
+//! [Willow sideloading protocol] for the idea.
+//!
+//! A drop starts with a plaintext [`DropHeader`], which names the recipient and an ephemeral
+//! public key. The rest of the drop is a sequence of length-prefixed frames, each of which
+//! contains a single postcard-encoded record sealed with a [`ChaChaBox`] between the ephemeral
+//! key and the key of the recipient. The frames are numbered through their nonces and bound to
+//! the header as associated data, so that frames cannot be reordered, dropped or moved between
+//! drops. The drop ends with an explicit end record to detect truncation.
+//!
+//! When ingesting a drop, all entries are verified against their authorisation tokens and must
+//! be included in one of the read capabilities contained in the drop.
+//!
+//! [Willow sideloading protocol]: https://willowprotocol.org/specs/sideloading/
+
+use std::collections::HashSet;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bytes::Bytes;
+use crypto_box::{
+    aead::{Aead, Nonce, Payload},
+    ChaChaBox,
+};
+use futures_concurrency::future::TryJoin;
+use iroh_blobs::{
+    store::{MapEntry, Store as PayloadStore},
+    util::progress::IgnoreProgressSender,
+    BlobFormat, Hash,
+};
+use iroh_io::AsyncSliceReader;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::debug;
+
+use crate::{
+    interest::{CapabilityPack, Interests},
+    proto::{
+        data_model::{serde_encoding::SerdeAuthorisedEntry, AuthorisedEntry},
+        grouping::AreaExt,
+        keys::UserId,
+        meadowcap::{serde_encoding::SerdeReadAuthorisation, ReadAuthorisation},
+    },
+    store::{
+        traits::{EntryOrigin, EntryReader, EntryStorage, SecretStorage, Storage},
+        Store,
+    },
+};
+
+/// Version of the drop format.
+const DROP_VERSION: u16 = 1;
+
+/// Maximum size of the header or a single frame of a drop.
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Size of the chunks in which payloads are written into a drop.
+const CHUNK_SIZE: usize = 1024 * 64;
+
+/// Number of items written into or ingested from a drop.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DropStats {
+    pub capabilities: u64,
+    pub entries: u64,
+    pub payloads: u64,
+}
+
+/// The plaintext header of a drop.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DropHeader {
+    /// Version of the drop format.
+    pub version: u16,
+    /// The user for which the drop is encrypted.
+    pub recipient: UserId,
+    /// The ephemeral public key of the sender.
+    pub ephemeral_key: [u8; 32],
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum DropRecord {
+    /// A read capability of the recipient. All entries of the drop are included in one of them.
+    ReadCap(SerdeReadAuthorisation),
+    /// An entry, followed by [`DropRecord::PayloadChunk`]s with its payload if `payload` is set.
+    Entry {
+        entry: SerdeAuthorisedEntry,
+        payload: bool,
+    },
+    PayloadChunk(Bytes),
+    End,
+}
+
+/// Writes a drop with the entries and payloads selected by `interests`, for the receiver of
+/// `caps`.
+///
+/// The drop contains the read capabilities from `caps`, which must all be issued to the same
+/// user, e.g. as returned from delegating read access to them. Only entries which are included in
+/// both our `interests` and one of these capabilities are written. Like in sync sessions, only the
+/// areas of the areas of interest are considered. Payloads which are not completely stored are
+/// left out of the drop.
+pub(crate) async fn create_drop<S: Storage>(
+    store: &Store<S>,
+    interests: Interests,
+    caps: Vec<CapabilityPack>,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<DropStats> {
+    let caps: Vec<ReadAuthorisation> = caps
+        .into_iter()
+        .filter_map(|cap| match cap {
+            CapabilityPack::Read(cap) => Some(cap),
+            CapabilityPack::Write(_) => None,
+        })
+        .collect();
+    let recipient = *caps
+        .first()
+        .context("a drop needs at least one read capability")?
+        .read_cap()
+        .receiver();
+    ensure!(
+        caps.iter()
+            .all(|cap| *cap.read_cap().receiver() == recipient),
+        "the capabilities of a drop must be issued to the same user"
+    );
+    let interests = store.auth().resolve_interests(interests)?;
+
+    let recipient_key = recipient.into_public_key()?.to_crypto_box();
+    let ephemeral = crypto_box::SecretKey::generate(&mut rand::thread_rng());
+    let header = DropHeader {
+        version: DROP_VERSION,
+        recipient,
+        ephemeral_key: *ephemeral.public_key().as_bytes(),
+    };
+    let header = postcard::to_stdvec(&header)?;
+    writer.write_u32(header.len() as u32).await?;
+    writer.write_all(&header).await?;
+    let cipher = ChaChaBox::new(&recipient_key, &ephemeral);
+    let mut writer = DropWriter::new(writer, cipher, header);
+
+    let mut stats = DropStats::default();
+    for cap in &caps {
+        writer
+            .write(&DropRecord::ReadCap(SerdeReadAuthorisation(cap.clone())))
+            .await?;
+        stats.capabilities += 1;
+    }
+
+    let snapshot = store.entries().snapshot()?;
+    let mut seen = HashSet::new();
+    for (our_cap, aois) in interests {
+        let namespace = our_cap.namespace();
+        let our_area = our_cap.read_cap().granted_area();
+        let their_areas = caps
+            .iter()
+            .filter(|cap| cap.namespace() == namespace)
+            .map(|cap| cap.read_cap().granted_area());
+        let areas: Vec<_> = their_areas
+            .filter_map(|area| area.intersection(&our_area))
+            .flat_map(|area| {
+                aois.iter()
+                    .filter_map(move |aoi| aoi.area.intersection(&area))
+            })
+            .collect();
+        for area in areas {
+            for entry in snapshot.get_authorised_entries(namespace, &area.to_range())? {
+                let entry = entry?;
+                let key = (
+                    namespace,
+                    *entry.entry().subspace_id(),
+                    entry.entry().path().clone(),
+                );
+                if !seen.insert(key) {
+                    continue;
+                }
+                if write_entry(&mut writer, store.payloads(), entry).await? {
+                    stats.payloads += 1;
+                }
+                stats.entries += 1;
+            }
+        }
+    }
+    writer.write(&DropRecord::End).await?;
+    writer.writer.flush().await?;
+    debug!(recipient=%recipient.fmt_short(), ?stats, "drop created");
+    Ok(stats)
+}
+
+/// Ingests a drop created with [`create_drop`].
+///
+/// The secret key of the recipient must be in the store. The read capabilities of the drop are
+/// imported into the store, and the entries are ingested with their payloads. Storage quotas are
+/// enforced like for entries received in sync sessions.
+pub(crate) async fn ingest_drop<S: Storage>(
+    store: &Store<S>,
+    mut reader: impl AsyncRead + Unpin,
+) -> Result<DropStats> {
+    let header_bytes = read_frame(&mut reader).await?;
+    let header: DropHeader = postcard::from_bytes(&header_bytes)?;
+    ensure!(
+        header.version == DROP_VERSION,
+        "unsupported drop version {}, expected {DROP_VERSION}",
+        header.version
+    );
+    let secret = store
+        .secrets()
+        .get_user(&header.recipient)?
+        .with_context(|| {
+            format!(
+                "missing secret key for drop recipient {}",
+                header.recipient.fmt_short()
+            )
+        })?;
+    let ephemeral_key = crypto_box::PublicKey::from(header.ephemeral_key);
+    let cipher = ChaChaBox::new(&ephemeral_key, &secret.to_crypto_box());
+    let mut reader = DropReader::new(reader, cipher, header_bytes);
+
+    let mut stats = DropStats::default();
+    let mut caps: Vec<ReadAuthorisation> = Vec::new();
+    loop {
+        match reader.read().await? {
+            DropRecord::ReadCap(SerdeReadAuthorisation(cap)) => {
+                ensure!(
+                    *cap.read_cap().receiver() == header.recipient,
+                    "drop contains a capability for another user"
+                );
+                store
+                    .auth()
+                    .import_caps([CapabilityPack::Read(cap.clone())])?;
+                caps.push(cap);
+                stats.capabilities += 1;
+            }
+            DropRecord::Entry {
+                entry: SerdeAuthorisedEntry(entry),
+                payload,
+            } => {
+                // The authorisation token of the entry was verified when decoding it.
+                let is_covered = caps.iter().any(|cap| {
+                    cap.namespace() == *entry.entry().namespace_id()
+                        && cap.read_cap().granted_area().includes_entry(entry.entry())
+                });
+                ensure!(
+                    is_covered,
+                    "drop contains an entry not covered by its capabilities"
+                );
+                // Ingest the entry before its payload, so that the payload is protected from GC.
+                // An entry over quota is skipped, together with its payload.
+                let store_payload = match store.check_remote_quotas(entry.entry())? {
+                    Ok(store_payload) => {
+                        if store
+                            .entries()
+                            .ingest_entry(&entry, EntryOrigin::Sideload)?
+                        {
+                            stats.entries += 1;
+                        }
+                        store_payload
+                    }
+                    Err(err) => {
+                        debug!(%err, "skip entry: exceeds quota");
+                        false
+                    }
+                };
+                if payload {
+                    let payload_store = store_payload.then(|| store.payloads());
+                    if read_payload(&mut reader, payload_store, &entry).await? {
+                        stats.payloads += 1;
+                    }
+                }
+            }
+            DropRecord::PayloadChunk(_) => bail!("invalid drop: unexpected payload chunk"),
+            DropRecord::End => break,
+        }
+    }
+    debug!(recipient=%header.recipient.fmt_short(), ?stats, "drop ingested");
+    Ok(stats)
+}
+
+/// Writes an entry and, if it is complete in the payload store, its payload.
+///
+/// Returns `true` if the payload was written.
+async fn write_entry<W: AsyncWrite + Unpin, P: PayloadStore>(
+    writer: &mut DropWriter<W>,
+    payload_store: &P,
+    entry: AuthorisedEntry,
+) -> Result<bool> {
+    let len = entry.entry().payload_length();
+    let hash = Hash::from(*entry.entry().payload_digest());
+    let blob = match len {
+        0 => None,
+        _ => payload_store
+            .get(&hash)
+            .await?
+            .filter(|blob| blob.is_complete()),
+    };
+    writer
+        .write(&DropRecord::Entry {
+            entry: SerdeAuthorisedEntry(entry),
+            payload: blob.is_some(),
+        })
+        .await?;
+    let Some(blob) = blob else {
+        return Ok(false);
+    };
+    let mut reader = blob.data_reader().await?;
+    let mut offset = 0;
+    while offset < len {
+        let chunk = reader.read_at(offset, CHUNK_SIZE).await?;
+        ensure!(!chunk.is_empty(), "payload {hash} ended before its length");
+        offset += chunk.len() as u64;
+        writer.write(&DropRecord::PayloadChunk(chunk)).await?;
+    }
+    Ok(true)
+}
+
+/// Reads the payload chunks of `entry` and imports them into the payload store.
+///
+/// If `payload_store` is `None`, the chunks are skipped and `false` is returned.
+async fn read_payload<R: AsyncRead + Unpin, P: PayloadStore>(
+    reader: &mut DropReader<R>,
+    payload_store: Option<&P>,
+    entry: &AuthorisedEntry,
+) -> Result<bool> {
+    let len = entry.entry().payload_length();
+    let hash = Hash::from(*entry.entry().payload_digest());
+    let (tx, rx) = tokio::sync::mpsc::channel(2);
+    let import_fut = async {
+        let Some(payload_store) = payload_store else {
+            // Consume the chunks without storing them.
+            let mut rx = rx;
+            while rx.recv().await.is_some() {}
+            return anyhow::Ok(None);
+        };
+        let progress = IgnoreProgressSender::default();
+        let (tag, _size) = payload_store
+            .import_stream(ReceiverStream::new(rx), BlobFormat::Raw, progress)
+            .await?;
+        anyhow::Ok(Some(tag))
+    };
+    let read_fut = async {
+        let mut remaining = len;
+        while remaining > 0 {
+            let DropRecord::PayloadChunk(chunk) = reader.read().await? else {
+                bail!("invalid drop: payload ended before its length");
+            };
+            ensure!(
+                chunk.len() as u64 <= remaining,
+                "invalid drop: payload exceeds its length"
+            );
+            remaining -= chunk.len() as u64;
+            tx.send(Ok(chunk)).await.context("payload import failed")?;
+        }
+        drop(tx);
+        anyhow::Ok(())
+    };
+    let (tag, ()) = (import_fut, read_fut).try_join().await?;
+    let Some(tag) = tag else {
+        debug!(%hash, len, "skip payload: exceeds quota");
+        return Ok(false);
+    };
+    ensure!(
+        *tag.hash() == hash,
+        "invalid drop: payload does not match its entry"
+    );
+    Ok(true)
+}
+
+struct DropWriter<W> {
+    writer: W,
+    cipher: ChaChaBox,
+    header: Vec<u8>,
+    counter: u64,
+}
+
+impl<W: AsyncWrite + Unpin> DropWriter<W> {
+    fn new(writer: W, cipher: ChaChaBox, header: Vec<u8>) -> Self {
+        Self {
+            writer,
+            cipher,
+            header,
+            counter: 0,
+        }
+    }
+
+    async fn write(&mut self, record: &DropRecord) -> Result<()> {
+        let plaintext = postcard::to_stdvec(record)?;
+        let payload = Payload {
+            msg: &plaintext,
+            aad: &self.header,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce(self.counter), payload)
+            .map_err(|_| anyhow!("failed to encrypt drop"))?;
+        self.counter += 1;
+        self.writer.write_u32(ciphertext.len() as u32).await?;
+        self.writer.write_all(&ciphertext).await?;
+        Ok(())
+    }
+}
+
+struct DropReader<R> {
+    reader: R,
+    cipher: ChaChaBox,
+    header: Vec<u8>,
+    counter: u64,
+}
+
+impl<R: AsyncRead + Unpin> DropReader<R> {
+    fn new(reader: R, cipher: ChaChaBox, header: Vec<u8>) -> Self {
+        Self {
+            reader,
+            cipher,
+            header,
+            counter: 0,
+        }
+    }
+
+    async fn read(&mut self) -> Result<DropRecord> {
+        let ciphertext = read_frame(&mut self.reader).await?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: &self.header,
+        };
+        let plaintext = self
+            .cipher
+            .decrypt(&nonce(self.counter), payload)
+            .map_err(|_| anyhow!("failed to decrypt drop: invalid key or corrupted data"))?;
+        self.counter += 1;
+        let record = postcard::from_bytes(&plaintext)?;
+        Ok(record)
+    }
+}
+
+/// Returns the nonce for the frame with index `counter`.
+fn nonce(counter: u64) -> Nonce<ChaChaBox> {
+    let mut nonce = [0u8; 24];
+    nonce[..8].copy_from_slice(&counter.to_le_bytes());
+    nonce.into()
+}
+
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+    let len = reader
+        .read_u32()
+        .await
+        .context("invalid drop: unexpected end")? as usize;
+    ensure!(len <= MAX_FRAME_SIZE, "invalid drop: frame too large");
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(data)
+}
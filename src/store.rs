@@ -4,20 +4,23 @@
 //!
 //! The only implementation is currently an in-memory store at [`memory`].
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, ensure, Result};
+use iroh_blobs::{store::Store as _, TempTag};
 use rand_core::CryptoRngCore;
-use traits::EntryStorage;
+use traits::{EntryReader, EntryStorage, QuotaExceeded, QuotaScope, Usage};
 
 pub(crate) use self::traits::EntryOrigin;
 use self::{
     auth::{Auth, AuthError},
+    signer::UserSigner,
     traits::Storage,
 };
 use crate::{
     form::{AuthForm, EntryForm, EntryOrForm, SubspaceForm, TimestampForm},
     interest::{CapSelector, UserSelector},
     proto::{
-        data_model::{AuthorisedEntry, Entry, PayloadDigest},
+        data_model::{AuthorisedEntry, Entry, Path, PayloadDigest, SubspaceId},
+        grouping::{Area, AreaExt, AreaSubspace, Range, RangeEnd},
         keys::{NamespaceId, NamespaceKind, NamespaceSecretKey, UserId},
     },
     store::traits::SecretStorage,
@@ -25,15 +28,18 @@ use crate::{
 };
 
 pub(crate) mod auth;
+pub mod backup;
+pub mod encrypted;
 pub mod memory;
 pub mod persistent;
+pub mod signer;
 pub mod traits;
 pub(crate) mod willow_store_glue;
 
 /// Storage for the Willow engine.
 ///
 /// Wraps a `Storage` instance and adds the [`Auth`] struct that uses the secret and caps storage to provide
-/// authentication when inserting entries.
+/// authentication when inserting entries, and enforces the [`Quotas`](traits::Quotas) of the storage.
 #[derive(Debug, Clone)]
 pub(crate) struct Store<S: Storage> {
     storage: S,
@@ -48,6 +54,10 @@ impl<S: Storage> Store<S> {
         }
     }
 
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
     pub fn entries(&self) -> &S::Entries {
         self.storage.entries()
     }
@@ -64,16 +74,107 @@ impl<S: Storage> Store<S> {
         &self.auth
     }
 
+    /// Checks whether ingesting `entry` stays within the [`Quotas`](traits::Quotas) of the storage.
+    ///
+    /// Entries which would be pruned by `entry` are not counted, and entries which are already
+    /// stored always pass.
+    pub fn check_quotas(&self, entry: &Entry) -> Result<Result<(), QuotaExceeded>> {
+        self.check_quotas_impl(entry, false)
+    }
+
+    /// Checks the [`Quotas`](traits::Quotas) for an entry received from another peer.
+    ///
+    /// Returns `Ok(false)` if the entry may be ingested, but its payload exceeds the maximum
+    /// payload size and must not be stored. Such payloads do not count towards the quotas.
+    pub fn check_remote_quotas(&self, entry: &Entry) -> Result<Result<bool, QuotaExceeded>> {
+        let store_payload = !self.storage.quotas().is_oversized(entry.payload_length());
+        Ok(self.check_quotas_impl(entry, true)?.map(|()| store_payload))
+    }
+
+    fn check_quotas_impl(
+        &self,
+        entry: &Entry,
+        allow_oversized: bool,
+    ) -> Result<Result<(), QuotaExceeded>> {
+        let quotas = self.storage.quotas();
+        if quotas.is_unlimited() {
+            return Ok(Ok(()));
+        }
+        let scopes = [
+            (QuotaScope::Namespace, quotas.namespace),
+            (QuotaScope::Subspace, quotas.subspace),
+        ];
+        if !allow_oversized {
+            for (scope, quota) in &scopes {
+                if quota
+                    .max_payload_size
+                    .is_some_and(|max| entry.payload_length() > max)
+                {
+                    return Ok(Err(QuotaExceeded::PayloadSize(*scope)));
+                }
+            }
+        }
+        if scopes
+            .iter()
+            .all(|(_, quota)| quota.max_entries.is_none() && quota.max_payload_bytes.is_none())
+        {
+            return Ok(Ok(()));
+        }
+        let namespace = *entry.namespace_id();
+        let subspace = *entry.subspace_id();
+
+        let times = Range::new(0, RangeEnd::Closed(entry.timestamp().saturating_add(1)));
+        let prune_area = Area::new(AreaSubspace::Id(subspace), entry.path().clone(), times);
+        let mut pruned = Usage::default();
+        for existing in self
+            .entries()
+            .get_entries(namespace, &prune_area.to_range())?
+        {
+            let existing = existing?;
+            if existing == *entry {
+                // The entry is already stored, ingesting it does not change the usage.
+                return Ok(Ok(()));
+            }
+            if entry.is_newer_than(&existing) {
+                pruned.add(quotas.counted_payload_bytes(existing.payload_length()));
+            }
+        }
+
+        for (scope, quota) in &scopes {
+            if quota.max_entries.is_none() && quota.max_payload_bytes.is_none() {
+                continue;
+            }
+            let subspace = match scope {
+                QuotaScope::Namespace => None,
+                QuotaScope::Subspace => Some(subspace),
+            };
+            let mut usage = self
+                .entries()
+                .quota_usage(namespace, subspace, &quotas)?
+                .saturating_sub(&pruned);
+            usage.add(quotas.counted_payload_bytes(entry.payload_length()));
+            if let Err(err) = usage.check(quota, *scope) {
+                return Ok(Err(err));
+            }
+        }
+        Ok(Ok(()))
+    }
+
     pub async fn insert_entry(
         &self,
         entry: EntryOrForm,
         auth: AuthForm,
     ) -> Result<(AuthorisedEntry, bool)> {
         let user_id = auth.user_id();
-        let entry = match entry {
-            EntryOrForm::Entry(entry) => Ok(entry),
-            EntryOrForm::Form(form) => self.form_to_entry(form, user_id).await,
-        }?;
+        // The temp tag protects the payload from garbage collection until the entry is stored.
+        let (entry, _payload_tag) = match entry {
+            EntryOrForm::Entry(entry) => (entry, None),
+            EntryOrForm::Form(form) => {
+                let (entry, tag) = self.form_to_entry(form, user_id).await?;
+                (entry, Some(tag))
+            }
+        };
+        self.check_quotas(&entry)??;
         let capability = match auth {
             AuthForm::Exact(cap) => cap,
             AuthForm::Any(user_id) => {
@@ -83,17 +184,16 @@ impl<S: Storage> Store<S> {
                     .ok_or_else(|| anyhow!("no write capability available"))?
             }
         };
-        let secret_key = self
-            .secrets()
-            .get_user(&user_id)?
-            .context("Missing user keypair")?;
+        ensure!(self.secrets().has_user(&user_id)?, "Missing user keypair");
+        // Sign through the secret store, so that the secret key never has to be read.
+        let signer = UserSigner::new(self.secrets(), user_id);
 
         // TODO(frando): This should use `authorisation_token_unchecked` if we uphold the invariant
         // that `user_id` is a pubkey for `secret_key`. However, that is `unsafe` at the moment
         // (but should not be, IMO).
         // Not using the `_unchecked` variant has the cost of an additional signature verification,
         // so significant.
-        let token = capability.authorisation_token(&entry, secret_key)?;
+        let token = capability.authorisation_token(&entry, signer)?;
         let authorised_entry = AuthorisedEntry::new_unchecked(entry, token);
         let inserted = self
             .entries()
@@ -101,6 +201,38 @@ impl<S: Storage> Store<S> {
         Ok((authorised_entry, inserted))
     }
 
+    /// Removes the payload of an entry from the payload store.
+    ///
+    /// Payloads are content-addressed, so this fails if other stored entries reference the same
+    /// payload digest. The entry itself is kept.
+    ///
+    /// Returns `false` if there is no entry at `subspace` and `path`.
+    pub async fn forget_payload(
+        &self,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: &Path,
+    ) -> Result<bool> {
+        let Some(entry) = self
+            .entries()
+            .reader()
+            .get_entry(namespace, subspace, path)?
+        else {
+            return Ok(false);
+        };
+        let digest = *entry.entry().payload_digest();
+        let references = self.entries().payload_references(&digest)?;
+        anyhow::ensure!(
+            references <= 1,
+            "the payload is referenced by {} other entries",
+            references - 1
+        );
+        let hash: iroh_blobs::Hash = digest.into();
+        self.payloads().delete(vec![hash]).await?;
+        self.entries().forget_payload(namespace, subspace, path)?;
+        Ok(true)
+    }
+
     pub fn create_namespace(
         &self,
         rng: &mut impl CryptoRngCore,
@@ -118,11 +250,13 @@ impl<S: Storage> Store<S> {
     /// the provided [`Store`].
     ///
     /// `user_id` must be set to the user who is authenticating the entry.
+    ///
+    /// Also returns a [`TempTag`] which protects the payload from garbage collection.
     async fn form_to_entry(
         &self,
         form: EntryForm,
         user_id: UserId, // auth: AuthForm,
-    ) -> anyhow::Result<Entry> {
+    ) -> anyhow::Result<(Entry, TempTag)> {
         let timestamp = match form.timestamp {
             TimestampForm::Now => system_time_now(),
             TimestampForm::Exact(timestamp) => timestamp,
@@ -131,15 +265,15 @@ impl<S: Storage> Store<S> {
             SubspaceForm::User => user_id,
             SubspaceForm::Exact(subspace) => subspace,
         };
-        let (payload_digest, payload_length) = form.payload.submit(self.payloads()).await?;
+        let (payload_tag, payload_length) = form.payload.submit(self.payloads()).await?;
         let entry = Entry::new(
             form.namespace_id,
             subspace_id,
             form.path,
             timestamp,
             payload_length,
-            PayloadDigest(payload_digest),
+            PayloadDigest(*payload_tag.hash()),
         );
-        Ok(entry)
+        Ok((entry, payload_tag))
     }
 }
@@ -23,7 +23,10 @@ use crate::{
             ReadAuthorisation,
         },
     },
-    store::traits::{CapsStorage, SecretStorage, SecretStoreError, Storage},
+    store::{
+        signer::{NamespaceSigner, UserSigner},
+        traits::{CapsStorage, SecretStorage, SecretStoreError, Storage},
+    },
 };
 
 #[derive(Debug, Clone)]
@@ -145,10 +148,7 @@ impl<S: Storage> Auth<S> {
             let read_cap = McCapability::new_communal(namespace_key, user_key, AccessMode::Read)?;
             ReadAuthorisation::new(read_cap, None)
         } else {
-            let namespace_secret = self
-                .secrets
-                .get_namespace(&namespace_key)?
-                .ok_or(AuthError::MissingNamespaceSecret(namespace_key))?;
+            let namespace_secret = self.namespace_signer(namespace_key)?;
             let read_cap = McCapability::new_owned(
                 namespace_key,
                 &namespace_secret,
@@ -172,10 +172,7 @@ impl<S: Storage> Auth<S> {
         let cap = if namespace_key.is_communal() {
             McCapability::new_communal(namespace_key, user_key, AccessMode::Write)?
         } else {
-            let namespace_secret = self
-                .secrets
-                .get_namespace(&namespace_key)?
-                .ok_or(AuthError::MissingNamespaceSecret(namespace_key))?;
+            let namespace_secret = self.namespace_signer(namespace_key)?;
             McCapability::new_owned(
                 namespace_key,
                 &namespace_secret,
@@ -222,10 +219,7 @@ impl<S: Storage> Auth<S> {
         let read_cap = auth.read_cap();
         let subspace_cap = auth.subspace_cap();
         let user_id = read_cap.receiver();
-        let user_secret = self
-            .secrets
-            .get_user(user_id)?
-            .ok_or(AuthError::MissingUserSecret(*user_id))?;
+        let user_secret = self.user_signer(*user_id)?;
         let area = restrict_area.or_default(read_cap.granted_area());
         let new_read_cap = read_cap.delegate(&user_secret, &to, &area)?;
 
@@ -253,14 +247,30 @@ impl<S: Storage> Auth<S> {
         restrict_area: RestrictArea,
     ) -> Result<CapabilityPack, AuthError> {
         let cap = self.get_write_cap(from)?.ok_or(AuthError::NoCapability)?;
-        let user_secret = self
-            .secrets
-            .get_user(cap.receiver())?
-            .ok_or(AuthError::MissingUserSecret(*cap.receiver()))?;
+        let user_secret = self.user_signer(*cap.receiver())?;
         let area = restrict_area.or_default(cap.granted_area());
         let new_cap = cap.delegate(&user_secret, &to, &area)?;
         Ok(CapabilityPack::Write(new_cap))
     }
+
+    /// Returns a signer for the user `id`, which signs through the secret store.
+    fn user_signer(&self, id: UserId) -> Result<UserSigner<'_, S::Secrets>, AuthError> {
+        if !self.secrets.has_user(&id)? {
+            return Err(AuthError::MissingUserSecret(id));
+        }
+        Ok(UserSigner::new(&self.secrets, id))
+    }
+
+    /// Returns a signer for the namespace `id`, which signs through the secret store.
+    fn namespace_signer(
+        &self,
+        id: NamespaceId,
+    ) -> Result<NamespaceSigner<'_, S::Secrets>, AuthError> {
+        if !self.secrets.has_namespace(&id)? {
+            return Err(AuthError::MissingNamespaceSecret(id));
+        }
+        Ok(NamespaceSigner::new(&self.secrets, id))
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -0,0 +1,377 @@
+//! Export and import of whole stores.
+//!
+//! An export contains all entries with their authorisation tokens, all capabilities from the
+//! [`CapsStorage`] and, optionally, the user and namespace secrets of a store. It can be imported
+//! into any other [`Storage`], so that exports also serve to migrate between storage backends,
+//! e.g. from a [`memory::Store`](super::memory::Store) to a
+//! [`persistent::Store`](super::persistent::Store).
+//!
+//! The export is a sequence of length-prefixed, postcard-encoded records. The payloads of the
+//! exported entries can be written to a separate companion archive, which follows the same
+//! framing but contains the raw payload bytes after each payload record.
+
+use std::collections::BTreeSet;
+
+use anyhow::{ensure, Context, Result};
+use bytes::BytesMut;
+use futures_concurrency::future::TryJoin;
+use iroh_blobs::{
+    store::{MapEntry, Store as PayloadStore},
+    util::progress::IgnoreProgressSender,
+    BlobFormat, Hash,
+};
+use iroh_io::AsyncSliceReader;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::debug;
+
+use super::traits::{CapsStorage, EntryOrigin, EntryReader, EntryStorage, SecretStorage, Storage};
+use crate::{
+    interest::CapabilityPack,
+    proto::{data_model::serde_encoding::SerdeAuthorisedEntry, grouping::Range3d, meadowcap},
+};
+
+/// Version of the export format, written at the start of each archive.
+const FORMAT_VERSION: u16 = 1;
+
+/// Maximum size of a single encoded record.
+const MAX_RECORD_SIZE: usize = 1024 * 1024;
+
+/// Size of the chunks in which payloads are copied.
+const CHUNK_SIZE: usize = 1024 * 64;
+
+/// Options for [`export`].
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ExportOpts {
+    /// Whether to include the user and namespace secrets.
+    ///
+    /// Secrets are written in plain text, so only set this if the export is stored safely.
+    pub include_secrets: bool,
+}
+
+/// Number of items written by [`export`] or ingested by [`import`].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BackupStats {
+    pub entries: u64,
+    pub secrets: u64,
+    pub capabilities: u64,
+    pub payloads: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Record {
+    Header { version: u16 },
+    Secret(meadowcap::SecretKey),
+    Capability(CapabilityPack),
+    Entry(SerdeAuthorisedEntry),
+    End,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum PayloadRecord {
+    Header {
+        version: u16,
+    },
+    /// Followed by `len` bytes of payload data.
+    Payload {
+        hash: Hash,
+        len: u64,
+    },
+    End,
+}
+
+/// Exports the contents of `storage` to `writer`.
+///
+/// The entries are read from a single [`EntryStorage::snapshot`], so the store can be used
+/// while the export is running. If `payloads` is set, the complete payloads of the exported
+/// entries are written to it. Payloads which are missing or incomplete are skipped.
+pub async fn export<S: Storage>(
+    storage: &S,
+    opts: ExportOpts,
+    mut writer: impl AsyncWrite + Unpin,
+    payloads: Option<&mut (dyn AsyncWrite + Unpin)>,
+) -> Result<BackupStats> {
+    let mut stats = BackupStats::default();
+    let snapshot = storage.entries().snapshot()?;
+    write_record(
+        &mut writer,
+        &Record::Header {
+            version: FORMAT_VERSION,
+        },
+    )
+    .await?;
+
+    if opts.include_secrets {
+        for secret in storage.secrets().list_secrets()? {
+            write_record(&mut writer, &Record::Secret(secret)).await?;
+            stats.secrets += 1;
+        }
+    }
+
+    let caps = storage.caps();
+    let read_caps = caps.list_read_caps(None)?.map(CapabilityPack::Read);
+    let write_caps = caps.list_write_caps(None)?.map(CapabilityPack::Write);
+    let caps: Vec<_> = read_caps.chain(write_caps).collect();
+    for cap in caps {
+        write_record(&mut writer, &Record::Capability(cap)).await?;
+        stats.capabilities += 1;
+    }
+
+    let mut digests = BTreeSet::new();
+    for namespace in snapshot.namespaces()? {
+        for entry in snapshot.get_authorised_entries(namespace, &Range3d::new_full())? {
+            let entry = entry?;
+            if entry.entry().payload_length() > 0 {
+                digests.insert(Hash::from(*entry.entry().payload_digest()));
+            }
+            write_record(&mut writer, &Record::Entry(SerdeAuthorisedEntry(entry))).await?;
+            stats.entries += 1;
+        }
+    }
+    write_record(&mut writer, &Record::End).await?;
+    writer.flush().await?;
+
+    if let Some(writer) = payloads {
+        stats.payloads = export_payloads(storage.payloads(), digests, writer).await?;
+    }
+    debug!(?stats, "export complete");
+    Ok(stats)
+}
+
+/// Imports an export created with [`export`] into `storage`.
+///
+/// Secrets and capabilities are inserted, and entries are ingested as local entries. If
+/// `payloads` is set, the payloads from the companion archive are imported after the entries.
+///
+/// Entries which are already stored or pruned by newer entries are not counted in the returned
+/// stats. Storage quotas are not enforced.
+pub async fn import<S: Storage>(
+    storage: &S,
+    mut reader: impl AsyncRead + Unpin,
+    payloads: Option<&mut (dyn AsyncRead + Unpin)>,
+) -> Result<BackupStats> {
+    let mut stats = BackupStats::default();
+    match read_record(&mut reader).await? {
+        Record::Header { version } => ensure_version(version)?,
+        _ => anyhow::bail!("invalid export: missing header"),
+    }
+    loop {
+        match read_record(&mut reader).await? {
+            Record::Header { .. } => anyhow::bail!("invalid export: duplicate header"),
+            Record::Secret(secret) => {
+                storage.secrets().insert(secret)?;
+                stats.secrets += 1;
+            }
+            Record::Capability(cap) => {
+                storage.caps().insert(cap)?;
+                stats.capabilities += 1;
+            }
+            Record::Entry(SerdeAuthorisedEntry(entry)) => {
+                if storage.entries().ingest_entry(&entry, EntryOrigin::Local)? {
+                    stats.entries += 1;
+                }
+            }
+            Record::End => break,
+        }
+    }
+
+    if let Some(reader) = payloads {
+        stats.payloads = import_payloads(storage.payloads(), reader).await?;
+    }
+    debug!(?stats, "import complete");
+    Ok(stats)
+}
+
+async fn export_payloads<P: PayloadStore>(
+    store: &P,
+    digests: BTreeSet<Hash>,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<u64> {
+    let mut count = 0;
+    write_record(
+        &mut writer,
+        &PayloadRecord::Header {
+            version: FORMAT_VERSION,
+        },
+    )
+    .await?;
+    for hash in digests {
+        let Some(entry) = store.get(&hash).await? else {
+            debug!(%hash, "skip export of missing payload");
+            continue;
+        };
+        if !entry.is_complete() {
+            debug!(%hash, "skip export of incomplete payload");
+            continue;
+        }
+        let len = entry.size().value();
+        write_record(&mut writer, &PayloadRecord::Payload { hash, len }).await?;
+        let mut reader = entry.data_reader().await?;
+        let mut offset = 0;
+        while offset < len {
+            let chunk = reader.read_at(offset, CHUNK_SIZE).await?;
+            ensure!(!chunk.is_empty(), "payload {hash} ended before its length");
+            writer.write_all(&chunk).await?;
+            offset += chunk.len() as u64;
+        }
+        count += 1;
+    }
+    write_record(&mut writer, &PayloadRecord::End).await?;
+    writer.flush().await?;
+    Ok(count)
+}
+
+async fn import_payloads<P: PayloadStore>(
+    store: &P,
+    mut reader: impl AsyncRead + Unpin,
+) -> Result<u64> {
+    let mut count = 0;
+    match read_record(&mut reader).await? {
+        PayloadRecord::Header { version } => ensure_version(version)?,
+        _ => anyhow::bail!("invalid payload archive: missing header"),
+    }
+    loop {
+        let (hash, len) = match read_record(&mut reader).await? {
+            PayloadRecord::Header { .. } => {
+                anyhow::bail!("invalid payload archive: duplicate header")
+            }
+            PayloadRecord::Payload { hash, len } => (hash, len),
+            PayloadRecord::End => break,
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(2);
+        let import_fut = async {
+            let progress = IgnoreProgressSender::default();
+            let res = store
+                .import_stream(ReceiverStream::new(rx), BlobFormat::Raw, progress)
+                .await?;
+            anyhow::Ok(res)
+        };
+        let read_fut = async {
+            let mut remaining = len;
+            while remaining > 0 {
+                let mut chunk = BytesMut::zeroed(remaining.min(CHUNK_SIZE as u64) as usize);
+                reader.read_exact(&mut chunk).await?;
+                remaining -= chunk.len() as u64;
+                tx.send(Ok(chunk.freeze()))
+                    .await
+                    .context("payload import failed")?;
+            }
+            drop(tx);
+            anyhow::Ok(())
+        };
+        let ((tag, size), ()) = (import_fut, read_fut).try_join().await?;
+        ensure!(
+            *tag.hash() == hash && size == len,
+            "payload {hash} does not match its content"
+        );
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn ensure_version(version: u16) -> Result<()> {
+    ensure!(
+        version == FORMAT_VERSION,
+        "unsupported export format version {version}, expected {FORMAT_VERSION}"
+    );
+    Ok(())
+}
+
+async fn write_record<T: Serialize>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    record: &T,
+) -> Result<()> {
+    let data = postcard::to_stdvec(record)?;
+    writer.write_u32(data.len() as u32).await?;
+    writer.write_all(&data).await?;
+    Ok(())
+}
+
+async fn read_record<T: DeserializeOwned>(reader: &mut (impl AsyncRead + Unpin)) -> Result<T> {
+    let len = reader
+        .read_u32()
+        .await
+        .context("unexpected end of export")? as usize;
+    ensure!(len <= MAX_RECORD_SIZE, "record too large: {len} bytes");
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    let record = postcard::from_bytes(&data)?;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_blobs::store::Map;
+    use rand_core::SeedableRng;
+
+    use super::*;
+    use crate::{
+        form::{AuthForm, EntryForm},
+        proto::{
+            data_model::Path,
+            keys::{NamespaceKind, UserSecretKey},
+        },
+        store::{memory, persistent},
+    };
+
+    #[tokio::test]
+    async fn export_import_memory_to_persistent() -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let source =
+            crate::store::Store::new(memory::Store::new(iroh_blobs::store::mem::Store::default()));
+        let user = source
+            .secrets()
+            .insert_user(UserSecretKey::generate(&mut rng))?;
+        let namespace = source.create_namespace(&mut rng, NamespaceKind::Owned, user)?;
+        for (path, payload) in [("a", "hello"), ("b", "world"), ("c", "hello")] {
+            let path = Path::from_bytes(&[path.as_bytes()])?;
+            let entry = EntryForm::new_bytes(namespace, path, payload);
+            source
+                .insert_entry(entry.into(), AuthForm::Any(user))
+                .await?;
+        }
+
+        let mut export_data = Vec::new();
+        let mut payload_data = Vec::new();
+        let opts = ExportOpts {
+            include_secrets: true,
+        };
+        let stats = export(
+            source.storage(),
+            opts,
+            &mut export_data,
+            Some(&mut payload_data),
+        )
+        .await?;
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.payloads, 2);
+
+        let target = persistent::Store::new_memory(iroh_blobs::store::mem::Store::default())?;
+        let imported = import(&target, &export_data[..], Some(&mut &payload_data[..])).await?;
+        assert_eq!(imported, stats);
+
+        assert!(target.secrets().get_user(&user)?.is_some());
+        assert!(target.secrets().get_namespace(&namespace)?.is_some());
+        let source_caps = source.storage().caps();
+        assert_eq!(
+            target.caps().list_write_caps(None)?.count(),
+            source_caps.list_write_caps(None)?.count()
+        );
+        assert_eq!(
+            target.caps().list_read_caps(None)?.count(),
+            source_caps.list_read_caps(None)?.count()
+        );
+        let entries = target
+            .entries()
+            .get_authorised_entries(namespace, &Range3d::new_full())?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries.len(), 3);
+        for entry in entries {
+            let hash = Hash::from(*entry.entry().payload_digest());
+            let blob = target.payloads().get(&hash).await?;
+            assert!(blob.is_some_and(|blob| blob.is_complete()));
+        }
+        Ok(())
+    }
+}
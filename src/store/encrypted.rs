@@ -0,0 +1,465 @@
+//! Encryption at rest for user and namespace secrets.
+//!
+//! [`EncryptedStorage`] wraps a [`Storage`] whose secret store implements
+//! [`SealedSecretStorage`], and replaces its secret store with [`EncryptedSecrets`]. The secret
+//! keys are then only stored encrypted with a [`SecretsKey`], which is either derived from a
+//! passphrase or supplied by the application, e.g. from the keyring of the operating system.
+//!
+//! The key is set once with [`SecretStorage::set_passphrase`]. Secrets which were stored in
+//! plaintext before are encrypted with that key, and removed from the plaintext tables in the same
+//! transaction which stores their ciphertexts.
+//!
+//! Removing the plaintext secrets does not erase them from the storage medium. The
+//! [`persistent`](super::persistent) store compacts its database file after sealing them, but
+//! the file may still contain the old plaintext in pages which were freed and not overwritten,
+//! and so may copies and backups of the file made before. Secrets which were stored in plaintext
+//! should be treated as exposed to anyone with access to the file.
+//!
+//! The secret store starts out locked. While locked, secret keys can neither be read nor inserted,
+//! and the secret store fails with [`SecretStoreError::Locked`]. It is unlocked with
+//! [`SecretStorage::unlock`], and can be locked again at runtime with [`SecretStorage::lock`].
+
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use anyhow::{anyhow, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+
+use super::traits::{KeyScope, SecretStorage, SecretStoreError, Storage};
+pub use super::traits::{SecretsCredential, SecretsKey};
+use crate::proto::{
+    keys::{
+        NamespaceId, NamespaceSecretKey, NamespaceSignature, UserId, UserSecretKey, UserSignature,
+    },
+    meadowcap,
+};
+
+/// Length of the salt used to derive a [`SecretsKey`] from a passphrase.
+pub const SALT_LEN: usize = 16;
+
+const NONCE_LEN: usize = 24;
+
+/// Associated data of the ciphertext used to verify a [`SecretsKey`] when unlocking.
+const KEY_CHECK_AAD: &[u8] = b"iroh-willow secrets key check";
+
+impl SecretsKey {
+    /// Derives a key from a passphrase with Argon2id.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8; SALT_LEN]) -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut bytes)
+            .map_err(|err| anyhow!("failed to derive key from passphrase: {err}"))?;
+        Ok(Self::from_bytes(bytes))
+    }
+
+    fn seal(&self, aad: &[u8], msg: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&self.to_bytes()))
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg, aad })
+            .map_err(|_| anyhow!("failed to encrypt secret"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, SecretStoreError> {
+        if sealed.len() < NONCE_LEN {
+            return Err(SecretStoreError::InvalidKey);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        XChaCha20Poly1305::new(Key::from_slice(&self.to_bytes()))
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| SecretStoreError::InvalidKey)
+    }
+}
+
+impl SecretsCredential {
+    fn derive_key(&self, salt: &[u8; SALT_LEN]) -> Result<SecretsKey> {
+        match self {
+            Self::Passphrase(passphrase) => SecretsKey::from_passphrase(passphrase, salt),
+            Self::Key(key) => Ok(key.clone()),
+        }
+    }
+}
+
+/// Parameters of the encryption of a secret store, which are stored next to the secrets.
+#[derive(Debug, Clone)]
+pub struct EncryptionParams {
+    /// Salt to derive the key from a passphrase.
+    pub salt: [u8; SALT_LEN],
+    /// A ciphertext to verify the key when unlocking.
+    pub key_check: Vec<u8>,
+}
+
+/// Storage backend for encrypted secret keys.
+///
+/// The backend only stores opaque ciphertexts, keyed by the public key of each secret.
+pub trait SealedSecretStorage: fmt::Debug + Clone + 'static {
+    fn insert_sealed(&self, scope: KeyScope, id: [u8; 32], sealed: Vec<u8>) -> Result<()>;
+    fn get_sealed(&self, scope: KeyScope, id: &[u8; 32]) -> Result<Option<Vec<u8>>>;
+    fn list_sealed(&self) -> Result<Vec<(KeyScope, [u8; 32], Vec<u8>)>>;
+
+    fn encryption_params(&self) -> Result<Option<EncryptionParams>>;
+    fn set_encryption_params(&self, params: EncryptionParams) -> Result<()>;
+
+    /// Returns the secrets which are stored in plaintext.
+    fn plaintext_secrets(&self) -> Result<Vec<meadowcap::SecretKey>>;
+
+    /// Stores sealed secrets and removes their plaintext in the same transaction, and persists it.
+    ///
+    /// Each item is the scope, the public key and the ciphertext of a secret.
+    ///
+    /// Implementations should release the storage which held the plaintext, e.g. by compacting
+    /// the database, but need not guarantee that it is overwritten.
+    fn replace_plaintext_secrets(&self, sealed: Vec<(KeyScope, [u8; 32], Vec<u8>)>) -> Result<()>;
+}
+
+/// A [`SecretStorage`] which encrypts all secret keys at rest.
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug, Clone)]
+pub struct EncryptedSecrets<S> {
+    inner: S,
+    key: Rc<RefCell<Option<SecretsKey>>>,
+}
+
+impl<S: SealedSecretStorage> EncryptedSecrets<S> {
+    /// Wraps a sealed secret store. The store starts out locked.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            key: Default::default(),
+        }
+    }
+
+    fn key(&self) -> Result<SecretsKey, SecretStoreError> {
+        self.key.borrow().clone().ok_or(SecretStoreError::Locked)
+    }
+
+    /// Encrypts the secrets which are stored in plaintext with `key`.
+    fn seal_plaintext_secrets(&self, key: &SecretsKey) -> Result<()> {
+        let sealed = self
+            .inner
+            .plaintext_secrets()?
+            .iter()
+            .map(|secret| seal_secret(key, secret))
+            .collect::<Result<Vec<_>>>()?;
+        if !sealed.is_empty() {
+            self.inner.replace_plaintext_secrets(sealed)?;
+        }
+        Ok(())
+    }
+
+    fn open_secret(
+        &self,
+        scope: KeyScope,
+        id: &[u8; 32],
+    ) -> Result<Option<[u8; 32]>, SecretStoreError> {
+        let key = self.key()?;
+        let Some(sealed) = self.inner.get_sealed(scope, id)? else {
+            return Ok(None);
+        };
+        let bytes = open_bytes(&key, scope, id, &sealed)?;
+        Ok(Some(bytes))
+    }
+
+    fn open_user(&self, id: &UserId) -> Result<Option<UserSecretKey>, SecretStoreError> {
+        let secret = self.open_secret(KeyScope::User, id.as_bytes())?;
+        Ok(secret.map(|bytes| UserSecretKey::from_bytes(&bytes)))
+    }
+
+    fn open_namespace(
+        &self,
+        id: &NamespaceId,
+    ) -> Result<Option<NamespaceSecretKey>, SecretStoreError> {
+        let secret = self.open_secret(KeyScope::Namespace, id.as_bytes())?;
+        Ok(secret.map(|bytes| NamespaceSecretKey::from_bytes(&bytes)))
+    }
+}
+
+impl<S: SealedSecretStorage> SecretStorage for EncryptedSecrets<S> {
+    fn insert(&self, secret: meadowcap::SecretKey) -> Result<(), SecretStoreError> {
+        let key = self.key()?;
+        let (scope, id, sealed) = seal_secret(&key, &secret)?;
+        self.inner.insert_sealed(scope, id, sealed)?;
+        Ok(())
+    }
+
+    fn get_user(&self, id: &UserId) -> Result<Option<UserSecretKey>> {
+        Ok(self.open_user(id)?)
+    }
+
+    fn get_namespace(&self, id: &NamespaceId) -> Result<Option<NamespaceSecretKey>> {
+        Ok(self.open_namespace(id)?)
+    }
+
+    fn list_secrets(&self) -> Result<Vec<meadowcap::SecretKey>> {
+        let key = self.key()?;
+        let mut secrets = Vec::new();
+        for (scope, id, sealed) in self.inner.list_sealed()? {
+            let bytes = open_bytes(&key, scope, &id, &sealed)?;
+            let secret = match scope {
+                KeyScope::User => meadowcap::SecretKey::User(UserSecretKey::from_bytes(&bytes)),
+                KeyScope::Namespace => {
+                    meadowcap::SecretKey::Namespace(NamespaceSecretKey::from_bytes(&bytes))
+                }
+            };
+            secrets.push(secret);
+        }
+        Ok(secrets)
+    }
+
+    fn has_user(&self, id: &UserId) -> Result<bool> {
+        Ok(self
+            .inner
+            .get_sealed(KeyScope::User, id.as_bytes())?
+            .is_some())
+    }
+
+    fn has_namespace(&self, id: &NamespaceId) -> Result<bool> {
+        Ok(self
+            .inner
+            .get_sealed(KeyScope::Namespace, id.as_bytes())?
+            .is_some())
+    }
+
+    fn sign_user(&self, id: &UserId, message: &[u8]) -> Result<UserSignature, SecretStoreError> {
+        Ok(self
+            .open_user(id)?
+            .ok_or(SecretStoreError::MissingKey)?
+            .sign(message))
+    }
+
+    fn sign_namespace(
+        &self,
+        id: &NamespaceId,
+        message: &[u8],
+    ) -> Result<NamespaceSignature, SecretStoreError> {
+        Ok(self
+            .open_namespace(id)?
+            .ok_or(SecretStoreError::MissingKey)?
+            .sign(message))
+    }
+
+    fn is_locked(&self) -> bool {
+        self.key.borrow().is_none()
+    }
+
+    fn lock(&self) {
+        self.key.borrow_mut().take();
+    }
+
+    fn set_passphrase(&self, credential: &SecretsCredential) -> Result<(), SecretStoreError> {
+        if self.inner.encryption_params()?.is_some() {
+            return Err(SecretStoreError::KeyAlreadySet);
+        }
+        let salt: [u8; SALT_LEN] = rand::random();
+        let key = credential.derive_key(&salt)?;
+        let key_check = key.seal(KEY_CHECK_AAD, &[])?;
+        self.inner
+            .set_encryption_params(EncryptionParams { salt, key_check })?;
+        self.seal_plaintext_secrets(&key)?;
+        *self.key.borrow_mut() = Some(key);
+        Ok(())
+    }
+
+    fn unlock(&self, credential: &SecretsCredential) -> Result<(), SecretStoreError> {
+        let params = self
+            .inner
+            .encryption_params()?
+            .ok_or(SecretStoreError::NoKey)?;
+        let key = credential.derive_key(&params.salt)?;
+        key.open(KEY_CHECK_AAD, &params.key_check)?;
+        // Secrets may be left in plaintext if sealing them failed when the key was set.
+        self.seal_plaintext_secrets(&key)?;
+        *self.key.borrow_mut() = Some(key);
+        Ok(())
+    }
+}
+
+/// A [`Storage`] whose secret keys are encrypted at rest with [`EncryptedSecrets`].
+#[derive(Debug, Clone)]
+pub struct EncryptedStorage<S: Storage>
+where
+    S::Secrets: SealedSecretStorage,
+{
+    inner: S,
+    secrets: EncryptedSecrets<S::Secrets>,
+}
+
+impl<S: Storage> EncryptedStorage<S>
+where
+    S::Secrets: SealedSecretStorage,
+{
+    /// Wraps `storage`. The secret store starts out locked.
+    pub fn new(storage: S) -> Self {
+        Self {
+            secrets: EncryptedSecrets::new(storage.secrets().clone()),
+            inner: storage,
+        }
+    }
+
+    /// Wraps `storage` and unlocks the secret store with `credential`.
+    ///
+    /// The key must have been set with [`SecretStorage::set_passphrase`] before.
+    pub fn unlocked(
+        storage: S,
+        credential: impl Into<SecretsCredential>,
+    ) -> Result<Self, SecretStoreError> {
+        let this = Self::new(storage);
+        this.secrets.unlock(&credential.into())?;
+        Ok(this)
+    }
+}
+
+impl<S: Storage> Storage for EncryptedStorage<S>
+where
+    S::Secrets: SealedSecretStorage,
+{
+    type Entries = S::Entries;
+    type Secrets = EncryptedSecrets<S::Secrets>;
+    type Payloads = S::Payloads;
+    type Caps = S::Caps;
+
+    fn entries(&self) -> &Self::Entries {
+        self.inner.entries()
+    }
+
+    fn secrets(&self) -> &Self::Secrets {
+        &self.secrets
+    }
+
+    fn payloads(&self) -> &Self::Payloads {
+        self.inner.payloads()
+    }
+
+    fn caps(&self) -> &Self::Caps {
+        self.inner.caps()
+    }
+
+    fn quotas(&self) -> super::traits::Quotas {
+        self.inner.quotas()
+    }
+}
+
+/// Encrypts a secret, and returns it with its scope and public key.
+fn seal_secret(
+    key: &SecretsKey,
+    secret: &meadowcap::SecretKey,
+) -> Result<(KeyScope, [u8; 32], Vec<u8>)> {
+    let (scope, id, bytes) = match secret {
+        meadowcap::SecretKey::User(secret) => {
+            (KeyScope::User, *secret.id().as_bytes(), secret.to_bytes())
+        }
+        meadowcap::SecretKey::Namespace(secret) => (
+            KeyScope::Namespace,
+            *secret.id().as_bytes(),
+            secret.to_bytes(),
+        ),
+    };
+    let sealed = key.seal(&aad(scope, &id), &bytes)?;
+    Ok((scope, id, sealed))
+}
+
+/// Binds a ciphertext to the scope and public key of its secret.
+fn aad(scope: KeyScope, id: &[u8; 32]) -> Vec<u8> {
+    let scope = match scope {
+        KeyScope::Namespace => 0u8,
+        KeyScope::User => 1u8,
+    };
+    let mut aad = vec![scope];
+    aad.extend_from_slice(id);
+    aad
+}
+
+fn open_bytes(
+    key: &SecretsKey,
+    scope: KeyScope,
+    id: &[u8; 32],
+    sealed: &[u8],
+) -> Result<[u8; 32], SecretStoreError> {
+    let bytes = key.open(&aad(scope, id), sealed)?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| anyhow!("invalid length of secret key"))?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::persistent;
+
+    type TestStorage = persistent::Store<iroh_blobs::store::mem::Store>;
+
+    #[test]
+    fn lock_and_unlock() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let storage = TestStorage::new_memory(Default::default())?;
+        let alfie = storage
+            .secrets()
+            .insert_user(UserSecretKey::generate(&mut rng))?;
+
+        let storage = EncryptedStorage::new(storage);
+        let secrets = storage.secrets();
+        assert!(secrets.is_locked());
+        assert!(matches!(
+            secrets.insert_user(UserSecretKey::generate(&mut rng)),
+            Err(SecretStoreError::Locked)
+        ));
+
+        // The store cannot be unlocked before a key is set.
+        let passphrase = SecretsCredential::Passphrase("correct horse battery staple".into());
+        assert!(matches!(
+            secrets.unlock(&passphrase),
+            Err(SecretStoreError::NoKey)
+        ));
+
+        // Setting the key encrypts the plaintext secrets.
+        secrets.set_passphrase(&passphrase)?;
+        assert!(!secrets.is_locked());
+        assert!(matches!(
+            secrets.set_passphrase(&passphrase),
+            Err(SecretStoreError::KeyAlreadySet)
+        ));
+        assert!(storage.inner.secrets().list_secrets()?.is_empty());
+        assert!(secrets.has_user(&alfie)?);
+        let betty = secrets.insert_user(UserSecretKey::generate(&mut rng))?;
+        let sealed = storage
+            .inner
+            .secrets()
+            .get_sealed(KeyScope::User, betty.as_bytes())?
+            .expect("sealed secret exists");
+        let secret = secrets.get_user(&betty)?.expect("secret exists");
+        assert!(!sealed.windows(32).any(|window| window == secret.to_bytes()));
+        assert!(secrets.sign_user(&alfie, b"hello").is_ok());
+
+        secrets.lock();
+        assert!(matches!(
+            secrets.sign_user(&alfie, b"hello"),
+            Err(SecretStoreError::Locked)
+        ));
+        assert!(secrets.list_secrets().is_err());
+
+        let wrong = SecretsCredential::Passphrase("wrong".into());
+        assert!(matches!(
+            secrets.unlock(&wrong),
+            Err(SecretStoreError::InvalidKey)
+        ));
+        assert!(secrets.is_locked());
+
+        secrets.unlock(&passphrase)?;
+        assert_eq!(secrets.list_secrets()?.len(), 2);
+        Ok(())
+    }
+}
@@ -6,16 +6,18 @@
 //! hopefully easily kept correct.
 
 use std::{
-    cell::RefCell,
-    collections::{HashMap, VecDeque},
+    cell::{Cell, RefCell},
+    collections::{hash_map, HashMap, VecDeque},
     pin::Pin,
     rc::{Rc, Weak},
     task::{ready, Context, Poll, Waker},
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
 use futures_util::Stream;
 use tracing::debug;
+use willow_store::BlobStoreRead;
 
 use super::{
     traits::{StoreEvent, SubscribeParams},
@@ -24,12 +26,18 @@ use super::{
 use crate::{
     interest::{CapSelector, CapabilityPack},
     proto::{
-        data_model::{AuthorisedEntry, Path, PathExt, SubspaceId, WriteCapability},
+        data_model::{
+            AuthorisedEntry, Entry, Path, PathExt, PayloadDigest, SubspaceId, WriteCapability,
+        },
         grouping::{Area, Range3d},
         keys::{NamespaceId, NamespaceSecretKey, UserId, UserSecretKey},
         meadowcap::{self, is_wider_than, ReadAuthorisation},
+        wgps::Fingerprint,
+    },
+    store::{
+        traits,
+        willow_store_glue::{to_query, to_range3d, IrohWillowParams, StoredAuthorisedEntry},
     },
-    store::traits,
 };
 
 #[derive(Debug, Clone, Default)]
@@ -38,6 +46,7 @@ pub struct Store<PS> {
     entries: Rc<RefCell<EntryStore>>,
     payloads: PS,
     caps: Rc<RefCell<CapsStore>>,
+    quotas: traits::Quotas,
 }
 
 impl<PS: iroh_blobs::store::Store> Store<PS> {
@@ -47,8 +56,25 @@ impl<PS: iroh_blobs::store::Store> Store<PS> {
             secrets: Default::default(),
             entries: Default::default(),
             caps: Default::default(),
+            quotas: Default::default(),
         }
     }
+
+    /// Sets the storage quotas.
+    ///
+    /// See [`traits::Quotas`] for details.
+    pub fn with_quotas(mut self, quotas: traits::Quotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// Sets the limits for the events kept in memory for each namespace.
+    ///
+    /// See [`EventQueueLimits`] for details.
+    pub fn with_event_queue_limits(self, limits: EventQueueLimits) -> Self {
+        self.entries.borrow_mut().event_limits = limits;
+        self
+    }
 }
 
 impl<PS: iroh_blobs::store::Store> traits::Storage for Store<PS> {
@@ -72,6 +98,10 @@ impl<PS: iroh_blobs::store::Store> traits::Storage for Store<PS> {
     fn caps(&self) -> &Self::Caps {
         &self.caps
     }
+
+    fn quotas(&self) -> traits::Quotas {
+        self.quotas
+    }
 }
 
 #[derive(Debug, Default)]
@@ -101,17 +131,131 @@ impl traits::SecretStorage for Rc<RefCell<SecretStore>> {
     fn get_namespace(&self, id: &NamespaceId) -> Result<Option<NamespaceSecretKey>> {
         Ok(self.borrow().namespace.get(id).cloned())
     }
+
+    fn list_secrets(&self) -> Result<Vec<meadowcap::SecretKey>> {
+        let slf = self.borrow();
+        let users = slf.user.values().cloned().map(meadowcap::SecretKey::User);
+        let namespaces = slf
+            .namespace
+            .values()
+            .cloned()
+            .map(meadowcap::SecretKey::Namespace);
+        Ok(users.chain(namespaces).collect())
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct EntryStore {
     stores: HashMap<NamespaceId, NamespaceStore>,
+    event_limits: EventQueueLimits,
+    /// Number of stored entries referencing each payload.
+    payload_refcount: HashMap<PayloadDigest, u64>,
 }
 
 #[derive(Debug, Default)]
 pub struct NamespaceStore {
-    entries: Vec<AuthorisedEntry>,
+    /// Shared with snapshots and copied on the first write after a snapshot was taken.
+    entries: Rc<Vec<AuthorisedEntry>>,
+    /// Index over `entries` to count, fingerprint and split ranges without iterating them.
+    index: EntryIndex,
     events: EventQueue<StoreEvent>,
+    /// Entries whose payloads are marked as partially stored.
+    partial_payloads: Vec<Entry>,
+    /// Quota usage of `entries`, counted on the first quota check.
+    usage: Option<traits::UsageCounters>,
+}
+
+/// A [`willow_store`] tree over the entries of a namespace, kept in memory.
+///
+/// The tree aggregates the counts and fingerprints of its entries, which lets us split ranges
+/// by rank and compute their fingerprints in logarithmic time, just like the
+/// [`persistent`](super::persistent) store.
+///
+/// Cloning the index is cheap: the nodes are shared between the clones, and are only copied
+/// when a clone is modified while the nodes are still shared.
+#[derive(Debug, Clone)]
+struct EntryIndex {
+    root: willow_store::NodeId,
+    nodes: MemNodes,
+}
+
+#[derive(derive_more::Debug, Clone, Default)]
+struct MemNodes(#[debug(skip)] Rc<willow_store::MemStore>);
+
+impl BlobStoreRead for MemNodes {
+    fn peek<T>(&self, id: willow_store::NodeId, f: impl Fn(&[u8]) -> T) -> Result<T> {
+        self.0.peek(id, f)
+    }
+}
+
+impl Default for EntryIndex {
+    fn default() -> Self {
+        Self {
+            root: willow_store::NodeId::EMPTY,
+            nodes: Default::default(),
+        }
+    }
+}
+
+impl EntryIndex {
+    fn root(&self) -> willow_store::Node<IrohWillowParams> {
+        willow_store::Node::from(self.root)
+    }
+
+    fn insert(&mut self, entry: &AuthorisedEntry) -> Result<()> {
+        let (point, stored) = StoredAuthorisedEntry::from_authorised_entry(entry);
+        let mut root = self.root();
+        root.insert(&point, &stored, Rc::make_mut(&mut self.nodes.0))?;
+        self.root = root.id();
+        Ok(())
+    }
+
+    fn remove(&mut self, entry: &AuthorisedEntry) -> Result<()> {
+        let (point, _) = StoredAuthorisedEntry::from_authorised_entry(entry);
+        let mut root = self.root();
+        root.delete(&point, Rc::make_mut(&mut self.nodes.0))?;
+        self.root = root.id();
+        Ok(())
+    }
+
+    fn count(&self, range: &Range3d) -> Result<u64> {
+        self.root().range_count(&to_query(range), &self.nodes)
+    }
+
+    fn fingerprint(&self, range: &Range3d) -> Result<Fingerprint> {
+        self.root().range_summary(&to_query(range), &self.nodes)
+    }
+
+    fn split_range(
+        &self,
+        range: &Range3d,
+        config: &traits::SplitOpts,
+    ) -> Result<Vec<traits::RangeSplit>> {
+        let max_set_size = config.max_set_size as u64;
+        let count = self.count(range)?;
+        if count <= max_set_size {
+            return Ok(vec![(
+                range.clone(),
+                traits::SplitAction::SendEntries(count),
+            )]);
+        }
+        let root = self.root();
+        root.split_range_owned(
+            to_query(range),
+            config.split_factor as u64,
+            self.nodes.clone(),
+        )
+        .map(|result| {
+            let (range, count) = result?;
+            let action = if count <= max_set_size {
+                traits::SplitAction::SendEntries(count)
+            } else {
+                traits::SplitAction::SendFingerprint(root.range_summary(&range, &self.nodes)?)
+            };
+            Ok((to_range3d(range)?, action))
+        })
+        .collect()
+    }
 }
 
 // impl<T: std::ops::Deref<Target = MemoryEntryStore> + 'static> ReadonlyStore for T {
@@ -125,7 +269,7 @@ impl traits::EntryReader for Rc<RefCell<EntryStore>> {
         Ok(slf
             .stores
             .get(&namespace)
-            .map(|s| &s.entries)
+            .map(|s| s.entries.iter())
             .into_iter()
             .flatten()
             .filter(|entry| range.includes_entry(entry.entry()))
@@ -134,6 +278,43 @@ impl traits::EntryReader for Rc<RefCell<EntryStore>> {
             .into_iter())
     }
 
+    fn fingerprint(&self, namespace: NamespaceId, range: &Range3d) -> Result<Fingerprint> {
+        match self.borrow().stores.get(&namespace) {
+            Some(store) => store.index.fingerprint(range),
+            None => Ok(Fingerprint::default()),
+        }
+    }
+
+    fn count(&self, namespace: NamespaceId, range: &Range3d) -> Result<u64> {
+        match self.borrow().stores.get(&namespace) {
+            Some(store) => store.index.count(range),
+            None => Ok(0),
+        }
+    }
+
+    fn split_range(
+        &self,
+        namespace: NamespaceId,
+        range: &Range3d,
+        config: &traits::SplitOpts,
+    ) -> Result<impl Iterator<Item = Result<traits::RangeSplit>>> {
+        let splits = match self.borrow().stores.get(&namespace) {
+            Some(store) => store.index.split_range(range, config)?,
+            None => vec![(range.clone(), traits::SplitAction::SendEntries(0))],
+        };
+        Ok(splits.into_iter().map(Ok))
+    }
+
+    fn namespaces(&self) -> Result<Vec<NamespaceId>> {
+        Ok(self
+            .borrow()
+            .stores
+            .iter()
+            .filter(|(_, store)| !store.entries.is_empty())
+            .map(|(namespace, _)| *namespace)
+            .collect())
+    }
+
     fn get_entry(
         &self,
         namespace: NamespaceId,
@@ -156,12 +337,35 @@ impl traits::EntryReader for Rc<RefCell<EntryStore>> {
 }
 
 impl EntryStore {
+    fn namespace_mut(&mut self, namespace: NamespaceId) -> &mut NamespaceStore {
+        let limits = self.event_limits;
+        self.stores
+            .entry(namespace)
+            .or_insert_with(|| NamespaceStore {
+                entries: Default::default(),
+                index: Default::default(),
+                events: EventQueue::new(0, limits),
+                partial_payloads: Default::default(),
+                usage: None,
+            })
+    }
+
+    fn add_payload_ref(&mut self, digest: PayloadDigest) {
+        *self.payload_refcount.entry(digest).or_default() += 1;
+    }
+
+    fn remove_payload_ref(&mut self, digest: PayloadDigest) {
+        if let hash_map::Entry::Occupied(mut refcount) = self.payload_refcount.entry(digest) {
+            *refcount.get_mut() -= 1;
+            if *refcount.get() == 0 {
+                refcount.remove();
+            }
+        }
+    }
+
     fn ingest_entry(&mut self, entry: &AuthorisedEntry, origin: EntryOrigin) -> Result<bool> {
-        let store = self
-            .stores
-            .entry(*entry.entry().namespace_id())
-            .or_default();
-        let entries = &mut store.entries;
+        let store = self.namespace_mut(*entry.entry().namespace_id());
+        let entries = Rc::make_mut(&mut store.entries);
         let new = entry.entry();
         let mut to_prune = vec![];
         for (i, existing) in entries.iter().enumerate() {
@@ -185,8 +389,17 @@ impl EntryStore {
             }
         }
         let pruned_count = to_prune.len();
-        for i in to_prune {
+        let mut pruned_digests = Vec::with_capacity(pruned_count);
+        for i in to_prune.into_iter().rev() {
             let pruned = entries.remove(i);
+            store.index.remove(&pruned)?;
+            if let Some(usage) = &mut store.usage {
+                usage.remove(
+                    *pruned.entry().subspace_id(),
+                    pruned.entry().payload_length(),
+                );
+            }
+            pruned_digests.push(*pruned.entry().payload_digest());
             store.events.insert(move |id| {
                 StoreEvent::Pruned(
                     id,
@@ -198,10 +411,18 @@ impl EntryStore {
             });
         }
         entries.push(entry.clone());
+        store.index.insert(entry)?;
+        if let Some(usage) = &mut store.usage {
+            usage.add(*new.subspace_id(), new.payload_length());
+        }
         debug!(subspace=%entry.entry().subspace_id().fmt_short(), path=%entry.entry().path().fmt_utf8(), pruned=pruned_count, total=entries.len(), "ingest entry");
         store
             .events
             .insert(|id| StoreEvent::Ingested(id, entry.clone(), origin));
+        self.add_payload_ref(*new.payload_digest());
+        for digest in pruned_digests {
+            self.remove_payload_ref(digest);
+        }
         Ok(true)
     }
 }
@@ -215,8 +436,7 @@ impl traits::EntryStorage for Rc<RefCell<EntryStore>> {
     }
 
     fn snapshot(&self) -> Result<Self::Snapshot> {
-        // This is quite ugly. But this is a quick memory impl only.
-        // But we should really maybe strive to not expose snapshots.
+        // The entries and the index are shared with the snapshot until either side is modified.
         let stores = self
             .borrow()
             .stores
@@ -226,12 +446,19 @@ impl traits::EntryStorage for Rc<RefCell<EntryStore>> {
                     *key,
                     NamespaceStore {
                         entries: value.entries.clone(),
+                        index: value.index.clone(),
                         events: Default::default(),
+                        partial_payloads: value.partial_payloads.clone(),
+                        usage: value.usage.clone(),
                     },
                 )
             })
             .collect();
-        Ok(Rc::new(RefCell::new(EntryStore { stores })))
+        Ok(Rc::new(RefCell::new(EntryStore {
+            stores,
+            event_limits: self.borrow().event_limits,
+            payload_refcount: self.borrow().payload_refcount.clone(),
+        })))
     }
 
     fn ingest_entry(&self, entry: &AuthorisedEntry, origin: EntryOrigin) -> Result<bool> {
@@ -239,26 +466,159 @@ impl traits::EntryStorage for Rc<RefCell<EntryStore>> {
         slf.ingest_entry(entry, origin)
     }
 
+    fn forget_entry(
+        &self,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: &Path,
+    ) -> Result<Option<AuthorisedEntry>> {
+        let mut slf = self.borrow_mut();
+        let store = slf.namespace_mut(namespace);
+        let Some(i) = store
+            .entries
+            .iter()
+            .position(|e| *e.entry().subspace_id() == subspace && e.entry().path() == path)
+        else {
+            return Ok(None);
+        };
+        let entry = Rc::make_mut(&mut store.entries).remove(i);
+        store.index.remove(&entry)?;
+        if let Some(usage) = &mut store.usage {
+            usage.remove(*entry.entry().subspace_id(), entry.entry().payload_length());
+        }
+        store
+            .events
+            .insert(|id| StoreEvent::EntryForgotten(id, entry.clone()));
+        slf.remove_payload_ref(*entry.entry().payload_digest());
+        Ok(Some(entry))
+    }
+
+    fn forget_area(&self, namespace: NamespaceId, area: &Area) -> Result<u64> {
+        let mut slf = self.borrow_mut();
+        let store = slf.namespace_mut(namespace);
+        let mut forgotten = vec![];
+        Rc::make_mut(&mut store.entries).retain(|e| {
+            let included = area.includes_entry(e.entry());
+            if included {
+                forgotten.push(e.clone());
+            }
+            !included
+        });
+        for entry in &forgotten {
+            store.index.remove(entry)?;
+            if let Some(usage) = &mut store.usage {
+                usage.remove(*entry.entry().subspace_id(), entry.entry().payload_length());
+            }
+        }
+        let count = forgotten.len() as u64;
+        if count > 0 {
+            store
+                .events
+                .insert(|id| StoreEvent::AreaForgotten(id, namespace, area.clone()));
+        }
+        for entry in forgotten {
+            slf.remove_payload_ref(*entry.entry().payload_digest());
+        }
+        Ok(count)
+    }
+
+    fn forget_payload(
+        &self,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: &Path,
+    ) -> Result<Option<AuthorisedEntry>> {
+        let Some(entry) = traits::EntryReader::get_entry(self, namespace, subspace, path)? else {
+            return Ok(None);
+        };
+        self.borrow_mut()
+            .namespace_mut(namespace)
+            .events
+            .insert(|id| StoreEvent::PayloadForgotten(id, entry.clone()));
+        Ok(Some(entry))
+    }
+
+    fn quota_usage(
+        &self,
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+        quotas: &traits::Quotas,
+    ) -> Result<traits::Usage> {
+        let mut slf = self.borrow_mut();
+        let store = slf.namespace_mut(namespace);
+        if let Some(usage) = store
+            .usage
+            .as_ref()
+            .filter(|usage| usage.quotas() == quotas)
+        {
+            return Ok(usage.get(subspace));
+        }
+        let mut usage = traits::UsageCounters::new(*quotas);
+        for entry in store.entries.iter() {
+            usage.add(*entry.entry().subspace_id(), entry.entry().payload_length());
+        }
+        let out = usage.get(subspace);
+        store.usage = Some(usage);
+        Ok(out)
+    }
+
+    fn payload_references(&self, digest: &PayloadDigest) -> Result<u64> {
+        Ok(self
+            .borrow()
+            .payload_refcount
+            .get(digest)
+            .copied()
+            .unwrap_or_default())
+    }
+
+    fn referenced_payloads(&self) -> Result<Vec<PayloadDigest>> {
+        Ok(self.borrow().payload_refcount.keys().copied().collect())
+    }
+
+    fn set_partial_payload(&self, entry: &Entry, partial: bool) -> Result<()> {
+        let mut slf = self.borrow_mut();
+        let partial_payloads = &mut slf.namespace_mut(*entry.namespace_id()).partial_payloads;
+        partial_payloads.retain(|e| e != entry);
+        if partial {
+            partial_payloads.push(entry.clone());
+        }
+        Ok(())
+    }
+
+    fn partial_payloads(
+        &self,
+        namespace: NamespaceId,
+        area: &Area,
+    ) -> Result<Vec<AuthorisedEntry>> {
+        let slf = self.borrow();
+        let Some(store) = slf.stores.get(&namespace) else {
+            return Ok(vec![]);
+        };
+        Ok(store
+            .entries
+            .iter()
+            .filter(|e| {
+                area.includes_entry(e.entry()) && store.partial_payloads.contains(e.entry())
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn next_progress_id(&self, namespace: NamespaceId) -> u64 {
+        self.borrow_mut()
+            .namespace_mut(namespace)
+            .events
+            .next_progress_id()
+    }
+
     fn subscribe_area(
         &self,
         namespace: NamespaceId,
         area: Area,
         params: SubscribeParams,
     ) -> impl Stream<Item = traits::StoreEvent> + Unpin + 'static {
-        let progress_id = self
-            .borrow_mut()
-            .stores
-            .entry(namespace)
-            .or_default()
-            .events
-            .next_progress_id();
-        EventStream {
-            area,
-            params,
-            namespace,
-            progress_id,
-            store: Rc::downgrade(self),
-        }
+        let progress_id = traits::EntryStorage::next_progress_id(self, namespace);
+        EventStream::new(self, progress_id, namespace, area, params)
     }
 
     fn resume_subscription(
@@ -268,13 +628,7 @@ impl traits::EntryStorage for Rc<RefCell<EntryStore>> {
         area: Area,
         params: SubscribeParams,
     ) -> impl Stream<Item = traits::StoreEvent> + Unpin + 'static {
-        EventStream {
-            area,
-            params,
-            progress_id,
-            namespace,
-            store: Rc::downgrade(self),
-        }
+        EventStream::new(self, progress_id, namespace, area, params)
     }
 }
 
@@ -283,81 +637,200 @@ impl traits::EntryStorage for Rc<RefCell<EntryStore>> {
 /// We have weak pointer to the entry store and thus the EventQueue.
 /// Once the store is dropped, the EventQueue wakes all streams a last time in its drop impl,
 /// which then makes the stream return none because Weak::upgrade returns None.
-#[derive(Debug)]
+#[derive(derive_more::Debug)]
 struct EventStream {
-    progress_id: u64,
+    progress_id: Rc<Cell<u64>>,
     store: Weak<RefCell<EntryStore>>,
     namespace: NamespaceId,
-    area: Area,
-    params: SubscribeParams,
+    #[debug("EventFilter")]
+    filter: EventFilter<StoreEvent>,
+}
+
+impl EventStream {
+    fn new(
+        store: &Rc<RefCell<EntryStore>>,
+        progress_id: u64,
+        namespace: NamespaceId,
+        area: Area,
+        params: SubscribeParams,
+    ) -> Self {
+        Self {
+            progress_id: Rc::new(Cell::new(progress_id)),
+            store: Rc::downgrade(store),
+            namespace,
+            filter: Rc::new(move |e: &StoreEvent| e.matches(namespace, &area, &params)),
+        }
+    }
 }
 
 impl Stream for EventStream {
     type Item = StoreEvent;
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let Some(inner) = self.store.upgrade() else {
             return Poll::Ready(None);
         };
         let mut inner_mut = inner.borrow_mut();
-        let store = inner_mut.stores.entry(self.namespace).or_default();
-        let res = ready!(store.events.poll_next(
-            self.progress_id,
-            |e| e.matches(self.namespace, &self.area, &self.params),
-            cx,
-        ));
-        drop(inner_mut);
-        drop(inner);
-        Poll::Ready(match res {
-            None => None,
-            Some((next_id, event)) => {
-                self.progress_id = next_id;
-                Some(event)
-            }
-        })
+        let store = inner_mut.namespace_mut(self.namespace);
+        let res = ready!(store.events.poll_next(&self.progress_id, &self.filter, cx));
+        Poll::Ready(Some(match res {
+            Ok(event) => event,
+            Err(Lagged) => StoreEvent::Lagged(self.progress_id.get()),
+        }))
+    }
+}
+
+/// Limits for the events kept in memory for each namespace.
+///
+/// Once one of the limits is exceeded, the oldest events are dropped. Subscriptions which did not
+/// receive the dropped events yet get a [`StoreEvent::Lagged`] event.
+///
+/// By default, the latest [`DEFAULT_EVENT_QUEUE_LEN`] events are kept, regardless of their age.
+/// Both the memory and the [`persistent`](super::persistent) store use these limits.
+#[derive(Debug, Clone, Copy)]
+pub struct EventQueueLimits {
+    /// The maximum number of events to keep.
+    pub max_len: Option<usize>,
+    /// The maximum age of events to keep.
+    pub max_age: Option<Duration>,
+}
+
+/// Default for [`EventQueueLimits::max_len`].
+pub const DEFAULT_EVENT_QUEUE_LEN: usize = 1024;
+
+impl Default for EventQueueLimits {
+    fn default() -> Self {
+        Self {
+            max_len: Some(DEFAULT_EVENT_QUEUE_LEN),
+            max_age: None,
+        }
     }
 }
 
+/// Filter for the events a subscriber of an [`EventQueue`] is interested in.
+pub(crate) type EventFilter<T> = Rc<dyn Fn(&T) -> bool>;
+
+/// Returned from [`EventQueue::poll_next`] if events were dropped before the subscriber received
+/// them.
+#[derive(Debug)]
+pub(crate) struct Lagged;
+
+/// A subscriber waiting for new events in an [`EventQueue`].
+#[derive(derive_more::Debug)]
+struct Waiter<T> {
+    waker: Waker,
+    #[debug("EventFilter")]
+    filter: EventFilter<T>,
+    progress_id: Rc<Cell<u64>>,
+}
+
 /// A simple in-memory event queue.
 ///
 /// Events can be pushed, and get a unique monotonically-increasing *progress id*.
-/// Events can be polled, with a progress id to start at, and an optional filter function.
+/// Events can be polled, with a progress id to start at, and a filter function.
 ///
-/// Current in-memory impl keeps all events, forever.
-// TODO: Add max_len constructor, add a way to truncate old entries.
-// TODO: This would be quite a bit more efficient if we filtered the waker with a closure
-// that is set from the last poll, to not wake everyone for each new event.
+/// Old events are dropped according to the [`EventQueueLimits`]. Waiting subscribers are only
+/// woken for new events which pass their filter.
 #[derive(Debug)]
 pub(crate) struct EventQueue<T> {
-    events: VecDeque<T>,
+    events: VecDeque<(Instant, T)>,
     offset: u64,
-    wakers: VecDeque<Waker>,
+    limits: EventQueueLimits,
+    waiters: Vec<Waiter<T>>,
 }
 
 impl<T> Drop for EventQueue<T> {
     fn drop(&mut self) {
-        for waker in self.wakers.drain(..) {
-            waker.wake()
+        for waiter in self.waiters.drain(..) {
+            waiter.waker.wake()
         }
     }
 }
 
 impl<T> Default for EventQueue<T> {
     fn default() -> Self {
+        Self::new(0, Default::default())
+    }
+}
+
+impl<T> EventQueue<T> {
+    /// Creates an empty queue whose first event gets the progress id `offset`.
+    pub(crate) fn new(offset: u64, limits: EventQueueLimits) -> Self {
         Self {
             events: Default::default(),
-            offset: 0,
-            wakers: Default::default(),
+            offset,
+            limits,
+            waiters: Default::default(),
         }
     }
 }
 
 impl<T: Clone> EventQueue<T> {
+    /// Replaces the limits of the queue, and drops the events beyond them.
+    pub(crate) fn set_limits(&mut self, limits: EventQueueLimits) {
+        self.limits = limits;
+        self.truncate();
+    }
+
+    /// Returns the progress id of the oldest event in the queue.
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
     pub(crate) fn insert(&mut self, f: impl FnOnce(u64) -> T) {
         let progress_id = self.next_progress_id();
         let event = f(progress_id);
-        self.events.push_back(event);
-        for waker in self.wakers.drain(..) {
-            waker.wake()
+        self.waiters.retain(|waiter| {
+            // The subscription was dropped.
+            if Rc::strong_count(&waiter.progress_id) == 1 {
+                return false;
+            }
+            if (waiter.filter)(&event) {
+                waiter.waker.wake_by_ref();
+                false
+            } else {
+                // The subscriber is not interested in the event, so it may skip it. This way it
+                // does not lag behind if the event is dropped before it is polled again.
+                if waiter.progress_id.get() == progress_id {
+                    waiter.progress_id.set(progress_id + 1);
+                }
+                true
+            }
+        });
+        self.events.push_back((Instant::now(), event));
+        self.truncate();
+    }
+
+    /// Wakes the subscribers waiting for an event which is not inserted yet.
+    ///
+    /// This is used by stores which insert events only after they are committed.
+    pub(crate) fn wake_waiting_for(&mut self, event: &T) {
+        self.waiters.retain(|waiter| {
+            if (waiter.filter)(event) {
+                waiter.waker.wake_by_ref();
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Drops the oldest events until the queue is within its limits.
+    fn truncate(&mut self) {
+        let now = Instant::now();
+        while let Some((inserted_at, _)) = self.events.front() {
+            let too_long = self
+                .limits
+                .max_len
+                .is_some_and(|max_len| self.events.len() > max_len);
+            let too_old = self
+                .limits
+                .max_age
+                .is_some_and(|max_age| now.duration_since(*inserted_at) > max_age);
+            if !too_long && !too_old {
+                break;
+            }
+            self.events.pop_front();
+            self.offset += 1;
         }
     }
 
@@ -367,27 +840,43 @@ impl<T: Clone> EventQueue<T> {
 
     pub(crate) fn get(&self, progress_id: u64) -> Option<&T> {
         let index = progress_id.checked_sub(self.offset)?;
-        self.events.get(index as usize)
+        self.events.get(index as usize).map(|(_, event)| event)
     }
 
+    /// Polls the next event which matches `filter`, starting at `progress_id`.
+    ///
+    /// `progress_id` is advanced past the returned event. If events after `progress_id` were
+    /// dropped already, returns [`Lagged`] and sets `progress_id` to the oldest event in the
+    /// queue.
     pub(crate) fn poll_next(
         &mut self,
-        progress_id: u64,
-        filter: impl Fn(&T) -> bool,
+        progress_id: &Rc<Cell<u64>>,
+        filter: &EventFilter<T>,
         cx: &mut Context<'_>,
-    ) -> Poll<Option<(u64, T)>> {
-        if progress_id < self.offset {
-            return Poll::Ready(None);
+    ) -> Poll<Result<T, Lagged>> {
+        self.truncate();
+        if progress_id.get() < self.offset {
+            progress_id.set(self.offset);
+            return Poll::Ready(Err(Lagged));
         }
-        let mut i = progress_id;
+        let mut i = progress_id.get();
         loop {
             if let Some(event) = self.get(i) {
                 i += 1;
                 if filter(event) {
-                    break Poll::Ready(Some((i, event.clone())));
+                    progress_id.set(i);
+                    break Poll::Ready(Ok(event.clone()));
                 }
             } else {
-                self.wakers.push_back(cx.waker().clone());
+                progress_id.set(i);
+                // Replace the registration of this subscriber, if any.
+                self.waiters
+                    .retain(|waiter| !Rc::ptr_eq(&waiter.progress_id, progress_id));
+                self.waiters.push(Waiter {
+                    waker: cx.waker().clone(),
+                    filter: filter.clone(),
+                    progress_id: progress_id.clone(),
+                });
                 break Poll::Pending;
             }
         }
@@ -513,3 +1002,112 @@ impl traits::CapsStorage for Rc<RefCell<CapsStore>> {
         self.borrow().get_read_cap(selector)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        rc::Rc,
+        task::{Context, Poll},
+    };
+
+    use anyhow::Result;
+    use futures_util::task::noop_waker_ref;
+    use rand_core::SeedableRng;
+
+    use super::{EventFilter, EventQueue, EventQueueLimits, Lagged};
+    use crate::{
+        form::{AuthForm, EntryForm},
+        proto::{
+            data_model::{Path, PathExt},
+            grouping::Range3d,
+            keys::{NamespaceKind, UserSecretKey},
+            wgps::Fingerprint,
+        },
+        store::traits::{EntryReader, EntryStorage, SecretStorage, SplitAction, SplitOpts},
+    };
+
+    #[tokio::test]
+    async fn split_range_by_index() -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let store =
+            crate::store::Store::new(super::Store::new(iroh_blobs::store::mem::Store::default()));
+        let user = store
+            .secrets()
+            .insert_user(UserSecretKey::generate(&mut rng))?;
+        let namespace = store.create_namespace(&mut rng, NamespaceKind::Owned, user)?;
+        for i in 0..20 {
+            let path = Path::from_bytes(&[format!("{i:02}").as_bytes()])?;
+            let entry = EntryForm::new_bytes(namespace, path, "payload");
+            store
+                .insert_entry(entry.into(), AuthForm::Any(user))
+                .await?;
+        }
+        // Forgotten entries are removed from the index too.
+        store
+            .entries()
+            .forget_entry(namespace, user, &Path::from_bytes(&[b"00".as_slice()])?)?;
+
+        let range = Range3d::new_full();
+        let opts = SplitOpts {
+            max_set_size: 4,
+            split_factor: 2,
+        };
+        let reader = store.entries().reader();
+        assert_eq!(reader.count(namespace, &range)?, 19);
+        let mut total = 0;
+        for split in reader.split_range(namespace, &range, &opts)? {
+            let (range, action) = split?;
+            // Compare against the entries in the subrange.
+            let mut fingerprint = Fingerprint::default();
+            let mut count = 0;
+            for entry in reader.get_entries(namespace, &range)? {
+                fingerprint.add_entry(&entry?);
+                count += 1;
+            }
+            match action {
+                SplitAction::SendEntries(n) => assert_eq!(n, count),
+                SplitAction::SendFingerprint(f) => assert_eq!(f, fingerprint),
+            }
+            total += count;
+        }
+        assert_eq!(total, 19);
+        Ok(())
+    }
+
+    #[test]
+    fn event_queue_lagged() {
+        let limits = EventQueueLimits {
+            max_len: Some(2),
+            max_age: None,
+        };
+        let mut queue = EventQueue::<u64>::new(0, limits);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let all: EventFilter<u64> = Rc::new(|_| true);
+        let even: EventFilter<u64> = Rc::new(|e| e % 2 == 0);
+
+        let slow = Rc::new(Cell::new(0));
+        let waiting = Rc::new(Cell::new(0));
+        assert!(queue.poll_next(&waiting, &even, &mut cx).is_pending());
+
+        for i in 0..5 {
+            queue.insert(|_| i * 2 + 1);
+        }
+        assert_eq!(queue.offset(), 3);
+
+        // The slow subscriber missed the dropped events.
+        assert!(matches!(
+            queue.poll_next(&slow, &all, &mut cx),
+            Poll::Ready(Err(Lagged))
+        ));
+        assert_eq!(slow.get(), 3);
+        assert!(matches!(
+            queue.poll_next(&slow, &all, &mut cx),
+            Poll::Ready(Ok(7))
+        ));
+
+        // The waiting subscriber was not interested in the dropped events, so it did not lag.
+        assert!(queue.poll_next(&waiting, &even, &mut cx).is_pending());
+        assert_eq!(waiting.get(), 5);
+    }
+}
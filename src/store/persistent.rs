@@ -1,6 +1,6 @@
 use std::{
-    cell::{Ref, RefCell, RefMut},
-    collections::HashMap,
+    cell::{Cell, Ref, RefCell, RefMut},
+    collections::{HashMap, HashSet},
     ops::DerefMut,
     path::PathBuf,
     pin::Pin,
@@ -17,7 +17,7 @@ use willow_data_model::SubspaceId as _;
 use willow_store::{QueryRange, QueryRange3d};
 
 use super::{
-    memory,
+    encrypted, memory,
     traits::{self, SplitAction, StoreEvent, SubscribeParams},
     willow_store_glue::{to_query, IrohWillowParams},
 };
@@ -25,10 +25,10 @@ use crate::{
     interest::{CapSelector, CapabilityPack},
     proto::{
         data_model::{
-            AuthorisationToken, AuthorisedEntry, NamespaceId, Path, PathExt as _, SubspaceId,
-            WriteCapability,
+            serde_encoding::SerdeEntry, AuthorisationToken, AuthorisedEntry, Entry, NamespaceId,
+            Path, PathExt as _, PayloadDigest, SubspaceId, WriteCapability,
         },
-        grouping::{Area, Range3d},
+        grouping::{Area, AreaExt, Range3d},
         keys::{NamespaceSecretKey, UserId, UserSecretKey, UserSignature},
         meadowcap,
         wgps::Fingerprint,
@@ -38,14 +38,22 @@ use crate::{
     },
 };
 
+mod migrations;
 mod tables;
 
 const MAX_COMMIT_DELAY: Duration = Duration::from_millis(500);
 
+/// Default number of events kept in the event log of each namespace.
+///
+/// Older events are compacted. Subscriptions which resume from a compacted progress id get a
+/// [`StoreEvent::Lagged`] event.
+pub const DEFAULT_EVENT_LOG_LEN: u64 = 1 << 16;
+
 #[derive(derive_more::Debug, Clone)]
 pub struct Store<PS: iroh_blobs::store::Store> {
     payloads: PS,
     willow: Rc<WillowStore>,
+    quotas: traits::Quotas,
 }
 
 impl<PS: iroh_blobs::store::Store> Store<PS> {
@@ -53,6 +61,7 @@ impl<PS: iroh_blobs::store::Store> Store<PS> {
         Ok(Self {
             payloads: payload_store,
             willow: Rc::new(WillowStore::persistent(db_path)?),
+            quotas: Default::default(),
         })
     }
 
@@ -60,21 +69,61 @@ impl<PS: iroh_blobs::store::Store> Store<PS> {
         Ok(Self {
             payloads: payload_store,
             willow: Rc::new(WillowStore::memory()?),
+            quotas: Default::default(),
         })
     }
+
+    /// Sets the storage quotas.
+    ///
+    /// See [`traits::Quotas`] for details.
+    pub fn with_quotas(mut self, quotas: traits::Quotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// Sets the limits for the events kept in memory for each namespace.
+    ///
+    /// Events dropped from memory are read from the event log in the database, so subscriptions
+    /// only lag behind once events are compacted from the log, see
+    /// [`Self::with_event_log_len`]. See [`memory::EventQueueLimits`] for details.
+    pub fn with_event_queue_limits(self, limits: memory::EventQueueLimits) -> Self {
+        self.willow.db.event_limits.set(limits);
+        for queue in self.willow.db.events.borrow_mut().values_mut() {
+            queue.set_limits(limits);
+        }
+        self
+    }
+
+    /// Sets how many events are kept in the event log of each namespace.
+    ///
+    /// Defaults to [`DEFAULT_EVENT_LOG_LEN`]. At least one event is always kept, so that progress
+    /// ids continue after the store is reopened.
+    pub fn with_event_log_len(self, len: u64) -> Self {
+        self.willow.db.event_log_len.set(len.max(1));
+        self
+    }
 }
 
 #[derive(Debug)]
 pub struct WillowStore {
     db: Db,
-    namespace_events: RefCell<HashMap<NamespaceId, memory::EventQueue<StoreEvent>>>,
 }
 
 #[derive(derive_more::Debug)]
 struct Db {
     #[debug("redb::Database")]
-    redb: redb::Database,
+    redb: RefCell<redb::Database>,
     tx: RefCell<CurrentTransaction>,
+    /// The latest committed events of each namespace.
+    events: RefCell<HashMap<NamespaceId, memory::EventQueue<StoreEvent>>>,
+    /// Events recorded in the open write transaction, which are published once it is committed.
+    pending_events: RefCell<HashMap<NamespaceId, Vec<StoreEvent>>>,
+    /// Limits for the events kept in memory for each namespace.
+    event_limits: Cell<memory::EventQueueLimits>,
+    /// Number of events kept in the event log of each namespace.
+    event_log_len: Cell<u64>,
+    /// Quota usage of each namespace, counted on the first quota check.
+    usage: RefCell<HashMap<NamespaceId, traits::UsageCounters>>,
 }
 
 #[derive(derive_more::Debug, Default)]
@@ -100,23 +149,142 @@ impl WillowStore {
     }
 
     fn new_impl(db: Database) -> Result<Self> {
-        // Setup all tables
-        let write_tx = db.begin_write()?;
-        let _ = tables::Tables::new(&write_tx)?;
-        write_tx.commit()?;
+        // Setup all tables, and migrate them from older schema versions.
+        migrations::migrate(&db)?;
+
+        // Continue the progress ids of the event log of each namespace. Events from before
+        // the store was opened are read from the event log, see [`EventStream`].
+        let mut events = HashMap::new();
+        {
+            let read_tx = db.begin_read()?;
+            let read = tables::OpenRead::new(&read_tx)?;
+            let mut next = read.events.first()?.map(|(key, _)| key.value().0);
+            while let Some(namespace) = next {
+                let next_id = next_event_id(&read.events, namespace)?;
+                events.insert(
+                    NamespaceId::from_bytes_unchecked(namespace),
+                    memory::EventQueue::new(next_id, Default::default()),
+                );
+                // Skip to the first event of the next namespace.
+                next = read
+                    .events
+                    .range((namespace, u64::MAX)..)?
+                    .map(|item| item.map(|(key, _)| key.value().0))
+                    .find(|item| !matches!(item, Ok(ns) if *ns == namespace))
+                    .transpose()?;
+            }
+        }
 
         Ok(Self {
             db: Db {
-                redb: db,
+                redb: RefCell::new(db),
                 tx: Default::default(),
+                events: RefCell::new(events),
+                pending_events: Default::default(),
+                event_limits: Default::default(),
+                event_log_len: Cell::new(DEFAULT_EVENT_LOG_LEN),
+                usage: Default::default(),
             },
-            namespace_events: Default::default(),
         })
     }
 
     pub fn snapshot(&self) -> Result<WillowSnapshot> {
         Ok(WillowSnapshot(Rc::new(self.db.snapshot_owned()?)))
     }
+
+    /// Deletes all entries in `range`.
+    ///
+    /// If any entries were deleted, the event returned from `event` is recorded in the same
+    /// transaction.
+    fn forget_query(
+        &self,
+        namespace: NamespaceId,
+        range: &QueryRange3d<IrohWillowParams>,
+        event: impl FnOnce(u64, &[AuthorisedEntry]) -> StoreEvent,
+    ) -> Result<Vec<AuthorisedEntry>> {
+        self.db.tables()?.modify(|write| {
+            let Some(node_id) = write
+                .namespace_nodes
+                .get(namespace.as_bytes())?
+                .map(|guard| guard.value())
+            else {
+                return Ok(vec![]);
+            };
+            let mut ns_node = willow_store::Node::<IrohWillowParams>::from(node_id);
+            let candidates = ns_node
+                .query(range, &write.node_store)
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut forgotten = Vec::with_capacity(candidates.len());
+            for (pos, stored_entry) in candidates {
+                let token_id = stored_entry.authorisation_token_id;
+                let auth_token = get_entry_auth_token(token_id, &write.auth_tokens)?;
+                let digest = stored_entry.payload_digest;
+                forgotten.push(stored_entry.into_authorised_entry(namespace, &pos, auth_token)?);
+                ns_node.delete(&pos, &mut write.node_store)?;
+                remove_entry_auth_token(write, token_id)?;
+                remove_payload_ref(write, digest)?;
+            }
+            if !forgotten.is_empty() {
+                redact_event_log(write, namespace.to_bytes(), &forgotten)?;
+                self.db
+                    .log_event(write, namespace, |id| event(id, &forgotten))?;
+                write
+                    .namespace_nodes
+                    .insert(namespace.to_bytes(), ns_node.id())?;
+                if let Some(usage) = self.db.usage.borrow_mut().get_mut(&namespace) {
+                    for entry in &forgotten {
+                        usage.remove(*entry.entry().subspace_id(), entry.entry().payload_length());
+                    }
+                }
+            }
+            Ok(forgotten)
+        })
+    }
+
+    fn namespace_events(
+        &self,
+        namespace: NamespaceId,
+    ) -> RefMut<'_, memory::EventQueue<StoreEvent>> {
+        self.db.namespace_events(namespace)
+    }
+
+    /// Returns the first event in the event log with a progress id in `progress_ids` which
+    /// matches `filter`, together with the progress id to continue from.
+    ///
+    /// If the events at the start of `progress_ids` were compacted already, returns a
+    /// [`StoreEvent::Lagged`] event with the oldest progress id still available.
+    fn next_logged_event(
+        &self,
+        namespace: NamespaceId,
+        progress_ids: std::ops::Range<u64>,
+        filter: impl Fn(&StoreEvent) -> bool,
+    ) -> Result<Option<(u64, StoreEvent)>> {
+        let read = self.db.snapshot()?;
+        let start = (namespace.to_bytes(), progress_ids.start);
+        let end = (namespace.to_bytes(), progress_ids.end);
+        let mut expected_id = progress_ids.start;
+        for item in read.events.range(start..end)? {
+            let (key, value) = item?;
+            let progress_id = key.value().1;
+            if progress_id != expected_id {
+                return Ok(Some((progress_id, StoreEvent::Lagged(progress_id))));
+            }
+            expected_id += 1;
+            let Some(event) =
+                resolve_logged_event(namespace, progress_id, value.value(), &read.auth_tokens)?
+            else {
+                continue;
+            };
+            if filter(&event) {
+                return Ok(Some((progress_id + 1, event)));
+            }
+        }
+        if expected_id < progress_ids.end {
+            let next_id = progress_ids.end;
+            return Ok(Some((next_id, StoreEvent::Lagged(next_id))));
+        }
+        Ok(None)
+    }
 }
 
 impl Db {
@@ -125,11 +293,98 @@ impl Db {
     /// This is the cheapest way to ensure that the data is persisted.
     fn flush(&self) -> Result<()> {
         if let CurrentTransaction::Write(w) = std::mem::take(self.tx.borrow_mut().deref_mut()) {
-            w.commit()?;
+            self.commit(w)?;
         }
         Ok(())
     }
 
+    /// Commits the open write transaction, and compacts the database file.
+    ///
+    /// Compaction releases the pages freed by earlier transactions and shrinks the file. It fails
+    /// while a read transaction is open elsewhere, e.g. in a snapshot of the store.
+    fn compact(&self) -> Result<()> {
+        self.flush()?;
+        // Our own read transaction would prevent the compaction.
+        *self.tx.borrow_mut() = CurrentTransaction::None;
+        self.redb.borrow_mut().compact()?;
+        Ok(())
+    }
+
+    /// Commits a write transaction, and then publishes the events recorded in it.
+    ///
+    /// If the commit fails, the recorded events are dropped, and the usage counters are reset.
+    fn commit(&self, write: tables::OpenWrite) -> Result<()> {
+        let pending = std::mem::take(&mut *self.pending_events.borrow_mut());
+        if let Err(err) = write.commit() {
+            // The counters include the changes of the failed transaction, count again when needed.
+            self.usage.borrow_mut().clear();
+            return Err(err);
+        }
+        for (namespace, events) in pending {
+            let mut queue = self.namespace_events(namespace);
+            for event in events {
+                queue.insert(|progress_id| {
+                    debug_assert_eq!(progress_id, event.progress_id());
+                    event
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn namespace_events(
+        &self,
+        namespace: NamespaceId,
+    ) -> RefMut<'_, memory::EventQueue<StoreEvent>> {
+        let limits = self.event_limits.get();
+        RefMut::map(self.events.borrow_mut(), |events| {
+            events
+                .entry(namespace)
+                .or_insert_with(|| memory::EventQueue::new(0, limits))
+        })
+    }
+
+    /// Returns `true` if events of `namespace` wait for the open write transaction to be
+    /// committed.
+    fn has_pending_events(&self, namespace: NamespaceId) -> bool {
+        self.pending_events
+            .borrow()
+            .get(&namespace)
+            .is_some_and(|events| !events.is_empty())
+    }
+
+    /// Appends an event to the event log of a namespace.
+    ///
+    /// The event is written in the same transaction as the change to the entries it describes,
+    /// and published to subscribers once the transaction is committed. Subscribers waiting for
+    /// the event are woken, and commit the transaction when they poll, see [`EventStream`].
+    ///
+    /// Events beyond the [`Store::with_event_log_len`] most recent ones are compacted.
+    fn log_event(
+        &self,
+        write: &mut tables::Tables,
+        namespace: NamespaceId,
+        f: impl FnOnce(u64) -> StoreEvent,
+    ) -> Result<()> {
+        let mut pending = self.pending_events.borrow_mut();
+        let pending = pending.entry(namespace).or_default();
+        let progress_id = match pending.last() {
+            Some(event) => event.progress_id() + 1,
+            None => self.namespace_events(namespace).next_progress_id(),
+        };
+        let event = f(progress_id);
+        let logged = to_logged_event(write, &event)?;
+        write.events.insert(
+            (namespace.to_bytes(), progress_id),
+            postcard::to_stdvec(&logged)?.as_slice(),
+        )?;
+        let retained_from = (progress_id + 1).saturating_sub(self.event_log_len.get());
+        compact_event_log(write, namespace.to_bytes(), retained_from)?;
+        self.namespace_events(namespace).wake_waiting_for(&event);
+        pending.push(event);
+        Ok(())
+    }
+
     /// Get a read-only snapshot of the database.
     ///
     /// This has the side effect of committing any open write transaction,
@@ -138,12 +393,12 @@ impl Db {
         let mut guard = self.tx.borrow_mut();
         let tables = match std::mem::take(guard.deref_mut()) {
             CurrentTransaction::None => {
-                let tx = self.redb.begin_read()?;
+                let tx = self.redb.borrow().begin_read()?;
                 tables::OpenRead::new(&tx)?
             }
             CurrentTransaction::Write(w) => {
-                w.commit()?;
-                let tx = self.redb.begin_read()?;
+                self.commit(w)?;
+                let tx = self.redb.borrow().begin_read()?;
                 tables::OpenRead::new(&tx)?
             }
             CurrentTransaction::Read(tables) => tables,
@@ -166,7 +421,7 @@ impl Db {
     fn snapshot_owned(&self) -> Result<tables::OpenRead> {
         // make sure the current transaction is committed
         self.flush()?;
-        let tx = self.redb.begin_read()?;
+        let tx = self.redb.borrow().begin_read()?;
         let tables = tables::OpenRead::new(&tx)?;
         Ok(tables)
     }
@@ -185,14 +440,14 @@ impl Db {
         let mut guard = self.tx.borrow_mut();
         let tables = match std::mem::take(guard.deref_mut()) {
             CurrentTransaction::None | CurrentTransaction::Read(_) => {
-                let tx = self.redb.begin_write()?;
+                let tx = self.redb.borrow().begin_write()?;
                 tables::OpenWrite::new(tx)?
             }
             CurrentTransaction::Write(w) => {
                 if w.since.elapsed() > MAX_COMMIT_DELAY {
                     tracing::debug!("committing transaction because it's too old");
-                    w.commit()?;
-                    let tx = self.redb.begin_write()?;
+                    self.commit(w)?;
+                    let tx = self.redb.borrow().begin_write()?;
                     tables::OpenWrite::new(tx)?
                 } else {
                     w
@@ -228,6 +483,10 @@ impl<PS: iroh_blobs::store::Store> traits::Storage for Store<PS> {
     fn caps(&self) -> &Self::Caps {
         &self.willow
     }
+
+    fn quotas(&self) -> traits::Quotas {
+        self.quotas
+    }
 }
 
 #[derive(derive_more::Debug, Clone)]
@@ -328,6 +587,22 @@ impl traits::EntryReader for WillowSnapshot {
         ns_node.range_count(&to_query(range), &read.node_store)
     }
 
+    fn usage(&self, namespace: NamespaceId, range: &Range3d) -> Result<traits::Usage> {
+        let read = self.0.as_ref();
+        let Some(node_id) = read.namespace_nodes.get(namespace.as_bytes())? else {
+            return Ok(traits::Usage::default());
+        };
+        let ns_node = willow_store::Node::<IrohWillowParams>::from(node_id.value());
+        // Sum up the stored payload sizes, without loading the auth tokens of the entries.
+        let mut usage = traits::Usage::default();
+        for result in ns_node.query(&to_query(range), &read.node_store) {
+            let (_point, stored_entry) = result?;
+            usage.entries += 1;
+            usage.payload_bytes += stored_entry.payload_size;
+        }
+        Ok(usage)
+    }
+
     fn split_range(
         &self,
         namespace: NamespaceId,
@@ -337,6 +612,14 @@ impl traits::EntryReader for WillowSnapshot {
         self.clone().split_range_owned(namespace, range, config)
     }
 
+    fn namespaces(&self) -> Result<Vec<NamespaceId>> {
+        let mut namespaces = Vec::new();
+        for item in self.0.as_ref().namespace_nodes.iter()? {
+            namespaces.push(NamespaceId::from_bytes_unchecked(item?.0.value()));
+        }
+        Ok(namespaces)
+    }
+
     fn get_entry(
         &self,
         namespace: NamespaceId,
@@ -402,13 +685,7 @@ impl traits::EntryStorage for Rc<WillowStore> {
 
         let (insert_point, insert_entry) = StoredAuthorisedEntry::from_authorised_entry(entry);
 
-        let mut events = self.namespace_events.borrow_mut();
-        let ns_events = events.entry(namespace).or_default();
-
         self.db.tables()?.modify(|write| {
-            // TODO(matheus23): need to get a progress_id here somehow.
-            // There's ideas to use the willow-store NodeId for that.
-
             let mut ns_node: willow_store::Node<IrohWillowParams> = write
                 .namespace_nodes
                 .get(namespace.as_bytes())?
@@ -436,8 +713,10 @@ impl traits::EntryStorage for Rc<WillowStore> {
                 .query(&overwritten_range, &write.node_store)
                 .collect::<Result<Vec<_>, _>>()?;
 
+            let mut removed_payload_lengths = vec![];
             for (prune_pos, prune_candidate) in prune_candidates {
                 let pruned_token_id = prune_candidate.authorisation_token_id;
+                let prune_candidate_digest = prune_candidate.payload_digest;
                 let auth_token = get_entry_auth_token(pruned_token_id, &write.auth_tokens)?;
                 let pruned =
                     prune_candidate.into_authorised_entry(namespace, &prune_pos, auth_token)?; // fairly inefficient
@@ -445,7 +724,8 @@ impl traits::EntryStorage for Rc<WillowStore> {
                     // TODO(matheus23): Don't *actually* delete here? (depending on a potential traceless bit)
                     // There was some idea along the lines of "mark as deleted" by storing the identifier for the deletion.
                     ns_node.delete(&prune_pos, &mut write.node_store)?;
-                    ns_events.insert(move |id| {
+                    removed_payload_lengths.push(pruned.entry().payload_length());
+                    self.db.log_event(write, namespace, move |id| {
                         StoreEvent::Pruned(
                             id,
                             traits::PruneEvent {
@@ -453,9 +733,10 @@ impl traits::EntryStorage for Rc<WillowStore> {
                                 by: entry.clone(),
                             },
                         )
-                    });
+                    })?;
                     // Decrease auth token refcount to allow eventually cleaning up the token
                     remove_entry_auth_token(write, pruned_token_id)?;
+                    remove_payload_ref(write, prune_candidate_digest)?;
                 }
             }
 
@@ -469,36 +750,175 @@ impl traits::EntryStorage for Rc<WillowStore> {
 
             add_entry_auth_token(entry.token(), write)?;
 
-            let _replaced = ns_node.insert(&insert_point, &insert_entry, &mut write.node_store)?;
+            let replaced = ns_node.insert(&insert_point, &insert_entry, &mut write.node_store)?;
+            add_payload_ref(write, insert_entry.payload_digest)?;
+            if let Some(replaced) = replaced {
+                remove_payload_ref(write, replaced.payload_digest)?;
+                removed_payload_lengths.push(replaced.payload_size);
+            }
 
-            ns_events.insert(|id| StoreEvent::Ingested(id, entry.clone(), origin));
+            self.db.log_event(write, namespace, |id| {
+                StoreEvent::Ingested(id, entry.clone(), origin)
+            })?;
 
             write
                 .namespace_nodes
                 .insert(namespace.to_bytes(), ns_node.id())?;
 
+            if let Some(usage) = self.db.usage.borrow_mut().get_mut(&namespace) {
+                let subspace = *entry.entry().subspace_id();
+                for payload_length in removed_payload_lengths {
+                    usage.remove(subspace, payload_length);
+                }
+                usage.add(subspace, entry.entry().payload_length());
+            }
+
             Ok(())
         })?;
 
         Ok(true)
     }
 
+    fn forget_entry(
+        &self,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: &Path,
+    ) -> Result<Option<AuthorisedEntry>> {
+        let blobseq = path_to_blobseq(path);
+        let end = blobseq.immediate_successor();
+        let range = QueryRange3d {
+            x: QueryRange::new(subspace, subspace.successor()),
+            y: QueryRange::all(),
+            z: QueryRange::new(blobseq, Some(end)),
+        };
+        let forgotten = self.forget_query(namespace, &range, |id, forgotten| {
+            StoreEvent::EntryForgotten(id, forgotten[0].clone())
+        })?;
+        Ok(forgotten.into_iter().next())
+    }
+
+    fn forget_area(&self, namespace: NamespaceId, area: &Area) -> Result<u64> {
+        let range = to_query(&area.to_range());
+        let forgotten = self.forget_query(namespace, &range, |id, _| {
+            StoreEvent::AreaForgotten(id, namespace, area.clone())
+        })?;
+        Ok(forgotten.len() as u64)
+    }
+
+    fn forget_payload(
+        &self,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: &Path,
+    ) -> Result<Option<AuthorisedEntry>> {
+        let Some(entry) =
+            traits::EntryReader::get_entry(&self.snapshot()?, namespace, subspace, path)?
+        else {
+            return Ok(None);
+        };
+        self.db.tables()?.modify(|write| {
+            self.db.log_event(write, namespace, |id| {
+                StoreEvent::PayloadForgotten(id, entry.clone())
+            })
+        })?;
+        Ok(Some(entry))
+    }
+
+    fn quota_usage(
+        &self,
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+        quotas: &traits::Quotas,
+    ) -> Result<traits::Usage> {
+        if let Some(usage) = self.db.usage.borrow().get(&namespace) {
+            if usage.quotas() == quotas {
+                return Ok(usage.get(subspace));
+            }
+        }
+        // Count the usage once, later changes update the counters.
+        let read = self.db.snapshot()?;
+        let mut usage = traits::UsageCounters::new(*quotas);
+        if let Some(node_id) = read.namespace_nodes.get(namespace.as_bytes())? {
+            let ns_node = willow_store::Node::<IrohWillowParams>::from(node_id.value());
+            for result in ns_node.query(&to_query(&Range3d::new_full()), &read.node_store) {
+                let (point, stored_entry) = result?;
+                usage.add(*point.x(), stored_entry.payload_size);
+            }
+        }
+        let out = usage.get(subspace);
+        self.db.usage.borrow_mut().insert(namespace, usage);
+        Ok(out)
+    }
+
+    fn payload_references(&self, digest: &PayloadDigest) -> Result<u64> {
+        let read = self.db.snapshot()?;
+        let refcount = read.payload_refcount.get(digest.0.as_bytes())?;
+        Ok(refcount.map_or(0, |refcount| refcount.value()))
+    }
+
+    fn referenced_payloads(&self) -> Result<Vec<PayloadDigest>> {
+        let read = self.db.snapshot()?;
+        read.payload_refcount
+            .iter()?
+            .map(|item| {
+                let (digest, _refcount) = item?;
+                Ok(PayloadDigest(iroh_blobs::Hash::from_bytes(digest.value())))
+            })
+            .collect()
+    }
+
+    fn set_partial_payload(&self, entry: &Entry, partial: bool) -> Result<()> {
+        let namespace = entry.namespace_id().to_bytes();
+        let key = postcard::to_stdvec(&SerdeEntry(entry.clone()))?;
+        self.db.tables()?.modify(|write| {
+            if partial {
+                write.partial_payloads.insert(namespace, key.as_slice())?;
+            } else {
+                write.partial_payloads.remove(namespace, key.as_slice())?;
+            }
+            Ok(())
+        })
+    }
+
+    fn partial_payloads(
+        &self,
+        namespace: NamespaceId,
+        area: &Area,
+    ) -> Result<Vec<AuthorisedEntry>> {
+        let snapshot = self.snapshot()?;
+        let mut entries = vec![];
+        for value in snapshot.0.partial_payloads.get(namespace.as_bytes())? {
+            let SerdeEntry(entry) = postcard::from_bytes(value?.value())?;
+            if !area.includes_entry(&entry) {
+                continue;
+            }
+            // Skip the mark if the entry was pruned or forgotten in the meantime.
+            let stored = traits::EntryReader::get_entry(
+                &snapshot,
+                namespace,
+                *entry.subspace_id(),
+                entry.path(),
+            )?;
+            if let Some(stored) = stored.filter(|stored| *stored.entry() == entry) {
+                entries.push(stored);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn next_progress_id(&self, namespace: NamespaceId) -> u64 {
+        self.namespace_events(namespace).next_progress_id()
+    }
+
     fn subscribe_area(
         &self,
         namespace: NamespaceId,
         area: Area,
         params: traits::SubscribeParams,
     ) -> impl Stream<Item = StoreEvent> + Unpin + 'static {
-        let namespaces = &mut self.namespace_events.borrow_mut();
-        let ns_events = namespaces.entry(namespace).or_default();
-        let progress_id = ns_events.next_progress_id();
-        EventStream {
-            area,
-            params,
-            namespace,
-            progress_id,
-            store: Rc::downgrade(self),
-        }
+        let progress_id = traits::EntryStorage::next_progress_id(self, namespace);
+        EventStream::new(self, progress_id, namespace, area, params)
     }
 
     fn resume_subscription(
@@ -508,13 +928,7 @@ impl traits::EntryStorage for Rc<WillowStore> {
         area: Area,
         params: traits::SubscribeParams,
     ) -> impl Stream<Item = StoreEvent> + Unpin + 'static {
-        EventStream {
-            area,
-            params,
-            progress_id,
-            namespace,
-            store: Rc::downgrade(self),
-        }
+        EventStream::new(self, progress_id, namespace, area, params)
     }
 }
 
@@ -527,6 +941,10 @@ impl traits::EntryReader for Rc<WillowStore> {
         self.snapshot()?.count(namespace, range)
     }
 
+    fn usage(&self, namespace: NamespaceId, range: &Range3d) -> Result<traits::Usage> {
+        self.snapshot()?.usage(namespace, range)
+    }
+
     fn split_range(
         &self,
         namespace: NamespaceId,
@@ -536,6 +954,10 @@ impl traits::EntryReader for Rc<WillowStore> {
         self.snapshot()?.split_range_owned(namespace, range, config)
     }
 
+    fn namespaces(&self) -> Result<Vec<NamespaceId>> {
+        self.snapshot()?.namespaces()
+    }
+
     fn get_entry(
         &self,
         namespace: NamespaceId,
@@ -584,6 +1006,131 @@ impl traits::SecretStorage for Rc<WillowStore> {
         let namespace = tables.read().namespace_secrets.get(id.as_bytes())?;
         Ok(namespace.map(|ns| NamespaceSecretKey::from_bytes(&ns.value())))
     }
+
+    fn list_secrets(&self) -> Result<Vec<meadowcap::SecretKey>> {
+        let tables = self.db.tables()?;
+        let read = tables.read();
+        let mut secrets = Vec::new();
+        for item in read.user_secrets.iter()? {
+            let secret = UserSecretKey::from_bytes(&item?.1.value());
+            secrets.push(meadowcap::SecretKey::User(secret));
+        }
+        for item in read.namespace_secrets.iter()? {
+            let secret = NamespaceSecretKey::from_bytes(&item?.1.value());
+            secrets.push(meadowcap::SecretKey::Namespace(secret));
+        }
+        Ok(secrets)
+    }
+}
+
+impl encrypted::SealedSecretStorage for Rc<WillowStore> {
+    fn insert_sealed(&self, scope: traits::KeyScope, id: [u8; 32], sealed: Vec<u8>) -> Result<()> {
+        self.db.tables()?.modify(|write| {
+            match scope {
+                traits::KeyScope::User => {
+                    write.sealed_user_secrets.insert(id, sealed.as_slice())?
+                }
+                traits::KeyScope::Namespace => write
+                    .sealed_namespace_secrets
+                    .insert(id, sealed.as_slice())?,
+            };
+            Ok(())
+        })
+    }
+
+    fn get_sealed(&self, scope: traits::KeyScope, id: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        let tables = self.db.tables()?;
+        let read = tables.read();
+        let sealed = match scope {
+            traits::KeyScope::User => read.sealed_user_secrets.get(id)?,
+            traits::KeyScope::Namespace => read.sealed_namespace_secrets.get(id)?,
+        };
+        Ok(sealed.map(|sealed| sealed.value().to_vec()))
+    }
+
+    fn list_sealed(&self) -> Result<Vec<(traits::KeyScope, [u8; 32], Vec<u8>)>> {
+        let tables = self.db.tables()?;
+        let read = tables.read();
+        let mut secrets = Vec::new();
+        for item in read.sealed_user_secrets.iter()? {
+            let (id, sealed) = item?;
+            secrets.push((traits::KeyScope::User, id.value(), sealed.value().to_vec()));
+        }
+        for item in read.sealed_namespace_secrets.iter()? {
+            let (id, sealed) = item?;
+            secrets.push((
+                traits::KeyScope::Namespace,
+                id.value(),
+                sealed.value().to_vec(),
+            ));
+        }
+        Ok(secrets)
+    }
+
+    fn encryption_params(&self) -> Result<Option<encrypted::EncryptionParams>> {
+        let tables = self.db.tables()?;
+        let read = tables.read();
+        let salt = read.secrets_encryption.get(tables::SECRETS_SALT_KEY)?;
+        let key_check = read.secrets_encryption.get(tables::SECRETS_KEY_CHECK_KEY)?;
+        let (Some(salt), Some(key_check)) = (salt, key_check) else {
+            return Ok(None);
+        };
+        let salt = salt
+            .value()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid salt of secrets encryption"))?;
+        Ok(Some(encrypted::EncryptionParams {
+            salt,
+            key_check: key_check.value().to_vec(),
+        }))
+    }
+
+    fn set_encryption_params(&self, params: encrypted::EncryptionParams) -> Result<()> {
+        self.db.tables()?.modify(|write| {
+            write
+                .secrets_encryption
+                .insert(tables::SECRETS_SALT_KEY, params.salt.as_slice())?;
+            write
+                .secrets_encryption
+                .insert(tables::SECRETS_KEY_CHECK_KEY, params.key_check.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn plaintext_secrets(&self) -> Result<Vec<meadowcap::SecretKey>> {
+        traits::SecretStorage::list_secrets(self)
+    }
+
+    fn replace_plaintext_secrets(
+        &self,
+        sealed: Vec<(traits::KeyScope, [u8; 32], Vec<u8>)>,
+    ) -> Result<()> {
+        self.db.tables()?.modify(|write| {
+            for (scope, id, sealed) in sealed {
+                match scope {
+                    traits::KeyScope::User => {
+                        write.sealed_user_secrets.insert(id, sealed.as_slice())?;
+                        write.user_secrets.remove(id)?;
+                    }
+                    traits::KeyScope::Namespace => {
+                        write
+                            .sealed_namespace_secrets
+                            .insert(id, sealed.as_slice())?;
+                        write.namespace_secrets.remove(id)?;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        // Don't leave the plaintext secrets on disk until the next batched commit.
+        self.db.flush()?;
+        // Release the pages which held the plaintext secrets. This is best effort: the sealed
+        // secrets are committed already, and redb does not overwrite freed pages in any case.
+        if let Err(err) = self.db.compact() {
+            tracing::warn!(?err, "failed to compact the database after sealing secrets");
+        }
+        Ok(())
+    }
 }
 
 impl traits::CapsStorage for Rc<WillowStore> {
@@ -712,41 +1259,545 @@ fn remove_entry_auth_token(
     }
 }
 
+fn add_payload_ref(write: &mut tables::Tables<'_>, digest: tables::PayloadDigest) -> Result<()> {
+    let refcount = write
+        .payload_refcount
+        .get(&digest)?
+        .map_or(1, |rc| rc.value() + 1);
+    write.payload_refcount.insert(digest, refcount)?;
+    Ok(())
+}
+
+fn remove_payload_ref(write: &mut tables::Tables<'_>, digest: tables::PayloadDigest) -> Result<()> {
+    let Some(refcount) = write.payload_refcount.get(&digest)?.map(|v| v.value()) else {
+        return Ok(());
+    };
+    debug_assert_ne!(refcount, 0);
+    if refcount <= 1 {
+        write.payload_refcount.remove(&digest)?;
+    } else {
+        write.payload_refcount.insert(digest, refcount - 1)?;
+    }
+    Ok(())
+}
+
+/// Converts an event for the event log, and stores the authorisation tokens it references.
+fn to_logged_event(write: &mut tables::Tables, event: &StoreEvent) -> Result<tables::LoggedEvent> {
+    Ok(match event {
+        StoreEvent::Ingested(_, entry, origin) => {
+            tables::LoggedEvent::Ingested(log_entry(write, entry)?, *origin)
+        }
+        StoreEvent::Pruned(_, prune) => tables::LoggedEvent::Pruned {
+            pruned: log_entry(write, &prune.pruned)?,
+            by: log_entry(write, &prune.by)?,
+        },
+        StoreEvent::EntryForgotten(_, entry) => {
+            tables::LoggedEvent::EntryForgotten(log_entry(write, entry)?)
+        }
+        StoreEvent::AreaForgotten(_, _, area) => tables::LoggedEvent::AreaForgotten(area.clone()),
+        StoreEvent::PayloadForgotten(_, entry) => {
+            tables::LoggedEvent::PayloadForgotten(log_entry(write, entry)?)
+        }
+        StoreEvent::Lagged(_) => anyhow::bail!("lagged events are not logged"),
+    })
+}
+
+/// Stores the authorisation token of an entry referenced from the event log.
+fn log_entry(write: &mut tables::Tables, entry: &AuthorisedEntry) -> Result<tables::LoggedEntry> {
+    let token_id = add_entry_auth_token(entry.token(), write)?;
+    Ok(tables::LoggedEntry {
+        entry: entry.entry().clone(),
+        token_id: ed25519::Signature::from_bytes(&token_id),
+    })
+}
+
+/// Restores an event from the event log.
+///
+/// Returns `None` for redacted events.
+fn resolve_logged_event(
+    namespace: NamespaceId,
+    progress_id: u64,
+    value: &[u8],
+    auth_tokens: &impl ReadableTable<ed25519::SignatureBytes, tables::WriteCap>,
+) -> Result<Option<StoreEvent>> {
+    let resolve = |logged: tables::LoggedEntry| -> Result<AuthorisedEntry> {
+        let token = get_entry_auth_token(logged.token_id.to_bytes(), auth_tokens)?;
+        // The entry was verified when it was ingested.
+        Ok(AuthorisedEntry::new_unchecked(logged.entry, token))
+    };
+    let logged: tables::LoggedEvent = postcard::from_bytes(value)?;
+    Ok(Some(match logged {
+        tables::LoggedEvent::Ingested(entry, origin) => {
+            StoreEvent::Ingested(progress_id, resolve(entry)?, origin)
+        }
+        tables::LoggedEvent::Pruned { pruned, by } => StoreEvent::Pruned(
+            progress_id,
+            traits::PruneEvent {
+                pruned: resolve(pruned)?,
+                by: resolve(by)?,
+            },
+        ),
+        tables::LoggedEvent::EntryForgotten(entry) => {
+            StoreEvent::EntryForgotten(progress_id, resolve(entry)?)
+        }
+        tables::LoggedEvent::AreaForgotten(area) => {
+            StoreEvent::AreaForgotten(progress_id, namespace, area)
+        }
+        tables::LoggedEvent::PayloadForgotten(entry) => {
+            StoreEvent::PayloadForgotten(progress_id, resolve(entry)?)
+        }
+        tables::LoggedEvent::Redacted => return Ok(None),
+    }))
+}
+
+/// Redacts the events in the event log of a namespace which reference any of `entries`, and
+/// releases the authorisation tokens they reference.
+///
+/// Redacted events keep their progress ids, so that they are not mistaken for compacted events,
+/// but are skipped by subscriptions. This scans the whole event log of the namespace.
+fn redact_event_log(
+    write: &mut tables::Tables,
+    namespace: tables::NamespaceId,
+    entries: &[AuthorisedEntry],
+) -> Result<()> {
+    let token_ids: HashSet<ed25519::SignatureBytes> = entries
+        .iter()
+        .map(|entry| entry.token().signature.to_bytes())
+        .collect();
+    let mut redacted = vec![];
+    for item in write.events.range((namespace, 0)..=(namespace, u64::MAX))? {
+        let (key, value) = item?;
+        let logged: tables::LoggedEvent = postcard::from_bytes(value.value())?;
+        if logged
+            .entries()
+            .any(|entry| token_ids.contains(&entry.token_id.to_bytes()))
+        {
+            redacted.push((key.value().1, logged));
+        }
+    }
+    let value = postcard::to_stdvec(&tables::LoggedEvent::Redacted)?;
+    for (progress_id, logged) in redacted {
+        write
+            .events
+            .insert((namespace, progress_id), value.as_slice())?;
+        for entry in logged.entries() {
+            remove_entry_auth_token(write, entry.token_id.to_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the events with progress ids before `before` from the event log of a namespace, and
+/// releases the authorisation tokens they reference.
+fn compact_event_log(
+    write: &mut tables::Tables,
+    namespace: tables::NamespaceId,
+    before: u64,
+) -> Result<()> {
+    let compacted = write
+        .events
+        .range((namespace, 0)..(namespace, before))?
+        .map(|item| {
+            let (key, value) = item?;
+            Ok((key.value().1, value.value().to_vec()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (progress_id, value) in compacted {
+        write.events.remove((namespace, progress_id))?;
+        let logged: tables::LoggedEvent = postcard::from_bytes(&value)?;
+        for entry in logged.entries() {
+            remove_entry_auth_token(write, entry.token_id.to_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the progress id for the next event in the event log of a namespace.
+fn next_event_id(
+    events: &impl ReadableTable<(tables::NamespaceId, u64), &'static [u8]>,
+    namespace: tables::NamespaceId,
+) -> Result<u64> {
+    let last = events
+        .range((namespace, 0)..=(namespace, u64::MAX))?
+        .next_back()
+        .transpose()?;
+    Ok(last.map_or(0, |(key, _)| key.value().1 + 1))
+}
+
 /// Stream of events from a store subscription.
 ///
 /// We have weak pointer to the entry store and thus the EventQueue.
 /// Once the store is dropped, the EventQueue wakes all streams a last time in its drop impl,
 /// which then makes the stream return none because Weak::upgrade returns None.
-#[derive(Debug)]
+///
+/// Events from before the store was opened are not in the in-memory queue, and are read from
+/// the event log in the database instead.
+#[derive(derive_more::Debug)]
 struct EventStream {
-    progress_id: u64,
+    progress_id: Rc<Cell<u64>>,
     store: Weak<WillowStore>,
     namespace: NamespaceId,
-    area: Area,
-    params: SubscribeParams,
+    #[debug("EventFilter")]
+    filter: memory::EventFilter<StoreEvent>,
+}
+
+impl EventStream {
+    fn new(
+        store: &Rc<WillowStore>,
+        progress_id: u64,
+        namespace: NamespaceId,
+        area: Area,
+        params: SubscribeParams,
+    ) -> Self {
+        Self {
+            progress_id: Rc::new(Cell::new(progress_id)),
+            store: Rc::downgrade(store),
+            namespace,
+            filter: Rc::new(move |e: &StoreEvent| e.matches(namespace, &area, &params)),
+        }
+    }
 }
 
 impl Stream for EventStream {
     type Item = StoreEvent;
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let Some(inner) = self.store.upgrade() else {
             return Poll::Ready(None);
         };
-        let mut inner_mut = inner.namespace_events.borrow_mut();
-        let events = inner_mut.entry(self.namespace).or_default();
-        let res = ready!(events.poll_next(
-            self.progress_id,
-            |e| e.matches(self.namespace, &self.area, &self.params),
-            cx,
+        // Events are published once their transaction is committed. Commit it now instead of
+        // waiting for the next write.
+        if inner.db.has_pending_events(self.namespace) {
+            if let Err(err) = inner.db.flush() {
+                tracing::error!(%err, "Failed to commit the store");
+                return Poll::Ready(None);
+            }
+        }
+        let offset = inner.namespace_events(self.namespace).offset();
+        let progress_id = self.progress_id.get();
+        if progress_id < offset {
+            let res = inner.next_logged_event(self.namespace, progress_id..offset, &*self.filter);
+            match res {
+                Ok(Some((next_id, event))) => {
+                    self.progress_id.set(next_id);
+                    return Poll::Ready(Some(event));
+                }
+                Ok(None) => self.progress_id.set(offset),
+                Err(err) => {
+                    tracing::error!(%err, "Failed to read from the event log");
+                    return Poll::Ready(None);
+                }
+            }
+        }
+        let res = ready!(inner.namespace_events(self.namespace).poll_next(
+            &self.progress_id,
+            &self.filter,
+            cx
         ));
-        drop(inner_mut);
-        drop(inner);
-        Poll::Ready(match res {
-            None => None,
-            Some((next_id, event)) => {
-                self.progress_id = next_id;
-                Some(event)
+        Poll::Ready(Some(match res {
+            Ok(event) => event,
+            Err(memory::Lagged) => StoreEvent::Lagged(self.progress_id.get()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::StreamExt;
+    use rand_core::SeedableRng;
+
+    use super::*;
+    use crate::{
+        form::{AuthForm, EntryForm},
+        proto::keys::NamespaceKind,
+        store::traits::{EntryReader, EntryStorage, SecretStorage},
+    };
+
+    type TestStore = crate::store::Store<Store<iroh_blobs::store::mem::Store>>;
+
+    async fn insert(
+        store: &TestStore,
+        namespace: NamespaceId,
+        user: UserId,
+        path: &str,
+        payload: &'static str,
+    ) -> Result<()> {
+        let path = Path::from_bytes(&[path.as_bytes()])?;
+        let entry = EntryForm::new_bytes(namespace, path, payload);
+        store
+            .insert_entry(entry.into(), AuthForm::Any(user))
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_subscription_after_reopen() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db_path = dir.path().join("willow.db");
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let (namespace, user) = {
+            let store = crate::store::Store::new(Store::new(
+                db_path.clone(),
+                iroh_blobs::store::mem::Store::default(),
+            )?);
+            let user = store
+                .secrets()
+                .insert_user(UserSecretKey::generate(&mut rng))?;
+            let namespace = store.create_namespace(&mut rng, NamespaceKind::Owned, user)?;
+            for path in ["a", "b", "c"] {
+                insert(&store, namespace, user, path, "payload").await?;
             }
-        })
+            // Commit the open write transaction.
+            store.entries().snapshot()?;
+            (namespace, user)
+        };
+
+        let store = crate::store::Store::new(Store::new(
+            db_path,
+            iroh_blobs::store::mem::Store::default(),
+        )?);
+        // Events from before the restart are replayed from the event log.
+        let mut events =
+            store
+                .entries()
+                .resume_subscription(1, namespace, Area::new_full(), Default::default());
+        for (id, path) in [(1, "b"), (2, "c")] {
+            let Some(StoreEvent::Ingested(progress_id, entry, _)) = events.next().await else {
+                panic!("expected ingested event");
+            };
+            assert_eq!(progress_id, id);
+            assert_eq!(*entry.entry().path(), Path::from_bytes(&[path.as_bytes()])?);
+        }
+        // New events continue the progress ids of the event log.
+        insert(&store, namespace, user, "d", "payload").await?;
+        let Some(StoreEvent::Ingested(progress_id, _, _)) = events.next().await else {
+            panic!("expected ingested event");
+        };
+        assert_eq!(progress_id, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_event_log() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db_path = dir.path().join("willow.db");
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let namespace = {
+            let store = crate::store::Store::new(
+                Store::new(db_path.clone(), iroh_blobs::store::mem::Store::default())?
+                    .with_event_log_len(2),
+            );
+            let user = store
+                .secrets()
+                .insert_user(UserSecretKey::generate(&mut rng))?;
+            let namespace = store.create_namespace(&mut rng, NamespaceKind::Owned, user)?;
+            for path in ["a", "b", "c", "d"] {
+                insert(&store, namespace, user, path, "payload").await?;
+            }
+            store.entries().snapshot()?;
+            namespace
+        };
+
+        let store = crate::store::Store::new(Store::new(
+            db_path,
+            iroh_blobs::store::mem::Store::default(),
+        )?);
+        let mut events =
+            store
+                .entries()
+                .resume_subscription(0, namespace, Area::new_full(), Default::default());
+        // The first two events were compacted.
+        assert!(matches!(events.next().await, Some(StoreEvent::Lagged(2))));
+        for (id, path) in [(2, "c"), (3, "d")] {
+            let Some(StoreEvent::Ingested(progress_id, entry, _)) = events.next().await else {
+                panic!("expected ingested event");
+            };
+            assert_eq!(progress_id, id);
+            assert_eq!(*entry.entry().path(), Path::from_bytes(&[path.as_bytes()])?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forget_entry_redacts_event_log() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db_path = dir.path().join("willow.db");
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+
+        let namespace = {
+            let store = crate::store::Store::new(Store::new(
+                db_path.clone(),
+                iroh_blobs::store::mem::Store::default(),
+            )?);
+            let user = store
+                .secrets()
+                .insert_user(UserSecretKey::generate(&mut rng))?;
+            let namespace = store.create_namespace(&mut rng, NamespaceKind::Owned, user)?;
+            for path in ["a", "b"] {
+                insert(&store, namespace, user, path, "payload").await?;
+            }
+            // The payload is shared by both entries, so it cannot be forgotten.
+            let a = Path::from_bytes(&[b"a".as_slice()])?;
+            assert!(store.forget_payload(namespace, user, &a).await.is_err());
+            assert!(store.entries().forget_entry(namespace, user, &a)?.is_some());
+            store.entries().snapshot()?;
+            namespace
+        };
+
+        let store = crate::store::Store::new(Store::new(
+            db_path,
+            iroh_blobs::store::mem::Store::default(),
+        )?);
+        let mut events =
+            store
+                .entries()
+                .resume_subscription(0, namespace, Area::new_full(), Default::default());
+        // The ingestion of the forgotten entry was redacted.
+        let Some(StoreEvent::Ingested(1, entry, _)) = events.next().await else {
+            panic!("expected ingested event");
+        };
+        assert_eq!(*entry.entry().path(), Path::from_bytes(&[b"b".as_slice()])?);
+        assert!(matches!(
+            events.next().await,
+            Some(StoreEvent::EntryForgotten(2, _))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn referenced_payloads() -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let store =
+            crate::store::Store::new(Store::new_memory(iroh_blobs::store::mem::Store::default())?);
+        let user = store
+            .secrets()
+            .insert_user(UserSecretKey::generate(&mut rng))?;
+        let namespace = store.create_namespace(&mut rng, NamespaceKind::Owned, user)?;
+        let referenced = |payloads: &[&str]| -> Result<()> {
+            let mut expected: Vec<_> = payloads
+                .iter()
+                .map(|payload| PayloadDigest(iroh_blobs::Hash::new(payload)))
+                .collect();
+            expected.sort();
+            let mut actual = store.entries().referenced_payloads()?;
+            actual.sort();
+            assert_eq!(actual, expected);
+            Ok(())
+        };
+
+        insert(&store, namespace, user, "a", "1").await?;
+        insert(&store, namespace, user, "b", "2").await?;
+        insert(&store, namespace, user, "c", "2").await?;
+        referenced(&["1", "2"])?;
+
+        let path = |path: &str| Path::from_bytes(&[path.as_bytes()]);
+        store.entries().forget_entry(namespace, user, &path("a")?)?;
+        referenced(&["2"])?;
+        // The payload is still referenced by the entry at "c".
+        store.entries().forget_entry(namespace, user, &path("b")?)?;
+        referenced(&["2"])?;
+        // Pruned entries release their payload.
+        insert(&store, namespace, user, "c", "3").await?;
+        referenced(&["3"])?;
+        store.entries().forget_area(namespace, &Area::new_full())?;
+        referenced(&[])?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partial_payloads() -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let store =
+            crate::store::Store::new(Store::new_memory(iroh_blobs::store::mem::Store::default())?);
+        let user = store
+            .secrets()
+            .insert_user(UserSecretKey::generate(&mut rng))?;
+        let namespace = store.create_namespace(&mut rng, NamespaceKind::Owned, user)?;
+        insert(&store, namespace, user, "a", "1").await?;
+        insert(&store, namespace, user, "b", "2").await?;
+        let path = |path: &str| Path::from_bytes(&[path.as_bytes()]);
+        let get = |path: &Path| -> Result<Entry> {
+            let entry = store.entries().get_entry(namespace, user, path)?.unwrap();
+            Ok(entry.entry().clone())
+        };
+        let partial = |area: &Area| -> Result<Vec<Path>> {
+            let entries = store.entries().partial_payloads(namespace, area)?;
+            Ok(entries.iter().map(|e| e.entry().path().clone()).collect())
+        };
+
+        let a = get(&path("a")?)?;
+        let b = get(&path("b")?)?;
+        store.entries().set_partial_payload(&a, true)?;
+        store.entries().set_partial_payload(&b, true)?;
+        assert_eq!(partial(&Area::new_path(path("a")?))?, vec![path("a")?]);
+        store.entries().set_partial_payload(&b, false)?;
+        assert_eq!(partial(&Area::new_full())?, vec![path("a")?]);
+        // Marks of entries which were overwritten are skipped.
+        insert(&store, namespace, user, "a", "3").await?;
+        assert!(partial(&Area::new_full())?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quotas() -> Result<()> {
+        use traits::{Quota, QuotaExceeded, QuotaScope, Quotas, Usage};
+
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let quotas = Quotas {
+            namespace: Quota {
+                max_payload_bytes: Some(12),
+                max_payload_size: Some(8),
+                ..Default::default()
+            },
+            subspace: Quota {
+                max_entries: Some(2),
+                ..Default::default()
+            },
+        };
+        let store = crate::store::Store::new(
+            Store::new_memory(iroh_blobs::store::mem::Store::default())?.with_quotas(quotas),
+        );
+        let user = store
+            .secrets()
+            .insert_user(UserSecretKey::generate(&mut rng))?;
+        let namespace = store.create_namespace(&mut rng, NamespaceKind::Owned, user)?;
+        let exceeded = |res: Result<()>| *res.unwrap_err().downcast_ref::<QuotaExceeded>().unwrap();
+
+        insert(&store, namespace, user, "a", "12345").await?;
+        insert(&store, namespace, user, "b", "12345").await?;
+        assert_eq!(
+            exceeded(insert(&store, namespace, user, "c", "1").await),
+            QuotaExceeded::Entries(QuotaScope::Subspace)
+        );
+        // Overwriting an entry prunes the old one, so it does not count against the quota.
+        insert(&store, namespace, user, "a", "123").await?;
+        insert(&store, namespace, user, "b", "1234567").await?;
+        assert_eq!(
+            exceeded(insert(&store, namespace, user, "a", "12345678").await),
+            QuotaExceeded::PayloadBytes(QuotaScope::Namespace)
+        );
+        assert_eq!(
+            exceeded(insert(&store, namespace, user, "b", "123456789").await),
+            QuotaExceeded::PayloadSize(QuotaScope::Namespace)
+        );
+        let usage = |subspace| store.entries().quota_usage(namespace, subspace, &quotas);
+        let expected = Usage {
+            entries: 2,
+            payload_bytes: 10,
+        };
+        assert_eq!(usage(None)?, expected);
+        assert_eq!(usage(Some(user))?, expected);
+        assert_eq!(
+            store.entries().usage(namespace, &Range3d::new_full())?,
+            expected
+        );
+        // Forgetting an entry updates the counters.
+        let path = |path: &str| Path::from_bytes(&[path.as_bytes()]);
+        store.entries().forget_entry(namespace, user, &path("a")?)?;
+        assert_eq!(usage(None)?.payload_bytes, 7);
+        insert(&store, namespace, user, "a", "12345").await?;
+        assert_eq!(usage(Some(user))?.entries, 2);
+        Ok(())
     }
 }
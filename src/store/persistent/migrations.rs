@@ -0,0 +1,239 @@
+//! Schema versioning and migrations for the redb database.
+//!
+//! The schema version is recorded in the [`METADATA`] table. Databases created before the
+//! version was recorded are treated as version 0. When a database is opened, all migrations up to
+//! [`SCHEMA_VERSION`] are applied in a single write transaction, so that a failed migration
+//! leaves the database unchanged. Databases with a newer schema version are refused.
+//!
+//! To change the schema, add a [`Migration`] to [`MIGRATIONS`] and bump [`SCHEMA_VERSION`].
+//! Migrations only use the table definitions frozen in this module, never the ones in
+//! [`tables`], so that later schema changes don't change what older migrations read and write.
+
+use std::collections::BTreeMap;
+
+use anyhow::{ensure, Result};
+use redb::{Database, ReadableTable, TableDefinition, WriteTransaction};
+use tracing::{debug, info};
+
+use super::tables::{self, METADATA, SCHEMA_VERSION_KEY};
+use crate::{
+    proto::grouping::Range3d,
+    store::willow_store_glue::{to_query, IrohWillowParams},
+};
+
+/// The schema version of databases written by this version of the crate.
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// A migration of the database to a new schema version.
+struct Migration {
+    /// The schema version after the migration.
+    to: u64,
+    description: &'static str,
+    run: fn(&WriteTransaction) -> Result<()>,
+}
+
+/// All migrations, in ascending order of versions.
+const MIGRATIONS: &[Migration] = &[Migration {
+    to: 1,
+    description: "add the payload refcounts, the event log, sealed secrets and partial payloads",
+    run: migrate_v1,
+}];
+
+/// Brings the schema of `db` to [`SCHEMA_VERSION`].
+///
+/// Empty databases are initialized with the current schema.
+pub fn migrate(db: &Database) -> Result<()> {
+    let tx = db.begin_write()?;
+    let is_empty =
+        tx.list_tables()?.next().is_none() && tx.list_multimap_tables()?.next().is_none();
+    let version = match read_version(&tx)? {
+        Some(version) => version,
+        None if is_empty => SCHEMA_VERSION,
+        None => 0,
+    };
+    ensure!(
+        version <= SCHEMA_VERSION,
+        "the database has schema version {version}, which is newer than the supported version {SCHEMA_VERSION}"
+    );
+    for migration in MIGRATIONS.iter().filter(|m| m.to > version) {
+        info!(
+            from = version,
+            to = migration.to,
+            "migrate database: {}",
+            migration.description
+        );
+        (migration.run)(&tx)?;
+    }
+    // Create all tables of the current schema, if they do not exist yet.
+    tables::Tables::new(&tx)?;
+    tx.open_table(METADATA)?
+        .insert(SCHEMA_VERSION_KEY, SCHEMA_VERSION)?;
+    tx.commit()?;
+    debug!(version = SCHEMA_VERSION, "database schema is up to date");
+    Ok(())
+}
+
+fn read_version(tx: &WriteTransaction) -> Result<Option<u64>> {
+    let table = tx.open_table(METADATA)?;
+    let version = table.get(SCHEMA_VERSION_KEY)?.map(|v| v.value());
+    Ok(version)
+}
+
+/// The tables of the unversioned schema which later migrations read.
+mod v0 {
+    use super::*;
+
+    pub const NAMESPACE_NODES: TableDefinition<[u8; 32], willow_store::NodeId> =
+        TableDefinition::new("namespace-nodes-0");
+}
+
+/// The tables added in version 1.
+mod v1 {
+    use super::*;
+
+    pub const PAYLOAD_REFCOUNT: TableDefinition<[u8; 32], u64> =
+        TableDefinition::new("payload-refcounts-0");
+}
+
+/// Version 1 is the first versioned schema. Compared to the unversioned schema of the first
+/// release, it adds the payload refcounts, the event log, the tables for sealed secrets and the
+/// index of partially stored payloads.
+///
+/// The refcounts are computed from the stored entries. All other new tables start empty, and are
+/// created with the tables of the current schema after the migrations ran.
+fn migrate_v1(tx: &WriteTransaction) -> Result<()> {
+    let namespace_nodes = tx.open_table(v0::NAMESPACE_NODES)?;
+    let node_store = willow_store::Tables::open(tx)?;
+    let mut refcounts = BTreeMap::<[u8; 32], u64>::new();
+    for item in namespace_nodes.iter()? {
+        let (_namespace, node_id) = item?;
+        let ns_node = willow_store::Node::<IrohWillowParams>::from(node_id.value());
+        for result in ns_node.query(&to_query(&Range3d::new_full()), &node_store) {
+            let (_point, stored_entry) = result?;
+            *refcounts.entry(stored_entry.payload_digest).or_default() += 1;
+        }
+    }
+    let mut payload_refcount = tx.open_table(v1::PAYLOAD_REFCOUNT)?;
+    for (digest, refcount) in refcounts {
+        payload_refcount.insert(digest, refcount)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::SeedableRng;
+
+    use super::*;
+    use crate::{
+        form::{AuthForm, EntryForm},
+        proto::{
+            data_model::{Path, PayloadDigest},
+            keys::{NamespaceKind, UserSecretKey},
+        },
+        store::traits::{EntryStorage, SecretStorage},
+    };
+
+    type TestStore = crate::store::Store<super::super::Store<iroh_blobs::store::mem::Store>>;
+
+    fn schema_version(db: &Database) -> Result<Option<u64>> {
+        let tx = db.begin_read()?;
+        let table = match tx.open_table(METADATA) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let version = table.get(SCHEMA_VERSION_KEY)?.map(|v| v.value());
+        Ok(version)
+    }
+
+    fn open(path: &std::path::Path) -> Result<TestStore> {
+        let store =
+            super::super::Store::new(path.to_path_buf(), iroh_blobs::store::mem::Store::default())?;
+        Ok(crate::store::Store::new(store))
+    }
+
+    /// Creates a database with entries and turns it into a fixture of the unversioned schema.
+    async fn create_v0_fixture(path: &std::path::Path) -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        {
+            let store = open(path)?;
+            let user = store
+                .secrets()
+                .insert_user(UserSecretKey::generate(&mut rng))?;
+            let namespace = store.create_namespace(&mut rng, NamespaceKind::Owned, user)?;
+            for (path, payload) in [("a", "1"), ("b", "2")] {
+                let path = Path::from_bytes(&[path.as_bytes()])?;
+                let entry = EntryForm::new_bytes(namespace, path, payload);
+                store
+                    .insert_entry(entry.into(), AuthForm::Any(user))
+                    .await?;
+            }
+            // Commit the open write transaction.
+            store.entries().snapshot()?;
+        }
+        // Delete the tables which did not exist in the unversioned schema.
+        let db = Database::create(path)?;
+        let tx = db.begin_write()?;
+        tx.delete_table(METADATA)?;
+        tx.delete_table(v1::PAYLOAD_REFCOUNT)?;
+        tx.delete_table(tables::EVENTS)?;
+        tx.delete_table(tables::SEALED_USER_SECRETS)?;
+        tx.delete_table(tables::SEALED_NAMESPACE_SECRETS)?;
+        tx.delete_table(tables::SECRETS_ENCRYPTION)?;
+        tx.delete_multimap_table(tables::PARTIAL_PAYLOADS)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_with_current_version() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("willow.db");
+        drop(open(&path)?);
+        let db = Database::create(&path)?;
+        assert_eq!(schema_version(&db)?, Some(SCHEMA_VERSION));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_from_v0() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("willow.db");
+        create_v0_fixture(&path).await?;
+        assert_eq!(schema_version(&Database::create(&path)?)?, None);
+
+        let store = open(&path)?;
+        let mut referenced = store.entries().referenced_payloads()?;
+        referenced.sort();
+        let mut expected: Vec<_> = ["1", "2"]
+            .iter()
+            .map(|payload| PayloadDigest(iroh_blobs::Hash::new(payload)))
+            .collect();
+        expected.sort();
+        assert_eq!(referenced, expected);
+        drop(store);
+
+        let db = Database::create(&path)?;
+        assert_eq!(schema_version(&db)?, Some(SCHEMA_VERSION));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuse_newer_version() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("willow.db");
+        drop(open(&path)?);
+        {
+            let db = Database::create(&path)?;
+            let tx = db.begin_write()?;
+            tx.open_table(METADATA)?
+                .insert(SCHEMA_VERSION_KEY, SCHEMA_VERSION + 1)?;
+            tx.commit()?;
+        }
+        assert!(open(&path).is_err());
+        let db = Database::create(&path)?;
+        assert_eq!(schema_version(&db)?, Some(SCHEMA_VERSION + 1));
+        Ok(())
+    }
+}
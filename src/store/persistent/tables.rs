@@ -9,14 +9,22 @@ use redb::{
 use ufotofu::sync::{consumer::IntoVec, producer::FromSlice};
 use willow_encoding::sync::{RelativeDecodable, RelativeEncodable};
 
-use crate::proto::{
-    grouping::Area,
-    meadowcap::{serde_encoding::SerdeReadAuthorisation, McCapability, ReadAuthorisation},
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    proto::{
+        data_model::{self, Entry},
+        grouping::Area,
+        meadowcap::{serde_encoding::SerdeReadAuthorisation, McCapability, ReadAuthorisation},
+    },
+    store::EntryOrigin,
 };
 
 // These consts are here so we don't accidentally break the schema!
+// Changes to the schema need a migration, see `super::migrations`.
 pub type NamespaceId = [u8; 32];
 pub type UserId = [u8; 32];
+pub type PayloadDigest = [u8; 32];
 
 pub const NAMESPACE_NODES: TableDefinition<NamespaceId, willow_store::NodeId> =
     TableDefinition::new("namespace-nodes-0");
@@ -26,15 +34,42 @@ pub const AUTH_TOKENS: TableDefinition<ed25519::SignatureBytes, WriteCap> =
 pub const AUTH_TOKEN_REFCOUNT: TableDefinition<ed25519::SignatureBytes, u64> =
     TableDefinition::new("auth-token-refcounts-0");
 
+/// Number of stored entries referencing each payload.
+pub const PAYLOAD_REFCOUNT: TableDefinition<PayloadDigest, u64> =
+    TableDefinition::new("payload-refcounts-0");
+
 pub const USER_SECRETS: TableDefinition<UserId, [u8; 32]> = TableDefinition::new("user-secrets-0");
 pub const NAMESPACE_SECRETS: TableDefinition<NamespaceId, [u8; 32]> =
     TableDefinition::new("namespaces-secrets-0");
 
+/// Secrets encrypted at rest, see [`crate::store::encrypted`].
+pub const SEALED_USER_SECRETS: TableDefinition<UserId, &[u8]> =
+    TableDefinition::new("sealed-user-secrets-0");
+pub const SEALED_NAMESPACE_SECRETS: TableDefinition<NamespaceId, &[u8]> =
+    TableDefinition::new("sealed-namespace-secrets-0");
+/// Parameters of the encryption of secrets, keyed by name.
+pub const SECRETS_ENCRYPTION: TableDefinition<&str, &[u8]> =
+    TableDefinition::new("secrets-encryption-0");
+pub const SECRETS_SALT_KEY: &str = "salt";
+pub const SECRETS_KEY_CHECK_KEY: &str = "key-check";
+
 pub const READ_CAPS: MultimapTableDefinition<NamespaceId, ReadCap> =
     MultimapTableDefinition::new("read-caps-0");
 pub const WRITE_CAPS: MultimapTableDefinition<NamespaceId, WriteCap> =
     MultimapTableDefinition::new("write-caps-0");
 
+/// Entries whose payloads are only partially stored, encoded with postcard, by namespace.
+pub const PARTIAL_PAYLOADS: MultimapTableDefinition<NamespaceId, &[u8]> =
+    MultimapTableDefinition::new("partial-payloads-0");
+
+/// The event log, keyed by namespace and progress id, with [`LoggedEvent`]s encoded with postcard.
+pub const EVENTS: TableDefinition<(NamespaceId, u64), &[u8]> = TableDefinition::new("events-0");
+
+/// Metadata of the database, keyed by name.
+pub const METADATA: TableDefinition<&str, u64> = TableDefinition::new("metadata-0");
+/// Key of the schema version in the [`METADATA`] table.
+pub const SCHEMA_VERSION_KEY: &str = "schema-version";
+
 self_cell::self_cell! {
     struct OpenWriteInner {
         owner: WriteTransaction,
@@ -78,10 +113,16 @@ pub struct Tables<'tx> {
     pub namespace_nodes: Table<'tx, NamespaceId, willow_store::NodeId>,
     pub auth_tokens: Table<'tx, ed25519::SignatureBytes, WriteCap>,
     pub auth_token_refcount: Table<'tx, ed25519::SignatureBytes, u64>,
+    pub payload_refcount: Table<'tx, PayloadDigest, u64>,
     pub user_secrets: Table<'tx, UserId, [u8; 32]>,
     pub namespace_secrets: Table<'tx, NamespaceId, [u8; 32]>,
+    pub sealed_user_secrets: Table<'tx, UserId, &'static [u8]>,
+    pub sealed_namespace_secrets: Table<'tx, NamespaceId, &'static [u8]>,
+    pub secrets_encryption: Table<'tx, &'static str, &'static [u8]>,
     pub read_caps: MultimapTable<'tx, NamespaceId, ReadCap>,
     pub write_caps: MultimapTable<'tx, NamespaceId, WriteCap>,
+    pub partial_payloads: MultimapTable<'tx, NamespaceId, &'static [u8]>,
+    pub events: Table<'tx, (NamespaceId, u64), &'static [u8]>,
     pub node_store: willow_store::Tables<'tx>,
 }
 
@@ -91,10 +132,16 @@ impl<'tx> Tables<'tx> {
             namespace_nodes: tx.open_table(NAMESPACE_NODES)?,
             auth_tokens: tx.open_table(AUTH_TOKENS)?,
             auth_token_refcount: tx.open_table(AUTH_TOKEN_REFCOUNT)?,
+            payload_refcount: tx.open_table(PAYLOAD_REFCOUNT)?,
             user_secrets: tx.open_table(USER_SECRETS)?,
             namespace_secrets: tx.open_table(NAMESPACE_SECRETS)?,
+            sealed_user_secrets: tx.open_table(SEALED_USER_SECRETS)?,
+            sealed_namespace_secrets: tx.open_table(SEALED_NAMESPACE_SECRETS)?,
+            secrets_encryption: tx.open_table(SECRETS_ENCRYPTION)?,
             read_caps: tx.open_multimap_table(READ_CAPS)?,
             write_caps: tx.open_multimap_table(WRITE_CAPS)?,
+            partial_payloads: tx.open_multimap_table(PARTIAL_PAYLOADS)?,
+            events: tx.open_table(EVENTS)?,
             node_store: willow_store::Tables::open(tx)?,
         })
     }
@@ -103,8 +150,11 @@ impl<'tx> Tables<'tx> {
 pub struct OpenRead {
     pub namespace_nodes: ReadOnlyTable<NamespaceId, willow_store::NodeId>,
     pub auth_tokens: ReadOnlyTable<ed25519::SignatureBytes, WriteCap>,
+    pub payload_refcount: ReadOnlyTable<PayloadDigest, u64>,
     pub read_caps: ReadOnlyMultimapTable<NamespaceId, ReadCap>,
     pub write_caps: ReadOnlyMultimapTable<NamespaceId, WriteCap>,
+    pub partial_payloads: ReadOnlyMultimapTable<NamespaceId, &'static [u8]>,
+    pub events: ReadOnlyTable<(NamespaceId, u64), &'static [u8]>,
     pub node_store: willow_store::Snapshot,
 }
 
@@ -113,8 +163,11 @@ impl OpenRead {
         Ok(Self {
             namespace_nodes: tx.open_table(NAMESPACE_NODES)?,
             auth_tokens: tx.open_table(AUTH_TOKENS)?,
+            payload_refcount: tx.open_table(PAYLOAD_REFCOUNT)?,
             read_caps: tx.open_multimap_table(READ_CAPS)?,
             write_caps: tx.open_multimap_table(WRITE_CAPS)?,
+            partial_payloads: tx.open_multimap_table(PARTIAL_PAYLOADS)?,
+            events: tx.open_table(EVENTS)?,
             node_store: willow_store::Snapshot::open(tx)?,
         })
     }
@@ -217,3 +270,47 @@ impl redb::Value for ReadCap {
         redb::TypeName::new("ReadCap")
     }
 }
+
+/// An entry referenced from the event log.
+///
+/// The authorisation token of the entry is not stored in the event, but in [`AUTH_TOKENS`],
+/// keyed by its signature. Each logged reference counts towards [`AUTH_TOKEN_REFCOUNT`] until
+/// the event is compacted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoggedEntry {
+    #[serde(with = "data_model::serde_encoding::entry")]
+    pub entry: Entry,
+    pub token_id: ed25519::Signature,
+}
+
+/// An event in the event log, see [`StoreEvent`].
+///
+/// The progress id is the key of the event, and the namespace is part of the key too.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LoggedEvent {
+    Ingested(LoggedEntry, EntryOrigin),
+    Pruned {
+        pruned: LoggedEntry,
+        by: LoggedEntry,
+    },
+    EntryForgotten(LoggedEntry),
+    AreaForgotten(#[serde(with = "crate::proto::grouping::serde_encoding::area")] Area),
+    PayloadForgotten(LoggedEntry),
+    /// An event about an entry which was forgotten since.
+    Redacted,
+}
+
+impl LoggedEvent {
+    /// Returns the entries referenced by the event.
+    pub fn entries(&self) -> impl Iterator<Item = &LoggedEntry> {
+        let (a, b) = match self {
+            LoggedEvent::Ingested(entry, _) => (Some(entry), None),
+            LoggedEvent::Pruned { pruned, by } => (Some(pruned), Some(by)),
+            LoggedEvent::EntryForgotten(entry) => (Some(entry), None),
+            LoggedEvent::AreaForgotten(_) => (None, None),
+            LoggedEvent::PayloadForgotten(entry) => (Some(entry), None),
+            LoggedEvent::Redacted => (None, None),
+        };
+        a.into_iter().chain(b)
+    }
+}
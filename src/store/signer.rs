@@ -0,0 +1,435 @@
+//! Signing through a [`SecretStorage`], and secret stores backed by an external signer.
+//!
+//! All signatures created by the store go through [`SecretStorage::sign_user`] and
+//! [`SecretStorage::sign_namespace`], so the raw secret keys never have to be read from the secret
+//! store. [`SignerStorage`] builds on this: it wraps a [`Storage`] and replaces its secret store
+//! with [`ExternalSecrets`], which forwards all signing requests to an [`ExternalSigner`], e.g. an
+//! agent in another process which is reached over a Unix socket. The secret keys then never live
+//! in the thread of the Willow engine.
+//!
+//! The external signer runs on a thread of its own, but signing is synchronous: the thread of the
+//! Willow engine blocks until the signer replies, and all sessions wait meanwhile. The wait is
+//! bounded by a timeout, see [`ExternalSecrets::with_timeout`], so a signer which hangs fails the
+//! request instead of blocking the engine for good. Signers should reply quickly, e.g. without
+//! waiting for user confirmation.
+//!
+//! Secret stores backed by an external signer never return secret keys from
+//! [`SecretStorage::get_user`] and [`SecretStorage::get_namespace`]. Operations which need the raw
+//! keys, like exporting secrets in backups or ingesting sideloaded drops, are not supported.
+
+use std::{
+    fmt::Debug,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use ed25519_dalek::{ed25519, Signer};
+
+use super::traits::{SecretStorage, SecretStoreError, Storage};
+use crate::proto::{
+    keys::{
+        NamespaceId, NamespaceSecretKey, NamespaceSignature, UserId, UserSecretKey, UserSignature,
+    },
+    meadowcap,
+};
+
+/// Signs with a user key held by a [`SecretStorage`], without reading the key.
+#[derive(Debug)]
+pub(crate) struct UserSigner<'a, S> {
+    secrets: &'a S,
+    id: UserId,
+}
+
+impl<'a, S: SecretStorage> UserSigner<'a, S> {
+    pub fn new(secrets: &'a S, id: UserId) -> Self {
+        Self { secrets, id }
+    }
+}
+
+impl<S: SecretStorage> Signer<UserSignature> for UserSigner<'_, S> {
+    fn try_sign(&self, msg: &[u8]) -> Result<UserSignature, ed25519::Error> {
+        self.secrets
+            .sign_user(&self.id, msg)
+            .map_err(ed25519::Error::from_source)
+    }
+}
+
+/// Signs with a namespace key held by a [`SecretStorage`], without reading the key.
+#[derive(Debug)]
+pub(crate) struct NamespaceSigner<'a, S> {
+    secrets: &'a S,
+    id: NamespaceId,
+}
+
+impl<'a, S: SecretStorage> NamespaceSigner<'a, S> {
+    pub fn new(secrets: &'a S, id: NamespaceId) -> Self {
+        Self { secrets, id }
+    }
+}
+
+impl<S: SecretStorage> Signer<NamespaceSignature> for NamespaceSigner<'_, S> {
+    fn try_sign(&self, msg: &[u8]) -> Result<NamespaceSignature, ed25519::Error> {
+        self.secrets
+            .sign_namespace(&self.id, msg)
+            .map_err(ed25519::Error::from_source)
+    }
+}
+
+/// Default for how long to wait for a reply of an [`ExternalSigner`].
+///
+/// The engine is blocked while it waits, so this is kept short.
+pub const DEFAULT_SIGNER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A signer which holds secret keys outside of the Willow engine.
+///
+/// The methods are called from a thread of the signer, and may block, e.g. to wait for the reply
+/// of a signing agent.
+pub trait ExternalSigner: Debug + Send + 'static {
+    /// Signs `message` with the user key `id`.
+    ///
+    /// Returns `None` if the signer does not hold the secret key for `id`.
+    fn sign_user(&self, id: &UserId, message: &[u8]) -> Result<Option<UserSignature>>;
+
+    /// Signs `message` with the namespace key `id`.
+    ///
+    /// Returns `None` if the signer does not hold the secret key for `id`.
+    fn sign_namespace(
+        &self,
+        id: &NamespaceId,
+        message: &[u8],
+    ) -> Result<Option<NamespaceSignature>>;
+
+    /// Returns `true` if the signer holds the secret key for the user `id`.
+    fn has_user(&self, id: &UserId) -> Result<bool>;
+
+    /// Returns `true` if the signer holds the secret key for the namespace `id`.
+    fn has_namespace(&self, id: &NamespaceId) -> Result<bool>;
+
+    /// Hands a new secret key to the signer.
+    ///
+    /// This is used when the engine creates users or namespaces. By default, the signer does not
+    /// accept secret keys, and keys have to be created with the signer itself.
+    fn insert(&self, secret: meadowcap::SecretKey) -> Result<()> {
+        let _ = secret;
+        bail!("the external signer does not accept secret keys")
+    }
+}
+
+/// A request to the thread of an [`ExternalSigner`].
+type SignerRequest<T> = Box<dyn FnOnce(&T) + Send>;
+
+/// A [`SecretStorage`] which forwards all signing requests to an [`ExternalSigner`].
+///
+/// The signer is moved to a thread of its own, which stops once all clones of the store are
+/// dropped. See the [module documentation](self) for details.
+#[derive(derive_more::Debug)]
+pub struct ExternalSecrets<T> {
+    #[debug("mpsc::Sender")]
+    requests: mpsc::Sender<SignerRequest<T>>,
+    timeout: Duration,
+}
+
+impl<T> Clone for ExternalSecrets<T> {
+    fn clone(&self) -> Self {
+        Self {
+            requests: self.requests.clone(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+impl<T: ExternalSigner> ExternalSecrets<T> {
+    pub fn new(signer: T) -> Self {
+        let (requests, requests_rx) = mpsc::channel::<SignerRequest<T>>();
+        std::thread::Builder::new()
+            .name("willow-signer".to_string())
+            .spawn(move || {
+                for request in requests_rx {
+                    request(&signer);
+                }
+            })
+            .expect("failed to spawn willow-signer thread");
+        Self {
+            requests,
+            timeout: DEFAULT_SIGNER_TIMEOUT,
+        }
+    }
+
+    /// Sets how long to wait for a reply of the signer.
+    ///
+    /// The thread of the Willow engine blocks while it waits, which stalls all sessions. Defaults
+    /// to [`DEFAULT_SIGNER_TIMEOUT`].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Runs `f` on the thread of the signer, and waits for its reply.
+    fn call<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&T) -> Result<R> + Send + 'static,
+    ) -> Result<R> {
+        let (reply, reply_rx) = mpsc::sync_channel(1);
+        self.requests
+            .send(Box::new(move |signer| {
+                reply.send(f(signer)).ok();
+            }))
+            .map_err(|_| anyhow!("the external signer stopped"))?;
+        match reply_rx.recv_timeout(self.timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => Err(anyhow!(
+                "the external signer did not reply within {:?}",
+                self.timeout
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(anyhow!("the external signer stopped")),
+        }
+    }
+}
+
+impl<T: ExternalSigner> SecretStorage for ExternalSecrets<T> {
+    fn insert(&self, secret: meadowcap::SecretKey) -> Result<(), SecretStoreError> {
+        Ok(self.call(move |signer| signer.insert(secret))?)
+    }
+
+    fn get_user(&self, _id: &UserId) -> Result<Option<UserSecretKey>> {
+        Ok(None)
+    }
+
+    fn get_namespace(&self, _id: &NamespaceId) -> Result<Option<NamespaceSecretKey>> {
+        Ok(None)
+    }
+
+    fn list_secrets(&self) -> Result<Vec<meadowcap::SecretKey>> {
+        Ok(vec![])
+    }
+
+    fn has_user(&self, id: &UserId) -> Result<bool> {
+        let id = *id;
+        self.call(move |signer| signer.has_user(&id))
+    }
+
+    fn has_namespace(&self, id: &NamespaceId) -> Result<bool> {
+        let id = *id;
+        self.call(move |signer| signer.has_namespace(&id))
+    }
+
+    fn sign_user(&self, id: &UserId, message: &[u8]) -> Result<UserSignature, SecretStoreError> {
+        let (id, message) = (*id, message.to_vec());
+        self.call(move |signer| signer.sign_user(&id, &message))?
+            .ok_or(SecretStoreError::MissingKey)
+    }
+
+    fn sign_namespace(
+        &self,
+        id: &NamespaceId,
+        message: &[u8],
+    ) -> Result<NamespaceSignature, SecretStoreError> {
+        let (id, message) = (*id, message.to_vec());
+        self.call(move |signer| signer.sign_namespace(&id, &message))?
+            .ok_or(SecretStoreError::MissingKey)
+    }
+}
+
+/// A [`Storage`] whose secret keys are held by an [`ExternalSigner`].
+#[derive(Debug)]
+pub struct SignerStorage<S: Storage, T> {
+    inner: S,
+    secrets: ExternalSecrets<T>,
+}
+
+impl<S: Storage, T> Clone for SignerStorage<S, T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            secrets: self.secrets.clone(),
+        }
+    }
+}
+
+impl<S: Storage, T: ExternalSigner> SignerStorage<S, T> {
+    /// Wraps `storage`, and uses `signer` instead of its secret store.
+    pub fn new(storage: S, signer: T) -> Self {
+        Self {
+            inner: storage,
+            secrets: ExternalSecrets::new(signer),
+        }
+    }
+
+    /// Sets how long to wait for a reply of the signer, see [`ExternalSecrets::with_timeout`].
+    pub fn with_signer_timeout(mut self, timeout: Duration) -> Self {
+        self.secrets = self.secrets.with_timeout(timeout);
+        self
+    }
+}
+
+impl<S: Storage, T: ExternalSigner> Storage for SignerStorage<S, T> {
+    type Entries = S::Entries;
+    type Secrets = ExternalSecrets<T>;
+    type Payloads = S::Payloads;
+    type Caps = S::Caps;
+
+    fn entries(&self) -> &Self::Entries {
+        self.inner.entries()
+    }
+
+    fn secrets(&self) -> &Self::Secrets {
+        &self.secrets
+    }
+
+    fn payloads(&self) -> &Self::Payloads {
+        self.inner.payloads()
+    }
+
+    fn caps(&self) -> &Self::Caps {
+        self.inner.caps()
+    }
+
+    fn quotas(&self) -> super::traits::Quotas {
+        self.inner.quotas()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use rand_core::SeedableRng;
+
+    use super::*;
+    use crate::{
+        form::{AuthForm, EntryForm},
+        interest::{CapSelector, DelegateTo, RestrictArea},
+        proto::{data_model::Path, keys::NamespaceKind, meadowcap::AccessMode},
+        store::{memory, traits::EntryReader},
+    };
+
+    /// A signer which keeps the keys in memory, and counts the signatures it creates.
+    #[derive(Debug, Default)]
+    struct MockSigner {
+        users: RefCell<HashMap<UserId, UserSecretKey>>,
+        namespaces: RefCell<HashMap<NamespaceId, NamespaceSecretKey>>,
+        signatures: Arc<AtomicUsize>,
+        /// Delay before each user signature, to simulate a slow signing agent.
+        delay: Duration,
+    }
+
+    impl ExternalSigner for MockSigner {
+        fn sign_user(&self, id: &UserId, message: &[u8]) -> Result<Option<UserSignature>> {
+            std::thread::sleep(self.delay);
+            self.signatures.fetch_add(1, Ordering::Relaxed);
+            Ok(self.users.borrow().get(id).map(|key| key.sign(message)))
+        }
+
+        fn sign_namespace(
+            &self,
+            id: &NamespaceId,
+            message: &[u8],
+        ) -> Result<Option<NamespaceSignature>> {
+            self.signatures.fetch_add(1, Ordering::Relaxed);
+            Ok(self
+                .namespaces
+                .borrow()
+                .get(id)
+                .map(|key| key.sign(message)))
+        }
+
+        fn has_user(&self, id: &UserId) -> Result<bool> {
+            Ok(self.users.borrow().contains_key(id))
+        }
+
+        fn has_namespace(&self, id: &NamespaceId) -> Result<bool> {
+            Ok(self.namespaces.borrow().contains_key(id))
+        }
+
+        fn insert(&self, secret: meadowcap::SecretKey) -> Result<()> {
+            match secret {
+                meadowcap::SecretKey::User(key) => {
+                    self.users.borrow_mut().insert(key.id(), key);
+                }
+                meadowcap::SecretKey::Namespace(key) => {
+                    self.namespaces.borrow_mut().insert(key.id(), key);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn sign_with_external_signer() -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let signatures = Arc::new(AtomicUsize::new(0));
+        let signer = MockSigner {
+            signatures: signatures.clone(),
+            ..Default::default()
+        };
+        let storage = SignerStorage::new(
+            memory::Store::new(iroh_blobs::store::mem::Store::default()),
+            signer,
+        );
+        let store = crate::store::Store::new(storage);
+
+        let alfie = store
+            .secrets()
+            .insert_user(UserSecretKey::generate(&mut rng))?;
+        let betty = UserSecretKey::generate(&mut rng).id();
+        let namespace = store.create_namespace(&mut rng, NamespaceKind::Owned, alfie)?;
+        assert!(store.secrets().get_user(&alfie)?.is_none());
+        assert!(store.secrets().get_namespace(&namespace)?.is_none());
+        assert!(store.secrets().has_user(&alfie)?);
+        assert!(store.secrets().has_namespace(&namespace)?);
+
+        let path = Path::from_bytes(&[b"hello"])?;
+        let entry = EntryForm::new_bytes(namespace, path.clone(), "world");
+        let (entry, inserted) = store
+            .insert_entry(entry.into(), AuthForm::Any(alfie))
+            .await?;
+        assert!(inserted);
+        let stored = store
+            .entries()
+            .get_entry(namespace, alfie, &path)?
+            .expect("entry is stored");
+        assert_eq!(stored, entry);
+
+        let caps = store.auth().delegate_full_caps(
+            CapSelector::any(namespace),
+            AccessMode::Write,
+            DelegateTo::new(betty, RestrictArea::None),
+            false,
+        )?;
+        assert_eq!(caps.len(), 2);
+        assert!(signatures.load(Ordering::Relaxed) > 0);
+
+        // Signing with keys unknown to the signer fails.
+        let entry = EntryForm::new_bytes(namespace, path, "nope");
+        assert!(store
+            .insert_entry(entry.into(), AuthForm::Any(betty))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn external_signer_timeout() -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let signer = MockSigner {
+            delay: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let secrets = ExternalSecrets::new(signer).with_timeout(Duration::from_millis(10));
+        let alfie = secrets.insert_user(UserSecretKey::generate(&mut rng))?;
+        // The signer is too slow, so signing fails instead of blocking until it replies.
+        assert!(matches!(
+            secrets.sign_user(&alfie, b"hello"),
+            Err(SecretStoreError::Store(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,9 +1,13 @@
 //! Traits for storage backends for the Willow store.
 
-use std::fmt::Debug;
+use std::{
+    collections::{hash_map, HashMap},
+    fmt::Debug,
+};
 
 use anyhow::Result;
 use futures_lite::Stream;
+use rand_core::CryptoRngCore;
 use serde::{Deserialize, Serialize};
 use willow_data_model::grouping::{Range, RangeEnd};
 
@@ -11,8 +15,8 @@ use crate::{
     interest::{CapSelector, CapabilityPack},
     proto::{
         data_model::{
-            self, AuthorisedEntry, Entry, EntryExt as _, NamespaceId, Path, SubspaceId,
-            WriteCapability,
+            self, AuthorisedEntry, Entry, EntryExt as _, NamespaceId, Path, PayloadDigest,
+            SubspaceId, WriteCapability,
         },
         grouping::{Area, Range3d},
         keys::{NamespaceSecretKey, NamespaceSignature, UserId, UserSecretKey, UserSignature},
@@ -33,6 +37,13 @@ pub trait Storage: Debug + Clone + 'static {
     fn secrets(&self) -> &Self::Secrets;
     fn payloads(&self) -> &Self::Payloads;
     fn caps(&self) -> &Self::Caps;
+
+    /// Returns the [`Quotas`] for the entries in this storage.
+    ///
+    /// By default, no limits are enforced.
+    fn quotas(&self) -> Quotas {
+        Quotas::default()
+    }
 }
 
 /// Storage for user and namespace secrets.
@@ -41,12 +52,15 @@ pub trait SecretStorage: Debug + Clone + 'static {
     fn get_user(&self, id: &UserId) -> Result<Option<UserSecretKey>>;
     fn get_namespace(&self, id: &NamespaceId) -> Result<Option<NamespaceSecretKey>>;
 
+    /// Returns all user and namespace secrets in the store.
+    fn list_secrets(&self) -> Result<Vec<meadowcap::SecretKey>>;
+
     fn has_user(&self, id: &UserId) -> Result<bool> {
         Ok(self.get_user(id)?.is_some())
     }
 
-    fn has_namespace(&self, id: &UserId) -> Result<bool> {
-        Ok(self.get_user(id)?.is_some())
+    fn has_namespace(&self, id: &NamespaceId) -> Result<bool> {
+        Ok(self.get_namespace(id)?.is_some())
     }
 
     fn insert_user(&self, secret: UserSecretKey) -> Result<UserId, SecretStoreError> {
@@ -79,6 +93,33 @@ pub trait SecretStorage: Debug + Clone + 'static {
             .ok_or(SecretStoreError::MissingKey)?
             .sign(message))
     }
+
+    /// Returns `true` if the secrets are encrypted at rest and the store is locked.
+    ///
+    /// See [`super::encrypted`] for secret stores which can be locked.
+    fn is_locked(&self) -> bool {
+        false
+    }
+
+    /// Locks the store, if its secrets are encrypted at rest.
+    fn lock(&self) {}
+
+    /// Sets the passphrase or key of the store, if its secrets are encrypted at rest.
+    ///
+    /// This encrypts the secrets which are stored in plaintext, and unlocks the store. It fails
+    /// with [`SecretStoreError::KeyAlreadySet`] if the store already has a key. Stores which keep
+    /// their secrets in plaintext ignore the credential.
+    fn set_passphrase(&self, _credential: &SecretsCredential) -> Result<(), SecretStoreError> {
+        Ok(())
+    }
+
+    /// Unlocks the store, if its secrets are encrypted at rest.
+    ///
+    /// Fails with [`SecretStoreError::NoKey`] if no key was set with [`Self::set_passphrase`].
+    /// Stores which keep their secrets in plaintext ignore the credential.
+    fn unlock(&self, _credential: &SecretsCredential) -> Result<(), SecretStoreError> {
+        Ok(())
+    }
 }
 
 /// Storage for entries.
@@ -94,6 +135,78 @@ pub trait EntryStorage: EntryReader + Clone + Debug + 'static {
     /// Returns `true` if the entry was ingested, and `false` if the entry was not ingested because a newer entry exists.
     fn ingest_entry(&self, entry: &AuthorisedEntry, origin: EntryOrigin) -> Result<bool>;
 
+    /// Locally forget an entry.
+    ///
+    /// Forgetting an entry leaves no trace. Earlier events about the entry are removed from the
+    /// event log of stores which keep one, only the event about forgetting it is recorded. The
+    /// entry may be ingested again later, e.g. when syncing with a peer which still has it.
+    ///
+    /// Returns the forgotten entry, or `None` if there is no entry at `subspace` and `path`.
+    fn forget_entry(
+        &self,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: &Path,
+    ) -> Result<Option<AuthorisedEntry>>;
+
+    /// Locally forget all entries [included](https://willowprotocol.org/specs/grouping-entries/index.html#area_include)
+    /// in an [`Area`].
+    ///
+    /// Returns the number of forgotten entries.
+    fn forget_area(&self, namespace: NamespaceId, area: &Area) -> Result<u64>;
+
+    /// Record that the payload of an entry was forgotten locally.
+    ///
+    /// Payloads are kept in the payload store, so this only emits a
+    /// [`StoreEvent::PayloadForgotten`] event. Removing the payload is up to the caller.
+    ///
+    /// Returns the entry, or `None` if there is no entry at `subspace` and `path`.
+    fn forget_payload(
+        &self,
+        namespace: NamespaceId,
+        subspace: SubspaceId,
+        path: &Path,
+    ) -> Result<Option<AuthorisedEntry>>;
+
+    /// Returns the number of stored entries which reference the payload with `digest`.
+    fn payload_references(&self, digest: &PayloadDigest) -> Result<u64>;
+
+    /// Returns the [`Usage`] of a namespace, or of one of its subspaces, as counted for `quotas`.
+    ///
+    /// Payloads over the maximum payload size of `quotas` are not counted. Stores count the usage
+    /// of a namespace once, and then keep the counters up to date as entries are ingested, pruned
+    /// or forgotten, so this does not iterate the entries.
+    fn quota_usage(
+        &self,
+        namespace: NamespaceId,
+        subspace: Option<SubspaceId>,
+        quotas: &Quotas,
+    ) -> Result<Usage>;
+
+    /// Returns the digests of all payloads which are referenced by at least one stored entry.
+    ///
+    /// This is the live set of payloads which must be protected from garbage collection in the
+    /// payload store. Stores keep it up to date when entries are ingested, pruned or forgotten.
+    fn referenced_payloads(&self) -> Result<Vec<PayloadDigest>>;
+
+    /// Marks the payload of an entry as partially stored, or clears the mark.
+    ///
+    /// Sessions mark a payload once they start to receive it, and clear the mark once the
+    /// payload is complete. Payloads which stay marked were interrupted, and are resumed in later
+    /// sessions, see [`Self::partial_payloads`].
+    fn set_partial_payload(&self, entry: &Entry, partial: bool) -> Result<()>;
+
+    /// Returns the stored entries in `area` whose payloads are marked as partially stored.
+    ///
+    /// Marks of entries which were pruned or forgotten in the meantime are skipped.
+    fn partial_payloads(&self, namespace: NamespaceId, area: &Area)
+        -> Result<Vec<AuthorisedEntry>>;
+
+    /// Returns the progress id of the next event in `namespace`.
+    ///
+    /// Pass it to [`Self::resume_subscription`] later on, to receive all events from now on.
+    fn next_progress_id(&self, namespace: NamespaceId) -> u64;
+
     /// Subscribe to events concerning entries [included](https://willowprotocol.org/specs/grouping-entries/index.html#area_include)
     /// by an [`AreaOfInterest`], returning a producer of `StoreEvent`s which occurred since the moment of calling this function.
     ///
@@ -128,6 +241,13 @@ pub trait EntryReader: Debug + 'static {
         Ok(fingerprint)
     }
 
+    /// Splits `range` into up to [`SplitOpts::split_factor`] subranges with roughly the same
+    /// number of entries, and returns what to send to the other peer for each of them.
+    ///
+    /// The default implementation loads all entries in the range, and takes linear time in their
+    /// number. Stores which keep aggregated data for their entries should override it. Both the
+    /// [`memory`](super::memory) and the [`persistent`](super::persistent) store keep their
+    /// entries in a [`willow_store`] tree, and split along it in logarithmic time.
     fn split_range(
         &self,
         namespace: NamespaceId,
@@ -145,57 +265,83 @@ pub trait EntryReader: Debug + 'static {
             .filter_map(|e| e.ok())
             .collect();
 
-        entries.sort_by(|e1, e2| e1.as_sortable_tuple().cmp(&e2.as_sortable_tuple()));
-
-        let split_index = count / 2;
-        let mid = entries.get(split_index).expect("not empty");
-        let mut ranges = vec![];
-        // split in two halves by subspace
-        if *mid.subspace_id() != range.subspaces().start {
-            ranges.push(Range3d::new(
-                Range::new_closed(range.subspaces().start, *mid.subspace_id()).unwrap(),
-                range.paths().clone(),
-                *range.times(),
-            ));
-            ranges.push(Range3d::new(
-                Range::new(*mid.subspace_id(), range.subspaces().end),
-                range.paths().clone(),
-                *range.times(),
-            ));
+        // Select up to `split_factor - 1` entries at evenly spaced positions in the sort order as
+        // the split points. Each selection partitions the list, so that the next selection only
+        // has to look at the entries after the previous split point, and the list is never fully
+        // sorted.
+        let split_factor = config.split_factor.max(2);
+        let len = entries.len();
+        let mut split_entries = Vec::with_capacity(split_factor - 1);
+        let mut offset = 0;
+        for i in 1..split_factor {
+            let index = i * len / split_factor;
+            if index < offset || index >= len {
+                continue;
+            }
+            let (_, entry, _) = entries[offset..].select_nth_unstable_by(index - offset, |a, b| {
+                a.as_sortable_tuple().cmp(&b.as_sortable_tuple())
+            });
+            split_entries.push(entry.clone());
+            offset = index + 1;
+        }
+        let subspaces = split_points(
+            &range.subspaces().start,
+            split_entries.iter().map(|e| *e.subspace_id()),
+        );
+        let paths = split_points(
+            &range.paths().start,
+            split_entries.iter().map(|e| e.path().clone()),
+        );
+        let times = split_points(
+            &range.times().start,
+            split_entries.iter().map(|e| e.timestamp()),
+        );
+        // split by subspace
+        let ranges: Vec<_> = if !subspaces.is_empty() {
+            split_range_at(range.subspaces(), subspaces)
+                .map(|subspaces| Range3d::new(subspaces, range.paths().clone(), *range.times()))
+                .collect()
         }
         // split by path
-        else if *mid.path() != range.paths().start {
-            ranges.push(Range3d::new(
-                *range.subspaces(),
-                Range::new(
-                    range.paths().start.clone(),
-                    RangeEnd::Closed(mid.path().clone()),
-                ),
-                *range.times(),
-            ));
-            ranges.push(Range3d::new(
-                *range.subspaces(),
-                Range::new(mid.path().clone(), range.paths().end.clone()),
-                *range.times(),
-            ));
+        else if !paths.is_empty() {
+            split_range_at(range.paths(), paths)
+                .map(|paths| Range3d::new(*range.subspaces(), paths, *range.times()))
+                .collect()
+        }
         // split by time
-        } else {
-            ranges.push(Range3d::new(
-                *range.subspaces(),
-                range.paths().clone(),
-                Range::new(range.times().start, RangeEnd::Closed(mid.timestamp())),
-            ));
-            ranges.push(Range3d::new(
-                *range.subspaces(),
-                range.paths().clone(),
-                Range::new(mid.timestamp(), range.times().end),
-            ));
+        else {
+            split_range_at(range.times(), times)
+                .map(|times| Range3d::new(*range.subspaces(), range.paths().clone(), times))
+                .collect()
+        };
+        // If the range cannot be split, send its entries instead.
+        if ranges.len() < 2 {
+            return Ok(
+                vec![Ok((range.clone(), SplitAction::SendEntries(count as u64)))].into_iter(),
+            );
         }
-        let mut out = vec![];
-        for range in ranges {
-            let fingerprint = self.fingerprint(namespace, &range)?;
-            out.push(Ok((range, SplitAction::SendFingerprint(fingerprint))));
+
+        // Compute the fingerprints and counts of all subranges in a single pass over the entries
+        // we loaded already.
+        let mut summaries = vec![(Fingerprint::default(), 0u64); ranges.len()];
+        for entry in &entries {
+            if let Some(i) = ranges.iter().position(|range| range.includes_entry(entry)) {
+                summaries[i].0.add_entry(entry);
+                summaries[i].1 += 1;
+            }
         }
+        let out = ranges
+            .into_iter()
+            .zip(summaries)
+            .map(|(range, (fingerprint, count))| {
+                let action = if count <= config.max_set_size as u64 {
+                    SplitAction::SendEntries(count)
+                } else {
+                    SplitAction::SendFingerprint(fingerprint)
+                };
+                Ok((range, action))
+            })
+            .collect::<Vec<_>>();
         Ok(out.into_iter())
     }
 
@@ -203,6 +349,21 @@ pub trait EntryReader: Debug + 'static {
         Ok(self.get_entries(namespace, range)?.count() as u64)
     }
 
+    /// Returns the number of entries in `range` and the sum of their payload lengths.
+    ///
+    /// This iterates all entries in `range`. To check [`Quotas`], use
+    /// [`EntryStorage::quota_usage`], which is kept up to date incrementally.
+    fn usage(&self, namespace: NamespaceId, range: &Range3d) -> Result<Usage> {
+        let mut usage = Usage::default();
+        for entry in self.get_entries(namespace, range)? {
+            usage.add_entry(&entry?);
+        }
+        Ok(usage)
+    }
+
+    /// Returns the ids of all namespaces with entries in the store.
+    fn namespaces(&self) -> Result<Vec<NamespaceId>>;
+
     fn get_entry(
         &self,
         namespace: NamespaceId,
@@ -227,6 +388,31 @@ pub trait EntryReader: Debug + 'static {
     }
 }
 
+/// Returns the distinct values from `points` which are greater than `start`, in ascending order.
+fn split_points<T: Ord>(start: &T, points: impl Iterator<Item = T>) -> Vec<T> {
+    let mut points: Vec<T> = points.filter(|point| point > start).collect();
+    points.sort();
+    points.dedup();
+    points
+}
+
+/// Splits `range` into consecutive ranges which start at `start` and at each of the `points`.
+///
+/// The `points` must be sorted, distinct, and included in `range`.
+fn split_range_at<T: Ord + Clone>(
+    range: &Range<T>,
+    points: Vec<T>,
+) -> impl Iterator<Item = Range<T>> {
+    let mut out = Vec::with_capacity(points.len() + 1);
+    let mut start = range.start.clone();
+    for point in points {
+        out.push(Range::new(start, RangeEnd::Closed(point.clone())));
+        start = point;
+    }
+    out.push(Range::new(start, range.end.clone()));
+    out.into_iter()
+}
+
 /// Error returned from [`SecretStorage`].
 #[derive(Debug, thiserror::Error)]
 pub enum SecretStoreError {
@@ -234,6 +420,14 @@ pub enum SecretStoreError {
     Store(#[from] anyhow::Error),
     #[error("missing secret key")]
     MissingKey,
+    #[error("the secret store is locked")]
+    Locked,
+    #[error("invalid key to unlock the secret store")]
+    InvalidKey,
+    #[error("no key was set for the secret store")]
+    NoKey,
+    #[error("the secret store already has a key")]
+    KeyAlreadySet,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -242,6 +436,52 @@ pub enum KeyScope {
     User,
 }
 
+/// A symmetric key to encrypt secret keys at rest.
+///
+/// See [`super::encrypted`] for secret stores which are encrypted at rest.
+#[derive(Clone)]
+pub struct SecretsKey([u8; 32]);
+
+impl Debug for SecretsKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretsKey(..)")
+    }
+}
+
+impl SecretsKey {
+    /// Creates a key from bytes supplied by the application.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Returns the bytes of this key, e.g. to store them in a keyring.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    /// Generates a new random key.
+    pub fn generate<R: CryptoRngCore + ?Sized>(rng: &mut R) -> Self {
+        let mut bytes = [0u8; 32];
+        rng.fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+}
+
+/// Credential to set or unlock the key of a secret store which is encrypted at rest.
+#[derive(derive_more::Debug, Clone)]
+pub enum SecretsCredential {
+    /// A passphrase, from which the key is derived with the salt of the store.
+    Passphrase(#[debug("..")] String),
+    /// A key supplied by the application.
+    Key(SecretsKey),
+}
+
+impl From<SecretsKey> for SecretsCredential {
+    fn from(key: SecretsKey) -> Self {
+        Self::Key(key)
+    }
+}
+
 pub type RangeSplit = (Range3d, SplitAction);
 
 #[derive(Debug)]
@@ -267,6 +507,188 @@ impl Default for SplitOpts {
     }
 }
 
+/// Limits for the entries in a namespace or subspace.
+///
+/// All limits are unset by default.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Quota {
+    /// The maximum number of entries.
+    pub max_entries: Option<u64>,
+    /// The maximum sum of the payload lengths of all entries.
+    pub max_payload_bytes: Option<u64>,
+    /// The maximum payload length of a single entry.
+    ///
+    /// Entries from other peers with larger payloads are stored, but their payloads are skipped
+    /// and do not count towards `max_payload_bytes`.
+    pub max_payload_size: Option<u64>,
+}
+
+/// Storage quotas, returned from [`Storage::quotas`].
+///
+/// Entries which would exceed the limits of their namespace or subspace are rejected, unless
+/// they prune enough entries to stay within the limits.
+///
+/// Entries from other peers are rejected one by one, and reported to the intents of the session
+/// with [`EventKind::EntryRejected`]. Because we do not store them, the other peer sends them again
+/// in each later reconciliation of their area.
+///
+/// [`EventKind::EntryRejected`]: crate::session::intents::EventKind::EntryRejected
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Quotas {
+    /// Limits for each namespace.
+    pub namespace: Quota,
+    /// Limits for each subspace within a namespace.
+    pub subspace: Quota,
+}
+
+impl Quotas {
+    /// Returns `true` if no limits are set.
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns `true` if a payload of `payload_length` bytes exceeds a maximum payload size.
+    pub fn is_oversized(&self, payload_length: u64) -> bool {
+        [
+            self.namespace.max_payload_size,
+            self.subspace.max_payload_size,
+        ]
+        .into_iter()
+        .flatten()
+        .any(|max| payload_length > max)
+    }
+
+    /// Returns the bytes of a payload of `payload_length` which count towards the quotas.
+    ///
+    /// Payloads over the maximum payload size are never stored, so they are not counted.
+    pub fn counted_payload_bytes(&self, payload_length: u64) -> u64 {
+        if self.is_oversized(payload_length) {
+            0
+        } else {
+            payload_length
+        }
+    }
+}
+
+/// The number of entries and the sum of their payload lengths in some range.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Usage {
+    pub entries: u64,
+    pub payload_bytes: u64,
+}
+
+impl Usage {
+    pub fn add_entry(&mut self, entry: &Entry) {
+        self.entries += 1;
+        self.payload_bytes += entry.payload_length();
+    }
+
+    /// Adds an entry whose payload counts with `payload_bytes`.
+    pub fn add(&mut self, payload_bytes: u64) {
+        self.entries += 1;
+        self.payload_bytes += payload_bytes;
+    }
+
+    /// Removes an entry whose payload counted with `payload_bytes`.
+    pub fn remove(&mut self, payload_bytes: u64) {
+        self.entries = self.entries.saturating_sub(1);
+        self.payload_bytes = self.payload_bytes.saturating_sub(payload_bytes);
+    }
+
+    /// Returns the usage without the entries counted in `other`.
+    pub fn saturating_sub(&self, other: &Usage) -> Usage {
+        Usage {
+            entries: self.entries.saturating_sub(other.entries),
+            payload_bytes: self.payload_bytes.saturating_sub(other.payload_bytes),
+        }
+    }
+
+    /// Checks the usage against the limits of `quota`.
+    pub fn check(&self, quota: &Quota, scope: QuotaScope) -> Result<(), QuotaExceeded> {
+        if quota.max_entries.is_some_and(|max| self.entries > max) {
+            return Err(QuotaExceeded::Entries(scope));
+        }
+        if quota
+            .max_payload_bytes
+            .is_some_and(|max| self.payload_bytes > max)
+        {
+            return Err(QuotaExceeded::PayloadBytes(scope));
+        }
+        Ok(())
+    }
+}
+
+/// The [`Usage`] of a namespace and of each of its subspaces, counted for some [`Quotas`].
+///
+/// Stores build the counters with a single pass over a namespace, and then update them as
+/// entries are ingested, pruned and forgotten.
+#[derive(Debug, Clone)]
+pub(crate) struct UsageCounters {
+    quotas: Quotas,
+    namespace: Usage,
+    subspaces: HashMap<SubspaceId, Usage>,
+}
+
+impl UsageCounters {
+    pub(crate) fn new(quotas: Quotas) -> Self {
+        Self {
+            quotas,
+            namespace: Usage::default(),
+            subspaces: Default::default(),
+        }
+    }
+
+    pub(crate) fn quotas(&self) -> &Quotas {
+        &self.quotas
+    }
+
+    /// Counts an entry in `subspace` with a payload of `payload_length`.
+    pub(crate) fn add(&mut self, subspace: SubspaceId, payload_length: u64) {
+        let bytes = self.quotas.counted_payload_bytes(payload_length);
+        self.namespace.add(bytes);
+        self.subspaces.entry(subspace).or_default().add(bytes);
+    }
+
+    /// Removes an entry counted with [`Self::add`].
+    pub(crate) fn remove(&mut self, subspace: SubspaceId, payload_length: u64) {
+        let bytes = self.quotas.counted_payload_bytes(payload_length);
+        self.namespace.remove(bytes);
+        if let hash_map::Entry::Occupied(mut usage) = self.subspaces.entry(subspace) {
+            usage.get_mut().remove(bytes);
+            if usage.get().entries == 0 {
+                usage.remove();
+            }
+        }
+    }
+
+    /// Returns the usage of the namespace, or of `subspace` if set.
+    pub(crate) fn get(&self, subspace: Option<SubspaceId>) -> Usage {
+        match subspace {
+            None => self.namespace,
+            Some(subspace) => self.subspaces.get(&subspace).copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// Whether a [`Quota`] applies to a namespace or subspace.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum QuotaScope {
+    Namespace,
+    Subspace,
+}
+
+/// Error for an entry which exceeds the [`Quotas`] of a store.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, thiserror::Error)]
+pub enum QuotaExceeded {
+    #[error("the maximum number of entries in the {0} is reached")]
+    Entries(QuotaScope),
+    #[error("the maximum payload bytes in the {0} are reached")]
+    PayloadBytes(QuotaScope),
+    #[error("the payload is larger than the maximum payload size in the {0}")]
+    PayloadSize(QuotaScope),
+}
+
 /// Capability storage.
 pub trait CapsStorage: Debug + Clone {
     fn insert(&self, cap: CapabilityPack) -> Result<()>;
@@ -296,14 +718,31 @@ pub enum StoreEvent {
         #[serde(with = "data_model::serde_encoding::authorised_entry")] AuthorisedEntry,
         EntryOrigin,
     ),
-    // PayloadForgotten(u64, PD),
     /// An entry was pruned via prefix pruning.
     Pruned(u64, PruneEvent),
+    /// An entry was forgotten.
+    EntryForgotten(
+        u64,
+        #[serde(with = "data_model::serde_encoding::authorised_entry")] AuthorisedEntry,
+    ),
+    /// All entries in an area of a namespace were forgotten.
+    AreaForgotten(
+        u64,
+        NamespaceId,
+        #[serde(with = "crate::proto::grouping::serde_encoding::area")] Area,
+    ),
+    /// The payload of an entry was forgotten.
+    PayloadForgotten(
+        u64,
+        #[serde(with = "data_model::serde_encoding::authorised_entry")] AuthorisedEntry,
+    ),
+    /// The subscription fell behind, and events were dropped before they could be delivered.
+    ///
+    /// The subscription continues with the oldest event still available, whose progress id is
+    /// included.
+    Lagged(u64),
     // /// An existing entry received a portion of its corresponding payload.
     // Appended(u64, LengthyAuthorisedEntry),
-    // /// An entry was forgotten.
-    // EntryForgotten(u64, (S, Path<MCL, MCC, MPL>)),
-    // /// A payload was forgotten.
 }
 
 impl StoreEvent {
@@ -311,6 +750,10 @@ impl StoreEvent {
         match self {
             StoreEvent::Ingested(id, _, _) => *id,
             StoreEvent::Pruned(id, _) => *id,
+            StoreEvent::EntryForgotten(id, _) => *id,
+            StoreEvent::AreaForgotten(id, _, _) => *id,
+            StoreEvent::PayloadForgotten(id, _) => *id,
+            StoreEvent::Lagged(id) => *id,
         }
     }
 }
@@ -335,6 +778,17 @@ impl StoreEvent {
                     && *pruned.entry().namespace_id() == namespace_id
                     && area.includes_entry(pruned.entry())
             }
+            StoreEvent::EntryForgotten(_, entry) | StoreEvent::PayloadForgotten(_, entry) => {
+                !params.ingest_only
+                    && *entry.entry().namespace_id() == namespace_id
+                    && area.includes_entry(entry.entry())
+            }
+            StoreEvent::AreaForgotten(_, namespace, forgotten) => {
+                !params.ingest_only
+                    && *namespace == namespace_id
+                    && area.intersection(forgotten).is_some()
+            }
+            StoreEvent::Lagged(_) => true,
         }
     }
 }
@@ -356,6 +810,9 @@ pub enum EntryOrigin {
     Local,
     /// The entry was sourced from another device, e.g. a networked sync session.
     Remote(u64),
+    /// The entry was imported from a drop created on another device, see
+    /// [`ActorHandle::ingest_drop`](crate::engine::ActorHandle::ingest_drop).
+    Sideload,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -406,9 +863,106 @@ impl SubscribeParams {
         match &self.ignore_remote {
             None => true,
             Some(ignored_session) => match origin {
-                EntryOrigin::Local => true,
+                EntryOrigin::Local | EntryOrigin::Sideload => true,
                 EntryOrigin::Remote(session) => session != ignored_session,
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use rand_core::SeedableRng;
+
+    use super::{EntryReader, EntryStorage, SecretStorage, SplitAction, SplitOpts};
+    use crate::{
+        form::{AuthForm, EntryForm},
+        proto::{
+            data_model::{AuthorisedEntry, NamespaceId, Path, PathExt, SubspaceId},
+            grouping::Range3d,
+            keys::{NamespaceKind, UserSecretKey},
+            wgps::Fingerprint,
+        },
+    };
+
+    /// Wraps a reader to use the default implementations of [`EntryReader`].
+    #[derive(Debug)]
+    struct DefaultReader<R>(R);
+
+    impl<R: EntryReader> EntryReader for DefaultReader<R> {
+        fn namespaces(&self) -> Result<Vec<NamespaceId>> {
+            self.0.namespaces()
+        }
+
+        fn get_entry(
+            &self,
+            namespace: NamespaceId,
+            subspace: SubspaceId,
+            path: &Path,
+        ) -> Result<Option<AuthorisedEntry>> {
+            self.0.get_entry(namespace, subspace, path)
+        }
+
+        fn get_authorised_entries<'a>(
+            &'a self,
+            namespace: NamespaceId,
+            range: &Range3d,
+        ) -> Result<impl Iterator<Item = Result<AuthorisedEntry>> + 'a> {
+            self.0.get_authorised_entries(namespace, range)
+        }
+    }
+
+    #[tokio::test]
+    async fn default_split_range_split_factor() -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let store = crate::store::Store::new(crate::store::memory::Store::new(
+            iroh_blobs::store::mem::Store::default(),
+        ));
+        let user = store
+            .secrets()
+            .insert_user(UserSecretKey::generate(&mut rng))?;
+        let namespace = store.create_namespace(&mut rng, NamespaceKind::Owned, user)?;
+        for i in 0..20 {
+            let path = Path::from_bytes(&[format!("{i:02}").as_bytes()])?;
+            let entry = EntryForm::new_bytes(namespace, path, "payload");
+            store
+                .insert_entry(entry.into(), AuthForm::Any(user))
+                .await?;
+        }
+
+        let reader = DefaultReader(store.entries().reader());
+        let range = Range3d::new_full();
+        let opts = SplitOpts {
+            max_set_size: 1,
+            split_factor: 4,
+        };
+        let splits = reader
+            .split_range(namespace, &range, &opts)?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(splits.len(), 4);
+        for (range, action) in &splits {
+            let mut fingerprint = Fingerprint::default();
+            let mut count = 0;
+            for entry in reader.get_entries(namespace, range)? {
+                fingerprint.add_entry(&entry?);
+                count += 1;
+            }
+            assert!(count > 0, "empty subrange {range:?}");
+            match action {
+                SplitAction::SendEntries(n) => assert_eq!(*n, count),
+                SplitAction::SendFingerprint(f) => assert_eq!(*f, fingerprint),
+            }
+        }
+        // Each entry is included in exactly one of the subranges.
+        for entry in reader.get_entries(namespace, &range)? {
+            let entry = entry?;
+            let including = splits
+                .iter()
+                .filter(|(range, _)| range.includes_entry(&entry))
+                .count();
+            assert_eq!(including, 1);
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     cmp::{self},
+    collections::VecDeque,
     future::poll_fn,
     io,
     marker::PhantomData,
@@ -68,6 +69,9 @@ pub fn inbound_channel<T: Decoder>(max_buffer_size: usize) -> (Writer, Receiver<
     (writer, receiver)
 }
 
+/// A [`CapacityWatcher`] is woken once `1 / CONSUMED_THRESHOLD_FRACTION` of the buffer was freed.
+const CONSUMED_THRESHOLD_FRACTION: usize = 8;
+
 #[derive(Debug, thiserror::Error)]
 pub enum WriteError {
     #[error("writing to closed channel")]
@@ -131,6 +135,54 @@ struct Inner {
     read_wakers: Vec<Waker>,
     is_closed: bool,
     guarantees: Guarantees,
+    /// Messages which were sent optimistically, i.e. without being fully covered by guarantees,
+    /// together with the number of their bytes not yet covered by guarantees.
+    ///
+    /// They are kept until guarantees are issued that cover them, so that they can be resent if
+    /// the other peer announces that it dropped them.
+    unconfirmed: VecDeque<(Bytes, u64)>,
+    /// Total length of the messages in `unconfirmed`.
+    unconfirmed_len: usize,
+    /// Set after the other peer dropped our messages, to not send further messages optimistically
+    /// until all resent messages are confirmed.
+    pause_optimistic: bool,
+    /// Total number of bytes read from the channel by the [`Reader`].
+    read: u64,
+    /// Total number of bytes of the messages passed to [`Writer::write_or_drop`], including the
+    /// dropped messages.
+    received: u64,
+    /// Bytes consumed by the [`Receiver`] which were not yet reported to a [`CapacityWatcher`].
+    consumed: u64,
+    consumed_wakers: Vec<Waker>,
+    /// Whether we are dropping incoming messages.
+    dropping: Dropping,
+    /// The buffer size the channel was created with.
+    initial_buffer_size: usize,
+    /// Whether we pleaded the other peer to absolve us from our guarantees.
+    plead: Plead,
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+enum Dropping {
+    #[default]
+    No,
+    /// We started to drop messages, but did not yet announce this to the other peer.
+    Unannounced,
+    /// We are dropping messages until the other peer apologises.
+    Announced,
+    /// The other peer apologised, and resends the dropped messages starting at this offset of the
+    /// channel. The messages before it were sent before the apologise and are still dropped.
+    Apologised { resume_at: u64 },
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+enum Plead {
+    #[default]
+    None,
+    /// We sent a plead and wait for the other peer's absolve.
+    Pending,
+    /// We sent a plead, but need the full buffer again before the absolve arrived.
+    Cancelled,
 }
 
 impl Shared {
@@ -142,6 +194,16 @@ impl Shared {
             read_wakers: Default::default(),
             is_closed: false,
             guarantees,
+            unconfirmed: Default::default(),
+            unconfirmed_len: 0,
+            pause_optimistic: false,
+            read: 0,
+            received: 0,
+            consumed: 0,
+            consumed_wakers: Default::default(),
+            dropping: Dropping::No,
+            initial_buffer_size: max_buffer_size,
+            plead: Plead::None,
         };
         let shared = Self {
             inner: Mutex::new(inner),
@@ -167,18 +229,109 @@ impl Inner {
     //     }
     // }
 
-    fn add_guarantees(&mut self, amount: u64) {
-        let current_write_capacity = self.remaining_write_capacity();
+    fn add_guarantees(&mut self, mut amount: u64) {
+        // New guarantees first cover the messages we sent optimistically. Messages which are fully
+        // covered are confirmed: the other peer would have announced dropping before issuing
+        // guarantees for them if it had dropped them.
+        while amount > 0 {
+            let Some((message, uncovered)) = self.unconfirmed.front_mut() else {
+                break;
+            };
+            let covered = cmp::min(*uncovered, amount);
+            *uncovered -= covered;
+            amount -= covered;
+            if *uncovered == 0 {
+                self.unconfirmed_len -= message.len();
+                self.unconfirmed.pop_front();
+            }
+        }
+        if self.unconfirmed.is_empty() {
+            self.pause_optimistic = false;
+        }
         self.guarantees.add(amount);
-        if self.remaining_write_capacity() > current_write_capacity {
-            self.wake_writable();
+        self.wake_writable();
+    }
+
+    /// Use guarantees for a message that was just written to the buffer at `range`.
+    ///
+    /// If not enough guarantees are available, the message was sent optimistically and a copy is
+    /// kept in case the other peer drops it.
+    fn use_guarantees(&mut self, range: std::ops::Range<usize>) {
+        let len = range.len() as u64;
+        let available = self.guarantees.get();
+        if self.unconfirmed.is_empty() && available >= len {
+            self.guarantees.r#use(len);
+        } else {
+            let covered = cmp::min(available, len);
+            self.guarantees.r#use(covered);
+            let message = Bytes::copy_from_slice(&self.buf[range]);
+            self.unconfirmed_len += message.len();
+            self.unconfirmed.push_back((message, len - covered));
         }
     }
 
+    fn can_send(&self, len: usize) -> bool {
+        if self.remaining_buffer_capacity() < len {
+            return false;
+        }
+        match self.guarantees {
+            Guarantees::Unlimited => true,
+            Guarantees::Limited(available) => {
+                (self.unconfirmed.is_empty() && available >= len as u64)
+                    // Send optimistically, but keep the total of unconfirmed messages bounded.
+                    || (!self.pause_optimistic
+                        && self.unconfirmed_len + len <= self.max_buffer_size)
+            }
+        }
+    }
+
+    /// Reduce the available guarantees to `target`, and return by how much they were reduced.
+    fn absolve(&mut self, target: u64) -> u64 {
+        let amount = self.guarantees.get().saturating_sub(target);
+        self.guarantees.r#use(amount);
+        amount
+    }
+
+    /// Requeue all messages which were not yet confirmed.
+    ///
+    /// This is called after the other peer announced that it dropped messages. It drops all
+    /// messages which were sent after the first message which is not covered by guarantees.
+    ///
+    /// Returns the offset of the channel at which the resent messages start.
+    fn resend_dropped(&mut self) -> Result<u64, WriteError> {
+        if self.is_closed() {
+            return Err(WriteError::Closed);
+        }
+        let dropped = std::mem::take(&mut self.unconfirmed);
+        let dropped_len = std::mem::take(&mut self.unconfirmed_len);
+        // Give back the guarantees that were used for the dropped messages.
+        let refund: u64 = dropped
+            .iter()
+            .map(|(message, uncovered)| message.len() as u64 - uncovered)
+            .sum();
+        self.guarantees.add(refund);
+        // Messages that are still in our buffer were sent after the first dropped message, and
+        // thus were all dropped as well. Remove them, they are resent below.
+        self.buf
+            .truncate(self.buf.len().saturating_sub(dropped_len));
+        let resume_at = self.read + self.buf.len() as u64;
+        for (message, _uncovered) in dropped {
+            let start = self.buf.len();
+            self.buf.extend_from_slice(&message);
+            self.use_guarantees(start..self.buf.len());
+        }
+        self.pause_optimistic = !self.unconfirmed.is_empty();
+        self.wake_readable();
+        Ok(resume_at)
+    }
+
     fn close(&mut self) {
         self.is_closed = true;
+        self.unconfirmed.clear();
+        self.unconfirmed_len = 0;
         self.wake_writable();
         self.wake_readable();
+        self.wake_consumed();
     }
 
     fn is_closed(&self) -> bool {
@@ -198,6 +351,7 @@ impl Inner {
         if len > 0 {
             self.wake_writable();
         }
+        self.read += len as u64;
         self.buf.split_to(len).freeze()
     }
 
@@ -209,7 +363,7 @@ impl Inner {
         //     self.guarantees.get(),
         //     self.max_buffer_size - self.buf.len()
         // );
-        if self.remaining_write_capacity() < len {
+        if self.remaining_buffer_capacity() < len {
             None
         } else {
             let old_len = self.buf.len();
@@ -261,16 +415,22 @@ impl Inner {
             return Poll::Ready(Err(WriteError::Closed));
         }
         let len = item.encoded_len();
-        if let Some(slice) = self.writable_slice_exact(len) {
-            let mut cursor = io::Cursor::new(slice);
-            item.encode_into(&mut cursor).map_err(WriteError::Encode)?;
-            self.guarantees.r#use(len as u64);
-            self.wake_readable();
-            Poll::Ready(Ok(()))
-        } else {
+        if !self.can_send(len) {
             self.write_wakers.push(cx.waker().to_owned());
-            Poll::Pending
+            return Poll::Pending;
         }
+        let start = self.buf.len();
+        let slice = self
+            .writable_slice_exact(len)
+            .expect("buffer capacity was checked");
+        let mut cursor = io::Cursor::new(slice);
+        if let Err(err) = item.encode_into(&mut cursor) {
+            self.buf.truncate(start);
+            return Poll::Ready(Err(WriteError::Encode(err)));
+        }
+        self.use_guarantees(start..start + len);
+        self.wake_readable();
+        Poll::Ready(Ok(()))
     }
 
     fn poll_recv_message<T: Decoder>(
@@ -292,19 +452,124 @@ impl Inner {
             }
             DecodeOutcome::Decoded { item, consumed } => {
                 self.buf.advance(consumed);
+                self.consumed += consumed as u64;
+                if self.consumed >= self.consumed_threshold() {
+                    self.wake_consumed();
+                }
                 self.wake_writable();
                 Poll::Ready(Some(Ok(item)))
             }
         }
     }
 
+    /// Write a complete message into the buffer, or drop it if it does not fit.
+    ///
+    /// Once a message was dropped, all further messages are dropped as well until the other peer
+    /// apologised, and the messages it sent before the apologise were received. Returns `true` if
+    /// the message was written.
+    fn write_or_drop(&mut self, message: &[u8]) -> io::Result<bool> {
+        if self.is_closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let offset = self.received;
+        self.received += message.len() as u64;
+        match self.dropping {
+            Dropping::No => {}
+            Dropping::Apologised { resume_at } if offset >= resume_at => {
+                self.dropping = Dropping::No;
+            }
+            _ => return Ok(false),
+        }
+        if self.remaining_write_capacity() < message.len() {
+            self.dropping = Dropping::Unannounced;
+            self.wake_consumed();
+            return Ok(false);
+        }
+        self.buf.extend_from_slice(message);
+        self.wake_readable();
+        Ok(true)
+    }
+
+    /// Stop dropping messages once the messages before `resume_at` were received.
+    ///
+    /// Returns `false` if we did not announce that we drop messages.
+    fn apologised(&mut self, resume_at: u64) -> bool {
+        if self.dropping != Dropping::Announced {
+            return false;
+        }
+        self.dropping = if self.received >= resume_at {
+            Dropping::No
+        } else {
+            Dropping::Apologised { resume_at }
+        };
+        true
+    }
+
+    fn plead(&mut self) -> bool {
+        if self.plead != Plead::None || self.max_buffer_size < self.initial_buffer_size {
+            return false;
+        }
+        self.plead = Plead::Pending;
+        true
+    }
+
+    fn absolved(&mut self, amount: u64) -> u64 {
+        if std::mem::take(&mut self.plead) == Plead::Cancelled {
+            return amount;
+        }
+        self.max_buffer_size = self
+            .max_buffer_size
+            .saturating_sub(usize::try_from(amount).unwrap_or(usize::MAX));
+        0
+    }
+
+    fn restore_buffer_size(&mut self) -> u64 {
+        if self.plead == Plead::Pending {
+            self.plead = Plead::Cancelled;
+        }
+        let amount = self
+            .initial_buffer_size
+            .saturating_sub(self.max_buffer_size);
+        self.max_buffer_size = self.initial_buffer_size;
+        self.wake_writable();
+        amount as u64
+    }
+
+    fn poll_consumed(&mut self, cx: &mut task::Context<'_>) -> Poll<Option<CapacityEvent>> {
+        if self.is_closed() {
+            Poll::Ready(None)
+        } else if self.dropping == Dropping::Unannounced {
+            self.dropping = Dropping::Announced;
+            let freed = std::mem::take(&mut self.consumed);
+            Poll::Ready(Some(CapacityEvent::Dropping { freed }))
+        } else if self.consumed >= self.consumed_threshold() {
+            let freed = std::mem::take(&mut self.consumed);
+            Poll::Ready(Some(CapacityEvent::Freed(freed)))
+        } else {
+            self.consumed_wakers.push(cx.waker().to_owned());
+            Poll::Pending
+        }
+    }
+
+    /// The number of consumed bytes after which a [`CapacityWatcher`] is woken.
+    ///
+    /// Reporting each consumed message on its own would create a lot of small control messages,
+    /// so we wait until a fraction of the buffer was freed.
+    fn consumed_threshold(&self) -> u64 {
+        cmp::max(1, self.max_buffer_size / CONSUMED_THRESHOLD_FRACTION) as u64
+    }
+
     fn remaining_write_capacity(&self) -> usize {
         cmp::min(
-            self.max_buffer_size - self.buf.len(),
+            self.remaining_buffer_capacity(),
             self.guarantees.get() as usize,
         )
     }
 
+    fn remaining_buffer_capacity(&self) -> usize {
+        self.max_buffer_size.saturating_sub(self.buf.len())
+    }
+
     fn wake_readable(&mut self) {
         for waker in self.read_wakers.drain(..) {
             waker.wake();
@@ -316,6 +581,12 @@ impl Inner {
             waker.wake();
         }
     }
+
+    fn wake_consumed(&mut self) {
+        for waker in self.consumed_wakers.drain(..) {
+            waker.wake();
+        }
+    }
 }
 
 /// Asynchronous reader to read bytes from a channel.
@@ -360,6 +631,17 @@ impl Writer {
     pub fn max_buffer_size(&self) -> usize {
         self.shared.lock().unwrap().max_buffer_size
     }
+
+    /// Write a complete encoded message into the channel, or drop it if the channel is full.
+    ///
+    /// Returns `Ok(true)` if the message was written, and `Ok(false)` if it was dropped.
+    ///
+    /// Once a message was dropped, all further messages are dropped until the other peer
+    /// apologised, see [`CapacityWatcher::apologised`]. The [`CapacityWatcher`] of the channel will
+    /// yield [`CapacityEvent::Dropping`] once, so that the drop can be announced to the other peer.
+    pub fn write_or_drop(&self, message: &[u8]) -> io::Result<bool> {
+        self.shared.lock().unwrap().write_or_drop(message)
+    }
 }
 
 impl AsyncWrite for Writer {
@@ -414,6 +696,22 @@ impl<T: Encoder> Sender<T> {
         self.shared.lock().unwrap().add_guarantees(amount)
     }
 
+    /// Reduce the available guarantees to at most `target`.
+    ///
+    /// Returns the amount by which the guarantees were reduced.
+    pub fn absolve(&self, target: u64) -> u64 {
+        self.shared.lock().unwrap().absolve(target)
+    }
+
+    /// Resend all messages which were sent optimistically and not yet confirmed.
+    ///
+    /// This must be called once the other peer announced that it dropped messages. Returns the
+    /// offset of the channel at which the resent messages start, which has to be sent to the
+    /// other peer with the apologise, so that it knows from where on to accept messages again.
+    pub fn resend_dropped(&self) -> Result<u64, WriteError> {
+        self.shared.lock().unwrap().resend_dropped()
+    }
+
     // pub fn set_max_buffer_size(&self, max_buffer_size: usize) -> bool {
     //     self.shared.lock().unwrap().set_max_buffer_size(max_buffer_size)
     // }
@@ -440,6 +738,18 @@ impl<T: Decoder> Receiver<T> {
         poll_fn(|cx| self.shared.lock().unwrap().poll_recv_message(cx)).await
     }
 
+    /// Get the maximum buffer size of the channel.
+    pub fn max_buffer_size(&self) -> usize {
+        self.shared.lock().unwrap().max_buffer_size
+    }
+
+    /// Create a [`CapacityWatcher`] to get notified when messages are consumed from the channel.
+    pub fn capacity_watcher(&self) -> CapacityWatcher {
+        CapacityWatcher {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
     // pub fn set_max_buffer_size(&self, max_buffer_size: usize) -> bool {
     //     self.shared
     //         .lock()
@@ -455,6 +765,76 @@ impl<T: Decoder> Stream for Receiver<T> {
     }
 }
 
+/// Watches the consumption of messages from an inbound channel.
+///
+/// This is a [`Stream`] of [`CapacityEvent`]s. It is used to issue new guarantees to the remote
+/// peer once space in our buffer becomes available again, and to announce when we started to
+/// drop messages.
+///
+/// The stream ends once the channel is closed. The watcher does not keep the channel open.
+#[derive(Debug, Clone)]
+pub struct CapacityWatcher {
+    shared: Arc<Shared>,
+}
+
+/// Event emitted from a [`CapacityWatcher`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CapacityEvent {
+    /// The receiver consumed messages and freed this many bytes in the channel buffer.
+    Freed(u64),
+    /// The channel started to drop messages, because a message did not fit into the buffer.
+    ///
+    /// `freed` is the number of bytes freed since the last event, which have to be issued as
+    /// guarantees before announcing that we drop messages.
+    Dropping { freed: u64 },
+}
+
+impl CapacityWatcher {
+    /// Get the maximum buffer size of the channel.
+    pub fn max_buffer_size(&self) -> usize {
+        self.shared.lock().unwrap().max_buffer_size
+    }
+
+    /// Stop dropping messages once all messages before the offset `resume_at` of the channel
+    /// were received.
+    ///
+    /// This is called once the other peer apologised for the messages we dropped. Returns `false`
+    /// if the channel did not announce dropping, in which case the apologise is ignored.
+    pub fn apologised(&self, resume_at: u64) -> bool {
+        self.shared.lock().unwrap().apologised(resume_at)
+    }
+
+    /// Marks that we plead the other peer to absolve us from our guarantees.
+    ///
+    /// Returns `false` if we already pleaded, or the buffer was already reduced.
+    pub fn plead(&self) -> bool {
+        self.shared.lock().unwrap().plead()
+    }
+
+    /// Handles that the other peer absolved us from `amount` guarantees.
+    ///
+    /// This reduces the maximum buffer size of the channel by `amount`. If the buffer was
+    /// restored with [`Self::restore_buffer_size`] after we pleaded, the buffer is kept instead,
+    /// and `amount` is returned, to be issued as guarantees again.
+    pub fn absolved(&self, amount: u64) -> u64 {
+        self.shared.lock().unwrap().absolved(amount)
+    }
+
+    /// Restores the buffer size the channel was created with.
+    ///
+    /// Returns by how much the buffer grew, to be issued as guarantees to the other peer.
+    pub fn restore_buffer_size(&self) -> u64 {
+        self.shared.lock().unwrap().restore_buffer_size()
+    }
+}
+
+impl Stream for CapacityWatcher {
+    type Item = CapacityEvent;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.shared.lock().unwrap().poll_consumed(cx)
+    }
+}
+
 impl<T> Clone for Sender<T> {
     fn clone(&self) -> Self {
         self.shared.sender_count.fetch_add(1, Ordering::Relaxed);
@@ -496,3 +876,108 @@ impl<T> Drop for Receiver<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::StreamExt;
+
+    use super::*;
+    use crate::proto::wgps::{ControlIssueGuarantee, LogicalChannel, Message};
+
+    fn message(amount: u64) -> Message {
+        ControlIssueGuarantee {
+            amount,
+            channel: LogicalChannel::Data,
+        }
+        .into()
+    }
+
+    fn amounts(mut data: &[u8]) -> Vec<Option<u64>> {
+        let mut out = vec![];
+        while let DecodeOutcome::Decoded { item, consumed } = Message::decode_from(data).unwrap() {
+            out.push(match item {
+                Message::ControlIssueGuarantee(msg) => Some(msg.amount),
+                _ => None,
+            });
+            data = &data[consumed..];
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn optimistic_send_and_resend() -> anyhow::Result<()> {
+        let (sender, reader) = outbound_channel::<Message>(1024, Guarantees::Limited(0));
+        let len = message(1).encoded_len() as u64;
+
+        // Without guarantees, messages are sent optimistically.
+        for i in 1..=3 {
+            sender.send_message(&message(i)).await?;
+        }
+        let sent = reader.read_bytes().await.unwrap();
+        assert_eq!(amounts(&sent), vec![Some(1), Some(2), Some(3)]);
+
+        // Guarantees arrive which cover the first message, then the other peer announces that it
+        // dropped messages.
+        sender.add_guarantees(len);
+        sender.send_message(&message(4)).await?;
+        let resume_at = sender.resend_dropped()?;
+        assert_eq!(resume_at, 3 * len);
+
+        // The unsent message was removed from the buffer and is resent after the sent messages.
+        let resent = reader.read_bytes().await.unwrap();
+        assert_eq!(amounts(&resent), vec![Some(2), Some(3), Some(4)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drop_until_apologise() -> anyhow::Result<()> {
+        let encoded = |amount| message(amount).encode().unwrap();
+        let len = encoded(1).len();
+        let (writer, receiver) = inbound_channel::<Message>(len * 2);
+        let mut watcher = receiver.capacity_watcher();
+
+        // An apologise without a preceding announcement is ignored.
+        assert!(!watcher.apologised(0));
+
+        assert!(writer.write_or_drop(&encoded(1))?);
+        assert!(writer.write_or_drop(&encoded(2))?);
+        assert!(!writer.write_or_drop(&encoded(3))?);
+
+        // Once dropping, messages are dropped even if they would fit.
+        let received = receiver.recv().await.unwrap()?;
+        assert!(matches!(received, Message::ControlIssueGuarantee(msg) if msg.amount == 1));
+        assert!(!writer.write_or_drop(&encoded(4))?);
+
+        // The watcher reports the freed capacity together with the dropping.
+        let event = watcher.next().await.unwrap();
+        assert_eq!(event, CapacityEvent::Dropping { freed: len as u64 });
+
+        // The other peer apologises and resends from the third message on. The fourth and fifth
+        // message were sent before the apologise, and are still dropped.
+        assert!(watcher.apologised(2 * len as u64 + 3 * len as u64));
+        assert!(!writer.write_or_drop(&encoded(5))?);
+        assert!(writer.write_or_drop(&encoded(3))?);
+        assert!(!watcher.apologised(0));
+        Ok(())
+    }
+
+    #[test]
+    fn plead_and_restore() {
+        let (_writer, receiver) = inbound_channel::<Message>(1024);
+        let watcher = receiver.capacity_watcher();
+
+        assert!(watcher.plead());
+        assert!(!watcher.plead());
+        assert_eq!(watcher.absolved(1000), 0);
+        assert_eq!(watcher.max_buffer_size(), 24);
+        assert_eq!(watcher.restore_buffer_size(), 1000);
+        assert_eq!(watcher.max_buffer_size(), 1024);
+
+        // If the buffer is restored before the absolve arrives, the absolved amount is issued
+        // again instead of shrinking the buffer.
+        assert!(watcher.plead());
+        assert_eq!(watcher.restore_buffer_size(), 0);
+        assert_eq!(watcher.absolved(1000), 1000);
+        assert_eq!(watcher.max_buffer_size(), 1024);
+    }
+}
@@ -1,14 +1,24 @@
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::Result;
 use bytes::Bytes;
 use futures_concurrency::future::TryJoin;
 use futures_lite::StreamExt;
 use iroh::SecretKey;
-use iroh_blobs::store::{Map, MapEntry};
-use iroh_io::AsyncSliceReaderExt;
+use iroh_blobs::{
+    store::{Map, MapEntry, Store as _},
+    BlobFormat,
+};
+use iroh_io::{AsyncSliceReaderExt, TokioStreamReader};
 use iroh_willow::{
-    form::EntryForm,
+    engine::{AcceptOpts, ConnStatus, ConnectionLimits, EngineEvent, QueuePolicy},
+    form::{EntryForm, PayloadForm, SubspaceForm, TimestampForm},
     interest::{CapSelector, DelegateTo, Interests, IntoAreaOfInterest, RestrictArea},
     proto::{
         data_model::{Path, PathExt},
@@ -17,12 +27,15 @@ use iroh_willow::{
     },
     session::{
         intents::{Completion, EventKind},
-        SessionInit, SessionMode,
+        EvictionPolicy, ReconcileOpts, RetryPolicy, Role, SessionInit, SessionMode,
+        StaticTokenLimits,
     },
+    store::traits::{EntryOrigin, StoreEvent},
+    util::pipe::chunked_pipe,
 };
 use meadowcap::AccessMode;
 
-use self::util::{create_rng, insert, setup_and_delegate, spawn_two, Peer};
+use self::util::{create_rng, insert, setup_and_delegate, spawn_two, spawn_two_with_opts, Peer};
 
 #[tokio::test(flavor = "multi_thread")]
 async fn peer_manager_two_intents() -> Result<()> {
@@ -198,6 +211,54 @@ async fn peer_manager_update_intent() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_upgrade_mode() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_upgrade_mode");
+
+    let [alfie, betty] = spawn_two(&mut rng).await?;
+    let (namespace, _alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let betty_node_id = betty.node_id();
+
+    insert(&betty, namespace, betty_user, &[b"foo"], "foo 1").await?;
+
+    let init = SessionInit::new(Interests::all(), SessionMode::ReconcileOnce);
+    let mut intent_once = alfie.sync_with_peer(betty_node_id, init).await.unwrap();
+    assert_eq!(
+        intent_once.next().await.unwrap(),
+        EventKind::CapabilityIntersection {
+            namespace,
+            area: Area::new_full(),
+        }
+    );
+
+    // Submit a continuous intent while the session is running, which upgrades the session.
+    let init = SessionInit::new(Interests::all(), SessionMode::Continuous);
+    let mut intent_live = alfie.sync_with_peer(betty_node_id, init).await.unwrap();
+    intent_once.complete().await?;
+
+    // Entries inserted after the reconciliation are received in live mode.
+    insert(&betty, namespace, betty_user, &[b"bar"], "bar 1").await?;
+    let path = Path::from_bytes(&[b"bar"])?;
+    let mut found = false;
+    for _ in 0..50 {
+        if alfie
+            .get_entry(namespace, betty_user, path.clone())
+            .await?
+            .is_some()
+        {
+            found = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(found, "live entry was not received");
+
+    intent_live.close().await;
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+    Ok(())
+}
+
 /// Test immediate shutdown.
 // TODO: This does not really test much. Used it for log reading of graceful connection termination.
 // Not sure where we should expose whether connections closed gracefully or not?
@@ -474,12 +535,19 @@ mod util {
         }
     }
 
-    pub async fn spawn_two(mut rng: &mut impl CryptoRngCore) -> Result<[Peer; 2]> {
+    pub async fn spawn_two(rng: &mut impl CryptoRngCore) -> Result<[Peer; 2]> {
+        spawn_two_with_opts(rng, AcceptOpts::default).await
+    }
+
+    pub async fn spawn_two_with_opts(
+        mut rng: &mut impl CryptoRngCore,
+        accept_opts: impl Fn() -> AcceptOpts,
+    ) -> Result<[Peer; 2]> {
         let peers = [
             iroh::SecretKey::generate(&mut rng),
             iroh::SecretKey::generate(&mut rng),
         ]
-        .map(|secret_key| Peer::spawn(secret_key, Default::default()))
+        .map(|secret_key| Peer::spawn(secret_key, accept_opts()))
         .try_join()
         .await?;
 
@@ -609,3 +677,787 @@ async fn peer_manager_big_payload() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_resume_payload() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_resume_payload");
+
+    let [alfie, betty] = spawn_two(&mut rng).await?;
+    let (namespace, _alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let betty_node_id = betty.node_id();
+
+    // Betty only holds the first half of the payload, so the first session can only transfer
+    // that part of it to alfie.
+    let payload = Bytes::from(vec![5u8; 1024 * 1024 * 16]);
+    let hash = iroh_blobs::Hash::new(&payload);
+    let len = payload.len() as u64;
+    let verified_stream = {
+        let store = iroh_blobs::store::mem::Store::default();
+        let _tag = store.import_bytes(payload.clone(), BlobFormat::Raw).await?;
+        let entry = store.get(&hash).await?.expect("missing blob");
+        let (writer, reader) = chunked_pipe(1024 * 32);
+        let write = async {
+            entry.write_verifiable_stream(0, writer).await?;
+            anyhow::Ok(())
+        };
+        let read = async {
+            let chunks: Vec<Bytes> = reader.try_collect().await?;
+            anyhow::Ok(chunks)
+        };
+        let ((), chunks) = (write, read).try_join().await?;
+        chunks
+    };
+    let prefix = verified_stream[..verified_stream.len() / 2].to_vec();
+    let mut reader = TokioStreamReader(tokio_util::io::StreamReader::new(
+        futures_lite::stream::iter(prefix.into_iter().map(std::io::Result::Ok)),
+    ));
+    // The import fails at the end of the truncated stream, but keeps the verified prefix.
+    let _ = betty
+        .blobs
+        .import_verifiable_stream(hash, len, 0, &mut reader)
+        .await;
+    let blob = betty.blobs.get(&hash).await?.expect("missing blob");
+    assert!(!blob.is_complete());
+
+    let path = Path::from_bytes(&[b"big"])?;
+    let entry = EntryForm {
+        namespace_id: namespace,
+        subspace_id: SubspaceForm::User,
+        path,
+        timestamp: TimestampForm::Now,
+        payload: PayloadForm::HashUnchecked(hash, len),
+    };
+    betty.insert_entry(entry, betty_user).await?;
+
+    let init = SessionInit::new(Interests::all(), SessionMode::ReconcileOnce);
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await.unwrap();
+    intent.complete().await?;
+    let blob = alfie.blobs.get(&hash).await?.expect("missing blob");
+    assert!(!blob.is_complete());
+
+    // Once betty has the full payload, the next session resumes the transfer.
+    betty
+        .blobs
+        .import_bytes(payload.clone(), BlobFormat::Raw)
+        .await?;
+
+    let init = SessionInit::new(Interests::all(), SessionMode::ReconcileOnce);
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await.unwrap();
+    intent.complete().await?;
+
+    let blob = alfie.blobs.get(&hash).await?.expect("missing blob");
+    assert!(blob.is_complete());
+    let actual = blob.data_reader().await?.read_to_end().await?;
+    assert!(actual == payload);
+
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_fetch_payload() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_fetch_payload");
+
+    let [alfie, betty] = spawn_two(&mut rng).await?;
+    let (namespace, _alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let betty_node_id = betty.node_id();
+
+    // The payload is larger than the maximum payload size, so it is not sent with the entry.
+    let payload = Bytes::from(vec![4u8; 1024 * 512]);
+    insert(&betty, namespace, betty_user, &[b"big"], payload.clone()).await?;
+
+    let init = SessionInit::new(Interests::all(), SessionMode::Continuous);
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await.unwrap();
+    while let Some(event) = intent.next().await {
+        if event == EventKind::ReconciledAll {
+            break;
+        }
+    }
+
+    let path = Path::from_bytes(&[b"big"])?;
+    let entry = alfie
+        .get_entry(namespace, betty_user, path.clone())
+        .await?
+        .expect("missing entry");
+    let hash: iroh_blobs::Hash = (*entry.entry().payload_digest()).into();
+    assert!(alfie.blobs.get(&hash).await?.is_none());
+
+    alfie
+        .fetch_payload(betty_node_id, namespace, betty_user, path)
+        .await?;
+
+    let blob = alfie.blobs.get(&hash).await?.expect("missing blob");
+    assert!(blob.is_complete());
+    let actual = blob.data_reader().await?.read_to_end().await?;
+    assert!(actual == payload);
+
+    intent.close().await;
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_lazy_payloads() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_lazy_payloads");
+
+    let [alfie, betty] = spawn_two(&mut rng).await?;
+    let (namespace, _alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let betty_node_id = betty.node_id();
+
+    let photos = Area::new_path(Path::from_bytes(&[b"photos"])?);
+    let docs = Area::new_path(Path::from_bytes(&[b"docs"])?);
+    let init = SessionInit::continuous(Interests::builder().add_area(namespace, [docs]))
+        .lazy_interests(Interests::builder().add_area(namespace, [photos]));
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await.unwrap();
+    while let Some(event) = intent.next().await {
+        if event == EventKind::ReconciledAll {
+            break;
+        }
+    }
+
+    // The entries are sent live, after the payload preferences were exchanged.
+    insert(&betty, namespace, betty_user, &[b"photos", b"a"], "photo").await?;
+    insert(&betty, namespace, betty_user, &[b"docs", b"b"], "doc").await?;
+    let photo_path = Path::from_bytes(&[b"photos", b"a"])?;
+    let photo_hash = iroh_blobs::Hash::new("photo");
+    let doc_hash = iroh_blobs::Hash::new("doc");
+    let mut found = false;
+    for _ in 0..50 {
+        let photo_entry = alfie
+            .get_entry(namespace, betty_user, photo_path.clone())
+            .await?;
+        let doc_blob = alfie.blobs.get(&doc_hash).await?;
+        if photo_entry.is_some() && doc_blob.is_some_and(|blob| blob.is_complete()) {
+            found = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(found, "live entries were not received");
+    // The payload in the lazy area was not sent.
+    assert!(alfie.blobs.get(&photo_hash).await?.is_none());
+
+    // It can be fetched while the session is active.
+    alfie
+        .fetch_payload(betty_node_id, namespace, betty_user, photo_path)
+        .await?;
+    assert!(alfie.blobs.get(&photo_hash).await?.is_some());
+
+    intent.close().await;
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_lazy_payloads_reconciled() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_lazy_payloads_reconciled");
+
+    let [alfie, betty] = spawn_two(&mut rng).await?;
+    let (namespace, _alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let betty_node_id = betty.node_id();
+
+    // The entries exist before the session starts, so they are sent during reconciliation,
+    // possibly before betty learns that alfie wants the photos lazily.
+    insert(&betty, namespace, betty_user, &[b"photos", b"a"], "photo").await?;
+    insert(&betty, namespace, betty_user, &[b"docs", b"b"], "doc").await?;
+
+    let photos = Area::new_path(Path::from_bytes(&[b"photos"])?);
+    let docs = Area::new_path(Path::from_bytes(&[b"docs"])?);
+    let init = SessionInit::reconcile_once(Interests::builder().add_area(namespace, [docs]))
+        .lazy_interests(Interests::builder().add_area(namespace, [photos]));
+    let intent = alfie.sync_with_peer(betty_node_id, init).await.unwrap();
+    intent.complete().await?;
+
+    let photo_path = Path::from_bytes(&[b"photos", b"a"])?;
+    assert!(alfie
+        .get_entry(namespace, betty_user, photo_path)
+        .await?
+        .is_some());
+    let doc_hash = iroh_blobs::Hash::new("doc");
+    assert!(alfie
+        .blobs
+        .get(&doc_hash)
+        .await?
+        .is_some_and(|blob| blob.is_complete()));
+    // The payload in the lazy area was not stored.
+    let photo_hash = iroh_blobs::Hash::new("photo");
+    assert!(alfie.blobs.get(&photo_hash).await?.is_none());
+
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_reconcile_opts() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_reconcile_opts");
+
+    // Split into four subranges and send up to three entries directly, and only send payloads
+    // eagerly up to 1 KiB.
+    let opts = ReconcileOpts {
+        max_set_size: 3,
+        split_factor: 4,
+        max_eager_payload_size: 1024,
+    };
+    let [alfie, betty] =
+        spawn_two_with_opts(&mut rng, || AcceptOpts::default().reconcile_opts(opts)).await?;
+    let (namespace, alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let betty_node_id = betty.node_id();
+
+    for i in 0..30 {
+        let path = format!("{i}");
+        insert(
+            &alfie,
+            namespace,
+            alfie_user,
+            &[path.as_bytes()],
+            path.clone(),
+        )
+        .await?;
+        if i % 3 == 0 {
+            insert(
+                &betty,
+                namespace,
+                betty_user,
+                &[path.as_bytes()],
+                path.clone(),
+            )
+            .await?;
+        }
+    }
+    let big_payload = Bytes::from(vec![1u8; 2048]);
+    insert(&betty, namespace, betty_user, &[b"big"], big_payload).await?;
+
+    let init = SessionInit::new(Interests::all(), SessionMode::ReconcileOnce).reconcile_opts(opts);
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await.unwrap();
+    intent.complete().await?;
+
+    let entries = alfie.get_entries(namespace, Range3d::new_full()).await?;
+    let entries: Vec<_> = entries.try_collect().await?;
+    let entries_betty = betty.get_entries(namespace, Range3d::new_full()).await?;
+    let entries_betty: Vec<_> = entries_betty.try_collect().await?;
+    assert_eq!(entries.len(), 41);
+    assert_eq!(entries_betty.len(), 41);
+
+    // The big payload exceeds the max eager payload size, so it was not sent.
+    let path = Path::from_bytes(&[b"big"])?;
+    let entry = entries
+        .iter()
+        .find(|e| *e.entry().path() == path)
+        .expect("missing entry");
+    let hash: iroh_blobs::Hash = (*entry.entry().payload_digest()).into();
+    assert!(alfie.blobs.get(&hash).await?.is_none());
+
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_static_token_limits() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_static_token_limits");
+
+    // Allow only two static token handles, so that handles have to be freed when syncing entries
+    // from more authors.
+    let limits = StaticTokenLimits {
+        max_handles: 2,
+        eviction: EvictionPolicy::LeastRecentlyUsed,
+        ..Default::default()
+    };
+    let [alfie, betty] = spawn_two_with_opts(&mut rng, || {
+        AcceptOpts::default().static_token_limits(limits)
+    })
+    .await?;
+    let (namespace, _alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let betty_node_id = betty.node_id();
+
+    let mut users = vec![betty_user];
+    for _ in 0..4 {
+        let user = betty.create_user().await?;
+        let cap = alfie
+            .delegate_caps(
+                CapSelector::any(namespace),
+                AccessMode::Write,
+                DelegateTo::new(user, RestrictArea::None),
+            )
+            .await?;
+        betty.import_caps(cap).await?;
+        users.push(user);
+    }
+    for i in 0..20 {
+        let path = format!("{i}");
+        let user = users[i % users.len()];
+        insert(&betty, namespace, user, &[path.as_bytes()], path.clone()).await?;
+    }
+
+    let init = SessionInit::new(Interests::all(), SessionMode::ReconcileOnce);
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await.unwrap();
+    intent.complete().await?;
+
+    let entries = alfie.get_entries(namespace, Range3d::new_full()).await?;
+    let entries: Vec<_> = entries.try_collect().await?;
+    assert_eq!(entries.len(), 20);
+
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_small_session_memory() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_small_session_memory");
+
+    // Use the smallest possible buffers, so that the sync can only proceed if guarantees are
+    // issued again after messages were consumed.
+    let [alfie, betty] =
+        spawn_two_with_opts(&mut rng, || AcceptOpts::default().session_memory_limit(0)).await?;
+    let (namespace, _alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let betty_node_id = betty.node_id();
+
+    let payload = Bytes::from(vec![3u8; 1024 * 256]);
+    insert(&betty, namespace, betty_user, &[b"big"], payload.clone()).await?;
+    for i in 0..100 {
+        let path = format!("{i}");
+        insert(
+            &betty,
+            namespace,
+            betty_user,
+            &[b"small", path.as_bytes()],
+            path.clone(),
+        )
+        .await?;
+    }
+
+    let init = SessionInit::new(Interests::all(), SessionMode::ReconcileOnce);
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await.unwrap();
+    intent.complete().await?;
+
+    let entries = alfie.get_entries(namespace, Range3d::new_full()).await?;
+    let entries: Vec<_> = entries.try_collect().await?;
+    assert_eq!(entries.len(), 101);
+
+    let path = Path::from_bytes(&[b"big"])?;
+    let entry = entries
+        .iter()
+        .find(|e| *e.entry().path() == path)
+        .expect("missing entry");
+    let hash: iroh_blobs::Hash = (*entry.entry().payload_digest()).into();
+    let blob = alfie.blobs.get(&hash).await?.expect("missing blob");
+    let actual = blob.data_reader().await?.read_to_end().await?;
+    assert!(actual == payload);
+
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sideload_drop() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("sideload_drop");
+
+    let [alfie, betty] = spawn_two(&mut rng).await?;
+    let user_alfie = alfie.create_user().await?;
+    let user_betty = betty.create_user().await?;
+    let namespace = alfie
+        .create_namespace(NamespaceKind::Owned, user_alfie)
+        .await?;
+
+    let payload = Bytes::from(vec![5u8; 1024 * 256]);
+    insert(
+        &alfie,
+        namespace,
+        user_alfie,
+        &[b"shared", b"big"],
+        payload.clone(),
+    )
+    .await?;
+    insert(
+        &alfie,
+        namespace,
+        user_alfie,
+        &[b"shared", b"small"],
+        "small",
+    )
+    .await?;
+    insert(&alfie, namespace, user_alfie, &[b"private"], "private").await?;
+
+    // Betty may only read the entries below "shared".
+    let restriction = RestrictArea::Restrict(Area::new_path(Path::from_bytes(&[b"shared"])?));
+    let caps = alfie
+        .delegate_caps(
+            CapSelector::any(namespace),
+            AccessMode::Read,
+            DelegateTo::new(user_betty, restriction),
+        )
+        .await?;
+
+    // The drop is written through a pipe, like it would be written to a file.
+    let (writer, mut reader) = tokio::io::duplex(1024 * 64);
+    let read_fut = async {
+        let mut data = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut data).await?;
+        anyhow::Ok(data)
+    };
+    let (stats, data) = (alfie.create_drop(Interests::all(), caps, writer), read_fut)
+        .try_join()
+        .await?;
+    assert_eq!(stats.entries, 2);
+    assert_eq!(stats.payloads, 2);
+
+    // Only the recipient can ingest the drop.
+    let drop = std::io::Cursor::new(data.clone());
+    assert!(alfie.ingest_drop(drop).await.is_err());
+
+    let drop = std::io::Cursor::new(data);
+    let ingested = betty.ingest_drop(drop).await?;
+    assert_eq!(ingested, stats);
+
+    // Entries from a drop are reported with their own origin.
+    let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+    betty
+        .resume_subscription(0, namespace, Area::new_full(), Default::default(), tx)
+        .await?;
+    for _ in 0..2 {
+        let event = rx.recv().await.expect("missing event");
+        assert!(matches!(
+            event,
+            StoreEvent::Ingested(_, _, EntryOrigin::Sideload)
+        ));
+    }
+
+    let entries: Vec<_> = betty
+        .get_entries(namespace, Range3d::new_full())
+        .await?
+        .try_collect()
+        .await?;
+    assert_eq!(entries.len(), 2);
+    let path = Path::from_bytes(&[b"shared", b"big"])?;
+    let entry = entries
+        .iter()
+        .find(|e| *e.entry().path() == path)
+        .expect("missing entry");
+    let hash: iroh_blobs::Hash = (*entry.entry().payload_digest()).into();
+    let blob = betty.blobs.get(&hash).await?.expect("missing blob");
+    let actual = blob.data_reader().await?.read_to_end().await?;
+    assert!(actual == payload);
+
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_reconnect() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_reconnect");
+
+    // Ignore the first incoming connection, which makes the first dial fail.
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let [alfie, betty] = spawn_two_with_opts(&mut rng, || {
+        let accepted = accepted.clone();
+        AcceptOpts::default().accept_custom(move |_peer| {
+            let first = accepted.fetch_add(1, Ordering::SeqCst) == 0;
+            async move { (!first).then(|| SessionInit::continuous(Interests::all())) }
+        })
+    })
+    .await?;
+    let (namespace, _alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let betty_node_id = betty.node_id();
+
+    insert(&betty, namespace, betty_user, &[b"foo"], "foo 1").await?;
+
+    let retry = RetryPolicy {
+        initial_backoff: Duration::from_millis(10),
+        jitter: false,
+        ..Default::default()
+    };
+    let init = SessionInit::continuous(Interests::all()).retry_policy(retry);
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await.unwrap();
+    assert!(matches!(
+        intent.next().await.unwrap(),
+        EventKind::Reconnecting { attempt: 1, delay, .. } if delay == Duration::from_millis(10)
+    ));
+    assert_eq!(intent.next().await.unwrap(), EventKind::Reconnected);
+    loop {
+        let event = intent.next().await.unwrap();
+        if event == EventKind::ReconciledAll {
+            break;
+        }
+        assert!(!matches!(event, EventKind::Abort { .. }), "{event:?}");
+    }
+    let path = Path::from_bytes(&[b"foo"])?;
+    assert!(alfie
+        .get_entry(namespace, betty_user, path)
+        .await?
+        .is_some());
+
+    intent.close().await;
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_peer_policy() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_peer_policy");
+
+    let [alfie, betty] = spawn_two(&mut rng).await?;
+    let (namespace, _alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let alfie_node_id = alfie.node_id();
+    let betty_node_id = betty.node_id();
+
+    insert(&betty, namespace, betty_user, &[b"foo"], "foo 1").await?;
+
+    betty.deny_peer(alfie_node_id).await?;
+    assert!(!betty.peer_policy().await?.is_allowed(&alfie_node_id));
+
+    // Intents are not retried if the peer denies us.
+    let init = SessionInit::reconcile_once(Interests::all()).retry_policy(RetryPolicy::default());
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await?;
+    let event = intent.next().await.unwrap();
+    assert!(
+        matches!(
+            &event,
+            EventKind::Abort { error } if error.to_string().contains("denied by peer policy")
+        ),
+        "{event:?}"
+    );
+
+    betty.allow_peer(alfie_node_id).await?;
+    let init = SessionInit::reconcile_once(Interests::all());
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await?;
+    intent.complete().await?;
+    let path = Path::from_bytes(&[b"foo"])?;
+    assert!(alfie
+        .get_entry(namespace, betty_user, path)
+        .await?
+        .is_some());
+
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_connection_limits() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_connection_limits");
+
+    // Alfie does not accept sessions, and aborts intents over the limit. Betty has no limits.
+    let spawned = AtomicUsize::new(0);
+    let [alfie, betty] = spawn_two_with_opts(&mut rng, || {
+        if spawned.fetch_add(1, Ordering::SeqCst) == 0 {
+            AcceptOpts::default().connection_limits(ConnectionLimits {
+                max_sessions: Some(0),
+                queue_policy: QueuePolicy::Abort,
+                ..Default::default()
+            })
+        } else {
+            AcceptOpts::default()
+        }
+    })
+    .await?;
+    let alfie_node_id = alfie.node_id();
+    let betty_node_id = betty.node_id();
+
+    // Our outgoing intent is aborted because of our own limits.
+    let init = SessionInit::reconcile_once(Interests::all());
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await?;
+    let event = intent.next().await.unwrap();
+    assert!(
+        matches!(
+            &event,
+            EventKind::Abort { error } if error.to_string().contains("connection limits")
+        ),
+        "{event:?}"
+    );
+
+    // Their incoming connection is rejected because alfie is busy.
+    let init = SessionInit::reconcile_once(Interests::all());
+    let mut intent = betty.sync_with_peer(alfie_node_id, init).await?;
+    let event = intent.next().await.unwrap();
+    assert!(
+        matches!(
+            &event,
+            EventKind::Abort { error } if error.to_string().contains("peer is busy")
+        ),
+        "{event:?}"
+    );
+
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_session_info() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_session_info");
+
+    let [alfie, betty] = spawn_two(&mut rng).await?;
+    let (namespace, _alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let betty_node_id = betty.node_id();
+
+    insert(&betty, namespace, betty_user, &[b"foo"], "foo 1").await?;
+
+    assert!(alfie.session_info(betty_node_id).await?.is_none());
+
+    let init = SessionInit::continuous(Interests::all());
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await?;
+    loop {
+        let event = intent.next().await.unwrap();
+        if event == EventKind::ReconciledAll {
+            break;
+        }
+    }
+
+    let info = alfie
+        .session_info(betty_node_id)
+        .await?
+        .expect("session is active");
+    assert_eq!(info.role, Role::Alfie);
+    assert_eq!(info.mode, SessionMode::Continuous);
+    assert_eq!(info.intents.len(), 1);
+    assert_eq!(info.intents[0].mode, SessionMode::Continuous);
+    assert!(matches!(info.intents[0].interests, Interests::All));
+    assert!(!info.capability_intersections.is_empty());
+    assert!(!info.interest_intersections.is_empty());
+    assert!(!info.reconciled.is_empty());
+    assert!(info.channels.iter().any(|channel| channel.bytes_sent > 0));
+    assert!(info
+        .channels
+        .iter()
+        .any(|channel| channel.bytes_received > 0));
+
+    // The same information is available over RPC.
+    let peers = alfie.client().peers().await?;
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].node_id, betty_node_id);
+    assert_eq!(peers[0].conn, ConnStatus::Active);
+    assert_eq!(
+        peers[0].session.as_ref().map(|info| info.role),
+        Some(Role::Alfie)
+    );
+    let info = alfie.client().session_info(betty_node_id).await?;
+    assert_eq!(info.map(|info| info.mode), Some(SessionMode::Continuous));
+
+    intent.close().await;
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_subscribe_events() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_subscribe_events");
+
+    let [alfie, betty] = spawn_two(&mut rng).await?;
+    let (namespace, _alfie_user, betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let betty_node_id = betty.node_id();
+
+    insert(&betty, namespace, betty_user, &[b"foo"], "foo 1").await?;
+
+    let mut events = Box::pin(alfie.subscribe_events());
+    let mut rpc_events = Box::pin(alfie.client().subscribe_events().await?);
+
+    let init = SessionInit::reconcile_once(Interests::all());
+    let intent = alfie.sync_with_peer(betty_node_id, init).await?;
+    let completion = intent.complete().await?;
+    assert_eq!(completion, Completion::Complete);
+
+    let mut received = vec![];
+    while !matches!(received.last(), Some(EngineEvent::SessionCompleted { .. })) {
+        let (peer, event) = tokio::time::timeout(Duration::from_secs(2), events.next())
+            .await?
+            .expect("event stream ended");
+        assert_eq!(peer, betty_node_id);
+        received.push(event);
+    }
+    assert_eq!(
+        received[0],
+        EngineEvent::ConnectionEstablished { role: Role::Alfie }
+    );
+    assert_eq!(
+        received[1],
+        EngineEvent::SessionStarted { role: Role::Alfie }
+    );
+    assert!(received
+        .iter()
+        .any(|event| matches!(event, EngineEvent::CapabilityIntersection { namespace: n, .. } if *n == namespace)));
+    assert!(received.iter().any(
+        |event| matches!(event, EngineEvent::Reconciled { namespace: n, .. } if *n == namespace)
+    ));
+    assert_eq!(
+        received.last(),
+        Some(&EngineEvent::SessionCompleted { result: Ok(()) })
+    );
+
+    // The same events are streamed over RPC.
+    for expected in &received {
+        let (peer, event) = tokio::time::timeout(Duration::from_secs(2), rpc_events.next())
+            .await?
+            .expect("event stream ended")?;
+        assert_eq!(peer, betty_node_id);
+        assert_eq!(&event, expected);
+    }
+
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_manager_pending_connects_limit() -> Result<()> {
+    iroh_test::logging::setup_multithreaded();
+    let mut rng = create_rng("peer_manager_pending_connects_limit");
+
+    // Alfie may not dial out, but still accepts incoming connections.
+    let spawned = AtomicUsize::new(0);
+    let [alfie, betty] = spawn_two_with_opts(&mut rng, || {
+        if spawned.fetch_add(1, Ordering::SeqCst) == 0 {
+            AcceptOpts::default().connection_limits(ConnectionLimits {
+                max_pending_connects: Some(0),
+                queue_policy: QueuePolicy::Abort,
+                ..Default::default()
+            })
+        } else {
+            AcceptOpts::default()
+        }
+    })
+    .await?;
+    let (namespace, alfie_user, _betty_user) = setup_and_delegate(&alfie, &betty).await?;
+    let alfie_node_id = alfie.node_id();
+    let betty_node_id = betty.node_id();
+
+    insert(&alfie, namespace, alfie_user, &[b"foo"], "foo 1").await?;
+
+    let init = SessionInit::reconcile_once(Interests::all());
+    let mut intent = alfie.sync_with_peer(betty_node_id, init).await?;
+    let event = intent.next().await.unwrap();
+    assert!(
+        matches!(
+            &event,
+            EventKind::Abort { error } if error.to_string().contains("connection limits")
+        ),
+        "{event:?}"
+    );
+
+    let init = SessionInit::reconcile_once(Interests::all());
+    let mut intent = betty.sync_with_peer(alfie_node_id, init).await?;
+    intent.complete().await?;
+    let path = Path::from_bytes(&[b"foo"])?;
+    assert!(betty
+        .get_entry(namespace, alfie_user, path)
+        .await?
+        .is_some());
+
+    [alfie, betty].map(Peer::shutdown).try_join().await?;
+    Ok(())
+}
@@ -8,12 +8,15 @@ use iroh_willow::{
     engine::AcceptOpts,
     interest::{AreaOfInterestSelector, CapSelector, DelegateTo, RestrictArea},
     proto::{
-        data_model::{Path, PathExt},
+        data_model::{EntryExt, Path, PathExt},
         grouping::{Area, Range3d},
         keys::{NamespaceKind, UserId},
         meadowcap::AccessMode,
     },
-    rpc::client::{Client, EntryForm, Space},
+    rpc::{
+        client::{Client, EntryForm, Space},
+        proto::GetEntriesOpts,
+    },
     session::{intents::Completion, SessionMode},
     store::traits::{EntryOrigin, StoreEvent},
     Engine,
@@ -504,3 +507,186 @@ async fn test_restricted_area() -> testresult::TestResult {
     println!("{entries:#?}");
     Ok(())
 }
+
+#[tokio::test]
+async fn spaces_forget() -> TestResult {
+    iroh_test::logging::setup_multithreaded();
+    for persist in [false, true] {
+        let (_addr, client, blobs, _guard) = spawn_node(persist).await;
+        let user = client.create_user().await?;
+        let space = client.create(NamespaceKind::Owned, user).await?;
+
+        let mut sub = space
+            .subscribe_area(Area::new_full(), Default::default())
+            .await?;
+
+        for path in [&[b"a", b"1"][..], &[b"a", b"2"], &[b"b"], &[b"c"]] {
+            space
+                .insert_bytes(&blobs, EntryForm::new(user, Path::from_bytes(path)?), "hi")
+                .await?;
+        }
+        for _ in 0..4 {
+            let ev = sub.next().await.unwrap()?;
+            assert!(matches!(ev, StoreEvent::Ingested(..)));
+        }
+
+        // forget a single entry
+        let path = Path::from_bytes(&[b"b"])?;
+        assert!(space.forget_entry(user, path.clone()).await?);
+        assert!(space.get_one(user, path.clone()).await?.is_none());
+        assert!(!space.forget_entry(user, path).await?);
+        let ev = sub.next().await.unwrap()?;
+        assert!(matches!(ev, StoreEvent::EntryForgotten(4, _)));
+
+        // forget an area
+        let area = Area::new_path(Path::from_bytes(&[b"a"])?);
+        assert_eq!(space.forget_area(area).await?, 2);
+        let ev = sub.next().await.unwrap()?;
+        assert!(matches!(ev, StoreEvent::AreaForgotten(5, _, _)));
+
+        // forget a payload
+        let path = Path::from_bytes(&[b"c"])?;
+        let entry = space.get_one(user, path.clone()).await?.unwrap();
+        assert!(space.forget_payload(user, path.clone()).await?);
+        assert!(space.get_one(user, path).await?.is_some());
+        let hash: iroh_blobs::Hash = (*entry.entry().payload_digest()).into();
+        assert!(iroh_blobs::store::Map::get(&blobs, &hash).await?.is_none());
+        let ev = sub.next().await.unwrap()?;
+        assert!(matches!(ev, StoreEvent::PayloadForgotten(6, _)));
+
+        let entries: Vec<_> = space
+            .get_many(Range3d::new_full())
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(entries.len(), 1);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn spaces_delete() -> TestResult {
+    iroh_test::logging::setup_multithreaded();
+    for persist in [false, true] {
+        let (_addr, client, blobs, _guard) = spawn_node(persist).await;
+        let user = client.create_user().await?;
+        let space = client.create(NamespaceKind::Owned, user).await?;
+
+        for path in [&[b"a", b"1"][..], &[b"a", b"2"], &[b"b"]] {
+            space
+                .insert_bytes(&blobs, EntryForm::new(user, Path::from_bytes(path)?), "hi")
+                .await?;
+        }
+
+        let paths = |hide_tombstones| {
+            let space = space.clone();
+            async move {
+                let opts = GetEntriesOpts { hide_tombstones };
+                let entries: Vec<_> = space
+                    .get_many_with_opts(Range3d::new_full(), opts)
+                    .await?
+                    .try_collect()
+                    .await?;
+                let mut paths: Vec<_> = entries
+                    .into_iter()
+                    .map(|entry| entry.entry().path().fmt_utf8())
+                    .collect();
+                paths.sort();
+                anyhow::Ok(paths)
+            }
+        };
+
+        // delete a single entry
+        let path = Path::from_bytes(&[b"a", b"1"])?;
+        let tombstone = space.delete(user, path.clone(), user).await?.unwrap();
+        assert!(tombstone.entry().is_tombstone());
+        assert!(space
+            .get_one(user, path.clone())
+            .await?
+            .unwrap()
+            .entry()
+            .is_tombstone());
+        assert!(space.delete(user, path.clone(), user).await?.is_none());
+        assert_eq!(paths(true).await?, ["a/2", "b"]);
+        assert_eq!(paths(false).await?, ["a/1", "a/2", "b"]);
+
+        // delete a prefix
+        let prefix = Path::from_bytes(&[b"a"])?;
+        space.delete_prefix(user, prefix, user).await?.inserted()?;
+        assert!(space.get_one(user, path).await?.is_none());
+        assert_eq!(paths(true).await?, ["b"]);
+        assert_eq!(paths(false).await?, ["a", "b"]);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn spaces_gc_protects_payloads() -> TestResult {
+    iroh_test::logging::setup_multithreaded();
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    for persist in [false, true] {
+        let blobs_store = iroh_blobs::store::mem::Store::default();
+        let endpoint = Endpoint::builder()
+            .relay_mode(iroh::RelayMode::Disabled)
+            .bind()
+            .await?;
+        let store = blobs_store.clone();
+        let engine = if persist {
+            Engine::spawn(
+                endpoint.clone(),
+                move || {
+                    iroh_willow::store::persistent::Store::new_memory(store)
+                        .expect("couldn't initialize store")
+                },
+                AcceptOpts::default(),
+            )
+        } else {
+            Engine::spawn(
+                endpoint.clone(),
+                move || iroh_willow::store::memory::Store::new(store),
+                AcceptOpts::default(),
+            )
+        };
+        let blobs = iroh_blobs::net_protocol::Blobs::builder(blobs_store.clone()).build(&endpoint);
+        blobs.add_protected(engine.protect_cb())?;
+
+        let client = engine.client().clone().boxed();
+        let user = client.create_user().await?;
+        let space = client.create(NamespaceKind::Owned, user).await?;
+        let path = Path::from_bytes(&[b"referenced"])?;
+        space
+            .insert_bytes(&blobs_store, EntryForm::new(user, path), "referenced")
+            .await?;
+        let unreferenced = {
+            let tag = iroh_blobs::store::Store::import_bytes(
+                &blobs_store,
+                "unreferenced".into(),
+                iroh_blobs::BlobFormat::Raw,
+            )
+            .await?;
+            *tag.hash()
+        };
+
+        let (done_tx, mut done_rx) = tokio::sync::mpsc::unbounded_channel();
+        blobs.start_gc(iroh_blobs::store::GcConfig {
+            period: Duration::from_millis(10),
+            done_callback: Some(Box::new(move || {
+                done_tx.send(()).ok();
+            })),
+        })?;
+        // Wait for a full run after the blobs were added.
+        for _ in 0..2 {
+            tokio::time::timeout(TIMEOUT, done_rx.recv()).await?;
+        }
+
+        let referenced = iroh_blobs::Hash::new("referenced");
+        assert!(iroh_blobs::store::Map::get(&blobs_store, &referenced)
+            .await?
+            .is_some());
+        assert!(iroh_blobs::store::Map::get(&blobs_store, &unreferenced)
+            .await?
+            .is_none());
+        engine.shutdown().await?;
+    }
+    Ok(())
+}